@@ -0,0 +1,64 @@
+//! A command line client for a store service
+//!
+//! A service crate builds its command line client by calling [quic_rpc::cli::run] with
+//! its service type. E.g. to get a file from a store server listening on quinn:
+//!
+//! ```text
+//! cargo run --example cli --features cli,macros -- \
+//!     --quinn 127.0.0.1:12345 --cert server.der get_file '[0,0,...]'
+//! ```
+//!
+//! It also registers the schema of the service for the generic `quic-rpc-cli` binary,
+//! which does the same without the types of the service:
+//!
+//! ```text
+//! cargo run --example cli --features cli,macros -- --print-schema > store.json
+//! cargo run --bin quic-rpc-cli --features cli -- --schema store.json \
+//!     --quinn 127.0.0.1:12345 --cert server.der get_file '[0,0,...]'
+//! ```
+mod store_rpc {
+    use quic_rpc::rpc_service;
+    use serde::{Deserialize, Serialize};
+    use std::fmt::Debug;
+
+    pub type Cid = [u8; 32];
+
+    #[derive(Debug, Serialize, Deserialize)]
+    pub struct Put(pub Vec<u8>);
+    #[derive(Debug, Serialize, Deserialize)]
+    pub struct PutResponse(pub Cid);
+
+    #[derive(Debug, Serialize, Deserialize)]
+    pub struct Get(pub Cid);
+    #[derive(Debug, Serialize, Deserialize)]
+    pub struct GetResponse(pub Vec<u8>);
+
+    #[derive(Debug, Serialize, Deserialize)]
+    pub struct PutFile;
+    #[derive(Debug, Serialize, Deserialize)]
+    pub struct PutFileUpdate(pub Vec<u8>);
+    #[derive(Debug, Serialize, Deserialize)]
+    pub struct PutFileResponse(pub Cid);
+
+    #[derive(Debug, Serialize, Deserialize)]
+    pub struct GetFile(pub Cid);
+    #[derive(Debug, Serialize, Deserialize)]
+    pub struct GetFileResponse(pub Vec<u8>);
+
+    rpc_service! {
+        Request = StoreRequest;
+        Response = StoreResponse;
+        Service = StoreService;
+        CreateDispatch = _;
+
+        Rpc put = Put, _ -> PutResponse;
+        Rpc get = Get, _ -> GetResponse;
+        ClientStreaming put_file = PutFile, PutFileUpdate -> PutFileResponse;
+        ServerStreaming get_file = GetFile, _ -> GetFileResponse;
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    quic_rpc::cli::run::<store_rpc::StoreService>(std::env::args().skip(1)).await
+}
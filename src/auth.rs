@@ -0,0 +1,330 @@
+//! Authentication of the channels accepted by a server
+//!
+//! An [Authenticator] is set on a server using
+//! [RpcServer::with_authenticator](crate::RpcServer::with_authenticator). It is invoked
+//! for every accepted channel once the request has been received, before the channel is
+//! handed to the handler. It either returns the [Identity] of the client, which handlers
+//! get from [RpcChannel::identity](crate::server::RpcChannel::identity), or a [Status] to
+//! reject the channel with, see [ServerEndpoint::reject](crate::transport::ServerEndpoint::reject).
+//!
+//! Authenticators are provided for
+//! - bearer tokens checked by a function: [BearerAuth]
+//! - bearer tokens listed in a file: [TokenFile]
+//! - certificate fingerprints of quinn clients: [CertFingerprints]
+//!
+//! Once a channel is authenticated, a [Policy] decides which methods its identity may
+//! call. Requests for other methods are rejected with [Status::PermissionDenied] before
+//! the handler is called.
+//!
+//! Clients send a bearer token with every call by setting [bearer_metadata] as the
+//! metadata of the client, see [RpcClient::with_metadata](crate::RpcClient::with_metadata).
+//! The transport has to carry the metadata, e.g. the quinn transport only does with
+//! `metadata_frame` enabled on both sides. Otherwise the client logs a warning, and the
+//! server rejects all calls as [Status::Unauthenticated].
+use crate::transport::{Metadata, PeerInfo, Status};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt, io,
+    path::Path,
+};
+
+/// Who a client is, as established by an [Authenticator]
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Identity(String);
+
+impl Identity {
+    /// An identity with the given name
+    pub fn new(name: impl Into<String>) -> Self {
+        Self(name.into())
+    }
+
+    /// The name of the identity
+    pub fn name(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for Identity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// Checks the credentials of the channels accepted by a server
+pub trait Authenticator: fmt::Debug + Send + Sync + 'static {
+    /// Check the credentials of a channel, given what the transport knows about the
+    /// client and the metadata the client sent.
+    ///
+    /// This is called for every channel before it is handed to the handler, so it
+    /// should not block.
+    fn authenticate(&self, peer: &PeerInfo, metadata: &Metadata) -> Result<Identity, Status>;
+}
+
+/// The metadata key for bearer tokens, same as the http header
+pub const AUTHORIZATION: &str = "authorization";
+
+/// Metadata that contains a bearer token
+pub fn bearer_metadata(token: &str) -> Metadata {
+    [(AUTHORIZATION, format!("Bearer {token}"))]
+        .into_iter()
+        .collect()
+}
+
+/// The bearer token in the metadata, if any
+fn bearer_token(metadata: &Metadata) -> Option<&str> {
+    let (scheme, token) = metadata.get(AUTHORIZATION)?.split_once(' ')?;
+    if scheme.eq_ignore_ascii_case("bearer") {
+        Some(token.trim())
+    } else {
+        None
+    }
+}
+
+/// Authenticates bearer tokens using a function
+///
+/// The function gets the token and returns the identity of its owner, or None if the
+/// token is not valid. Channels without a bearer token are rejected.
+pub struct BearerAuth<F>(F);
+
+impl<F: Fn(&str) -> Option<Identity> + Send + Sync + 'static> BearerAuth<F> {
+    /// Authenticate bearer tokens using the function `f`
+    pub fn new(f: F) -> Self {
+        Self(f)
+    }
+}
+
+impl<F> fmt::Debug for BearerAuth<F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BearerAuth").finish()
+    }
+}
+
+impl<F: Fn(&str) -> Option<Identity> + Send + Sync + 'static> Authenticator for BearerAuth<F> {
+    fn authenticate(&self, _peer: &PeerInfo, metadata: &Metadata) -> Result<Identity, Status> {
+        bearer_token(metadata)
+            .and_then(&self.0)
+            .ok_or(Status::Unauthenticated)
+    }
+}
+
+/// Authenticates bearer tokens listed in a file
+///
+/// Every line of the file is a token and the name of its identity, separated by
+/// whitespace. Empty lines and lines starting with `#` are ignored. The file is only
+/// read when loading, so to pick up changes, load it again and create a new server.
+#[derive(Clone, Default)]
+pub struct TokenFile {
+    tokens: BTreeMap<String, Identity>,
+}
+
+impl TokenFile {
+    /// Load the tokens from a file
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::parse(&std::fs::read_to_string(path)?)
+    }
+
+    /// Parse the tokens from the contents of a token file
+    pub fn parse(contents: &str) -> io::Result<Self> {
+        let mut tokens = BTreeMap::new();
+        for (i, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut parts = line.split_whitespace();
+            match (parts.next(), parts.next(), parts.next()) {
+                (Some(token), Some(name), None) => {
+                    tokens.insert(token.to_string(), Identity::new(name));
+                }
+                _ => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("line {}: expected a token and a name", i + 1),
+                    ))
+                }
+            }
+        }
+        Ok(Self { tokens })
+    }
+}
+
+/// Does not print the tokens
+impl fmt::Debug for TokenFile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TokenFile")
+            .field("tokens", &self.tokens.len())
+            .finish()
+    }
+}
+
+impl Authenticator for TokenFile {
+    fn authenticate(&self, _peer: &PeerInfo, metadata: &Metadata) -> Result<Identity, Status> {
+        bearer_token(metadata)
+            .and_then(|token| self.tokens.get(token))
+            .cloned()
+            .ok_or(Status::Unauthenticated)
+    }
+}
+
+/// Authenticates clients by the SHA-256 fingerprint of their certificate
+///
+/// This is for mutual TLS with the quinn transport, which puts the certificate chain of
+/// the client in the [PeerInfo]. The quinn server config has to request client
+/// certificates, e.g. using [rustls::server::AllowAnyAuthenticatedClient]. Only the
+/// fingerprint of the leaf certificate is checked.
+#[cfg(feature = "quinn-transport")]
+#[derive(Debug, Clone, Default)]
+pub struct CertFingerprints {
+    fingerprints: BTreeMap<[u8; 32], Identity>,
+}
+
+#[cfg(feature = "quinn-transport")]
+impl CertFingerprints {
+    /// No known fingerprints
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a fingerprint and the identity of the client that has it
+    pub fn with_fingerprint(mut self, fingerprint: [u8; 32], identity: Identity) -> Self {
+        self.fingerprints.insert(fingerprint, identity);
+        self
+    }
+
+    /// The SHA-256 fingerprint of a DER encoded certificate
+    pub fn fingerprint(certificate: &[u8]) -> [u8; 32] {
+        let digest = ring::digest::digest(&ring::digest::SHA256, certificate);
+        let mut fingerprint = [0u8; 32];
+        fingerprint.copy_from_slice(digest.as_ref());
+        fingerprint
+    }
+}
+
+#[cfg(feature = "quinn-transport")]
+impl Authenticator for CertFingerprints {
+    fn authenticate(&self, peer: &PeerInfo, _metadata: &Metadata) -> Result<Identity, Status> {
+        peer.certificates
+            .first()
+            .and_then(|leaf| self.fingerprints.get(&Self::fingerprint(leaf)))
+            .cloned()
+            .ok_or(Status::Unauthenticated)
+    }
+}
+
+/// Which methods each identity may call
+///
+/// Methods are named by [Msg::method_name](crate::message::Msg::method_name). For services
+/// declared with the [rpc_service](crate::rpc_service) macro, this is the name of the
+/// request enum variant, e.g. `Sqr`. Otherwise it is the full path of the request type,
+/// e.g. `my_crate::compute::Sqr`.
+///
+/// A role grants a set of methods, and each identity has a set of roles. Methods can
+/// also be granted to a single identity directly. Role names and identity names are
+/// separate, so an identity never gets the methods of a role with the same name. The
+/// method `*` stands for all methods of the service.
+///
+/// The policy is set on a server using
+/// [RpcServer::with_policy](crate::RpcServer::with_policy). It can be built in rust, or
+/// loaded from a file like this:
+///
+/// ```toml
+/// [roles]
+/// admin = ["*"]
+/// user = ["Get", "Put"]
+///
+/// [identities]
+/// alice = ["admin"]
+/// bob = ["user"]
+///
+/// [grants]
+/// carol = ["Get"]
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Policy {
+    /// Methods granted by each role, by role name
+    #[serde(default)]
+    roles: BTreeMap<String, BTreeSet<String>>,
+    /// Roles of each identity, by identity name
+    #[serde(default)]
+    identities: BTreeMap<String, BTreeSet<String>>,
+    /// Methods granted to each identity directly, by identity name
+    #[serde(default)]
+    grants: BTreeMap<String, BTreeSet<String>>,
+}
+
+impl Policy {
+    /// A policy that allows nothing
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Grant a method to a role
+    pub fn allow(mut self, role: impl Into<String>, method: impl Into<String>) -> Self {
+        self.roles
+            .entry(role.into())
+            .or_default()
+            .insert(method.into());
+        self
+    }
+
+    /// Give an identity a role
+    pub fn assign(mut self, identity: impl Into<String>, role: impl Into<String>) -> Self {
+        self.identities
+            .entry(identity.into())
+            .or_default()
+            .insert(role.into());
+        self
+    }
+
+    /// Grant a method to an identity directly
+    pub fn grant(mut self, identity: impl Into<String>, method: impl Into<String>) -> Self {
+        self.grants
+            .entry(identity.into())
+            .or_default()
+            .insert(method.into());
+        self
+    }
+
+    /// Check if an identity may call a method
+    pub fn is_allowed(&self, identity: &Identity, method: &str) -> bool {
+        let name = identity.name();
+        let roles = self.identities.get(name).into_iter().flatten();
+        self.grants
+            .get(name)
+            .into_iter()
+            .chain(roles.filter_map(|role| self.roles.get(role)))
+            .any(|methods| methods.contains(method) || methods.contains("*"))
+    }
+
+    /// Parse a policy in toml format
+    #[cfg(feature = "toml")]
+    pub fn from_toml(contents: &str) -> io::Result<Self> {
+        toml::from_str(contents).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    /// Parse a policy in json format
+    #[cfg(feature = "serde_json")]
+    pub fn from_json(contents: &str) -> io::Result<Self> {
+        serde_json::from_str(contents).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    /// Load a policy from a `.toml` or `.json` file
+    ///
+    /// Each format needs the crate feature of the same name.
+    #[cfg(any(feature = "toml", feature = "serde_json"))]
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref();
+        let contents = std::fs::read_to_string(path)?;
+        match path.extension().and_then(|ext| ext.to_str()) {
+            #[cfg(feature = "toml")]
+            Some("toml") => Self::from_toml(&contents),
+            #[cfg(feature = "serde_json")]
+            Some("json") => Self::from_json(&contents),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("unsupported policy file: {}", path.display()),
+            )),
+        }
+    }
+}
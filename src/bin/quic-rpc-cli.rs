@@ -0,0 +1,8 @@
+//! Command line client for any quic-rpc service, given the schema of the service
+//!
+//! See [quic_rpc::cli] for how to get the schema, and `quic-rpc-cli --help` for the
+//! arguments.
+#[tokio::main(flavor = "current_thread")]
+async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    quic_rpc::cli::run_with_schema(std::env::args().skip(1)).await
+}
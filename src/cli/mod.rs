@@ -0,0 +1,478 @@
+//! A generic command line client that sends requests given as json
+//!
+//! Messages are encoded with bincode on the wire, which is not self-describing. So a
+//! command line client needs to know the request and response types of the service it
+//! talks to. There are two ways to get them:
+//!
+//! - The `quic-rpc-cli` binary of this crate, built with the `cli` feature, reads them
+//!   from a [ServiceSchema] file. The service crate registers its
+//!   schema by tracing its messages with [ServiceSchema::of]
+//!   and storing the result as json, e.g. from a test, a build step, or a binary of its
+//!   own using `--print-schema`. Its service has to implement [Reflect], which
+//!   [rpc_service](crate::rpc_service) does for you.
+//! - A binary in the service crate calls [run] with the service type, and uses the serde
+//!   implementations of its request and response enums directly. The
+//!   [cli example](https://github.com/n0-computer/quic-rpc/blob/main/examples/cli.rs)
+//!   shows this for a store service. The binary is then just
+//!
+//! ```no_run
+//! # use quic_rpc::reflection::*;
+//! # #[derive(Debug, Clone)]
+//! # struct MyService;
+//! # impl quic_rpc::Service for MyService { type Req = (); type Res = (); }
+//! # impl Reflect for MyService {
+//! #     fn service_info() -> ServiceInfo { todo!() }
+//! # }
+//! #[tokio::main]
+//! async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+//!     quic_rpc::cli::run::<MyService>(std::env::args().skip(1)).await
+//! }
+//! ```
+//!
+//! See [run] and [run_with_schema] for the command line arguments.
+use crate::{
+    reflection::{PatternKind, Reflect, ServiceInfo},
+    transport::{hyper::HyperConnection, quinn::QuinnConnection, ConnectionErrors},
+    Service, ServiceConnection,
+};
+use futures::{
+    future,
+    stream::{self, BoxStream},
+    SinkExt, Stream, StreamExt,
+};
+use serde_json::Value;
+use std::{error, fmt, marker::PhantomData, net::SocketAddr, result, sync::Arc};
+use tokio::io::{AsyncBufReadExt, BufReader};
+
+pub mod schema;
+
+use schema::{DynamicService, ServiceSchema};
+
+/// Usage of the command line client
+pub const USAGE: &str = "\
+usage: <cli> [--schema <file>] (--quinn <addr> --cert <file> [--server-name <name>] | --hyper <uri>) <method> [<json>]
+       <cli> [--schema <file>] (--methods | --print-schema)
+
+  --quinn <addr>          connect to a quinn endpoint at addr
+  --cert <file>           DER encoded certificate of the quinn endpoint
+  --server-name <name>    name in the certificate of the quinn endpoint, default localhost
+  --hyper <uri>           connect to a hyper endpoint at uri
+  --schema <file>         json file with the schema of the service, required by quic-rpc-cli
+  --methods               print the methods of the service as json lines
+  --print-schema          print the schema of the service as json, for quic-rpc-cli
+  <method>                method name or request type name
+  <json>                  the request as json, default null
+
+For client and bidi streaming methods, updates are read from stdin as json lines.
+Responses are printed to stdout as json lines.";
+
+/// Error when calling a method with json messages
+#[derive(Debug)]
+pub enum CallError<C: ConnectionErrors> {
+    /// The service has no method with this name
+    UnknownMethod(String),
+    /// The method uses an interaction pattern that can not be driven by json
+    UnsupportedPattern(PatternKind),
+    /// A message could not be converted from or to json
+    Json(serde_json::Error),
+    /// Unable to open a channel
+    Open(C::OpenError),
+    /// Unable to send the request or an update
+    Send(C::SendError),
+    /// Unable to receive a response
+    Recv(C::RecvError),
+    /// The server closed the channel without sending a response
+    EarlyClose,
+}
+
+impl<C: ConnectionErrors> fmt::Display for CallError<C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self, f)
+    }
+}
+
+impl<C: ConnectionErrors> error::Error for CallError<C> {}
+
+/// Call a method of service `S` with json messages
+///
+/// The method is looked up by method name or request type name in the
+/// [ServiceInfo] of `S`. For methods with updates, `updates` is sent to the server. The
+/// returned stream yields the responses as json.
+pub async fn call<S, C>(
+    connection: &C,
+    method: &str,
+    request: Value,
+    updates: impl Stream<Item = Value> + Send + 'static,
+) -> result::Result<BoxStream<'static, result::Result<Value, CallError<C>>>, CallError<C>>
+where
+    S: Reflect,
+    C: ServiceConnection<S>,
+{
+    call_with(
+        connection,
+        Arc::new(Typed::<S>::new()),
+        method,
+        request,
+        updates,
+    )
+    .await
+}
+
+/// Call a method of the service described by `schema` with json messages
+///
+/// Like [call], for clients that do not have the types of the service.
+pub async fn call_with_schema<C>(
+    connection: &C,
+    schema: &ServiceSchema,
+    method: &str,
+    request: Value,
+    updates: impl Stream<Item = Value> + Send + 'static,
+) -> result::Result<BoxStream<'static, result::Result<Value, CallError<C>>>, CallError<C>>
+where
+    C: ServiceConnection<DynamicService>,
+{
+    call_with(
+        connection,
+        Arc::new(schema.clone()),
+        method,
+        request,
+        updates,
+    )
+    .await
+}
+
+async fn call_with<S, C, K>(
+    connection: &C,
+    codec: Arc<K>,
+    method: &str,
+    request: Value,
+    updates: impl Stream<Item = Value> + Send + 'static,
+) -> result::Result<BoxStream<'static, result::Result<Value, CallError<C>>>, CallError<C>>
+where
+    S: Service,
+    C: ServiceConnection<S>,
+    K: Codec<S>,
+{
+    let method = codec
+        .service_info()
+        .methods
+        .into_iter()
+        .find(|m| m.name == method || m.request == method)
+        .ok_or_else(|| CallError::UnknownMethod(method.to_string()))?;
+    if method.pattern == PatternKind::RawStreaming {
+        return Err(CallError::UnsupportedPattern(method.pattern));
+    }
+    let request = codec
+        .request(&method.request, request)
+        .map_err(CallError::Json)?;
+    let (mut send, recv) = connection.open_bi().await.map_err(CallError::Open)?;
+    send.send(request).await.map_err(CallError::Send)?;
+    let response = method.response.unwrap_or_default();
+    let responses = recv.map({
+        let codec = codec.clone();
+        move |res| {
+            let res = res.map_err(CallError::Recv)?;
+            codec.response(&response, res).map_err(CallError::Json)
+        }
+    });
+    let single = matches!(
+        method.pattern,
+        PatternKind::Rpc | PatternKind::ClientStreaming
+    );
+    let early_close = stream::once(future::ready(Err(CallError::EarlyClose)));
+    let responses = if single {
+        responses.chain(early_close).take(1).left_stream()
+    } else {
+        responses.right_stream()
+    };
+    Ok(match method.update {
+        Some(update) => {
+            // send the updates concurrently with receiving the responses, and only
+            // report errors from sending
+            let forward = async move {
+                tokio::pin!(updates);
+                while let Some(value) = updates.next().await {
+                    let value = codec.request(&update, value).map_err(CallError::Json)?;
+                    send.send(value).await.map_err(CallError::Send)?;
+                }
+                send.close().await.map_err(CallError::Send)
+            };
+            let forward = stream::once(forward).filter_map(|res| future::ready(res.err().map(Err)));
+            stream::select(forward, responses).boxed()
+        }
+        None => {
+            // closing the send side would cancel the request, so keep it open
+            // until all responses are received
+            responses
+                .map(move |res| {
+                    let _send = &send;
+                    res
+                })
+                .boxed()
+        }
+    })
+}
+
+/// Converts the messages of service `S` from and to json
+trait Codec<S: Service>: Send + Sync + 'static {
+    /// The methods of the service
+    fn service_info(&self) -> ServiceInfo;
+
+    /// The schema of the service, for `--print-schema`
+    fn schema(&self) -> result::Result<ServiceSchema, schema::SchemaError>;
+
+    /// The request or update variant with the given name
+    fn request(&self, variant: &str, value: Value) -> serde_json::Result<S::Req>;
+
+    /// The value of a response, which is expected to be of the variant with the given name
+    fn response(&self, variant: &str, response: S::Res) -> serde_json::Result<Value>;
+}
+
+/// Uses the serde implementations of the messages of `S`
+struct Typed<S>(PhantomData<fn() -> S>);
+
+impl<S> Typed<S> {
+    fn new() -> Self {
+        Self(PhantomData)
+    }
+}
+
+impl<S: Reflect> Codec<S> for Typed<S> {
+    fn service_info(&self) -> ServiceInfo {
+        S::service_info()
+    }
+
+    fn schema(&self) -> result::Result<ServiceSchema, schema::SchemaError> {
+        ServiceSchema::of::<S>()
+    }
+
+    fn request(&self, variant: &str, value: Value) -> serde_json::Result<S::Req> {
+        from_json(variant, value)
+    }
+
+    fn response(&self, variant: &str, response: S::Res) -> serde_json::Result<Value> {
+        to_json(variant, response)
+    }
+}
+
+/// Encodes the messages as described by the schema
+impl Codec<DynamicService> for ServiceSchema {
+    fn service_info(&self) -> ServiceInfo {
+        self.service.clone()
+    }
+
+    fn schema(&self) -> result::Result<ServiceSchema, schema::SchemaError> {
+        Ok(self.clone())
+    }
+
+    fn request(&self, variant: &str, value: Value) -> serde_json::Result<schema::Frame> {
+        self.encode_request(variant, value)
+    }
+
+    fn response(&self, variant: &str, response: schema::Frame) -> serde_json::Result<Value> {
+        Ok(unwrap_variant(variant, self.decode_response(&response)?))
+    }
+}
+
+/// Wrap the value in the variant with the given name and deserialize it
+fn from_json<T: serde::de::DeserializeOwned>(variant: &str, value: Value) -> serde_json::Result<T> {
+    let mut map = serde_json::Map::new();
+    map.insert(variant.to_string(), value);
+    serde_json::from_value(Value::Object(map))
+}
+
+/// Serialize the value, and unwrap it from the variant with the given name
+fn to_json<T: serde::Serialize>(variant: &str, value: T) -> serde_json::Result<Value> {
+    Ok(unwrap_variant(variant, serde_json::to_value(value)?))
+}
+
+/// The value of a variant with the given name, or the value itself if it is a different
+/// variant
+fn unwrap_variant(variant: &str, value: Value) -> Value {
+    match value {
+        Value::Object(mut map) if map.len() == 1 && map.contains_key(variant) => {
+            map.remove(variant).unwrap_or_default()
+        }
+        value => value,
+    }
+}
+
+/// Errors of [run]
+type BoxError = Box<dyn error::Error + Send + Sync>;
+
+/// Run the command line client for service `S`
+///
+/// `args` are the command line arguments without the program name, see [USAGE]. The
+/// schema of `S` is known, so `--schema` is not accepted.
+pub async fn run<S: Reflect>(
+    args: impl IntoIterator<Item = String>,
+) -> result::Result<(), BoxError> {
+    let args = Args::parse(args)?;
+    if args.schema.is_some() {
+        return Err("--schema is only used by clients without the service types".into());
+    }
+    args.run(Typed::<S>::new()).await
+}
+
+/// Run the command line client for the service described by the `--schema` file
+///
+/// This is the `quic-rpc-cli` binary. `args` are the command line arguments without the
+/// program name, see [USAGE].
+pub async fn run_with_schema(
+    args: impl IntoIterator<Item = String>,
+) -> result::Result<(), BoxError> {
+    let args = Args::parse(args)?;
+    if args.command == Command::Help {
+        println!("{USAGE}");
+        return Ok(());
+    }
+    let file = args.schema.as_ref().ok_or("--schema is required")?;
+    let schema: ServiceSchema = serde_json::from_slice(&std::fs::read(file)?)?;
+    args.run(schema).await
+}
+
+/// What the command line asks for
+#[derive(Debug, PartialEq, Eq)]
+enum Command {
+    Help,
+    Methods,
+    PrintSchema,
+    Call { method: String, request: Value },
+}
+
+/// The parsed command line
+#[derive(Debug)]
+struct Args {
+    quinn: Option<SocketAddr>,
+    cert: Option<String>,
+    server_name: String,
+    hyper: Option<hyper::Uri>,
+    schema: Option<String>,
+    command: Command,
+}
+
+impl Args {
+    fn parse(args: impl IntoIterator<Item = String>) -> result::Result<Self, BoxError> {
+        let mut quinn = None;
+        let mut cert = None;
+        let mut server_name = "localhost".to_string();
+        let mut hyper = None;
+        let mut schema = None;
+        let mut command = None;
+        let mut positional = Vec::new();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let mut value = || {
+                args.next()
+                    .ok_or_else(|| format!("missing value for {arg}"))
+            };
+            match arg.as_str() {
+                "--quinn" => quinn = Some(value()?.parse::<SocketAddr>()?),
+                "--cert" => cert = Some(value()?),
+                "--server-name" => server_name = value()?,
+                "--hyper" => hyper = Some(value()?.parse::<hyper::Uri>()?),
+                "--schema" => schema = Some(value()?),
+                "--methods" => command = Some(Command::Methods),
+                "--print-schema" => command = Some(Command::PrintSchema),
+                "--help" | "-h" => command = Some(Command::Help),
+                other => positional.push(other.to_string()),
+            }
+        }
+        let command = match (command, positional.as_slice()) {
+            (Some(command), _) => command,
+            (None, [method]) => Command::Call {
+                method: method.clone(),
+                request: Value::Null,
+            },
+            (None, [method, request]) => Command::Call {
+                method: method.clone(),
+                request: serde_json::from_str(request)?,
+            },
+            _ => return Err(USAGE.into()),
+        };
+        Ok(Self {
+            quinn,
+            cert,
+            server_name,
+            hyper,
+            schema,
+            command,
+        })
+    }
+
+    async fn run<S: Service, K: Codec<S>>(self, codec: K) -> result::Result<(), BoxError> {
+        let (method, request) = match self.command {
+            Command::Help => {
+                println!("{USAGE}");
+                return Ok(());
+            }
+            Command::Methods => {
+                for method in codec.service_info().methods {
+                    println!("{}", serde_json::to_string(&method)?);
+                }
+                return Ok(());
+            }
+            Command::PrintSchema => {
+                println!("{}", serde_json::to_string(&codec.schema()?)?);
+                return Ok(());
+            }
+            Command::Call { method, request } => (method, request),
+        };
+        let codec = Arc::new(codec);
+        match (self.quinn, self.hyper) {
+            (Some(addr), None) => {
+                let cert = self.cert.ok_or("--cert is required for --quinn")?;
+                let mut roots = rustls::RootCertStore::empty();
+                roots.add(&rustls::Certificate(std::fs::read(cert)?))?;
+                let bind_addr: SocketAddr = match addr {
+                    SocketAddr::V4(_) => "0.0.0.0:0".parse()?,
+                    SocketAddr::V6(_) => "[::]:0".parse()?,
+                };
+                let mut endpoint = quinn::Endpoint::client(bind_addr)?;
+                endpoint
+                    .set_default_client_config(quinn::ClientConfig::with_root_certificates(roots));
+                let connection =
+                    QuinnConnection::<S::Res, S::Req>::new(endpoint, addr, self.server_name);
+                print_responses(&connection, codec, &method, request).await
+            }
+            (None, Some(uri)) => {
+                let connection = HyperConnection::<S::Res, S::Req>::new(uri);
+                print_responses(&connection, codec, &method, request).await
+            }
+            _ => Err(USAGE.into()),
+        }
+    }
+}
+
+async fn print_responses<S, C, K>(
+    connection: &C,
+    codec: Arc<K>,
+    method: &str,
+    request: Value,
+) -> result::Result<(), BoxError>
+where
+    S: Service,
+    C: ServiceConnection<S>,
+    K: Codec<S>,
+{
+    let mut responses = call_with(connection, codec, method, request, stdin_lines()).await?;
+    while let Some(response) = responses.next().await {
+        println!("{}", serde_json::to_string(&response?)?);
+    }
+    Ok(())
+}
+
+/// Json values from stdin, one per line, ending at the first invalid line
+fn stdin_lines() -> impl Stream<Item = Value> + Send + 'static {
+    let lines = BufReader::new(tokio::io::stdin()).lines();
+    stream::unfold(lines, |mut lines| async move {
+        let line = lines.next_line().await.ok()??;
+        match serde_json::from_str(&line) {
+            Ok(value) => Some((value, lines)),
+            Err(cause) => {
+                tracing::warn!("invalid update {:?}: {}", line, cause);
+                None
+            }
+        }
+    })
+}
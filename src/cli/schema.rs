@@ -0,0 +1,832 @@
+//! Descriptions of the messages of a service, for clients without its types
+//!
+//! The `quic-rpc-cli` binary does not link the crate of the service it talks to, so it
+//! can not use the serde implementations of the request and response enums. Instead, the
+//! service crate describes them in a [ServiceSchema], by tracing the serde
+//! implementations of the messages with [serde_reflection], and stores it as json. The
+//! binary then encodes json requests and decodes responses using the schema. The formats
+//! of the schema are the ones of [serde_reflection], without the parts that are only
+//! needed while tracing.
+//!
+//! The schema describes the bincode encoding of the quinn and hyper transports. Since
+//! bincode is not self-describing, messages whose serde implementations depend on the
+//! data, e.g. untagged enums, can not be described.
+use crate::{
+    reflection::{Reflect, ServiceInfo},
+    Service,
+};
+use bincode::Options;
+use serde::{
+    de::{self, DeserializeSeed, EnumAccess, MapAccess, SeqAccess, VariantAccess, Visitor},
+    ser::{self, SerializeMap, SerializeSeq, SerializeTuple},
+    Deserialize, Deserializer, Serialize, Serializer,
+};
+use serde_json::{Map, Value};
+use serde_reflection::{Tracer, TracerConfig};
+use std::{collections::BTreeMap, error, fmt, iter, result};
+
+/// The request and response enums of a service, and the types they contain
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ServiceSchema {
+    /// The methods of the service
+    pub service: ServiceInfo,
+    /// Name of the request enum in the registry
+    pub request: String,
+    /// Name of the response enum in the registry
+    pub response: String,
+    /// The formats of the types of the service, by name
+    pub registry: Registry,
+}
+
+impl ServiceSchema {
+    /// Describe the messages of service `S`
+    ///
+    /// Fails if the messages contain other enums, since the variants of nested enums are
+    /// only found by tracing them on their own. Use [Self::with_tracer] for these.
+    pub fn of<S: Reflect>() -> result::Result<Self, SchemaError> {
+        Self::with_tracer::<S>(Tracer::new(TracerConfig::default()))
+    }
+
+    /// Describe the messages of service `S`, using a tracer that already knows some types
+    ///
+    /// Enums nested in the messages need to be traced with [Tracer::trace_simple_type]
+    /// before, so all their variants are known.
+    pub fn with_tracer<S: Reflect>(mut tracer: Tracer) -> result::Result<Self, SchemaError> {
+        let (request, _) = tracer.trace_simple_type::<S::Req>()?;
+        let (response, _) = tracer.trace_simple_type::<S::Res>()?;
+        let registry = tracer
+            .registry()?
+            .into_iter()
+            .map(|(name, container)| Ok((name, container.try_into()?)))
+            .collect::<result::Result<_, SchemaError>>()?;
+        Ok(Self {
+            service: S::service_info(),
+            request: enum_name(request)?,
+            response: enum_name(response)?,
+            registry,
+        })
+    }
+
+    /// Encode `value` as the variant of the request enum with the given name
+    pub fn encode_request(&self, variant: &str, value: Value) -> serde_json::Result<Frame> {
+        let mut map = Map::new();
+        map.insert(variant.to_string(), value);
+        let format = Format::TypeName(self.request.clone());
+        let message = Encode {
+            registry: &self.registry,
+            format: &format,
+            value: &Value::Object(map),
+        };
+        bincode_options()
+            .serialize(&message)
+            .map(Frame)
+            .map_err(ser::Error::custom)
+    }
+
+    /// Decode a response into json, as an object with the variant name as the only key
+    pub fn decode_response(&self, frame: &Frame) -> serde_json::Result<Value> {
+        let format = Format::TypeName(self.response.clone());
+        let message = Decode {
+            registry: &self.registry,
+            format: &format,
+        };
+        bincode_options()
+            .deserialize_seed(message, &frame.0)
+            .map_err(de::Error::custom)
+    }
+}
+
+/// The name of the enum a message type was traced as
+fn enum_name(format: serde_reflection::Format) -> result::Result<String, SchemaError> {
+    match format {
+        serde_reflection::Format::TypeName(name) => Ok(name),
+        other => Err(SchemaError(format!(
+            "messages have to be enums, not {other:?}"
+        ))),
+    }
+}
+
+/// Error when describing the messages of a service
+#[derive(Debug)]
+pub struct SchemaError(String);
+
+impl fmt::Display for SchemaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl error::Error for SchemaError {}
+
+impl From<serde_reflection::Error> for SchemaError {
+    fn from(cause: serde_reflection::Error) -> Self {
+        Self(cause.to_string())
+    }
+}
+
+/// The formats of the types of a service, by name
+pub type Registry = BTreeMap<String, ContainerFormat>;
+
+/// The format of a type, see [serde_reflection::Format]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Format {
+    /// A struct or enum in the [Registry]
+    TypeName(String),
+    /// `()`
+    Unit,
+    /// `bool`
+    Bool,
+    /// `i8`
+    I8,
+    /// `i16`
+    I16,
+    /// `i32`
+    I32,
+    /// `i64`
+    I64,
+    /// `i128`
+    I128,
+    /// `u8`
+    U8,
+    /// `u16`
+    U16,
+    /// `u32`
+    U32,
+    /// `u64`
+    U64,
+    /// `u128`
+    U128,
+    /// `f32`
+    F32,
+    /// `f64`
+    F64,
+    /// `char`
+    Char,
+    /// A string
+    Str,
+    /// Bytes, e.g. of `serde_bytes::ByteBuf`
+    Bytes,
+    /// An `Option` of the format
+    Option(Box<Format>),
+    /// A sequence of the format, e.g. `Vec<Format>`
+    Seq(Box<Format>),
+    /// A map, e.g. `BTreeMap<Key, Value>`
+    Map {
+        /// The format of the keys
+        key: Box<Format>,
+        /// The format of the values
+        value: Box<Format>,
+    },
+    /// A tuple of the formats
+    Tuple(Vec<Format>),
+    /// An array, e.g. `[Content; size]`
+    TupleArray {
+        /// The format of the elements
+        content: Box<Format>,
+        /// The number of elements
+        size: usize,
+    },
+}
+
+/// The format of a struct or enum, see [serde_reflection::ContainerFormat]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ContainerFormat {
+    /// A struct without fields, e.g. `struct Unit;`
+    UnitStruct,
+    /// A struct with a single unnamed field
+    NewTypeStruct(Box<Format>),
+    /// A struct with unnamed fields
+    TupleStruct(Vec<Format>),
+    /// A struct with named fields
+    Struct(Vec<Named<Format>>),
+    /// An enum, with the variants by index
+    Enum(BTreeMap<u32, Named<VariantFormat>>),
+}
+
+/// A named field or variant
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Named<T> {
+    /// The name of the field or variant
+    pub name: String,
+    /// Its format
+    pub value: T,
+}
+
+/// The format of an enum variant, see [serde_reflection::VariantFormat]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum VariantFormat {
+    /// A variant without fields
+    Unit,
+    /// A variant with a single unnamed field
+    NewType(Box<Format>),
+    /// A variant with unnamed fields
+    Tuple(Vec<Format>),
+    /// A variant with named fields
+    Struct(Vec<Named<Format>>),
+}
+
+/// The error for the placeholders of formats that are not known, which are only left
+/// if tracing is incomplete
+fn incomplete() -> SchemaError {
+    SchemaError("incomplete tracing".to_string())
+}
+
+impl TryFrom<serde_reflection::Format> for Format {
+    type Error = SchemaError;
+
+    fn try_from(format: serde_reflection::Format) -> result::Result<Self, SchemaError> {
+        use serde_reflection::Format as F;
+        Ok(match format {
+            F::Variable(_) => return Err(incomplete()),
+            F::TypeName(name) => Self::TypeName(name),
+            F::Unit => Self::Unit,
+            F::Bool => Self::Bool,
+            F::I8 => Self::I8,
+            F::I16 => Self::I16,
+            F::I32 => Self::I32,
+            F::I64 => Self::I64,
+            F::I128 => Self::I128,
+            F::U8 => Self::U8,
+            F::U16 => Self::U16,
+            F::U32 => Self::U32,
+            F::U64 => Self::U64,
+            F::U128 => Self::U128,
+            F::F32 => Self::F32,
+            F::F64 => Self::F64,
+            F::Char => Self::Char,
+            F::Str => Self::Str,
+            F::Bytes => Self::Bytes,
+            F::Option(format) => Self::Option(Box::new((*format).try_into()?)),
+            F::Seq(format) => Self::Seq(Box::new((*format).try_into()?)),
+            F::Map { key, value } => Self::Map {
+                key: Box::new((*key).try_into()?),
+                value: Box::new((*value).try_into()?),
+            },
+            F::Tuple(formats) => Self::Tuple(convert_all(formats)?),
+            F::TupleArray { content, size } => Self::TupleArray {
+                content: Box::new((*content).try_into()?),
+                size,
+            },
+        })
+    }
+}
+
+impl TryFrom<serde_reflection::ContainerFormat> for ContainerFormat {
+    type Error = SchemaError;
+
+    fn try_from(container: serde_reflection::ContainerFormat) -> result::Result<Self, SchemaError> {
+        use serde_reflection::ContainerFormat as C;
+        Ok(match container {
+            C::UnitStruct => Self::UnitStruct,
+            C::NewTypeStruct(format) => Self::NewTypeStruct(Box::new((*format).try_into()?)),
+            C::TupleStruct(formats) => Self::TupleStruct(convert_all(formats)?),
+            C::Struct(fields) => Self::Struct(convert_all(fields)?),
+            C::Enum(variants) => Self::Enum(
+                variants
+                    .into_iter()
+                    .map(|(index, variant)| Ok((index, variant.try_into()?)))
+                    .collect::<result::Result<_, SchemaError>>()?,
+            ),
+        })
+    }
+}
+
+impl TryFrom<serde_reflection::VariantFormat> for VariantFormat {
+    type Error = SchemaError;
+
+    fn try_from(variant: serde_reflection::VariantFormat) -> result::Result<Self, SchemaError> {
+        use serde_reflection::VariantFormat as V;
+        Ok(match variant {
+            V::Variable(_) => return Err(incomplete()),
+            V::Unit => Self::Unit,
+            V::NewType(format) => Self::NewType(Box::new((*format).try_into()?)),
+            V::Tuple(formats) => Self::Tuple(convert_all(formats)?),
+            V::Struct(fields) => Self::Struct(convert_all(fields)?),
+        })
+    }
+}
+
+impl<T, U: TryFrom<T, Error = SchemaError>> TryFrom<serde_reflection::Named<T>> for Named<U> {
+    type Error = SchemaError;
+
+    fn try_from(named: serde_reflection::Named<T>) -> result::Result<Self, SchemaError> {
+        Ok(Self {
+            name: named.name,
+            value: named.value.try_into()?,
+        })
+    }
+}
+
+fn convert_all<T, U: TryFrom<T, Error = SchemaError>>(
+    items: Vec<T>,
+) -> result::Result<Vec<U>, SchemaError> {
+    items.into_iter().map(U::try_from).collect()
+}
+
+/// The bincode options of the quinn and hyper transports
+fn bincode_options() -> impl Options {
+    bincode::DefaultOptions::new().with_fixint_encoding()
+}
+
+/// A service whose messages are the encoded bytes of the messages of another service
+///
+/// This is the service the `quic-rpc-cli` binary talks to, see [ServiceSchema] for how
+/// the messages are encoded.
+#[derive(Debug, Clone)]
+pub struct DynamicService;
+
+impl Service for DynamicService {
+    type Req = Frame;
+    type Res = Frame;
+}
+
+/// An encoded message
+///
+/// Is serialized as the bytes themselves, without a length, so it ends up on the wire
+/// just like the message it encodes. Bincode can not tell how long the message is, so
+/// deserializing it takes all the remaining input.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame(pub Vec<u8>);
+
+impl Serialize for Frame {
+    fn serialize<S: Serializer>(&self, serializer: S) -> result::Result<S::Ok, S::Error> {
+        // bincode writes the elements of a tuple without a length
+        let mut tuple = serializer.serialize_tuple(self.0.len())?;
+        for byte in &self.0 {
+            tuple.serialize_element(byte)?;
+        }
+        tuple.end()
+    }
+}
+
+impl<'de> Deserialize<'de> for Frame {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> result::Result<Self, D::Error> {
+        struct FrameVisitor;
+
+        impl<'de> Visitor<'de> for FrameVisitor {
+            type Value = Frame;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("the bytes of a message")
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> result::Result<Frame, A::Error> {
+                let mut bytes = Vec::new();
+                // the end of the input is the only way to find the end of the message
+                while let Ok(Some(byte)) = seq.next_element() {
+                    bytes.push(byte);
+                }
+                Ok(Frame(bytes))
+            }
+        }
+
+        deserializer.deserialize_tuple(usize::MAX, FrameVisitor)
+    }
+}
+
+/// A json value that is serialized in the given format
+///
+/// Bincode does not write names, so all names are left empty. Structs are written as
+/// tuples, and tuple and struct variants as newtype variants holding a tuple, which is
+/// the same in bincode.
+struct Encode<'a> {
+    registry: &'a Registry,
+    format: &'a Format,
+    value: &'a Value,
+}
+
+impl<'a> Encode<'a> {
+    fn nested(&self, format: &'a Format, value: &'a Value) -> Self {
+        Self {
+            registry: self.registry,
+            format,
+            value,
+        }
+    }
+
+    /// The elements of a tuple, or the fields of a struct, in order
+    fn fields(
+        &self,
+        formats: impl IntoIterator<Item = &'a Format>,
+        values: Vec<&'a Value>,
+    ) -> Fields<'a> {
+        Fields {
+            registry: self.registry,
+            items: formats.into_iter().zip(values).collect(),
+        }
+    }
+
+    fn container<S: Serializer>(
+        &self,
+        container: &'a ContainerFormat,
+        serializer: S,
+    ) -> result::Result<S::Ok, S::Error> {
+        match container {
+            ContainerFormat::UnitStruct => serializer.serialize_unit(),
+            ContainerFormat::NewTypeStruct(format) => {
+                serializer.serialize_newtype_struct("", &self.nested(format, self.value))
+            }
+            ContainerFormat::TupleStruct(formats) => {
+                let values = array(self.value, formats.len())?;
+                self.fields(formats, values).serialize(serializer)
+            }
+            ContainerFormat::Struct(fields) => {
+                let values = struct_values(self.value, fields)?;
+                self.fields(fields.iter().map(|f| &f.value), values)
+                    .serialize(serializer)
+            }
+            ContainerFormat::Enum(variants) => {
+                let (name, value) = match self.value {
+                    Value::String(name) => (name, &Value::Null),
+                    Value::Object(map) if map.len() == 1 => map.iter().next().unwrap(),
+                    other => return Err(expected("a variant", other)),
+                };
+                let (index, variant) =
+                    variants
+                        .iter()
+                        .find(|(_, variant)| &variant.name == name)
+                        .ok_or_else(|| ser::Error::custom(format!("unknown variant {name}")))?;
+                match &variant.value {
+                    VariantFormat::Unit => serializer.serialize_unit_variant("", *index, ""),
+                    VariantFormat::NewType(format) => serializer.serialize_newtype_variant(
+                        "",
+                        *index,
+                        "",
+                        &self.nested(format, value),
+                    ),
+                    VariantFormat::Tuple(formats) => {
+                        let values = array(value, formats.len())?;
+                        let fields = self.fields(formats, values);
+                        serializer.serialize_newtype_variant("", *index, "", &fields)
+                    }
+                    VariantFormat::Struct(fields) => {
+                        let values = struct_values(value, fields)?;
+                        let fields = self.fields(fields.iter().map(|f| &f.value), values);
+                        serializer.serialize_newtype_variant("", *index, "", &fields)
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl Serialize for Encode<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> result::Result<S::Ok, S::Error> {
+        let value = self.value;
+        match self.format {
+            Format::TypeName(name) => {
+                let container = self
+                    .registry
+                    .get(name)
+                    .ok_or_else(|| ser::Error::custom(format!("unknown type {name}")))?;
+                self.container(container, serializer)
+            }
+            Format::Unit => serializer.serialize_unit(),
+            Format::Bool => serializer.serialize_bool(parse(value)?),
+            Format::I8 => serializer.serialize_i8(parse(value)?),
+            Format::I16 => serializer.serialize_i16(parse(value)?),
+            Format::I32 => serializer.serialize_i32(parse(value)?),
+            Format::I64 => serializer.serialize_i64(parse(value)?),
+            Format::I128 => serializer.serialize_i128(parse(value)?),
+            Format::U8 => serializer.serialize_u8(parse(value)?),
+            Format::U16 => serializer.serialize_u16(parse(value)?),
+            Format::U32 => serializer.serialize_u32(parse(value)?),
+            Format::U64 => serializer.serialize_u64(parse(value)?),
+            Format::U128 => serializer.serialize_u128(parse(value)?),
+            Format::F32 => serializer.serialize_f32(parse(value)?),
+            Format::F64 => serializer.serialize_f64(parse(value)?),
+            Format::Char => serializer.serialize_char(parse(value)?),
+            Format::Str => serializer.serialize_str(&parse::<String, _>(value)?),
+            Format::Bytes => serializer.serialize_bytes(&parse::<Vec<u8>, _>(value)?),
+            Format::Option(format) => match value {
+                Value::Null => serializer.serialize_none(),
+                value => serializer.serialize_some(&self.nested(format, value)),
+            },
+            Format::Seq(format) => {
+                let items = value
+                    .as_array()
+                    .ok_or_else(|| expected("an array", value))?;
+                let mut seq = serializer.serialize_seq(Some(items.len()))?;
+                for item in items {
+                    seq.serialize_element(&self.nested(format, item))?;
+                }
+                seq.end()
+            }
+            Format::Map {
+                key: key_format,
+                value: value_format,
+            } => {
+                let entries = value
+                    .as_object()
+                    .ok_or_else(|| expected("an object", value))?;
+                let mut map = serializer.serialize_map(Some(entries.len()))?;
+                for (key, value) in entries {
+                    // json keys are strings, other keys are given as json in the string
+                    let key = match key_format.as_ref() {
+                        Format::Str => Value::String(key.clone()),
+                        _ => serde_json::from_str(key).map_err(ser::Error::custom)?,
+                    };
+                    map.serialize_entry(
+                        &self.nested(key_format, &key),
+                        &self.nested(value_format, value),
+                    )?;
+                }
+                map.end()
+            }
+            Format::Tuple(formats) => {
+                let values = array(value, formats.len())?;
+                self.fields(formats, values).serialize(serializer)
+            }
+            Format::TupleArray { content, size } => {
+                let values = array(value, *size)?;
+                self.fields(iter::repeat(content.as_ref()), values)
+                    .serialize(serializer)
+            }
+        }
+    }
+}
+
+/// Json values that are serialized as a tuple of the given formats
+struct Fields<'a> {
+    registry: &'a Registry,
+    items: Vec<(&'a Format, &'a Value)>,
+}
+
+impl Serialize for Fields<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> result::Result<S::Ok, S::Error> {
+        let mut tuple = serializer.serialize_tuple(self.items.len())?;
+        for (format, value) in &self.items {
+            tuple.serialize_element(&Encode {
+                registry: self.registry,
+                format,
+                value,
+            })?;
+        }
+        tuple.end()
+    }
+}
+
+/// Convert a json value to a primitive type
+fn parse<T: de::DeserializeOwned, E: ser::Error>(value: &Value) -> result::Result<T, E> {
+    T::deserialize(value).map_err(E::custom)
+}
+
+fn expected<E: ser::Error>(what: &str, value: &Value) -> E {
+    E::custom(format!("expected {what}, got {value}"))
+}
+
+/// The elements of a json array of the given length
+fn array<E: ser::Error>(value: &Value, len: usize) -> result::Result<Vec<&Value>, E> {
+    match value {
+        Value::Array(items) if items.len() == len => Ok(items.iter().collect()),
+        other => Err(expected(&format!("an array of {len} elements"), other)),
+    }
+}
+
+/// The fields of a json object, in the order of the struct. Missing fields are null, so
+/// optional fields can be left out.
+fn struct_values<'a, E: ser::Error>(
+    value: &'a Value,
+    fields: &[Named<Format>],
+) -> result::Result<Vec<&'a Value>, E> {
+    let map = value
+        .as_object()
+        .ok_or_else(|| expected("an object", value))?;
+    Ok(fields
+        .iter()
+        .map(|field| map.get(&field.name).unwrap_or(&Value::Null))
+        .collect())
+}
+
+/// Deserializes a value in the given format into json
+///
+/// This is the counterpart of [Encode]. Unit variants become strings, the other variants
+/// objects with the variant name as the only key, like serde_json does.
+#[derive(Clone, Copy)]
+struct Decode<'a> {
+    registry: &'a Registry,
+    format: &'a Format,
+}
+
+impl<'a> Decode<'a> {
+    fn nested(&self, format: &'a Format) -> Self {
+        Self {
+            registry: self.registry,
+            format,
+        }
+    }
+
+    fn fields(&self, formats: impl IntoIterator<Item = &'a Format>) -> DecodeFields<'a> {
+        DecodeFields {
+            registry: self.registry,
+            formats: formats.into_iter().collect(),
+        }
+    }
+
+    fn container<'de, D: Deserializer<'de>>(
+        self,
+        container: &'a ContainerFormat,
+        deserializer: D,
+    ) -> result::Result<Value, D::Error> {
+        match container {
+            ContainerFormat::UnitStruct => json(<()>::deserialize(deserializer)?),
+            ContainerFormat::NewTypeStruct(format) => self.nested(format).deserialize(deserializer),
+            ContainerFormat::TupleStruct(formats) => {
+                deserializer.deserialize_tuple(formats.len(), self.fields(formats))
+            }
+            ContainerFormat::Struct(fields) => {
+                let formats = fields.iter().map(|f| &f.value);
+                let values = deserializer.deserialize_tuple(fields.len(), self.fields(formats))?;
+                Ok(struct_object(fields, values))
+            }
+            ContainerFormat::Enum(variants) => deserializer.deserialize_enum(
+                "",
+                &[],
+                DecodeEnum {
+                    registry: self.registry,
+                    variants,
+                },
+            ),
+        }
+    }
+}
+
+impl<'de> DeserializeSeed<'de> for Decode<'_> {
+    type Value = Value;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> result::Result<Value, D::Error> {
+        match self.format {
+            Format::TypeName(name) => {
+                let container = self
+                    .registry
+                    .get(name)
+                    .ok_or_else(|| de::Error::custom(format!("unknown type {name}")))?;
+                self.container(container, deserializer)
+            }
+            Format::Unit => json(<()>::deserialize(deserializer)?),
+            Format::Bool => json(bool::deserialize(deserializer)?),
+            Format::I8 => json(i8::deserialize(deserializer)?),
+            Format::I16 => json(i16::deserialize(deserializer)?),
+            Format::I32 => json(i32::deserialize(deserializer)?),
+            Format::I64 => json(i64::deserialize(deserializer)?),
+            Format::I128 => json(i128::deserialize(deserializer)?),
+            Format::U8 => json(u8::deserialize(deserializer)?),
+            Format::U16 => json(u16::deserialize(deserializer)?),
+            Format::U32 => json(u32::deserialize(deserializer)?),
+            Format::U64 => json(u64::deserialize(deserializer)?),
+            Format::U128 => json(u128::deserialize(deserializer)?),
+            Format::F32 => json(f32::deserialize(deserializer)?),
+            Format::F64 => json(f64::deserialize(deserializer)?),
+            Format::Char => json(char::deserialize(deserializer)?),
+            Format::Str => json(String::deserialize(deserializer)?),
+            // bincode writes bytes like a sequence of u8
+            Format::Bytes => json(Vec::<u8>::deserialize(deserializer)?),
+            Format::Option(format) => deserializer.deserialize_option(self.nested(format)),
+            Format::Seq(format) => deserializer.deserialize_seq(self.nested(format)),
+            Format::Map { .. } => deserializer.deserialize_map(self),
+            Format::Tuple(formats) => {
+                deserializer.deserialize_tuple(formats.len(), self.fields(formats))
+            }
+            Format::TupleArray { content, size } => deserializer.deserialize_tuple(
+                *size,
+                self.fields(iter::repeat(content.as_ref()).take(*size)),
+            ),
+        }
+    }
+}
+
+/// Visits options, with [Decode::format] as the format of the value, sequences, with
+/// it as the format of the elements, and maps
+impl<'de> Visitor<'de> for Decode<'_> {
+    type Value = Value;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "a value of format {:?}", self.format)
+    }
+
+    fn visit_none<E: de::Error>(self) -> result::Result<Value, E> {
+        Ok(Value::Null)
+    }
+
+    fn visit_some<D: Deserializer<'de>>(self, deserializer: D) -> result::Result<Value, D::Error> {
+        self.deserialize(deserializer)
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> result::Result<Value, A::Error> {
+        let mut items = Vec::new();
+        while let Some(item) = seq.next_element_seed(self)? {
+            items.push(item);
+        }
+        Ok(Value::Array(items))
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut access: A) -> result::Result<Value, A::Error> {
+        let (key_format, value_format) = match self.format {
+            Format::Map { key, value } => (key, value),
+            other => return Err(de::Error::custom(format!("{other:?} is not a map"))),
+        };
+        let mut map = Map::new();
+        while let Some(key) = access.next_key_seed(self.nested(key_format))? {
+            // json keys are strings, other keys are given as json in the string
+            let key = match key {
+                Value::String(key) => key,
+                key => key.to_string(),
+            };
+            map.insert(key, access.next_value_seed(self.nested(value_format))?);
+        }
+        Ok(Value::Object(map))
+    }
+}
+
+/// Deserializes a tuple of the given formats into a json array
+struct DecodeFields<'a> {
+    registry: &'a Registry,
+    formats: Vec<&'a Format>,
+}
+
+impl<'de> Visitor<'de> for DecodeFields<'_> {
+    type Value = Value;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "a tuple of {} elements", self.formats.len())
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> result::Result<Value, A::Error> {
+        let mut items = Vec::with_capacity(self.formats.len());
+        for (i, format) in self.formats.iter().enumerate() {
+            let decode = Decode {
+                registry: self.registry,
+                format,
+            };
+            let item = seq
+                .next_element_seed(decode)?
+                .ok_or_else(|| de::Error::invalid_length(i, &self))?;
+            items.push(item);
+        }
+        Ok(Value::Array(items))
+    }
+}
+
+/// Deserializes an enum into json
+struct DecodeEnum<'a> {
+    registry: &'a Registry,
+    variants: &'a BTreeMap<u32, Named<VariantFormat>>,
+}
+
+impl<'de> Visitor<'de> for DecodeEnum<'_> {
+    type Value = Value;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("an enum variant")
+    }
+
+    fn visit_enum<A: EnumAccess<'de>>(self, data: A) -> result::Result<Value, A::Error> {
+        let (index, access) = data.variant::<u32>()?;
+        let variant = self
+            .variants
+            .get(&index)
+            .ok_or_else(|| de::Error::custom(format!("unknown variant index {index}")))?;
+        let decode = Decode {
+            registry: self.registry,
+            format: &Format::Unit,
+        };
+        let value = match &variant.value {
+            VariantFormat::Unit => {
+                access.unit_variant()?;
+                return Ok(Value::String(variant.name.clone()));
+            }
+            VariantFormat::NewType(format) => access.newtype_variant_seed(decode.nested(format))?,
+            VariantFormat::Tuple(formats) => {
+                access.tuple_variant(formats.len(), decode.fields(formats))?
+            }
+            // bincode reads struct variants like tuple variants
+            VariantFormat::Struct(fields) => {
+                let formats = fields.iter().map(|f| &f.value);
+                let values = access.tuple_variant(fields.len(), decode.fields(formats))?;
+                struct_object(fields, values)
+            }
+        };
+        let mut map = Map::new();
+        map.insert(variant.name.clone(), value);
+        Ok(Value::Object(map))
+    }
+}
+
+/// Convert a primitive value to json
+fn json<T: Serialize, E: de::Error>(value: T) -> result::Result<Value, E> {
+    serde_json::to_value(value).map_err(E::custom)
+}
+
+/// Name the elements of a json array after the fields of a struct
+fn struct_object(fields: &[Named<Format>], values: Value) -> Value {
+    let values = match values {
+        Value::Array(values) => values,
+        other => return other,
+    };
+    let map = fields
+        .iter()
+        .map(|field| field.name.clone())
+        .zip(values)
+        .collect();
+    Value::Object(map)
+}
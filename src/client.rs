@@ -2,22 +2,34 @@
 //!
 //! The main entry point is [RpcClient].
 use crate::{
-    message::{BidiStreamingMsg, ClientStreamingMsg, RpcMsg, ServerStreamingMsg},
-    transport::ConnectionErrors,
+    message::{
+        BidiStreamingMsg, ClientStreamingMsg, Msg, RawStreamingMsg, RpcMsg, ServerStreamingMsg,
+        StreamFrame,
+    },
+    metrics::VariantName,
+    telemetry::{CallSpan, Side, Telemetry},
+    transport::{ConnectionErrors, Metadata, RawStreams, SizeLimits, Status},
+    version::{Version, Versioned},
     Service, ServiceConnection,
 };
 use futures::{
-    future::BoxFuture, stream::BoxStream, FutureExt, Sink, SinkExt, Stream, StreamExt, TryFutureExt,
+    future::{self, BoxFuture},
+    stream::BoxStream,
+    Future, FutureExt, Sink, SinkExt, Stream, StreamExt, TryFutureExt,
 };
 use pin_project::pin_project;
 use std::{
     error,
     fmt::{self, Debug},
+    io,
     marker::PhantomData,
     pin::Pin,
     result,
+    sync::{Arc, Mutex, Weak},
     task::{Context, Poll},
 };
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tracing::Instrument;
 
 /// A client for a specific service
 ///
@@ -26,6 +38,9 @@ use std::{
 #[derive(Debug)]
 pub struct RpcClient<S, C> {
     source: C,
+    telemetry: Telemetry,
+    metadata: Metadata,
+    version: Option<Version>,
     p: PhantomData<S>,
 }
 
@@ -33,6 +48,9 @@ impl<S, C: Clone> Clone for RpcClient<S, C> {
     fn clone(&self) -> Self {
         Self {
             source: self.source.clone(),
+            telemetry: self.telemetry.clone(),
+            metadata: self.metadata.clone(),
+            version: self.version.clone(),
             p: PhantomData,
         }
     }
@@ -40,31 +58,50 @@ impl<S, C: Clone> Clone for RpcClient<S, C> {
 
 /// Sink that can be used to send updates to the server for the two interaction patterns
 /// that support it, [crate::message::ClientStreaming] and [crate::message::BidiStreaming].
-#[pin_project]
+///
+/// Use [UpdateSink::finish] to signal the end of the updates while still receiving the
+/// response. Dropping the sink has the same effect on all built-in transports, but does
+/// not report errors.
+///
+/// Dropping the response side of the call before it has ended cancels the call, see
+/// [ConnectionCommon::cancel](crate::transport::ConnectionCommon::cancel). This is only
+/// possible while the sink exists, since the sink owns the send side of the channel.
 #[derive(Debug)]
 pub struct UpdateSink<S: Service, C: ServiceConnection<S>, T: Into<S::Req>>(
-    #[pin] C::SendSink,
+    Arc<Mutex<C::SendSink>>,
     PhantomData<T>,
 );
 
+impl<S: Service, C: ServiceConnection<S>, T: Into<S::Req>> UpdateSink<S, C, T> {
+    /// Signal that there will be no more updates.
+    ///
+    /// This closes the send side of the channel, e.g. by finishing the quinn send stream,
+    /// ending the hyper request body or dropping the flume sender. The receive side stays
+    /// open, so the response is still delivered. The server sees the end of the updates as
+    /// a clean finish, as opposed to an abort due to an error.
+    pub async fn finish(self) -> result::Result<(), C::SendError> {
+        future::poll_fn(|cx| self.0.lock().unwrap().poll_close_unpin(cx)).await
+    }
+}
+
 impl<S: Service, C: ServiceConnection<S>, T: Into<S::Req>> Sink<T> for UpdateSink<S, C, T> {
     type Error = C::SendError;
 
     fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
-        self.project().0.poll_ready_unpin(cx)
+        self.0.lock().unwrap().poll_ready_unpin(cx)
     }
 
     fn start_send(self: Pin<&mut Self>, item: T) -> Result<(), Self::Error> {
         let req: S::Req = item.into();
-        self.project().0.start_send_unpin(req)
+        self.0.lock().unwrap().start_send_unpin(req)
     }
 
     fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
-        self.project().0.poll_flush_unpin(cx)
+        self.0.lock().unwrap().poll_flush_unpin(cx)
     }
 
     fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
-        self.project().0.poll_close_unpin(cx)
+        self.0.lock().unwrap().poll_close_unpin(cx)
     }
 }
 
@@ -76,30 +113,138 @@ impl<S: Service, C: ServiceConnection<S>> RpcClient<S, C> {
     pub fn new(source: C) -> Self {
         Self {
             source,
+            telemetry: Telemetry::default(),
+            metadata: Metadata::default(),
+            version: None,
             p: PhantomData,
         }
     }
 
+    /// Set the tracing configuration for the calls of this client.
+    ///
+    /// Default is [Telemetry::default], which creates a debug level span for every call.
+    pub fn with_telemetry(mut self, telemetry: Telemetry) -> Self {
+        self.telemetry = telemetry;
+        self
+    }
+
+    /// Set metadata that is sent with every call, e.g. credentials.
+    ///
+    /// The trace context is added to it for every call. Default is empty. Logs a warning
+    /// if the connection drops metadata, see
+    /// [Connection::carries_metadata](crate::transport::Connection::carries_metadata).
+    pub fn with_metadata(mut self, metadata: Metadata) -> Self {
+        if !metadata.is_empty() {
+            self.warn_if_dropped("metadata");
+        }
+        self.metadata = metadata;
+        self
+    }
+
+    /// Send the name and schema fingerprint of the service with every call, so servers
+    /// that check them reject the calls if they were built for a different version.
+    ///
+    /// See [crate::version]. Default is to not send them. Logs a warning if the connection
+    /// drops metadata, since servers then reject all calls.
+    pub fn with_handshake(mut self) -> Self
+    where
+        S: Versioned,
+    {
+        self.warn_if_dropped("version");
+        self.version = Some(Version::of::<S>());
+        self
+    }
+
+    /// Warn that `what` will not reach the server, if the connection drops metadata
+    fn warn_if_dropped(&self, what: &str) {
+        if !self.source.carries_metadata() {
+            tracing::warn!(
+                "The connection does not carry metadata, the {} is not sent to the server",
+                what
+            );
+        }
+    }
+
     /// Get the underlying connection
     pub fn into_inner(self) -> C {
         self.source
     }
 
+    /// The metadata for a call: the metadata of the client, the version of the service
+    /// if enabled, and the trace context of the call
+    ///
+    /// None if there is nothing to send.
+    fn call_metadata(&self, call: &CallSpan) -> Option<Metadata> {
+        if self.metadata.is_empty() && self.version.is_none() && !self.telemetry.has_metadata(call)
+        {
+            return None;
+        }
+        let mut metadata = self.metadata.clone();
+        if let Some(version) = &self.version {
+            version.to_metadata(&mut metadata);
+        }
+        Some(self.telemetry.metadata(call, metadata))
+    }
+
+    /// Open a channel for the message `M`, limited to the message sizes it declares
+    fn open<M: Msg<S>>(
+        &self,
+        metadata: Option<Metadata>,
+    ) -> impl Future<Output = result::Result<(C::SendSink, C::RecvStream), C::OpenError>> {
+        let open = match metadata {
+            Some(metadata) => self.source.open_bi_with_metadata(metadata),
+            None => self.source.open_bi(),
+        };
+        open.map_ok(|(mut send, mut recv)| {
+            let limits = SizeLimits {
+                send: M::MAX_REQUEST_SIZE,
+                recv: M::MAX_RESPONSE_SIZE,
+            };
+            let fits = C::limit_message_size(&mut send, &mut recv, limits);
+            // nothing has been received on a new channel, so there is nothing to reject
+            debug_assert!(fits, "message received before the request was sent");
+            (send, recv)
+        })
+    }
+
     /// RPC call to the server, single request, single response
     pub async fn rpc<M>(&self, msg: M) -> result::Result<M::Response, RpcClientError<C>>
     where
         M: RpcMsg<S>,
     {
+        let call = self.telemetry.start::<S, M>(Side::Client);
+        let metadata = self.call_metadata(&call);
         let msg = msg.into();
-        let (mut send, mut recv) = self.source.open_bi().await.map_err(RpcClientError::Open)?;
+        // chain the futures instead of awaiting them in a block, so the future of the call
+        // does not store the channel next to the future that opens it
+        let res = self
+            .open::<M>(metadata)
+            .map_err(RpcClientError::Open)
+            .and_then(|(send, recv)| self.exchange::<M>(send, recv, msg))
+            .instrument(call.span())
+            .await;
+        call.finish(res)
+    }
+
+    /// Send the request of a rpc call and receive the response
+    async fn exchange<M: RpcMsg<S>>(
+        &self,
+        mut send: C::SendSink,
+        mut recv: C::RecvStream,
+        msg: S::Req,
+    ) -> result::Result<M::Response, RpcClientError<C>> {
         send.send(msg).await.map_err(RpcClientError::<C>::Send)?;
-        let res = recv
-            .next()
-            .await
-            .ok_or(RpcClientError::<C>::EarlyClose)?
-            .map_err(RpcClientError::<C>::RecvError)?;
-        // keep send alive until we have the answer
-        drop(send);
+        // keep send alive until we have the answer, and cancel if dropped before
+        let cancel = CancelGuard::<S, C>::new(send);
+        let res = recv.next().await;
+        cancel.done();
+        let res =
+            res.ok_or(RpcClientError::<C>::EarlyClose)?.map_err(|e| {
+                match rejected_version::<S, C>(&self.version, &e) {
+                    Some(version) => RpcClientError::IncompatibleVersion(version),
+                    None => RpcClientError::RecvError(e),
+                }
+            })?;
         M::Response::try_from(res).map_err(|_| RpcClientError::DowncastError)
     }
 
@@ -114,23 +259,36 @@ impl<S: Service, C: ServiceConnection<S>> RpcClient<S, C> {
     where
         M: ServerStreamingMsg<S>,
     {
+        let call = self.telemetry.start::<S, M>(Side::Client);
+        let metadata = self.call_metadata(&call);
         let msg = msg.into();
-        let (mut send, recv) = self
-            .source
-            .open_bi()
-            .await
-            .map_err(StreamingResponseError::Open)?;
-        send.send(msg)
-            .map_err(StreamingResponseError::<C>::Send)
-            .await?;
-        let recv = recv.map(move |x| match x {
+        let (send, recv) = call.check(
+            async move {
+                let (mut send, recv) = self
+                    .open::<M>(metadata)
+                    .await
+                    .map_err(StreamingResponseError::Open)?;
+                send.send(msg)
+                    .map_err(StreamingResponseError::<C>::Send)
+                    .await?;
+                Ok((send, recv))
+            }
+            .instrument(call.span())
+            .await,
+        )?;
+        // keep send alive until the end of the responses, and cancel if dropped before
+        let cancel = CancelGuard::<S, C>::new(send);
+        let version = self.version.clone();
+        let recv = Counted::new(recv, call, C::received_size, cancel, move |x| match x {
             Ok(x) => {
                 M::Response::try_from(x).map_err(|_| StreamingResponseItemError::DowncastError)
             }
-            Err(e) => Err(StreamingResponseItemError::RecvError(e)),
-        });
-        // keep send alive so the request on the server side does not get cancelled
-        let recv = DeferDrop(recv, send).boxed();
+            Err(e) => Err(match rejected_version::<S, C>(&version, &e) {
+                Some(version) => StreamingResponseItemError::IncompatibleVersion(version),
+                None => StreamingResponseItemError::RecvError(e),
+            }),
+        })
+        .boxed();
         Ok(recv)
     }
 
@@ -148,27 +306,43 @@ impl<S: Service, C: ServiceConnection<S>> RpcClient<S, C> {
     where
         M: ClientStreamingMsg<S>,
     {
+        let call = self.telemetry.start::<S, M>(Side::Client);
+        let metadata = self.call_metadata(&call);
         let msg = msg.into();
-        let (mut send, mut recv) = self
-            .source
-            .open_bi()
-            .await
-            .map_err(ClientStreamingError::Open)?;
-        send.send(msg).map_err(ClientStreamingError::Send).await?;
+        let (send, mut recv) = call.check(
+            async move {
+                let (mut send, recv) = self
+                    .open::<M>(metadata)
+                    .await
+                    .map_err(ClientStreamingError::Open)?;
+                send.send(msg).map_err(ClientStreamingError::Send).await?;
+                Ok((send, recv))
+            }
+            .instrument(call.span())
+            .await,
+        )?;
+        let send = Arc::new(Mutex::new(send));
+        let cancel = CancelGuard::<S, C>::shared(&send);
         let send = UpdateSink::<S, C, M::Update>(send, PhantomData);
+        let span = call.span();
+        let version = self.version.clone();
         let recv = async move {
-            let item = recv
-                .next()
-                .await
-                .ok_or(ClientStreamingItemError::EarlyClose)?;
+            let item = recv.next().await;
+            cancel.done();
+            let item = item.ok_or(ClientStreamingItemError::EarlyClose)?;
 
             match item {
                 Ok(x) => {
                     M::Response::try_from(x).map_err(|_| ClientStreamingItemError::DowncastError)
                 }
-                Err(e) => Err(ClientStreamingItemError::RecvError(e)),
+                Err(e) => Err(match rejected_version::<S, C>(&version, &e) {
+                    Some(version) => ClientStreamingItemError::IncompatibleVersion(version),
+                    None => ClientStreamingItemError::RecvError(e),
+                }),
             }
         }
+        .instrument(span)
+        .map(move |res| call.finish(res))
         .boxed();
         Ok((send, recv))
     }
@@ -187,18 +361,106 @@ impl<S: Service, C: ServiceConnection<S>> RpcClient<S, C> {
     where
         M: BidiStreamingMsg<S>,
     {
+        let call = self.telemetry.start::<S, M>(Side::Client);
+        let metadata = self.call_metadata(&call);
         let msg = msg.into();
-        let (mut send, recv) = self.source.open_bi().await.map_err(BidiError::Open)?;
-        send.send(msg).await.map_err(BidiError::<C>::Send)?;
+        let (send, recv) = call.check(
+            async move {
+                let (mut send, recv) = self.open::<M>(metadata).await.map_err(BidiError::Open)?;
+                send.send(msg).await.map_err(BidiError::<C>::Send)?;
+                Ok((send, recv))
+            }
+            .instrument(call.span())
+            .await,
+        )?;
+        let send = Arc::new(Mutex::new(send));
+        let cancel = CancelGuard::<S, C>::shared(&send);
         let send = UpdateSink(send, PhantomData);
-        let recv = recv
-            .map(|x| match x {
-                Ok(x) => M::Response::try_from(x).map_err(|_| BidiItemError::DowncastError),
-                Err(e) => Err(BidiItemError::RecvError(e)),
-            })
-            .boxed();
+        let version = self.version.clone();
+        let recv = Counted::new(recv, call, C::received_size, cancel, move |x| match x {
+            Ok(x) => M::Response::try_from(x).map_err(|_| BidiItemError::DowncastError),
+            Err(e) => Err(match rejected_version::<S, C>(&version, &e) {
+                Some(version) => BidiItemError::IncompatibleVersion(version),
+                None => BidiItemError::RecvError(e),
+            }),
+        })
+        .boxed();
         Ok((send, recv))
     }
+
+    /// Server streaming call to the server, where the response stream ends with a trailer
+    ///
+    /// The returned [TrailerStream] yields the items, and makes the trailer available once
+    /// the stream has ended. If the server closes the stream without sending a trailer,
+    /// the stream yields [StreamingResponseItemError::EarlyClose].
+    pub async fn server_streaming_with_trailer<M, T, U>(
+        &self,
+        msg: M,
+    ) -> result::Result<TrailerStream<T, U, StreamingResponseItemError<C>>, StreamingResponseError<C>>
+    where
+        M: ServerStreamingMsg<S, Response = StreamFrame<T, U>>,
+    {
+        let recv = self.server_streaming(msg).await?;
+        Ok(TrailerStream::new(recv, || {
+            StreamingResponseItemError::EarlyClose
+        }))
+    }
+
+    /// Bidi call to the server, where the response stream ends with a trailer
+    ///
+    /// The returned [TrailerStream] yields the items, and makes the trailer available once
+    /// the stream has ended. If the server closes the stream without sending a trailer,
+    /// the stream yields [BidiItemError::EarlyClose].
+    pub async fn bidi_with_trailer<M, T, U>(
+        &self,
+        msg: M,
+    ) -> result::Result<
+        (
+            UpdateSink<S, C, M::Update>,
+            TrailerStream<T, U, BidiItemError<C>>,
+        ),
+        BidiError<C>,
+    >
+    where
+        M: BidiStreamingMsg<S, Response = StreamFrame<T, U>>,
+    {
+        let (send, recv) = self.bidi(msg).await?;
+        Ok((send, TrailerStream::new(recv, || BidiItemError::EarlyClose)))
+    }
+
+    /// Raw streaming call to the server, request is followed by raw bytes in both directions
+    ///
+    /// Only available for connections that implement [RawStreams]. The call lasts until
+    /// the end of the raw receive side, see [RawStream].
+    pub async fn raw_streaming<M>(
+        &self,
+        msg: M,
+    ) -> result::Result<(RawStream<C::RawSend>, RawStream<C::RawRecv>), RawStreamingError<C>>
+    where
+        M: RawStreamingMsg<S>,
+        C: RawStreams<S::Res, S::Req>,
+    {
+        let call = self.telemetry.start::<S, M>(Side::Client);
+        let metadata = self.call_metadata(&call);
+        let msg = msg.into();
+        let (send, recv) = call.check(
+            async move {
+                let (mut send, recv) = self
+                    .open::<M>(metadata)
+                    .await
+                    .map_err(RawStreamingError::Open)?;
+                send.send(msg).await.map_err(RawStreamingError::Send)?;
+                Ok((send, recv))
+            }
+            .instrument(call.span())
+            .await,
+        )?;
+        let (send, recv) = C::into_raw(send, recv);
+        Ok((
+            RawStream::new(send, call.clone()),
+            RawStream::new(recv, call),
+        ))
+    }
 }
 
 impl<S: Service, C: ServiceConnection<S>> AsRef<C> for RpcClient<S, C> {
@@ -207,6 +469,80 @@ impl<S: Service, C: ServiceConnection<S>> AsRef<C> for RpcClient<S, C> {
     }
 }
 
+/// A stream of responses that ends with a trailer
+///
+/// Yields the items of the stream. Once the stream has ended, the trailer is available
+/// using [TrailerStream::trailer]. If the stream is closed before the trailer has been
+/// received, the last item is an early close error.
+#[pin_project]
+pub struct TrailerStream<T, U, E> {
+    inner: BoxStream<'static, result::Result<StreamFrame<T, U>, E>>,
+    trailer: Option<U>,
+    done: bool,
+    early_close: fn() -> E,
+}
+
+impl<T, U: Debug, E> fmt::Debug for TrailerStream<T, U, E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TrailerStream")
+            .field("trailer", &self.trailer)
+            .field("done", &self.done)
+            .finish()
+    }
+}
+
+impl<T, U, E> TrailerStream<T, U, E> {
+    fn new(
+        inner: BoxStream<'static, result::Result<StreamFrame<T, U>, E>>,
+        early_close: fn() -> E,
+    ) -> Self {
+        Self {
+            inner,
+            trailer: None,
+            done: false,
+            early_close,
+        }
+    }
+
+    /// The trailer, if the stream has ended normally
+    pub fn trailer(&self) -> Option<&U> {
+        self.trailer.as_ref()
+    }
+
+    /// Consume the stream and return the trailer, if the stream has ended normally
+    pub fn into_trailer(self) -> Option<U> {
+        self.trailer
+    }
+}
+
+impl<T, U, E> Stream for TrailerStream<T, U, E> {
+    type Item = result::Result<T, E>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.project();
+        if *this.done {
+            return Poll::Ready(None);
+        }
+        match this.inner.poll_next_unpin(cx) {
+            Poll::Ready(Some(Ok(StreamFrame::Item(item)))) => Poll::Ready(Some(Ok(item))),
+            Poll::Ready(Some(Ok(StreamFrame::Trailer(trailer)))) => {
+                *this.trailer = Some(trailer);
+                *this.done = true;
+                Poll::Ready(None)
+            }
+            Poll::Ready(Some(Err(cause))) => {
+                *this.done = true;
+                Poll::Ready(Some(Err(cause)))
+            }
+            Poll::Ready(None) => {
+                *this.done = true;
+                Poll::Ready(Some(Err((this.early_close)())))
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
 /// Client error. All client DSL methods return a `Result` with this error type.
 #[derive(Debug)]
 pub enum RpcClientError<C: ConnectionErrors> {
@@ -220,6 +556,10 @@ pub enum RpcClientError<C: ConnectionErrors> {
     RecvError(C::RecvError),
     /// Unexpected response from the server
     DowncastError,
+    /// The server was built for a different version of the service, see [crate::version]
+    ///
+    /// This contains the version the client sent.
+    IncompatibleVersion(Version),
 }
 
 impl<C: ConnectionErrors> fmt::Display for RpcClientError<C> {
@@ -230,6 +570,19 @@ impl<C: ConnectionErrors> fmt::Display for RpcClientError<C> {
 
 impl<C: ConnectionErrors> error::Error for RpcClientError<C> {}
 
+impl<C: ConnectionErrors> VariantName for RpcClientError<C> {
+    fn variant_name(&self) -> &'static str {
+        match self {
+            Self::Open(_) => "Open",
+            Self::Send(_) => "Send",
+            Self::EarlyClose => "EarlyClose",
+            Self::RecvError(_) => "RecvError",
+            Self::DowncastError => "DowncastError",
+            Self::IncompatibleVersion(_) => "IncompatibleVersion",
+        }
+    }
+}
+
 /// Server error when accepting a bidi request
 #[derive(Debug)]
 pub enum BidiError<C: ConnectionErrors> {
@@ -247,6 +600,15 @@ impl<C: ConnectionErrors> fmt::Display for BidiError<C> {
 
 impl<C: ConnectionErrors> error::Error for BidiError<C> {}
 
+impl<C: ConnectionErrors> VariantName for BidiError<C> {
+    fn variant_name(&self) -> &'static str {
+        match self {
+            Self::Open(_) => "Open",
+            Self::Send(_) => "Send",
+        }
+    }
+}
+
 /// Server error when receiving an item for a bidi request
 #[derive(Debug)]
 pub enum BidiItemError<C: ConnectionErrors> {
@@ -254,6 +616,12 @@ pub enum BidiItemError<C: ConnectionErrors> {
     RecvError(C::RecvError),
     /// Unexpected response from the server
     DowncastError,
+    /// Server closed the stream before sending the trailer
+    EarlyClose,
+    /// The server was built for a different version of the service, see [crate::version]
+    ///
+    /// This contains the version the client sent.
+    IncompatibleVersion(Version),
 }
 
 impl<C: ConnectionErrors> fmt::Display for BidiItemError<C> {
@@ -264,6 +632,17 @@ impl<C: ConnectionErrors> fmt::Display for BidiItemError<C> {
 
 impl<C: ConnectionErrors> error::Error for BidiItemError<C> {}
 
+impl<C: ConnectionErrors> VariantName for BidiItemError<C> {
+    fn variant_name(&self) -> &'static str {
+        match self {
+            Self::RecvError(_) => "RecvError",
+            Self::DowncastError => "DowncastError",
+            Self::EarlyClose => "EarlyClose",
+            Self::IncompatibleVersion(_) => "IncompatibleVersion",
+        }
+    }
+}
+
 /// Server error when accepting a client streaming request
 #[derive(Debug)]
 pub enum ClientStreamingError<C: ConnectionErrors> {
@@ -281,6 +660,15 @@ impl<C: ConnectionErrors> fmt::Display for ClientStreamingError<C> {
 
 impl<C: ConnectionErrors> error::Error for ClientStreamingError<C> {}
 
+impl<C: ConnectionErrors> VariantName for ClientStreamingError<C> {
+    fn variant_name(&self) -> &'static str {
+        match self {
+            Self::Open(_) => "Open",
+            Self::Send(_) => "Send",
+        }
+    }
+}
+
 /// Server error when receiving an item for a client streaming request
 #[derive(Debug)]
 pub enum ClientStreamingItemError<C: ConnectionErrors> {
@@ -290,6 +678,10 @@ pub enum ClientStreamingItemError<C: ConnectionErrors> {
     RecvError(C::RecvError),
     /// Unexpected response from the server
     DowncastError,
+    /// The server was built for a different version of the service, see [crate::version]
+    ///
+    /// This contains the version the client sent.
+    IncompatibleVersion(Version),
 }
 
 impl<C: ConnectionErrors> fmt::Display for ClientStreamingItemError<C> {
@@ -300,6 +692,17 @@ impl<C: ConnectionErrors> fmt::Display for ClientStreamingItemError<C> {
 
 impl<C: ConnectionErrors> error::Error for ClientStreamingItemError<C> {}
 
+impl<C: ConnectionErrors> VariantName for ClientStreamingItemError<C> {
+    fn variant_name(&self) -> &'static str {
+        match self {
+            Self::EarlyClose => "EarlyClose",
+            Self::RecvError(_) => "RecvError",
+            Self::DowncastError => "DowncastError",
+            Self::IncompatibleVersion(_) => "IncompatibleVersion",
+        }
+    }
+}
+
 /// Server error when accepting a server streaming request
 #[derive(Debug)]
 pub enum StreamingResponseError<C: ConnectionErrors> {
@@ -317,6 +720,15 @@ impl<S: ConnectionErrors> fmt::Display for StreamingResponseError<S> {
 
 impl<S: ConnectionErrors> error::Error for StreamingResponseError<S> {}
 
+impl<C: ConnectionErrors> VariantName for StreamingResponseError<C> {
+    fn variant_name(&self) -> &'static str {
+        match self {
+            Self::Open(_) => "Open",
+            Self::Send(_) => "Send",
+        }
+    }
+}
+
 /// Client error when handling responses from a server streaming request
 #[derive(Debug)]
 pub enum StreamingResponseItemError<S: ConnectionErrors> {
@@ -324,6 +736,12 @@ pub enum StreamingResponseItemError<S: ConnectionErrors> {
     RecvError(S::RecvError),
     /// Unexpected response from the server
     DowncastError,
+    /// Server closed the stream before sending the trailer
+    EarlyClose,
+    /// The server was built for a different version of the service, see [crate::version]
+    ///
+    /// This contains the version the client sent.
+    IncompatibleVersion(Version),
 }
 
 impl<S: ConnectionErrors> fmt::Display for StreamingResponseItemError<S> {
@@ -334,14 +752,242 @@ impl<S: ConnectionErrors> fmt::Display for StreamingResponseItemError<S> {
 
 impl<S: ConnectionErrors> error::Error for StreamingResponseItemError<S> {}
 
-/// Wrap a stream with an additional item that is kept alive until the stream is dropped
+impl<S: ConnectionErrors> VariantName for StreamingResponseItemError<S> {
+    fn variant_name(&self) -> &'static str {
+        match self {
+            Self::RecvError(_) => "RecvError",
+            Self::DowncastError => "DowncastError",
+            Self::EarlyClose => "EarlyClose",
+            Self::IncompatibleVersion(_) => "IncompatibleVersion",
+        }
+    }
+}
+
+/// Client error when opening a raw streaming request
+#[derive(Debug)]
+pub enum RawStreamingError<C: ConnectionErrors> {
+    /// Unable to open a substream at all
+    Open(C::OpenError),
+    /// Unable to send the request to the server
+    Send(C::SendError),
+}
+
+impl<C: ConnectionErrors> fmt::Display for RawStreamingError<C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self, f)
+    }
+}
+
+impl<C: ConnectionErrors> error::Error for RawStreamingError<C> {}
+
+impl<C: ConnectionErrors> VariantName for RawStreamingError<C> {
+    fn variant_name(&self) -> &'static str {
+        match self {
+            Self::Open(_) => "Open",
+            Self::Send(_) => "Send",
+        }
+    }
+}
+
+/// The version the client sent, if `error` is the server rejecting it
+///
+/// Servers reject channels of clients with a different version with
+/// [Status::FailedPrecondition], see [RpcServer::with_handshake](crate::RpcServer::with_handshake).
+fn rejected_version<S: Service, C: ServiceConnection<S>>(
+    version: &Option<Version>,
+    error: &C::RecvError,
+) -> Option<Version> {
+    match C::rejection(error) {
+        Some(Status::FailedPrecondition) => version.clone(),
+        _ => None,
+    }
+}
+
+/// Cancels a call when dropped before the call has ended, see
+/// [ConnectionCommon::cancel](crate::transport::ConnectionCommon::cancel)
+struct CancelGuard<S: Service, C: ServiceConnection<S>>(Option<CancelSend<C::SendSink>>);
+
+/// The send side of a call, as far as the [CancelGuard] needs it
+enum CancelSend<T> {
+    /// The guard owns the send side
+    Owned(T),
+    /// The [UpdateSink] owns the send side
+    Shared(Weak<Mutex<T>>),
+}
+
+impl<S: Service, C: ServiceConnection<S>> CancelGuard<S, C> {
+    /// A guard that owns the send side of the call
+    fn new(send: C::SendSink) -> Self {
+        Self(Some(CancelSend::Owned(send)))
+    }
+
+    /// A guard for a call with updates, where the [UpdateSink] owns the send side
+    fn shared(send: &Arc<Mutex<C::SendSink>>) -> Self {
+        Self(Some(CancelSend::Shared(Arc::downgrade(send))))
+    }
+
+    /// The call has ended, so dropping the guard no longer cancels it
+    fn done(mut self) {
+        self.0 = None;
+    }
+}
+
+impl<S: Service, C: ServiceConnection<S>> Drop for CancelGuard<S, C> {
+    fn drop(&mut self) {
+        match self.0.take() {
+            Some(CancelSend::Owned(mut send)) => C::cancel(&mut send),
+            Some(CancelSend::Shared(send)) => {
+                if let Some(send) = send.upgrade() {
+                    C::cancel(&mut send.lock().unwrap());
+                }
+            }
+            None => {}
+        }
+    }
+}
+
+/// One side of the raw byte streams of a [RpcClient::raw_streaming] call
+///
+/// Polls the inner stream in the span of the call, and records the chunks that are read
+/// as response items. The call ends with the end of the receive side, or with the first
+/// io error on either side. If both sides are dropped before that, the call ends as
+/// dropped.
+#[pin_project]
+#[derive(Debug)]
+pub struct RawStream<T> {
+    #[pin]
+    inner: T,
+    call: CallSpan,
+}
+
+impl<T> RawStream<T> {
+    fn new(inner: T, call: CallSpan) -> Self {
+        Self { inner, call }
+    }
+
+    /// The inner stream, this ends the recording of the call
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+
+    fn check<R>(call: &CallSpan, res: Poll<io::Result<R>>) -> Poll<io::Result<R>> {
+        if let Poll::Ready(Err(cause)) = &res {
+            call.err(cause);
+        }
+        res
+    }
+}
+
+impl<T: AsyncRead> AsyncRead for RawStream<T> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.project();
+        let _entered = this.call.enter();
+        let before = buf.filled().len();
+        let res = Self::check(this.call, this.inner.poll_read(cx, buf));
+        if let Poll::Ready(Ok(())) = res {
+            match buf.filled().len() - before {
+                0 if buf.remaining() > 0 => this.call.ok(),
+                0 => {}
+                read => this.call.item(Some(read)),
+            }
+        }
+        res
+    }
+}
+
+impl<T: AsyncWrite> AsyncWrite for RawStream<T> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.project();
+        let _entered = this.call.enter();
+        Self::check(this.call, this.inner.poll_write(cx, buf))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.project();
+        let _entered = this.call.enter();
+        Self::check(this.call, this.inner.poll_flush(cx))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.project();
+        let _entered = this.call.enter();
+        Self::check(this.call, this.inner.poll_shutdown(cx))
+    }
+}
+
+/// Converts the messages of a response stream, and records the response items and the
+/// end of the stream in the span of the call
+///
+/// The size of the response items is taken from the stream after each item, see
+/// [crate::transport::ConnectionCommon::received_size]. Dropping the stream before its
+/// end cancels the call.
 #[pin_project]
-struct DeferDrop<S: Stream, X>(#[pin] S, X);
+struct Counted<S: Service, C: ServiceConnection<S>, St, F> {
+    #[pin]
+    inner: St,
+    call: CallSpan,
+    size: fn(&St) -> Option<usize>,
+    cancel: Option<CancelGuard<S, C>>,
+    convert: F,
+}
+
+impl<S: Service, C: ServiceConnection<S>, St, F> Counted<S, C, St, F> {
+    fn new(
+        inner: St,
+        call: CallSpan,
+        size: fn(&St) -> Option<usize>,
+        cancel: CancelGuard<S, C>,
+        convert: F,
+    ) -> Self {
+        Self {
+            inner,
+            call,
+            size,
+            cancel: Some(cancel),
+            convert,
+        }
+    }
+}
 
-impl<S: Stream, X> Stream for DeferDrop<S, X> {
-    type Item = S::Item;
+impl<S, C, St, F, R, E0, T, E> Stream for Counted<S, C, St, F>
+where
+    S: Service,
+    C: ServiceConnection<S>,
+    St: Stream<Item = result::Result<R, E0>>,
+    F: FnMut(result::Result<R, E0>) -> result::Result<T, E>,
+    E: Debug + VariantName,
+{
+    type Item = result::Result<T, E>;
 
     fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-        self.project().0.poll_next(cx)
+        let mut this = self.project();
+        let _entered = this.call.enter();
+        let item = match this.inner.as_mut().poll_next(cx) {
+            Poll::Ready(Some(item)) => item,
+            Poll::Ready(None) => {
+                this.call.ok();
+                if let Some(cancel) = this.cancel.take() {
+                    cancel.done();
+                }
+                return Poll::Ready(None);
+            }
+            Poll::Pending => return Poll::Pending,
+        };
+        if item.is_ok() {
+            this.call.item((this.size)(&this.inner));
+        }
+        let item = (this.convert)(item);
+        if let Err(cause) = &item {
+            this.call.err(cause);
+        }
+        Poll::Ready(Some(item))
     }
 }
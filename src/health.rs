@@ -0,0 +1,339 @@
+//! A standard health check service
+//!
+//! [HealthService] allows clients to ask a server whether it, or one of its
+//! sub-services, is able to handle requests. It has two messages:
+//!
+//! - [Check] is a rpc call that returns the current [ServingStatus] of a sub-service
+//! - [Watch] is a server streaming call that returns the current status, and then
+//!   every change of the status
+//!
+//! Servers keep track of the status of their sub-services using a [HealthReporter].
+//!
+//! The health service can run on its own endpoint using [HealthService] and
+//! [HealthReporter::handle]. It can also be mounted alongside any other service
+//! on the same endpoint. For that, add [Check] and [Watch] as variants of the
+//! request enum of the service, and [ServingStatus] as a variant of its response
+//! enum, and declare the interaction patterns of the two messages for the service.
+//! Then forward the two requests to [HealthReporter::handle].
+//!
+//! ```
+//! # use quic_rpc::{health::*, *};
+//! # use derive_more::{From, TryInto};
+//! # use serde::{Serialize, Deserialize};
+//! #[derive(Debug, Serialize, Deserialize, From, TryInto)]
+//! enum Request {
+//!     Check(Check),
+//!     Watch(Watch),
+//!     // ... the requests of the service
+//! }
+//!
+//! #[derive(Debug, Serialize, Deserialize, From, TryInto)]
+//! enum Response {
+//!     Health(ServingStatus),
+//!     // ... the responses of the service
+//! }
+//!
+//! #[derive(Debug, Clone)]
+//! struct MyService;
+//!
+//! impl Service for MyService {
+//!     type Req = Request;
+//!     type Res = Response;
+//! }
+//!
+//! impl message::RpcMsg<MyService> for Check {
+//!     type Response = ServingStatus;
+//! }
+//!
+//! impl message::Msg<MyService> for Watch {
+//!     type Pattern = message::ServerStreaming;
+//! }
+//!
+//! impl message::ServerStreamingMsg<MyService> for Watch {
+//!     type Response = ServingStatus;
+//! }
+//!
+//! async fn serve<E: ServiceEndpoint<MyService>>(
+//!     server: RpcServer<MyService, E>,
+//!     health: HealthReporter,
+//! ) -> anyhow::Result<()> {
+//!     loop {
+//!         let (req, chan) = server.accept().await?;
+//!         match req {
+//!             Request::Check(req) => health.clone().handle(req.into(), chan).await?,
+//!             Request::Watch(req) => health.clone().handle(req.into(), chan).await?,
+//!         }
+//!     }
+//! }
+//! ```
+//!
+//! The empty service name refers to the status of the server as a whole.
+use crate::{
+    message::{Msg, RpcMsg, ServerStreaming, ServerStreamingMsg},
+    server::{RpcChannel, RpcServerError},
+    Service, ServiceEndpoint,
+};
+use futures::{stream, Stream};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+};
+use tokio::sync::watch;
+
+/// The status of a service
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum ServingStatus {
+    /// The status is not known
+    Unknown,
+    /// The service is able to handle requests
+    Serving,
+    /// The service is not able to handle requests
+    NotServing,
+    /// The service is not known to the server
+    ServiceUnknown,
+}
+
+/// Get the current status of a service
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Check {
+    /// Name of the service. The empty string refers to the whole server.
+    pub service: String,
+}
+
+/// Get the current status of a service, and then every change of the status
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Watch {
+    /// Name of the service. The empty string refers to the whole server.
+    pub service: String,
+}
+
+/// Request enum of the [HealthService]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum HealthRequest {
+    /// A [Check] request
+    Check(Check),
+    /// A [Watch] request
+    Watch(Watch),
+}
+
+impl From<Check> for HealthRequest {
+    fn from(value: Check) -> Self {
+        Self::Check(value)
+    }
+}
+
+impl From<Watch> for HealthRequest {
+    fn from(value: Watch) -> Self {
+        Self::Watch(value)
+    }
+}
+
+impl TryFrom<HealthRequest> for Check {
+    type Error = HealthRequest;
+
+    fn try_from(value: HealthRequest) -> Result<Self, Self::Error> {
+        match value {
+            HealthRequest::Check(x) => Ok(x),
+            other => Err(other),
+        }
+    }
+}
+
+impl TryFrom<HealthRequest> for Watch {
+    type Error = HealthRequest;
+
+    fn try_from(value: HealthRequest) -> Result<Self, Self::Error> {
+        match value {
+            HealthRequest::Watch(x) => Ok(x),
+            other => Err(other),
+        }
+    }
+}
+
+/// The health check service
+///
+/// The response type is just [ServingStatus], since both requests return it.
+#[derive(Debug, Clone)]
+pub struct HealthService;
+
+impl Service for HealthService {
+    type Req = HealthRequest;
+    type Res = ServingStatus;
+}
+
+impl RpcMsg<HealthService> for Check {
+    type Response = ServingStatus;
+}
+
+impl Msg<HealthService> for Watch {
+    type Pattern = ServerStreaming;
+}
+
+impl ServerStreamingMsg<HealthService> for Watch {
+    type Response = ServingStatus;
+}
+
+/// Keeps track of the status of the sub-services of a server
+///
+/// This is cheap to clone. All clones share the same state, so one clone can be
+/// used to update the status while another one handles health requests.
+///
+/// The whole server, identified by the empty service name, starts out as
+/// [ServingStatus::Serving]. Services that have never been set are
+/// [ServingStatus::ServiceUnknown]. Only services that have been set take up
+/// space, asking for any other service does not.
+#[derive(Debug, Clone)]
+pub struct HealthReporter {
+    services: Arc<Mutex<BTreeMap<String, watch::Sender<ServingStatus>>>>,
+    /// Notified whenever a service is set for the first time
+    added: Arc<watch::Sender<()>>,
+}
+
+impl Default for HealthReporter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl HealthReporter {
+    /// Create a new reporter
+    pub fn new() -> Self {
+        let this = Self {
+            services: Default::default(),
+            added: Arc::new(watch::channel(()).0),
+        };
+        this.set_status("", ServingStatus::Serving);
+        this
+    }
+
+    /// Set the status of a service
+    ///
+    /// Watchers of the service are notified if the status changed.
+    pub fn set_status(&self, service: impl Into<String>, status: ServingStatus) {
+        let service = service.into();
+        let mut services = self.services.lock().unwrap();
+        match services.get(&service) {
+            Some(sender) => {
+                sender.send_if_modified(|current| {
+                    let changed = *current != status;
+                    *current = status;
+                    changed
+                });
+            }
+            // nothing to forget
+            None if status == ServingStatus::ServiceUnknown => {}
+            None => {
+                services.insert(service, watch::channel(status).0);
+                self.added.send_replace(());
+            }
+        }
+    }
+
+    /// Forget a service, so its status becomes [ServingStatus::ServiceUnknown]
+    pub fn clear_status(&self, service: &str) {
+        self.set_status(service, ServingStatus::ServiceUnknown);
+    }
+
+    /// Get the current status of a service
+    pub fn status(&self, service: &str) -> ServingStatus {
+        let services = self.services.lock().unwrap();
+        match services.get(service) {
+            Some(sender) => *sender.borrow(),
+            None => ServingStatus::ServiceUnknown,
+        }
+    }
+
+    /// Handler for a [Check] request
+    pub async fn check(self, req: Check) -> ServingStatus {
+        self.status(&req.service)
+    }
+
+    /// Handler for a [Watch] request
+    ///
+    /// The stream yields the current status, and then every change. It does not
+    /// end on its own.
+    pub fn watch(self, req: Watch) -> impl Stream<Item = ServingStatus> + Send + 'static {
+        // subscribe before looking up the service, so a concurrent set is not missed
+        let added = self.added.subscribe();
+        let receiver = self.subscribe(&req.service);
+        let state = WatchState {
+            reporter: self,
+            service: req.service,
+            added,
+            receiver,
+            fresh: true,
+            last: None,
+        };
+        stream::unfold(state, |mut state| async move {
+            let status = state.next().await?;
+            Some((status, state))
+        })
+    }
+
+    /// Subscribe to a service, if it has been set
+    fn subscribe(&self, service: &str) -> Option<watch::Receiver<ServingStatus>> {
+        let services = self.services.lock().unwrap();
+        services.get(service).map(|sender| sender.subscribe())
+    }
+
+    /// Handle a health request on a channel of any service that includes the
+    /// health messages
+    pub async fn handle<S, E>(
+        self,
+        req: HealthRequest,
+        chan: RpcChannel<S, E>,
+    ) -> Result<(), RpcServerError<E>>
+    where
+        S: Service,
+        E: ServiceEndpoint<S>,
+        Check: RpcMsg<S, Response = ServingStatus>,
+        Watch: ServerStreamingMsg<S, Response = ServingStatus>,
+    {
+        match req {
+            HealthRequest::Check(req) => chan.rpc(req, self, Self::check).await,
+            HealthRequest::Watch(req) => chan.server_streaming(req, self, Self::watch).await,
+        }
+    }
+}
+
+/// State of a [HealthReporter::watch] stream
+struct WatchState {
+    reporter: HealthReporter,
+    service: String,
+    added: watch::Receiver<()>,
+    /// None as long as the service has not been set
+    receiver: Option<watch::Receiver<ServingStatus>>,
+    /// Whether the current status has not been looked at yet
+    fresh: bool,
+    last: Option<ServingStatus>,
+}
+
+impl WatchState {
+    async fn next(&mut self) -> Option<ServingStatus> {
+        loop {
+            let status = match &mut self.receiver {
+                Some(receiver) => {
+                    if !self.fresh {
+                        receiver.changed().await.ok()?;
+                    }
+                    *receiver.borrow_and_update()
+                }
+                None if self.fresh => ServingStatus::ServiceUnknown,
+                None => {
+                    // wait for the service to be set
+                    self.added.changed().await.ok()?;
+                    self.receiver = self.reporter.subscribe(&self.service);
+                    self.fresh = self.receiver.is_some();
+                    continue;
+                }
+            };
+            self.fresh = false;
+            // a service that was set and cleared before the subscription is not a change
+            if self.last != Some(status) {
+                self.last = Some(status);
+                return Some(status);
+            }
+        }
+    }
+}
@@ -94,10 +94,21 @@
 use serde::{de::DeserializeOwned, Serialize};
 use std::fmt::{Debug, Display};
 use transport::{Connection, ServerEndpoint};
+pub mod auth;
+#[cfg(feature = "cli")]
+pub mod cli;
 pub mod client;
+#[cfg(feature = "health")]
+pub mod health;
 pub mod message;
+pub mod metrics;
+pub mod reflection;
 pub mod server;
+pub mod telemetry;
+#[cfg(feature = "test-utils")]
+pub mod testing;
 pub mod transport;
+pub mod version;
 pub use client::RpcClient;
 pub use server::RpcServer;
 #[cfg(feature = "macros")]
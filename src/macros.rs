@@ -114,6 +114,10 @@
 ///
 /// ```
 ///
+/// The service also implements [Reflect](crate::reflection::Reflect), so it can
+/// describe its methods at runtime, and [Versioned](crate::version::Versioned), so
+/// clients and servers can check that they agree on the messages.
+///
 /// The generation of the macros in `CreateDispatch` and `CreateClient`
 /// is optional. If you don't need them, pass `_` instead:
 ///
@@ -170,6 +174,46 @@ macro_rules! rpc_service {
             type Res = $response;
         }
 
+        impl $crate::reflection::Reflect for $service {
+            fn service_info() -> $crate::reflection::ServiceInfo {
+                $crate::reflection::ServiceInfo {
+                    name: stringify!($service).to_string(),
+                    methods: vec![
+                        $(
+                            $crate::reflection::MethodInfo {
+                                name: stringify!($m_name).to_string(),
+                                request: stringify!($m_input).to_string(),
+                                pattern: $crate::reflection::PatternKind::$m_pattern,
+                                update: $crate::__type_name!($m_update),
+                                response: Some(stringify!($m_output).to_string()),
+                            },
+                        )*
+                    ],
+                }
+            }
+        }
+
+        impl $crate::version::Versioned for $service {
+            const NAME: &'static str = stringify!($service);
+
+            fn schema() -> $crate::version::Schema {
+                // the request enum has the requests first, then the updates
+                let requests = vec![$(stringify!($m_input).to_string(),)*];
+                let updates = vec![$($crate::__type_name!($m_update),)*];
+                let variant = |ty: String| $crate::version::Variant::new(ty.clone(), ty);
+                $crate::version::Schema {
+                    request: requests
+                        .into_iter()
+                        .chain(updates.into_iter().flatten())
+                        .map(variant)
+                        .collect(),
+                    response: vec![$(variant(stringify!($m_output).to_string()),)*],
+                    formats: Default::default(),
+                }
+                .with_formats::<Self>()
+            }
+        }
+
         $crate::__derive_create_dispatch!(
             $service,
             $request,
@@ -179,6 +223,17 @@ macro_rules! rpc_service {
     };
 }
 
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __type_name {
+    (_) => {
+        None
+    };
+    ($t:ident) => {
+        Some(stringify!($t).to_string())
+    };
+}
+
 #[doc(hidden)]
 #[macro_export]
 macro_rules! __derive_create_dispatch {
@@ -259,11 +314,19 @@ macro_rules! __request_enum {
 ///    type Response = TestResponse;
 /// }
 /// ```
+///
+/// The maximum encoded sizes of the messages can be declared at the end, see
+/// [Msg::MAX_REQUEST_SIZE](crate::message::Msg::MAX_REQUEST_SIZE):
+/// ```ignore
+/// declare_rpc!(TestService, TestRequest, TestResponse, max_request_size = 1024, max_response_size = 4096);
+/// ```
 #[macro_export]
 macro_rules! declare_rpc {
-    ($service:ty, $m_input:ty, $m_output:ty) => {
+    ($service:ty, $m_input:ty, $m_output:ty $(, max_request_size = $max_request:expr)? $(, max_response_size = $max_response:expr)? $(,)?) => {
         impl $crate::message::RpcMsg<$service> for $m_input {
             type Response = $m_output;
+            $(const RPC_MAX_REQUEST_SIZE: Option<usize> = Some($max_request);)?
+            $(const RPC_MAX_RESPONSE_SIZE: Option<usize> = Some($max_response);)?
         }
     };
 }
@@ -284,11 +347,16 @@ macro_rules! declare_rpc {
 /// impl ServerStreamingMsg<TestService> for TestRequest {
 ///     type Response = TestResponse;
 /// }
+/// ```
+///
+/// Maximum message sizes can be declared at the end, like for [declare_rpc].
 #[macro_export]
 macro_rules! declare_server_streaming {
-    ($service:ident, $m_input:ident, $m_output:ident) => {
+    ($service:ident, $m_input:ident, $m_output:ty $(, max_request_size = $max_request:expr)? $(, max_response_size = $max_response:expr)? $(,)?) => {
         impl $crate::message::Msg<$service> for $m_input {
             type Pattern = $crate::message::ServerStreaming;
+            $(const MAX_REQUEST_SIZE: Option<usize> = Some($max_request);)?
+            $(const MAX_RESPONSE_SIZE: Option<usize> = Some($max_response);)?
         }
         impl $crate::message::ServerStreamingMsg<$service> for $m_input {
             type Response = $m_output;
@@ -314,11 +382,15 @@ macro_rules! declare_server_streaming {
 ///     type Response = TestResponse;
 /// }
 /// ```
+///
+/// Maximum message sizes can be declared at the end, like for [declare_rpc].
 #[macro_export]
 macro_rules! declare_client_streaming {
-    ($service:ident, $m_input:ident, $m_update:ident, $m_output:ident) => {
+    ($service:ident, $m_input:ident, $m_update:ty, $m_output:ty $(, max_request_size = $max_request:expr)? $(, max_response_size = $max_response:expr)? $(,)?) => {
         impl $crate::message::Msg<$service> for $m_input {
             type Pattern = $crate::message::ClientStreaming;
+            $(const MAX_REQUEST_SIZE: Option<usize> = Some($max_request);)?
+            $(const MAX_RESPONSE_SIZE: Option<usize> = Some($max_response);)?
         }
         impl $crate::message::ClientStreamingMsg<$service> for $m_input {
             type Update = $m_update;
@@ -345,11 +417,15 @@ macro_rules! declare_client_streaming {
 ///     type Response = TestResponse;
 /// }
 /// ```
+///
+/// Maximum message sizes can be declared at the end, like for [declare_rpc].
 #[macro_export]
 macro_rules! declare_bidi_streaming {
-    ($service:ident, $m_input:ident, $m_update:ident, $m_output:ident) => {
+    ($service:ident, $m_input:ident, $m_update:ty, $m_output:ty $(, max_request_size = $max_request:expr)? $(, max_response_size = $max_response:expr)? $(,)?) => {
         impl $crate::message::Msg<$service> for $m_input {
             type Pattern = $crate::message::BidiStreaming;
+            $(const MAX_REQUEST_SIZE: Option<usize> = Some($max_request);)?
+            $(const MAX_RESPONSE_SIZE: Option<usize> = Some($max_response);)?
         }
         impl $crate::message::BidiStreamingMsg<$service> for $m_input {
             type Update = $m_update;
@@ -358,17 +434,50 @@ macro_rules! declare_bidi_streaming {
     };
 }
 
+/// Declare a message to be a raw streaming message for a service.
+///
+/// Example:
+/// ```ignore
+/// declare_raw_streaming!(TestService, TestRequest);
+/// ```
+///
+/// This is equivalent to:
+/// ```ignore
+/// impl Msg<TestService> for TestRequest {
+///     type Pattern = RawStreamingPattern;
+/// }
+///
+/// impl RawStreamingMsg<TestService> for TestRequest {}
+/// ```
+#[macro_export]
+macro_rules! declare_raw_streaming {
+    ($service:ident, $m_input:ident) => {
+        impl $crate::message::Msg<$service> for $m_input {
+            type Pattern = $crate::message::RawStreaming;
+        }
+        impl $crate::message::RawStreamingMsg<$service> for $m_input {}
+    };
+}
+
 #[doc(hidden)]
 #[macro_export]
 macro_rules! __rpc_message {
     ($service:ident, Rpc, $m_input:ident, _, $m_output:ident) => {
         impl $crate::message::RpcMsg<$service> for $m_input {
             type Response = $m_output;
+
+            fn rpc_method_name() -> &'static str {
+                stringify!($m_input)
+            }
         }
     };
     ($service:ident, ServerStreaming, $m_input:ident, _, $m_output:ident) => {
         impl $crate::message::Msg<$service> for $m_input {
             type Pattern = $crate::message::ServerStreaming;
+
+            fn method_name() -> &'static str {
+                stringify!($m_input)
+            }
         }
         impl $crate::message::ServerStreamingMsg<$service> for $m_input {
             type Response = $m_output;
@@ -377,6 +486,10 @@ macro_rules! __rpc_message {
     ($service:ident, ClientStreaming, $m_input:ident, $m_update:ident, $m_output:ident) => {
         impl $crate::message::Msg<$service> for $m_input {
             type Pattern = $crate::message::ClientStreaming;
+
+            fn method_name() -> &'static str {
+                stringify!($m_input)
+            }
         }
         impl $crate::message::ClientStreamingMsg<$service> for $m_input {
             type Response = $m_output;
@@ -386,6 +499,10 @@ macro_rules! __rpc_message {
     ($service:ident, BidiStreaming, $m_input:ident, $m_update:ident, $m_output:ident) => {
         impl $crate::message::Msg<$service> for $m_input {
             type Pattern = $crate::message::BidiStreaming;
+
+            fn method_name() -> &'static str {
+                stringify!($m_input)
+            }
         }
         impl $crate::message::BidiStreamingMsg<$service> for $m_input {
             type Response = $m_output;
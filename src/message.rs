@@ -2,6 +2,7 @@
 //!
 //! Traits to define the behaviour of messages for services
 use crate::Service;
+use serde::{Deserialize, Serialize};
 use std::fmt::Debug;
 
 /// Declares the interaction pattern for a message and a service.
@@ -10,23 +11,68 @@ use std::fmt::Debug;
 pub trait Msg<S: Service>: Into<S::Req> + TryFrom<S::Req> + Send + 'static {
     /// The interaction pattern for this message with this service.
     type Pattern: InteractionPattern;
+
+    /// Maximum encoded size of the request and its updates, in bytes.
+    ///
+    /// Both the client and the server enforce this, on transports that encode messages.
+    /// Default is `None`, which only applies the frame size limit of the transport. For
+    /// rpc messages, set [RpcMsg::RPC_MAX_REQUEST_SIZE] instead.
+    ///
+    /// The server only knows which message a channel is for once it has received the first
+    /// request, so the first request is bounded only by the frame size limit of the
+    /// transport, and rejected after it has been received if it is larger than this.
+    /// Updates are rejected before they are buffered.
+    const MAX_REQUEST_SIZE: Option<usize> = None;
+
+    /// Maximum encoded size of each response, in bytes.
+    ///
+    /// See [Self::MAX_REQUEST_SIZE].
+    const MAX_RESPONSE_SIZE: Option<usize> = None;
+
+    /// Name of the method, as used by a [Policy](crate::auth::Policy).
+    ///
+    /// This must be unique within the service. Default is the full path of the type,
+    /// see [std::any::type_name]. Messages declared with the
+    /// [rpc_service](crate::rpc_service) macro use the name of their request enum
+    /// variant. For rpc messages, override [RpcMsg::rpc_method_name] instead.
+    fn method_name() -> &'static str {
+        std::any::type_name::<Self>()
+    }
 }
 
 /// Defines the response type for a rpc message.
 ///
 /// Since this is the most common interaction pattern, this also implements [Msg] for you
 /// automatically, with the interaction pattern set to [Rpc]. This is to reduce boilerplate
-/// when defining rpc messages.
+/// when defining rpc messages. The items that [Msg] has as well are prefixed with `rpc`,
+/// so that both traits can be in scope.
 pub trait RpcMsg<S: Service>: Msg<S, Pattern = Rpc> {
     /// The type for the response
     ///
     /// For requests that can produce errors, this can be set to [Result<T, E>](std::result::Result).
     type Response: Into<S::Res> + TryFrom<S::Res> + Send + 'static;
+
+    /// Maximum encoded size of the request, see [Msg::MAX_REQUEST_SIZE]
+    const RPC_MAX_REQUEST_SIZE: Option<usize> = None;
+
+    /// Maximum encoded size of the response, see [Msg::MAX_RESPONSE_SIZE]
+    const RPC_MAX_RESPONSE_SIZE: Option<usize> = None;
+
+    /// Name of the method, see [Msg::method_name]
+    fn rpc_method_name() -> &'static str {
+        std::any::type_name::<Self>()
+    }
 }
 
 /// We can only do this for one trait, so we do it for RpcMsg since it is the most common
 impl<T: RpcMsg<S>, S: Service> Msg<S> for T {
     type Pattern = Rpc;
+    const MAX_REQUEST_SIZE: Option<usize> = T::RPC_MAX_REQUEST_SIZE;
+    const MAX_RESPONSE_SIZE: Option<usize> = T::RPC_MAX_RESPONSE_SIZE;
+
+    fn method_name() -> &'static str {
+        T::rpc_method_name()
+    }
 }
 
 /// Defines update type and response type for a client streaming message.
@@ -65,13 +111,20 @@ pub trait BidiStreamingMsg<S: Service>: Msg<S, Pattern = BidiStreaming> {
     type Response: Into<S::Res> + TryFrom<S::Res> + Send + 'static;
 }
 
+/// Marks a message as a raw streaming message.
+///
+/// After the typed request, client and server exchange raw bytes in both directions.
+/// This is only supported by transports that implement [RawStreams](crate::transport::RawStreams).
+pub trait RawStreamingMsg<S: Service>: Msg<S, Pattern = RawStreaming> {}
+
 /// Trait defining interaction pattern.
 ///
-/// Currently there are 4 patterns:
+/// Currently there are 5 patterns:
 /// - [Rpc]: 1 request, 1 response
 /// - [ClientStreaming]: 1 request, stream of updates, 1 response
 /// - [ServerStreaming]: 1 request, stream of responses
 /// - [BidiStreaming]: 1 request, stream of updates, stream of responses
+/// - [RawStreaming]: 1 request, raw bytes in both directions
 ///
 /// You could define your own interaction patterns such as OneWay.
 pub trait InteractionPattern: Debug + Clone + Send + Sync + 'static {}
@@ -105,3 +158,28 @@ impl InteractionPattern for ServerStreaming {}
 #[derive(Debug, Clone, Copy)]
 pub struct BidiStreaming;
 impl InteractionPattern for BidiStreaming {}
+
+/// Raw streaming interaction pattern
+///
+/// After the initial request, the client and the server exchange raw bytes instead
+/// of messages.
+#[derive(Debug, Clone, Copy)]
+pub struct RawStreaming;
+impl InteractionPattern for RawStreaming {}
+
+/// A frame of a response stream that ends with a typed trailer.
+///
+/// To give a server streaming or bidi streaming message a trailer, use this as the
+/// response type, e.g. `type Response = StreamFrame<Item, Trailer>`. The server then
+/// uses [server_streaming_with_trailer](crate::server::RpcChannel::server_streaming_with_trailer)
+/// or [bidi_streaming_with_trailer](crate::server::RpcChannel::bidi_streaming_with_trailer),
+/// and the client gets a [TrailerStream](crate::client::TrailerStream) that yields the items
+/// and then the trailer. This allows the client to tell a stream that finished normally
+/// from one that was closed early.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum StreamFrame<T, U = ()> {
+    /// An item of the stream
+    Item(T),
+    /// The trailer, always the last frame of the stream
+    Trailer(U),
+}
@@ -0,0 +1,393 @@
+//! Call counts, errors and latencies of rpc interactions
+//!
+//! Measurements are taken per service and method, on the client and on the
+//! server side. They are passed to a [MetricsSink], which is configured using
+//! [Telemetry::with_metrics](crate::telemetry::Telemetry::with_metrics).
+//!
+//! [Metrics] keeps the measurements in memory, and provides a [MetricsSnapshot] on
+//! demand. With the `metrics` feature, `FacadeMetrics` forwards the measurements to the
+//! [metrics](https://docs.rs/metrics) crate facade instead.
+//!
+//! Per interaction, the following is measured:
+//!
+//! - the start, and the number of interactions that are in flight
+//! - the response items of streaming interactions, and their size in bytes. The size is the
+//!   size of the encoded frame, and only known for transports that encode messages, see
+//!   [ConnectionCommon::received_size](crate::transport::ConnectionCommon::received_size).
+//!   For raw streaming interactions, the items are the chunks read on the client, see
+//!   [RawStream](crate::client::RawStream).
+//! - the end, with the latency and, if the interaction failed, the name of the error variant,
+//!   e.g. `Open` for [RpcClientError::Open](crate::client::RpcClientError::Open), see
+//!   [VariantName]. For
+//!   streaming interactions, the latency is the time until the end of the response stream.
+//!   Interactions that are dropped before they end, e.g. because the client dropped the
+//!   response stream, end with the error `Dropped`.
+use crate::telemetry::{RequestInfo, Side};
+use std::{
+    collections::BTreeMap,
+    fmt,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+/// Receives the measurements of rpc interactions
+pub trait MetricsSink: Send + Sync + 'static {
+    /// An interaction has started
+    fn started(&self, info: &RequestInfo);
+
+    /// A response item of a streaming interaction was sent or received
+    fn item(&self, info: &RequestInfo, bytes: Option<u64>);
+
+    /// An interaction has ended, `error` is the name of the error variant if it failed
+    fn finished(&self, info: &RequestInfo, error: Option<&str>, latency: Duration);
+}
+
+/// Number of latency buckets, including the overflow bucket
+const BUCKETS: usize = 24;
+
+/// Upper bound of the first latency bucket, each next bucket doubles it
+const FIRST_BUCKET: Duration = Duration::from_micros(10);
+
+/// Histogram of latencies with exponential buckets
+///
+/// The first bucket holds latencies up to 10µs, and every next bucket doubles the
+/// upper bound, up to about 42s. The last bucket holds everything above.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LatencyHistogram {
+    counts: [u64; BUCKETS],
+    sum: Duration,
+    max: Duration,
+}
+
+impl Default for LatencyHistogram {
+    fn default() -> Self {
+        Self {
+            counts: [0; BUCKETS],
+            sum: Duration::ZERO,
+            max: Duration::ZERO,
+        }
+    }
+}
+
+impl LatencyHistogram {
+    /// Add a latency
+    pub fn record(&mut self, latency: Duration) {
+        let bucket = (0..BUCKETS - 1)
+            .find(|&i| latency <= Self::upper_bound(i))
+            .unwrap_or(BUCKETS - 1);
+        self.counts[bucket] += 1;
+        self.sum += latency;
+        self.max = self.max.max(latency);
+    }
+
+    /// Number of latencies
+    pub fn count(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    /// Sum of all latencies
+    pub fn sum(&self) -> Duration {
+        self.sum
+    }
+
+    /// The largest latency
+    pub fn max(&self) -> Duration {
+        self.max
+    }
+
+    /// Mean of all latencies, `None` if there are none
+    pub fn mean(&self) -> Option<Duration> {
+        let count = self.count();
+        if count == 0 {
+            return None;
+        }
+        Some(Duration::from_nanos(
+            (self.sum.as_nanos() / count as u128) as u64,
+        ))
+    }
+
+    /// Estimate of the latency below which the fraction `q` of the latencies are
+    ///
+    /// This is the upper bound of the bucket that contains the quantile, capped by the
+    /// largest latency. Returns `None` if there are no latencies.
+    pub fn quantile(&self, q: f64) -> Option<Duration> {
+        let count = self.count();
+        if count == 0 {
+            return None;
+        }
+        let rank = ((q.clamp(0.0, 1.0) * count as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (i, n) in self.counts.iter().enumerate() {
+            seen += n;
+            if seen >= rank {
+                return Some(Self::upper_bound(i).min(self.max));
+            }
+        }
+        Some(self.max)
+    }
+
+    /// The buckets as `(upper bound, count)`, the last bucket has no upper bound
+    pub fn buckets(&self) -> impl Iterator<Item = (Option<Duration>, u64)> + '_ {
+        self.counts.iter().enumerate().map(|(i, n)| {
+            let bound = if i < BUCKETS - 1 {
+                Some(Self::upper_bound(i))
+            } else {
+                None
+            };
+            (bound, *n)
+        })
+    }
+
+    fn upper_bound(bucket: usize) -> Duration {
+        if bucket < BUCKETS - 1 {
+            FIRST_BUCKET * (1 << bucket)
+        } else {
+            Duration::MAX
+        }
+    }
+}
+
+/// Measurements for one method on one side
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MethodMetrics {
+    /// The side of the interactions
+    pub side: Side,
+    /// Name of the service type
+    pub service: &'static str,
+    /// Name of the method, see [Msg::method_name](crate::message::Msg::method_name)
+    pub method: &'static str,
+    /// Name of the interaction pattern
+    pub pattern: &'static str,
+    /// Number of interactions that have started
+    pub calls: u64,
+    /// Number of interactions that have started but not yet ended
+    pub in_flight: u64,
+    /// Number of failed interactions, by error variant name
+    pub errors: BTreeMap<String, u64>,
+    /// Latencies of the interactions that have ended
+    pub latency: LatencyHistogram,
+    /// Number of response items of streaming interactions
+    pub items: u64,
+    /// Size of the response items of streaming interactions, if known
+    pub bytes: u64,
+}
+
+impl MethodMetrics {
+    fn new(info: &RequestInfo) -> Self {
+        Self {
+            side: info.side,
+            service: info.service,
+            method: info.method,
+            pattern: info.pattern,
+            calls: 0,
+            in_flight: 0,
+            errors: BTreeMap::new(),
+            latency: LatencyHistogram::default(),
+            items: 0,
+            bytes: 0,
+        }
+    }
+
+    /// Total number of failed interactions
+    pub fn error_count(&self) -> u64 {
+        self.errors.values().sum()
+    }
+}
+
+/// The measurements of a [Metrics] at some point in time
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MetricsSnapshot {
+    /// Measurements per side, service and method
+    pub methods: Vec<MethodMetrics>,
+}
+
+impl MetricsSnapshot {
+    /// The measurements for the given side and method name
+    pub fn get(&self, side: Side, method: &str) -> Option<&MethodMetrics> {
+        self.methods
+            .iter()
+            .find(|m| m.side == side && m.method == method)
+    }
+}
+
+type MethodKey = (&'static str, &'static str, Side);
+
+/// In memory [MetricsSink] that provides snapshots
+///
+/// This is cheap to clone, clones share the measurements.
+#[derive(Debug, Clone, Default)]
+pub struct Metrics(Arc<Mutex<BTreeMap<MethodKey, MethodMetrics>>>);
+
+impl Metrics {
+    /// Create an empty metrics registry
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Get the current measurements, ordered by service, method and side
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        MetricsSnapshot {
+            methods: self.0.lock().unwrap().values().cloned().collect(),
+        }
+    }
+
+    /// Remove all measurements
+    ///
+    /// Interactions that are in flight are still counted when they end.
+    pub fn reset(&self) {
+        self.0.lock().unwrap().clear();
+    }
+
+    fn update(&self, info: &RequestInfo, f: impl FnOnce(&mut MethodMetrics)) {
+        let mut methods = self.0.lock().unwrap();
+        let key = (info.service, info.method, info.side);
+        f(methods
+            .entry(key)
+            .or_insert_with(|| MethodMetrics::new(info)));
+    }
+}
+
+impl MetricsSink for Metrics {
+    fn started(&self, info: &RequestInfo) {
+        self.update(info, |m| {
+            m.calls += 1;
+            m.in_flight += 1;
+        })
+    }
+
+    fn item(&self, info: &RequestInfo, bytes: Option<u64>) {
+        self.update(info, |m| {
+            m.items += 1;
+            m.bytes += bytes.unwrap_or_default();
+        })
+    }
+
+    fn finished(&self, info: &RequestInfo, error: Option<&str>, latency: Duration) {
+        self.update(info, |m| {
+            m.in_flight = m.in_flight.saturating_sub(1);
+            m.latency.record(latency);
+            if let Some(error) = error {
+                *m.errors.entry(error.to_string()).or_default() += 1;
+            }
+        })
+    }
+}
+
+/// [MetricsSink] that forwards to the [metrics](https://docs.rs/metrics) crate facade
+///
+/// All metrics have the labels `side`, `service` and `method`:
+///
+/// - `quic_rpc_calls_total`: counter of interactions that have started
+/// - `quic_rpc_in_flight`: gauge of interactions that have started but not yet ended
+/// - `quic_rpc_errors_total`: counter of failed interactions, with the additional label `error`
+/// - `quic_rpc_latency_seconds`: histogram of the latencies of the interactions
+/// - `quic_rpc_items_total`: counter of response items of streaming interactions
+/// - `quic_rpc_bytes_total`: counter of the size of the response items, if known
+#[cfg(feature = "metrics")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FacadeMetrics;
+
+#[cfg(feature = "metrics")]
+impl FacadeMetrics {
+    fn labels(info: &RequestInfo) -> Vec<::metrics::Label> {
+        let side = match info.side {
+            Side::Client => "client",
+            Side::Server => "server",
+        };
+        vec![
+            ::metrics::Label::new("side", side),
+            ::metrics::Label::new("service", info.service),
+            ::metrics::Label::new("method", info.method),
+        ]
+    }
+}
+
+#[cfg(feature = "metrics")]
+impl MetricsSink for FacadeMetrics {
+    fn started(&self, info: &RequestInfo) {
+        let labels = Self::labels(info);
+        ::metrics::increment_counter!("quic_rpc_calls_total", labels.clone());
+        ::metrics::increment_gauge!("quic_rpc_in_flight", 1.0, labels);
+    }
+
+    fn item(&self, info: &RequestInfo, bytes: Option<u64>) {
+        let labels = Self::labels(info);
+        ::metrics::increment_counter!("quic_rpc_items_total", labels.clone());
+        if let Some(bytes) = bytes {
+            ::metrics::counter!("quic_rpc_bytes_total", bytes, labels);
+        }
+    }
+
+    fn finished(&self, info: &RequestInfo, error: Option<&str>, latency: Duration) {
+        let labels = Self::labels(info);
+        ::metrics::decrement_gauge!("quic_rpc_in_flight", 1.0, labels.clone());
+        ::metrics::histogram!("quic_rpc_latency_seconds", latency, labels.clone());
+        if let Some(error) = error {
+            let mut labels = labels;
+            labels.push(::metrics::Label::new("error", error.to_string()));
+            ::metrics::increment_counter!("quic_rpc_errors_total", labels);
+        }
+    }
+}
+
+/// The measurements of a single interaction
+///
+/// Reports the end of the interaction exactly once, when it is dropped at the latest.
+pub(crate) struct CallMetrics {
+    sink: Arc<dyn MetricsSink>,
+    info: RequestInfo,
+    start: Instant,
+    finished: AtomicBool,
+}
+
+impl fmt::Debug for CallMetrics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CallMetrics")
+            .field("info", &self.info)
+            .field("start", &self.start)
+            .finish()
+    }
+}
+
+impl CallMetrics {
+    pub(crate) fn start(sink: Arc<dyn MetricsSink>, info: RequestInfo) -> Self {
+        sink.started(&info);
+        Self {
+            sink,
+            info,
+            start: Instant::now(),
+            finished: AtomicBool::new(false),
+        }
+    }
+
+    pub(crate) fn item(&self, bytes: Option<usize>) {
+        self.sink.item(&self.info, bytes.map(|bytes| bytes as u64));
+    }
+
+    pub(crate) fn finish(&self, error: Option<&str>) {
+        if !self.finished.swap(true, Ordering::Relaxed) {
+            self.sink.finished(&self.info, error, self.start.elapsed());
+        }
+    }
+}
+
+impl Drop for CallMetrics {
+    fn drop(&mut self) {
+        self.finish(Some("Dropped"));
+    }
+}
+
+/// An error enum that can name its variants, for the `error` label of the measurements
+pub trait VariantName {
+    /// The name of the variant of the error, e.g. `EarlyClose`
+    fn variant_name(&self) -> &'static str;
+}
+
+impl VariantName for std::io::Error {
+    fn variant_name(&self) -> &'static str {
+        "Io"
+    }
+}
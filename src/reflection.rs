@@ -0,0 +1,107 @@
+//! Service reflection
+//!
+//! Describes the methods of a service at runtime, so generic tools such as debuggers
+//! and dashboards can list them without access to the rust source of the service.
+//!
+//! A service that implements [Reflect] returns a [ServiceInfo] that lists, for each
+//! request message, its interaction pattern and the names of its update and response
+//! types. The [rpc_service](crate::rpc_service) macro implements [Reflect] for the
+//! services it generates. For services declared by hand, implement it manually.
+//!
+//! Reflection is opt-in. To make the description available to clients, either run a
+//! [ReflectionService] on its own endpoint, or add [Describe] as a rpc message with
+//! response [ServiceInfo] to the service. In both cases, forward the request to
+//! [handle].
+use crate::{
+    message::RpcMsg,
+    server::{RpcChannel, RpcServerError},
+    Service, ServiceEndpoint,
+};
+use serde::{Deserialize, Serialize};
+
+/// The interaction pattern of a method
+///
+/// This is the runtime counterpart of the interaction patterns in [crate::message].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum PatternKind {
+    /// See [crate::message::Rpc]
+    Rpc,
+    /// See [crate::message::ClientStreaming]
+    ClientStreaming,
+    /// See [crate::message::ServerStreaming]
+    ServerStreaming,
+    /// See [crate::message::BidiStreaming]
+    BidiStreaming,
+    /// See [crate::message::RawStreaming]
+    RawStreaming,
+}
+
+/// Description of a single method of a service
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MethodInfo {
+    /// Name of the method, e.g. the name of the client function
+    pub name: String,
+    /// Name of the request type, which is also the name of the request enum variant
+    pub request: String,
+    /// The interaction pattern
+    pub pattern: PatternKind,
+    /// Name of the update type, for patterns that have updates
+    pub update: Option<String>,
+    /// Name of the response type, for patterns that have responses
+    pub response: Option<String>,
+}
+
+/// Description of a service
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ServiceInfo {
+    /// Name of the service
+    pub name: String,
+    /// The methods of the service
+    pub methods: Vec<MethodInfo>,
+}
+
+impl ServiceInfo {
+    /// Get the description of a method by its request type name
+    pub fn method(&self, request: &str) -> Option<&MethodInfo> {
+        self.methods.iter().find(|m| m.request == request)
+    }
+}
+
+/// A service that can describe itself
+pub trait Reflect: Service {
+    /// Describe the service
+    fn service_info() -> ServiceInfo;
+}
+
+/// Ask a server to describe a service
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Describe;
+
+/// A service that just answers [Describe] requests
+///
+/// Which service is described is up to the server, see [handle].
+#[derive(Debug, Clone)]
+pub struct ReflectionService;
+
+impl Service for ReflectionService {
+    type Req = Describe;
+    type Res = ServiceInfo;
+}
+
+impl RpcMsg<ReflectionService> for Describe {
+    type Response = ServiceInfo;
+}
+
+/// Answer a [Describe] request with the description of `S`
+///
+/// The channel can belong to any service `T` that declares [Describe] as a rpc message,
+/// such as [ReflectionService] or `S` itself.
+pub async fn handle<S, T, E>(req: Describe, chan: RpcChannel<T, E>) -> Result<(), RpcServerError<E>>
+where
+    S: Reflect,
+    T: Service,
+    E: ServiceEndpoint<T>,
+    Describe: RpcMsg<T, Response = ServiceInfo>,
+{
+    chan.rpc(req, (), |_, _| async { S::service_info() }).await
+}
@@ -2,13 +2,36 @@
 //!
 //! The main entry point is [RpcServer]
 use crate::{
-    message::{BidiStreamingMsg, ClientStreamingMsg, RpcMsg, ServerStreamingMsg},
-    transport::ConnectionErrors,
+    auth::{Authenticator, Identity, Policy},
+    message::{
+        BidiStreamingMsg, ClientStreamingMsg, Msg, RawStreamingMsg, RpcMsg, ServerStreamingMsg,
+        StreamFrame,
+    },
+    metrics::VariantName,
+    telemetry::{CallSpan, Telemetry},
+    transport::{ConnectionErrors, Metadata, RawStreams, SizeLimits, Status},
+    version::{IncompatibleVersion, Version, Versioned},
     Service, ServiceEndpoint,
 };
-use futures::{channel::oneshot, task, task::Poll, Future, FutureExt, SinkExt, Stream, StreamExt};
+use futures::{
+    channel::oneshot, future, ready, stream, task, task::Poll, Future, FutureExt, SinkExt, Stream,
+    StreamExt,
+};
 use pin_project::pin_project;
-use std::{error, fmt, fmt::Debug, marker::PhantomData, pin::Pin, result};
+use std::{
+    cell::RefCell,
+    error, fmt,
+    fmt::Debug,
+    marker::PhantomData,
+    pin::Pin,
+    result,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+};
+use tokio_util::sync::CancellationToken;
+use tracing::Instrument;
 
 /// A server channel for a specific service.
 ///
@@ -21,6 +44,16 @@ pub struct RpcServer<S, C> {
     /// Each new request is a receiver and channel pair on which messages for this request
     /// are received and responses sent.
     source: C,
+    /// Tracing configuration, passed on to every [RpcChannel].
+    telemetry: Telemetry,
+    /// When to flush responses of streaming interactions, passed on to every [RpcChannel].
+    flush_policy: FlushPolicy,
+    /// Checks the credentials of every channel before it is handed out.
+    authenticator: Option<Arc<dyn Authenticator>>,
+    /// Which methods the identities may call, passed on to every [RpcChannel].
+    policy: Option<Arc<Policy>>,
+    /// The version clients have to be compatible with, if they send one.
+    version: Option<Version>,
     p: PhantomData<S>,
 }
 
@@ -28,6 +61,11 @@ impl<S, C: Clone> Clone for RpcServer<S, C> {
     fn clone(&self) -> Self {
         Self {
             source: self.source.clone(),
+            telemetry: self.telemetry.clone(),
+            flush_policy: self.flush_policy,
+            authenticator: self.authenticator.clone(),
+            policy: self.policy.clone(),
+            version: self.version.clone(),
             p: PhantomData,
         }
     }
@@ -41,9 +79,69 @@ impl<S: Service, C: ServiceEndpoint<S>> RpcServer<S, C> {
     pub fn new(source: C) -> Self {
         Self {
             source,
+            telemetry: Telemetry::default(),
+            flush_policy: FlushPolicy::default(),
+            authenticator: None,
+            policy: None,
+            version: None,
             p: PhantomData,
         }
     }
+
+    /// Set the tracing configuration for the channels of this server.
+    ///
+    /// Default is [Telemetry::default], which creates a debug level span for every handler.
+    pub fn with_telemetry(mut self, telemetry: Telemetry) -> Self {
+        self.telemetry = telemetry;
+        self
+    }
+
+    /// Set the policy for flushing responses of streaming interactions for all channels.
+    ///
+    /// Default is [FlushPolicy::EveryItem]. It can be changed for a single channel using
+    /// [RpcChannel::with_flush_policy].
+    pub fn with_flush_policy(mut self, flush_policy: FlushPolicy) -> Self {
+        self.flush_policy = flush_policy;
+        self
+    }
+
+    /// Check the credentials of every channel before it is handed out by [Self::accept].
+    ///
+    /// Channels the authenticator rejects are closed with the status it returns. Default is
+    /// no authenticator, which hands out all channels without an [Identity].
+    pub fn with_authenticator(mut self, authenticator: impl Authenticator) -> Self {
+        self.authenticator = Some(Arc::new(authenticator));
+        self
+    }
+
+    /// Only allow the methods the policy grants to the identity of a channel.
+    ///
+    /// The policy is checked by the handler methods of [RpcChannel], before the handler is
+    /// called. Requests that are not allowed are rejected with
+    /// [Status::PermissionDenied], and the
+    /// handler method returns [RpcServerError::PermissionDenied]. Channels without an
+    /// identity are not allowed anything, so this needs an authenticator, see
+    /// [Self::with_authenticator]. Default is no policy, which allows all methods.
+    pub fn with_policy(mut self, policy: Policy) -> Self {
+        self.policy = Some(Arc::new(policy));
+        self
+    }
+
+    /// Check the name and schema fingerprint that clients send, see [crate::version].
+    ///
+    /// Channels of clients built for a different version of the service are rejected with
+    /// [Status::FailedPrecondition] and logged, and [Self::accept] goes on with the next
+    /// channel, like for channels the authenticator rejects. Channels of clients that do
+    /// not send a version are rejected the same way, so all clients need
+    /// [RpcClient::with_handshake](crate::RpcClient::with_handshake), and a transport that
+    /// carries metadata. Default is to not check the version.
+    pub fn with_handshake(mut self) -> Self
+    where
+        S: Versioned,
+    {
+        self.version = Some(Version::of::<S>());
+        self
+    }
 }
 
 /// A channel for requests and responses for a specific service.
@@ -59,29 +157,144 @@ pub struct RpcChannel<S: Service, C: ServiceEndpoint<S>> {
     pub send: C::SendSink,
     /// Stream to receive requests from the client.
     pub recv: C::RecvStream,
+    /// Token that is cancelled when the client cancels the interaction.
+    cancel: Mutex<LazyToken>,
+    /// When to flush responses of streaming interactions.
+    flush_policy: FlushPolicy,
+    /// Lets the handler flush responses regardless of the policy.
+    flush: FlushHandle,
+    /// Tracing configuration for the handler.
+    telemetry: Telemetry,
+    /// Metadata sent by the client when opening the channel.
+    metadata: Metadata,
+    /// Identity of the client, if the server has an authenticator.
+    identity: Option<Identity>,
+    /// Which methods the client may call, if the server has a policy.
+    policy: Option<Arc<Policy>>,
     /// Phantom data to make the type parameter `S` non-instantiable.
     p: PhantomData<S>,
 }
 
 impl<S: Service, C: ServiceEndpoint<S>> RpcChannel<S, C> {
     /// Create a new channel from a sink and a stream.
+    ///
+    /// The [metadata](Self::metadata) is taken from the stream, so it should be created
+    /// after the first message has been received.
     pub fn new(send: C::SendSink, recv: C::RecvStream) -> Self {
         Self {
+            metadata: C::metadata(&recv),
             send,
             recv,
+            cancel: Mutex::default(),
+            flush_policy: FlushPolicy::default(),
+            flush: FlushHandle::default(),
+            telemetry: Telemetry::default(),
+            identity: None,
+            policy: None,
             p: PhantomData,
         }
     }
 
+    /// Set the tracing configuration for the handler.
+    ///
+    /// Default is [Telemetry::default]. Channels accepted by a [RpcServer] get the
+    /// configuration of the server.
+    pub fn with_telemetry(mut self, telemetry: Telemetry) -> Self {
+        self.telemetry = telemetry;
+        self
+    }
+
+    /// Set the policy for flushing responses of streaming interactions.
+    ///
+    /// Default is [FlushPolicy::EveryItem]. Channels accepted by a [RpcServer] get the
+    /// policy of the server.
+    pub fn with_flush_policy(mut self, flush_policy: FlushPolicy) -> Self {
+        self.flush_policy = flush_policy;
+        if flush_policy != FlushPolicy::EveryItem && self.flush.0.is_none() {
+            self.flush = FlushHandle(Some(Default::default()));
+        }
+        self
+    }
+
+    /// The metadata the client sent when opening the channel.
+    pub fn metadata(&self) -> &Metadata {
+        &self.metadata
+    }
+
+    /// The identity of the client, as established by the authenticator of the server.
+    ///
+    /// None if the server has no authenticator, see [RpcServer::with_authenticator].
+    pub fn identity(&self) -> Option<&Identity> {
+        self.identity.as_ref()
+    }
+
+    /// A token that is cancelled when the interaction is aborted.
+    ///
+    /// When the client cancels an interaction, e.g. by dropping the future or the response
+    /// stream, the transport signals this to the server, see
+    /// [ConnectionCommon::cancel](crate::transport::ConnectionCommon::cancel). The handler
+    /// future is then dropped and the interaction ends with [RpcServerError::Cancelled].
+    /// If receiving from the client fails instead, the interaction ends with
+    /// [RpcServerError::RecvError]. The token is cancelled in both cases, so handlers that
+    /// spawn work that outlives the handler future can use it to stop that work. Handlers
+    /// can also get the token with [current_cancellation_token].
+    pub fn cancellation_token(&self) -> CancellationToken {
+        self.cancel.lock().unwrap().get()
+    }
+
+    /// A handle to flush the responses of a streaming interaction regardless of the
+    /// [FlushPolicy].
+    ///
+    /// Like the [cancellation token](Self::cancellation_token), get this before calling the
+    /// handler method and move it into the handler. Get it after setting the policy: with
+    /// [FlushPolicy::EveryItem] every response is flushed anyway, so the handle does nothing.
+    pub fn flush_handle(&self) -> FlushHandle {
+        self.flush.clone()
+    }
+
+    /// Reject the channel if the policy does not allow the client to call `M`
+    fn authorize<M: Msg<S>>(self) -> result::Result<Self, RpcServerError<C>> {
+        let policy = match &self.policy {
+            Some(policy) => policy,
+            None => return Ok(self),
+        };
+        let method = M::method_name();
+        match &self.identity {
+            Some(identity) if policy.is_allowed(identity, method) => Ok(self),
+            identity => {
+                tracing::debug!("Denied {} to {:?}", method, identity);
+                C::reject(self.send, self.recv, Status::PermissionDenied);
+                Err(RpcServerError::PermissionDenied)
+            }
+        }
+    }
+
+    /// Limit the channel to the message sizes `M` declares
+    ///
+    /// Rejects the channel if the request is already larger than allowed.
+    fn limit_size<M: Msg<S>>(mut self) -> result::Result<Self, RpcServerError<C>> {
+        let limits = SizeLimits {
+            send: M::MAX_RESPONSE_SIZE,
+            recv: M::MAX_REQUEST_SIZE,
+        };
+        if C::limit_message_size(&mut self.send, &mut self.recv, limits) {
+            Ok(self)
+        } else {
+            tracing::debug!("Request for {} is too large", M::method_name());
+            C::reject(self.send, self.recv, Status::ResourceExhausted);
+            Err(RpcServerError::RequestTooLarge)
+        }
+    }
+
     /// handle the message of type `M` using the given function on the target object
     ///
     /// If you want to support concurrent requests, you need to spawn this on a tokio task yourself.
-    pub async fn rpc<M, F, Fut, T>(
+    pub fn rpc<M, F, Fut, T>(
         self,
         req: M,
         target: T,
         f: F,
-    ) -> result::Result<(), RpcServerError<C>>
+    ) -> impl Future<Output = result::Result<(), RpcServerError<C>>>
     where
         M: RpcMsg<S>,
         F: FnOnce(T, M) -> Fut,
@@ -89,96 +302,125 @@ impl<S: Service, C: ServiceEndpoint<S>> RpcChannel<S, C> {
         T: Send + 'static,
     {
         let Self {
-            mut send, mut recv, ..
-        } = self;
-        // cancel if we get an update, no matter what it is
-        let cancel = recv
-            .next()
-            .map(|_| RpcServerError::UnexpectedUpdateMessage::<C>);
+            mut send,
+            recv,
+            cancel,
+            telemetry,
+            metadata,
+            ..
+        } = match self.authorize::<M>().and_then(Self::limit_size::<M>) {
+            Ok(chan) => chan,
+            Err(cause) => return future::Either::Left(future::ready(Err(cause))),
+        };
+        let call = telemetry.start_server::<S, M>(&metadata);
+        // abort if we get an update, a cancel signal or a receive error
+        let aborted = aborted::<S, C>(recv);
         // race the computation and the cancellation
-        race2(cancel.map(Err), async move {
+        let handler = guarded(aborted, cancel, async move {
             // get the response
             let res = f(target, req).await;
             // turn into a S::Res so we can send it
             let res: S::Res = res.into();
             // send it and return the error if any
             send.send(res).await.map_err(RpcServerError::SendError)
-        })
-        .await
+        });
+        future::Either::Right(traced(call, handler))
     }
 
     /// handle the message M using the given function on the target object
     ///
     /// If you want to support concurrent requests, you need to spawn this on a tokio task yourself.
-    pub async fn client_streaming<M, F, Fut, T>(
+    pub fn client_streaming<M, F, Fut, T>(
         self,
         req: M,
         target: T,
         f: F,
-    ) -> result::Result<(), RpcServerError<C>>
+    ) -> impl Future<Output = result::Result<(), RpcServerError<C>>>
     where
         M: ClientStreamingMsg<S>,
         F: FnOnce(T, M, UpdateStream<S, C, M::Update>) -> Fut + Send + 'static,
         Fut: Future<Output = M::Response> + Send + 'static,
         T: Send + 'static,
     {
-        let Self { mut send, recv, .. } = self;
+        let Self {
+            mut send,
+            recv,
+            cancel,
+            telemetry,
+            metadata,
+            ..
+        } = match self.authorize::<M>().and_then(Self::limit_size::<M>) {
+            Ok(chan) => chan,
+            Err(cause) => return future::Either::Left(future::ready(Err(cause))),
+        };
+        let call = telemetry.start_server::<S, M>(&metadata);
         let (updates, read_error) = UpdateStream::new(recv);
-        race2(read_error.map(Err), async move {
+        let aborted = updates_aborted::<S, C>(read_error);
+        let handler = guarded(aborted, cancel, async move {
             // get the response
             let res = f(target, req, updates).await;
             // turn into a S::Res so we can send it
             let res: S::Res = res.into();
             // send it and return the error if any
             send.send(res).await.map_err(RpcServerError::SendError)
-        })
-        .await
+        });
+        future::Either::Right(traced(call, handler))
     }
 
     /// handle the message M using the given function on the target object
     ///
     /// If you want to support concurrent requests, you need to spawn this on a tokio task yourself.
-    pub async fn bidi_streaming<M, F, Str, T>(
+    pub fn bidi_streaming<M, F, Str, T>(
         self,
         req: M,
         target: T,
         f: F,
-    ) -> result::Result<(), RpcServerError<C>>
+    ) -> impl Future<Output = result::Result<(), RpcServerError<C>>>
     where
         M: BidiStreamingMsg<S>,
         F: FnOnce(T, M, UpdateStream<S, C, M::Update>) -> Str + Send + 'static,
         Str: Stream<Item = M::Response> + Send + 'static,
         T: Send + 'static,
     {
-        let Self { mut send, recv, .. } = self;
+        let Self {
+            mut send,
+            recv,
+            cancel,
+            flush_policy,
+            flush,
+            telemetry,
+            metadata,
+            ..
+        } = match self.authorize::<M>().and_then(Self::limit_size::<M>) {
+            Ok(chan) => chan,
+            Err(cause) => return future::Either::Left(future::ready(Err(cause))),
+        };
+        let call = telemetry.start_server::<S, M>(&metadata);
         // downcast the updates
         let (updates, read_error) = UpdateStream::new(recv);
-        // get the response
-        let responses = f(target, req, updates);
-        race2(read_error.map(Err), async move {
-            tokio::pin!(responses);
-            while let Some(response) = responses.next().await {
-                // turn into a S::Res so we can send it
-                let response: S::Res = response.into();
-                // send it and return the error if any
-                send.send(response)
-                    .await
-                    .map_err(RpcServerError::SendError)?;
+        let aborted = updates_aborted::<S, C>(read_error);
+        let handler = guarded(aborted, cancel, {
+            let call = call.clone();
+            async move {
+                // get the response
+                tokio::pin! {
+                    let responses = f(target, req, updates);
+                }
+                send_all::<S, C, _>(&mut send, responses, flush_policy, &flush, &call).await
             }
-            Ok(())
-        })
-        .await
+        });
+        future::Either::Right(traced(call, handler))
     }
 
     /// handle the message M using the given function on the target object
     ///
     /// If you want to support concurrent requests, you need to spawn this on a tokio task yourself.
-    pub async fn server_streaming<M, F, Str, T>(
+    pub fn server_streaming<M, F, Str, T>(
         self,
         req: M,
         target: T,
         f: F,
-    ) -> result::Result<(), RpcServerError<C>>
+    ) -> impl Future<Output = result::Result<(), RpcServerError<C>>>
     where
         M: ServerStreamingMsg<S>,
         F: FnOnce(T, M) -> Str + Send + 'static,
@@ -186,26 +428,110 @@ impl<S: Service, C: ServiceEndpoint<S>> RpcChannel<S, C> {
         T: Send + 'static,
     {
         let Self {
-            mut send, mut recv, ..
-        } = self;
-        // cancel if we get an update, no matter what it is
-        let cancel = recv
-            .next()
-            .map(|_| RpcServerError::UnexpectedUpdateMessage::<C>);
+            mut send,
+            recv,
+            cancel,
+            flush_policy,
+            flush,
+            telemetry,
+            metadata,
+            ..
+        } = match self.authorize::<M>().and_then(Self::limit_size::<M>) {
+            Ok(chan) => chan,
+            Err(cause) => return future::Either::Left(future::ready(Err(cause))),
+        };
+        let call = telemetry.start_server::<S, M>(&metadata);
+        // abort if we get an update, a cancel signal or a receive error
+        let aborted = aborted::<S, C>(recv);
         // race the computation and the cancellation
-        race2(cancel.map(Err), async move {
-            // get the response
-            let responses = f(target, req);
-            tokio::pin!(responses);
-            while let Some(response) = responses.next().await {
-                // turn into a S::Res so we can send it
-                let response: S::Res = response.into();
-                // send it and return the error if any
-                send.send(response)
-                    .await
-                    .map_err(RpcServerError::SendError)?;
+        let handler = guarded(aborted, cancel, {
+            let call = call.clone();
+            async move {
+                // get the response
+                tokio::pin! {
+                    let responses = f(target, req);
+                }
+                send_all::<S, C, _>(&mut send, responses, flush_policy, &flush, &call).await
             }
-            Ok(())
+        });
+        future::Either::Right(traced(call, handler))
+    }
+
+    /// handle the raw streaming message M using the given function on the target object
+    ///
+    /// After the request, the handler gets the raw byte streams of the channel. Bytes the
+    /// client sent right after the request are not lost. Only available for endpoints that
+    /// implement [RawStreams].
+    pub async fn raw_streaming<M, F, Fut, T>(
+        self,
+        req: M,
+        target: T,
+        f: F,
+    ) -> result::Result<(), RpcServerError<C>>
+    where
+        M: RawStreamingMsg<S>,
+        C: RawStreams<S::Req, S::Res>,
+        F: FnOnce(T, M, C::RawSend, C::RawRecv) -> Fut,
+        Fut: Future<Output = ()>,
+        T: Send + 'static,
+    {
+        let Self {
+            send,
+            recv,
+            telemetry,
+            metadata,
+            ..
+        } = self.authorize::<M>()?.limit_size::<M>()?;
+        let call = telemetry.start_server::<S, M>(&metadata);
+        let (send, recv) = C::into_raw(send, recv);
+        f(target, req, send, recv).instrument(call.span()).await;
+        call.finish(Ok(()))
+    }
+
+    /// A server streaming call where the response stream ends with a trailer
+    ///
+    /// The handler produces a stream of [StreamFrame]s, and is expected to end it with a
+    /// [StreamFrame::Trailer]. Anything the handler produces after the trailer is ignored.
+    /// If the handler stream ends without a trailer, the client will see an early close.
+    pub async fn server_streaming_with_trailer<M, F, Str, T, I, U>(
+        self,
+        req: M,
+        target: T,
+        f: F,
+    ) -> result::Result<(), RpcServerError<C>>
+    where
+        M: ServerStreamingMsg<S, Response = StreamFrame<I, U>>,
+        I: Send + 'static,
+        U: Send + 'static,
+        F: FnOnce(T, M) -> Str + Send + 'static,
+        Str: Stream<Item = StreamFrame<I, U>> + Send + 'static,
+        T: Send + 'static,
+    {
+        self.server_streaming(req, target, |target, req| until_trailer(f(target, req)))
+            .await
+    }
+
+    /// A bidi streaming call where the response stream ends with a trailer
+    ///
+    /// The handler produces a stream of [StreamFrame]s, and is expected to end it with a
+    /// [StreamFrame::Trailer]. Anything the handler produces after the trailer is ignored.
+    /// If the handler stream ends without a trailer, the client will see an early close.
+    pub async fn bidi_streaming_with_trailer<M, F, Str, T, I, U>(
+        self,
+        req: M,
+        target: T,
+        f: F,
+    ) -> result::Result<(), RpcServerError<C>>
+    where
+        M: BidiStreamingMsg<S, Response = StreamFrame<I, U>>,
+        I: Send + 'static,
+        U: Send + 'static,
+        F: FnOnce(T, M, UpdateStream<S, C, M::Update>) -> Str + Send + 'static,
+        Str: Stream<Item = StreamFrame<I, U>> + Send + 'static,
+        T: Send + 'static,
+    {
+        self.bidi_streaming(req, target, |target, req, updates| {
+            until_trailer(f(target, req, updates))
         })
         .await
     }
@@ -236,6 +562,75 @@ impl<S: Service, C: ServiceEndpoint<S>> RpcChannel<S, C> {
         };
         self.rpc(req, target, fut).await
     }
+
+    /// A client streaming call that also maps the error from the user type to the wire type
+    ///
+    /// See [RpcChannel::rpc_map_err].
+    pub async fn client_streaming_map_err<M, F, Fut, T, R, E1, E2>(
+        self,
+        req: M,
+        target: T,
+        f: F,
+    ) -> result::Result<(), RpcServerError<C>>
+    where
+        M: ClientStreamingMsg<S, Response = result::Result<R, E2>>,
+        F: FnOnce(T, M, UpdateStream<S, C, M::Update>) -> Fut + Send + 'static,
+        Fut: Future<Output = result::Result<R, E1>> + Send + 'static,
+        E2: From<E1>,
+        T: Send + 'static,
+    {
+        let fut = |target: T, msg: M, updates: UpdateStream<S, C, M::Update>| async move {
+            // call the inner fn and convert the error type
+            f(target, msg, updates).await.map_err(E2::from)
+        };
+        self.client_streaming(req, target, fut).await
+    }
+
+    /// A server streaming call that also maps the error from the user type to the wire type
+    ///
+    /// The response stream is terminated after the first error. See [RpcChannel::rpc_map_err].
+    pub async fn server_streaming_map_err<M, F, Str, T, R, E1, E2>(
+        self,
+        req: M,
+        target: T,
+        f: F,
+    ) -> result::Result<(), RpcServerError<C>>
+    where
+        M: ServerStreamingMsg<S, Response = result::Result<R, E2>>,
+        F: FnOnce(T, M) -> Str + Send + 'static,
+        Str: Stream<Item = result::Result<R, E1>> + Send + 'static,
+        E2: From<E1> + Send + 'static,
+        R: Send + 'static,
+        T: Send + 'static,
+    {
+        self.server_streaming(req, target, |target, msg| {
+            until_err(f(target, msg).map(|res| res.map_err(E2::from)))
+        })
+        .await
+    }
+
+    /// A bidi streaming call that also maps the error from the user type to the wire type
+    ///
+    /// The response stream is terminated after the first error. See [RpcChannel::rpc_map_err].
+    pub async fn bidi_streaming_map_err<M, F, Str, T, R, E1, E2>(
+        self,
+        req: M,
+        target: T,
+        f: F,
+    ) -> result::Result<(), RpcServerError<C>>
+    where
+        M: BidiStreamingMsg<S, Response = result::Result<R, E2>>,
+        F: FnOnce(T, M, UpdateStream<S, C, M::Update>) -> Str + Send + 'static,
+        Str: Stream<Item = result::Result<R, E1>> + Send + 'static,
+        E2: From<E1> + Send + 'static,
+        R: Send + 'static,
+        T: Send + 'static,
+    {
+        self.bidi_streaming(req, target, |target, msg, updates| {
+            until_err(f(target, msg, updates).map(|res| res.map_err(E2::from)))
+        })
+        .await
+    }
 }
 
 impl<S: Service, C: ServiceEndpoint<S>> RpcServer<S, C> {
@@ -248,22 +643,60 @@ impl<S: Service, C: ServiceEndpoint<S>> RpcServer<S, C> {
     ///
     /// Often sink and stream will wrap an an underlying byte stream. In this case you can
     /// call into_inner() on them to get it back to perform byte level reads and writes.
+    ///
+    /// If the server has an authenticator, channels it rejects are closed and skipped.
     pub async fn accept(&self) -> result::Result<(S::Req, RpcChannel<S, C>), RpcServerError<C>> {
-        let (send, mut recv) = self
-            .source
-            .accept_bi()
-            .await
-            .map_err(RpcServerError::Accept)?;
+        loop {
+            let (send, mut recv) = self
+                .source
+                .accept_bi()
+                .await
+                .map_err(RpcServerError::Accept)?;
 
-        // get the first message from the client. This will tell us what it wants to do.
-        let request: S::Req = recv
-            .next()
-            .await
-            // no msg => early close
-            .ok_or(RpcServerError::EarlyClose)?
-            // recv error
-            .map_err(RpcServerError::RecvError)?;
-        Ok((request, RpcChannel::new(send, recv)))
+            // get the first message from the client. This will tell us what it wants to do.
+            let first = recv.next().await;
+            // the metadata is only available once the first message has been received.
+            // The version is checked before looking at the message, since a client with
+            // a different version might send a message that can not be decoded.
+            // A missing version is rejected as well, since it also happens when the
+            // transport drops the metadata of a client that did send it.
+            if let Some(server) = &self.version {
+                let client = Version::from_metadata(&C::metadata(&recv));
+                if client.as_ref() != Some(server) {
+                    let error = IncompatibleVersion {
+                        server: server.clone(),
+                        client,
+                    };
+                    tracing::warn!("Rejected channel: {}", error);
+                    C::reject(send, recv, Status::FailedPrecondition);
+                    continue;
+                }
+            }
+            let request: S::Req = first
+                // no msg => early close
+                .ok_or(RpcServerError::EarlyClose)?
+                // recv error
+                .map_err(RpcServerError::RecvError)?;
+            let identity = match &self.authenticator {
+                Some(authenticator) => {
+                    match authenticator.authenticate(&C::peer(&recv), &C::metadata(&recv)) {
+                        Ok(identity) => Some(identity),
+                        Err(status) => {
+                            tracing::debug!("Rejected channel: {}", status);
+                            C::reject(send, recv, status);
+                            continue;
+                        }
+                    }
+                }
+                None => None,
+            };
+            let mut chan = RpcChannel::new(send, recv)
+                .with_telemetry(self.telemetry.clone())
+                .with_flush_policy(self.flush_policy);
+            chan.identity = identity;
+            chan.policy = self.policy.clone();
+            return Ok((request, chan));
+        }
     }
 
     /// Get the underlying service endpoint
@@ -278,23 +711,168 @@ impl<S: Service, C: ServiceEndpoint<S>> AsRef<C> for RpcServer<S, C> {
     }
 }
 
+/// When to flush the responses of a streaming interaction.
+///
+/// Flushing after every item is the simplest option, but for transports like quinn and hyper
+/// every flush costs a frame and a syscall. With [FlushPolicy::Batch], responses are written
+/// to the sink without flushing, and the sink is flushed once `max_items` responses are
+/// pending, whenever the handler stream has no response ready, and at the end of the stream.
+/// So batching never delays a response while the handler is waiting for something.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum FlushPolicy {
+    /// Flush after every response
+    #[default]
+    EveryItem,
+    /// Flush after at most `max_items` responses
+    Batch {
+        /// Maximum number of responses to write before flushing
+        max_items: usize,
+    },
+}
+
+/// Flushes the responses of a streaming interaction, see [RpcChannel::flush_handle]
+///
+/// This is cheap to clone. All clones refer to the same interaction.
+#[derive(Debug, Clone, Default)]
+pub struct FlushHandle(Option<Arc<AtomicBool>>);
+
+impl FlushHandle {
+    /// Flush once the next response has been written.
+    ///
+    /// Call this right before the handler stream yields a response to get that response
+    /// and all responses before it to the client without waiting for the [FlushPolicy].
+    pub fn flush(&self) {
+        if let Some(flag) = &self.0 {
+            flag.store(true, Ordering::Relaxed);
+        }
+    }
+
+    /// Whether a flush was requested, resetting the request
+    fn take(&self) -> bool {
+        match &self.0 {
+            Some(flag) => flag.swap(false, Ordering::Relaxed),
+            None => false,
+        }
+    }
+}
+
+/// Send all responses of a streaming interaction, flushing according to the policy and
+/// whenever the handler asks for it
+async fn send_all<S, C, M>(
+    send: &mut C::SendSink,
+    mut responses: Pin<&mut impl Stream<Item = M>>,
+    flush_policy: FlushPolicy,
+    flush: &FlushHandle,
+    call: &CallSpan,
+) -> result::Result<(), RpcServerError<C>>
+where
+    S: Service,
+    C: ServiceEndpoint<S>,
+    M: Into<S::Res>,
+{
+    let max_items = match flush_policy {
+        FlushPolicy::EveryItem => 1,
+        FlushPolicy::Batch { max_items } => max_items.max(1),
+    };
+    let mut pending = 0;
+    loop {
+        let response = match responses.next().now_or_never() {
+            Some(response) => response,
+            None => {
+                // nothing ready, so flush what we have before waiting
+                if pending > 0 {
+                    send.flush().await.map_err(RpcServerError::SendError)?;
+                    pending = 0;
+                }
+                responses.next().await
+            }
+        };
+        let response: S::Res = match response {
+            // turn into a S::Res so we can send it
+            Some(response) => response.into(),
+            None => break,
+        };
+        send.feed(response)
+            .await
+            .map_err(RpcServerError::SendError)?;
+        call.item(C::sent_size(send));
+        pending += 1;
+        if pending >= max_items || flush.take() {
+            send.flush().await.map_err(RpcServerError::SendError)?;
+            pending = 0;
+        }
+    }
+    if pending > 0 {
+        send.flush().await.map_err(RpcServerError::SendError)?;
+    }
+    Ok(())
+}
+
 /// A stream of updates
 ///
-/// If there is any error with receiving or with decoding the updates, the stream will stall and the error will
-/// cause a termination of the RPC call.
-#[pin_project]
+/// When the client finishes the updates, e.g. using [UpdateSink::finish](crate::client::UpdateSink::finish),
+/// the stream ends, and the handler can still produce the response.
+///
+/// If there is any error with receiving or with decoding the updates, or the client cancels the
+/// interaction, the stream will stall and the error will cause a termination of the RPC call.
+/// This also works after the handler dropped the stream.
 #[derive(Debug)]
 pub struct UpdateStream<S: Service, C: ServiceEndpoint<S>, T>(
-    #[pin] C::RecvStream,
-    Option<oneshot::Sender<RpcServerError<C>>>,
+    Option<C::RecvStream>,
+    Option<oneshot::Sender<UpdatesEnd<S, C>>>,
     PhantomData<T>,
 );
 
 impl<S: Service, C: ServiceEndpoint<S>, T> UpdateStream<S, C, T> {
-    fn new(recv: C::RecvStream) -> (Self, UnwrapToPending<RpcServerError<C>>) {
+    fn new(recv: C::RecvStream) -> (Self, UnwrapToPending<UpdatesEnd<S, C>>) {
         let (error_send, error_recv) = oneshot::channel();
         let error_recv = UnwrapToPending(error_recv);
-        (Self(recv, Some(error_send), PhantomData), error_recv)
+        (Self(Some(recv), Some(error_send), PhantomData), error_recv)
+    }
+}
+
+// the receive stream is Unpin, and nothing else is pinned
+impl<S: Service, C: ServiceEndpoint<S>, T> Unpin for UpdateStream<S, C, T> {}
+
+impl<S: Service, C: ServiceEndpoint<S>, T> Drop for UpdateStream<S, C, T> {
+    fn drop(&mut self) {
+        // hand the stream back, so a cancel or an error still terminates the call
+        if let (Some(recv), Some(tx)) = (self.0.take(), self.1.take()) {
+            let _ = tx.send(UpdatesEnd::Dropped(recv));
+        }
+    }
+}
+
+/// How the [UpdateStream] of an interaction ended
+#[derive(Debug)]
+enum UpdatesEnd<S: Service, C: ServiceEndpoint<S>> {
+    /// Receiving or decoding an update failed
+    Error(C::RecvError),
+    /// An update had the wrong type
+    Unexpected,
+    /// The handler dropped the stream
+    Dropped(C::RecvStream),
+}
+
+/// Wait for an interaction with updates to be aborted.
+///
+/// Updates that arrive after the handler dropped the [UpdateStream] are ignored. The end
+/// of the stream is the end of the updates, not a cancellation, so it does not abort the
+/// interaction. Without a cancel signal from the transport, a client that went away is
+/// only noticed once sending the response fails.
+async fn updates_aborted<S: Service, C: ServiceEndpoint<S>>(
+    end: UnwrapToPending<UpdatesEnd<S, C>>,
+) -> RpcServerError<C> {
+    match end.await {
+        UpdatesEnd::Error(cause) => abort::<S, C>(cause),
+        UpdatesEnd::Unexpected => RpcServerError::UnexpectedUpdateMessage,
+        UpdatesEnd::Dropped(mut recv) => loop {
+            match recv.next().await {
+                Some(Ok(_)) => {}
+                Some(Err(cause)) => break abort::<S, C>(cause),
+                None => future::pending().await,
+            }
+        },
     }
 }
 
@@ -305,15 +883,20 @@ where
     type Item = T;
 
     fn poll_next(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<Option<Self::Item>> {
-        let mut this = self.project();
-        match this.0.poll_next_unpin(cx) {
+        let this = self.get_mut();
+        let recv = match (&mut this.0, &this.1) {
+            (Some(recv), Some(_)) => recv,
+            // an error was already reported, stall so the error terminates the call
+            _ => return Poll::Pending,
+        };
+        match recv.poll_next_unpin(cx) {
             Poll::Ready(Some(msg)) => match msg {
                 Ok(msg) => match T::try_from(msg) {
                     Ok(msg) => Poll::Ready(Some(msg)),
                     Err(_cause) => {
                         // we were unable to downcast, so we need to send an error
                         if let Some(tx) = this.1.take() {
-                            let _ = tx.send(RpcServerError::UnexpectedUpdateMessage);
+                            let _ = tx.send(UpdatesEnd::Unexpected);
                         }
                         Poll::Pending
                     }
@@ -321,7 +904,7 @@ where
                 Err(cause) => {
                     // we got a recv error, so return pending and send the error
                     if let Some(tx) = this.1.take() {
-                        let _ = tx.send(RpcServerError::RecvError(cause));
+                        let _ = tx.send(UpdatesEnd::Error(cause));
                     }
                     Poll::Pending
                 }
@@ -346,17 +929,26 @@ pub enum RpcServerError<C: ConnectionErrors> {
     SendError(C::SendError),
     /// Got an unexpected update message, e.g. a request message or a non-matching update message
     UnexpectedUpdateMessage,
+    /// The client cancelled the interaction, e.g. by dropping the request future
+    Cancelled,
+    /// The policy of the server does not allow the client to call the method
+    PermissionDenied,
+    /// The request is larger than the message declares, see [Msg::MAX_REQUEST_SIZE]
+    RequestTooLarge,
 }
 
 impl<C: ConnectionErrors> fmt::Debug for RpcServerError<C> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            Self::Accept(arg0) => f.debug_tuple("Open").field(arg0).finish(),
+            Self::Accept(arg0) => f.debug_tuple("Accept").field(arg0).finish(),
             Self::EarlyClose => write!(f, "EarlyClose"),
             Self::RecvError(arg0) => f.debug_tuple("RecvError").field(arg0).finish(),
             Self::SendError(arg0) => f.debug_tuple("SendError").field(arg0).finish(),
             Self::UnexpectedStartMessage => f.debug_tuple("UnexpectedStartMessage").finish(),
-            Self::UnexpectedUpdateMessage => f.debug_tuple("UnexpectedStartMessage").finish(),
+            Self::UnexpectedUpdateMessage => f.debug_tuple("UnexpectedUpdateMessage").finish(),
+            Self::Cancelled => write!(f, "Cancelled"),
+            Self::PermissionDenied => write!(f, "PermissionDenied"),
+            Self::RequestTooLarge => write!(f, "RequestTooLarge"),
         }
     }
 }
@@ -369,6 +961,53 @@ impl<C: ConnectionErrors> fmt::Display for RpcServerError<C> {
 
 impl<C: ConnectionErrors> error::Error for RpcServerError<C> {}
 
+impl<C: ConnectionErrors> VariantName for RpcServerError<C> {
+    fn variant_name(&self) -> &'static str {
+        match self {
+            Self::Accept(_) => "Accept",
+            Self::EarlyClose => "EarlyClose",
+            Self::UnexpectedStartMessage => "UnexpectedStartMessage",
+            Self::RecvError(_) => "RecvError",
+            Self::SendError(_) => "SendError",
+            Self::UnexpectedUpdateMessage => "UnexpectedUpdateMessage",
+            Self::Cancelled => "Cancelled",
+            Self::PermissionDenied => "PermissionDenied",
+            Self::RequestTooLarge => "RequestTooLarge",
+        }
+    }
+}
+
+/// Ends the stream after the first trailer
+///
+/// The inner stream is dropped as soon as the trailer has been produced.
+fn until_trailer<I, U>(
+    frames: impl Stream<Item = StreamFrame<I, U>>,
+) -> impl Stream<Item = StreamFrame<I, U>> {
+    stream::unfold(Some(Box::pin(frames)), |frames| async move {
+        let mut frames = frames?;
+        let frame = frames.next().await?;
+        let frames = match frame {
+            StreamFrame::Item(_) => Some(frames),
+            StreamFrame::Trailer(_) => None,
+        };
+        Some((frame, frames))
+    })
+}
+
+/// Ends the stream after the first error
+///
+/// The inner stream is dropped as soon as the error has been produced.
+fn until_err<R, E>(
+    items: impl Stream<Item = result::Result<R, E>>,
+) -> impl Stream<Item = result::Result<R, E>> {
+    stream::unfold(Some(Box::pin(items)), |items| async move {
+        let mut items = items?;
+        let item = items.next().await?;
+        let items = if item.is_ok() { Some(items) } else { None };
+        Some((item, items))
+    })
+}
+
 /// Take an oneshot receiver and just return Pending the underlying future returns `Err(oneshot::Canceled)`
 struct UnwrapToPending<T>(oneshot::Receiver<T>);
 
@@ -384,10 +1023,155 @@ impl<T> Future for UnwrapToPending<T> {
     }
 }
 
-async fn race2<T, A: Future<Output = T>, B: Future<Output = T>>(f1: A, f2: B) -> T {
-    tokio::select! {
-        x = f1 => x,
-        x = f2 => x,
+/// Wait for an interaction that does not expect updates to be aborted.
+///
+/// Any message is an unexpected update. The client keeps its side of the channel open
+/// until it has all responses, so the end of the stream means that the client went away.
+/// This is how the server notices a cancelled call on transports that do not implement
+/// [ConnectionCommon::cancel](crate::transport::ConnectionCommon::cancel).
+async fn aborted<S: Service, C: ServiceEndpoint<S>>(mut recv: C::RecvStream) -> RpcServerError<C> {
+    match recv.next().await {
+        Some(Ok(_)) => RpcServerError::UnexpectedUpdateMessage,
+        Some(Err(cause)) => abort::<S, C>(cause),
+        None => {
+            tracing::debug!("Interaction cancelled, client closed the channel");
+            RpcServerError::Cancelled
+        }
+    }
+}
+
+/// The error for a receive error that aborts an interaction
+///
+/// Tells the cancel signal of the client apart from other receive errors.
+fn abort<S: Service, C: ServiceEndpoint<S>>(cause: C::RecvError) -> RpcServerError<C> {
+    if C::is_cancelled(&cause) {
+        tracing::debug!("Interaction cancelled by client");
+        RpcServerError::Cancelled
+    } else {
+        tracing::debug!("Interaction aborted: {:?}", cause);
+        RpcServerError::RecvError(cause)
+    }
+}
+
+thread_local! {
+    /// The cancellation token of the interaction whose handler is being polled
+    static CURRENT_CANCEL: RefCell<Option<LazyToken>> = const { RefCell::new(None) };
+}
+
+/// The cancellation token of the interaction whose handler is running, see
+/// [RpcChannel::cancellation_token].
+///
+/// This is available while the handler future or response stream of a [RpcChannel] method
+/// is polled, so handlers do not need to get the token before calling the method. Returns
+/// None anywhere else, e.g. in tasks the handler spawns.
+pub fn current_cancellation_token() -> Option<CancellationToken> {
+    CURRENT_CANCEL.with(|current| current.borrow_mut().as_mut().map(LazyToken::get))
+}
+
+/// The cancellation token of an interaction, created when it is first asked for
+///
+/// Most handlers never look at the token, and creating one costs an allocation.
+#[derive(Debug, Default)]
+struct LazyToken(Option<CancellationToken>);
+
+impl LazyToken {
+    fn get(&mut self) -> CancellationToken {
+        self.0.get_or_insert_with(CancellationToken::new).clone()
+    }
+
+    fn cancel(&self) {
+        if let Some(token) = &self.0 {
+            token.cancel();
+        }
+    }
+}
+
+/// Run a handler until it completes or the interaction is aborted
+///
+/// If both happen, the handler wins. If the client cancelled the interaction or receiving failed, the cancellation token
+/// is cancelled. While the handler is polled, the token is available with
+/// [current_cancellation_token].
+fn guarded<C, A, F>(aborted: A, token: Mutex<LazyToken>, handler: F) -> Guarded<A, F>
+where
+    C: ConnectionErrors,
+    A: Future<Output = RpcServerError<C>>,
+    F: Future<Output = result::Result<(), RpcServerError<C>>>,
+{
+    Guarded {
+        aborted,
+        handler,
+        token: Some(token.into_inner().unwrap()),
+    }
+}
+
+/// Run a handler in the span of the call, and record its outcome
+///
+/// The handler methods of [RpcChannel] build their future with this instead of being async
+/// fns, which would store the channel next to the parts taken out of it.
+fn traced<C: ConnectionErrors>(
+    call: CallSpan,
+    handler: impl Future<Output = result::Result<(), RpcServerError<C>>>,
+) -> impl Future<Output = result::Result<(), RpcServerError<C>>> {
+    handler
+        .instrument(call.span())
+        .map(move |res| call.finish(res))
+}
+
+#[pin_project]
+struct Guarded<A, F> {
+    #[pin]
+    aborted: A,
+    #[pin]
+    handler: F,
+    /// None while the handler is polled, the token is in [CURRENT_CANCEL] then
+    token: Option<LazyToken>,
+}
+
+impl<C, A, F> Future for Guarded<A, F>
+where
+    C: ConnectionErrors,
+    A: Future<Output = RpcServerError<C>>,
+    F: Future<Output = result::Result<(), RpcServerError<C>>>,
+{
+    type Output = result::Result<(), RpcServerError<C>>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<Self::Output> {
+        /// Moves the token back and restores the previous one, also if the handler panics
+        struct Restore<'a> {
+            token: &'a mut Option<LazyToken>,
+            previous: Option<LazyToken>,
+        }
+
+        impl Drop for Restore<'_> {
+            fn drop(&mut self) {
+                *self.token = CURRENT_CANCEL.with(|current| current.replace(self.previous.take()));
+            }
+        }
+
+        let this = self.project();
+        // poll the handler first, so a handler that is done right away does not have to
+        // wait for anything from the client
+        let res = {
+            let previous = CURRENT_CANCEL.with(|current| current.replace(this.token.take()));
+            let _restore = Restore {
+                token: this.token,
+                previous,
+            };
+            this.handler.poll(cx)
+        };
+        if res.is_ready() {
+            return res;
+        }
+        let error = ready!(this.aborted.poll(cx));
+        if matches!(
+            error,
+            RpcServerError::Cancelled | RpcServerError::RecvError(_)
+        ) {
+            if let Some(token) = this.token {
+                token.cancel();
+            }
+        }
+        Poll::Ready(Err(error))
     }
 }
 
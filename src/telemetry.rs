@@ -0,0 +1,530 @@
+//! Tracing spans for rpc interactions
+//!
+//! Every client call and server handler runs in a [tracing::Span] that records the
+//! service, the method, the interaction pattern and a request id. The span
+//! also records the number of response items and the outcome of the interaction
+//! in the `items` and `outcome` fields once they are known.
+//!
+//! Spans are created by a [SpanFactory]. The default, [DefaultSpanFactory], creates
+//! spans at debug level. Use [Telemetry] to plug in a different factory, or to disable
+//! the spans, for a [RpcClient](crate::RpcClient) or a [RpcServer](crate::RpcServer).
+//!
+//! The request id is unique within the process, so the client side and the server side
+//! of the same interaction have different ids. To correlate them, the client sends its
+//! id in the `rpc-request-id` entry of the [Metadata] of the channel, and the server
+//! records it in the `client_request_id` field of its span. The id is only sent if the
+//! client span is recorded.
+//!
+//! Interactions without a recorded span and without metrics cost next to nothing: if
+//! [SpanFactory::is_enabled] returns false and there is no [MetricsSink], no
+//! [RequestInfo] and no span are created.
+//!
+//! To link the server span of an interaction to the client span, configure a
+//! [Propagator] on both sides. The client then sends the [TraceContext] of its span as
+//! a W3C `traceparent` in the [Metadata] of the channel, and the server makes its span
+//! a child of it. The propagator connects this to the tracing system in use, e.g.
+//! OpenTelemetry, so this crate does not depend on one.
+//!
+//! [Telemetry] also configures the [metrics](crate::metrics) of interactions.
+use crate::{
+    message::Msg,
+    metrics::{CallMetrics, MetricsSink, VariantName},
+    transport::Metadata,
+    Service,
+};
+use std::{
+    fmt,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+use tracing::{field, span::Entered, Level, Span};
+
+/// Which side of an interaction a span belongs to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Side {
+    /// The span of a [RpcClient](crate::RpcClient) call
+    Client,
+    /// The span of a [RpcChannel](crate::server::RpcChannel) handler
+    Server,
+}
+
+/// Description of an interaction, used to create its span
+#[derive(Debug, Clone)]
+pub struct RequestInfo {
+    /// The side of the interaction
+    pub side: Side,
+    /// Name of the service type
+    pub service: &'static str,
+    /// Name of the method, see [Msg::method_name]
+    pub method: &'static str,
+    /// Name of the interaction pattern
+    pub pattern: &'static str,
+    /// Id of the request, unique within the process
+    pub request_id: u64,
+    /// Id of the request on the client, on the server side if the client sent it
+    pub client_request_id: Option<u64>,
+}
+
+impl RequestInfo {
+    /// The metadata key for the request id of the client
+    pub const CLIENT_REQUEST_ID: &'static str = "rpc-request-id";
+
+    fn new<S: Service, M: Msg<S>>(side: Side, client_request_id: Option<u64>) -> Self {
+        static NEXT_REQUEST_ID: AtomicU64 = AtomicU64::new(0);
+        Self {
+            side,
+            service: short_type_name::<S>(),
+            method: M::method_name(),
+            pattern: short_type_name::<M::Pattern>(),
+            request_id: NEXT_REQUEST_ID.fetch_add(1, Ordering::Relaxed),
+            client_request_id,
+        }
+    }
+}
+
+/// Creates the spans for interactions
+///
+/// The span should declare the `items` and `outcome` fields, e.g. as
+/// [field::Empty], so they can be recorded when the interaction ends.
+pub trait SpanFactory: Send + Sync + 'static {
+    /// Create the span for an interaction
+    fn make_span(&self, info: &RequestInfo) -> Span;
+
+    /// Whether [SpanFactory::make_span] would currently create a span that is recorded
+    ///
+    /// This is checked before every interaction. Default is true.
+    fn is_enabled(&self) -> bool {
+        true
+    }
+}
+
+/// The default span factory, creates a debug level span named `rpc`
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DefaultSpanFactory;
+
+impl SpanFactory for DefaultSpanFactory {
+    fn is_enabled(&self) -> bool {
+        tracing::enabled!(Level::DEBUG)
+    }
+
+    fn make_span(&self, info: &RequestInfo) -> Span {
+        let span = tracing::debug_span!(
+            "rpc",
+            side = ?info.side,
+            service = info.service,
+            method = info.method,
+            pattern = info.pattern,
+            request_id = info.request_id,
+            client_request_id = field::Empty,
+            items = field::Empty,
+            outcome = field::Empty,
+        );
+        if let Some(id) = info.client_request_id {
+            span.record("client_request_id", id);
+        }
+        span
+    }
+}
+
+/// The W3C trace context of a span, see <https://www.w3.org/TR/trace-context/>
+///
+/// This is sent from the client to the server in the `traceparent` metadata entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct TraceContext {
+    /// Id of the trace, must not be zero
+    pub trace_id: u128,
+    /// Id of the span within the trace, must not be zero
+    pub span_id: u64,
+    /// Trace flags, see [TraceContext::SAMPLED]
+    pub flags: u8,
+}
+
+impl TraceContext {
+    /// The metadata key for the trace context
+    pub const TRACEPARENT: &'static str = "traceparent";
+
+    /// The trace flag that is set if the caller may have recorded the trace
+    pub const SAMPLED: u8 = 1;
+
+    /// Whether the [TraceContext::SAMPLED] flag is set
+    pub fn is_sampled(&self) -> bool {
+        self.flags & Self::SAMPLED != 0
+    }
+
+    /// Get the trace context from the `traceparent` entry of the metadata
+    ///
+    /// Returns `None` if there is no entry or it is invalid.
+    pub fn from_metadata(metadata: &Metadata) -> Option<Self> {
+        metadata.get(Self::TRACEPARENT)?.parse().ok()
+    }
+
+    /// Set the `traceparent` entry of the metadata
+    pub fn to_metadata(&self, metadata: &mut Metadata) {
+        metadata.insert(Self::TRACEPARENT, self.to_string());
+    }
+}
+
+/// Formats as a version 00 `traceparent` header value
+impl fmt::Display for TraceContext {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "00-{:032x}-{:016x}-{:02x}",
+            self.trace_id, self.span_id, self.flags
+        )
+    }
+}
+
+/// Error when parsing a [TraceContext] from a `traceparent` value
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ParseTraceContextError;
+
+impl fmt::Display for ParseTraceContextError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self, f)
+    }
+}
+
+impl std::error::Error for ParseTraceContextError {}
+
+/// Parses a `traceparent` header value
+///
+/// As required by the spec, values of unknown future versions are accepted as long as
+/// they start with the fields of version 00.
+impl FromStr for TraceContext {
+    type Err = ParseTraceContextError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // lowercase hex with exactly the given number of digits
+        fn hex(s: &str, len: usize) -> Result<u128, ParseTraceContextError> {
+            let valid = s.len() == len && s.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'));
+            if !valid {
+                return Err(ParseTraceContextError);
+            }
+            u128::from_str_radix(s, 16).map_err(|_| ParseTraceContextError)
+        }
+        let mut parts = s.trim().split('-');
+        let mut next = || parts.next().ok_or(ParseTraceContextError);
+        let version = hex(next()?, 2)?;
+        let trace_id = hex(next()?, 32)?;
+        let span_id = hex(next()?, 16)? as u64;
+        let flags = hex(next()?, 2)? as u8;
+        let rest = parts.next();
+        if version == 0xff || (version == 0 && rest.is_some()) || trace_id == 0 || span_id == 0 {
+            return Err(ParseTraceContextError);
+        }
+        Ok(Self {
+            trace_id,
+            span_id,
+            flags,
+        })
+    }
+}
+
+/// Connects the spans of this crate to the trace context of a tracing system
+///
+/// Implement this for the tracing system in use, e.g. using the extensions that
+/// `tracing-opentelemetry` adds to [Span].
+pub trait Propagator: Send + Sync + 'static {
+    /// The trace context of a client span, to send to the server
+    ///
+    /// Returns `None` if the span is not part of a trace.
+    fn context(&self, span: &Span) -> Option<TraceContext>;
+
+    /// Make a server span a child of the trace context received from the client
+    fn set_parent(&self, span: &Span, parent: TraceContext);
+}
+
+/// Tracing and metrics configuration for clients and servers
+///
+/// This is cheap to clone. Default is enabled, with the [DefaultSpanFactory], without
+/// a [Propagator] and without metrics. As long as the debug level is disabled for this
+/// crate, the default does not do any work per interaction.
+#[derive(Clone, Default)]
+pub struct Telemetry(Option<Arc<Config>>);
+
+/// The configuration of a [Telemetry] that is not the default
+///
+/// The default is not allocated, and the configuration is behind a single pointer, so
+/// every channel and handler future can carry a [Telemetry] without growing much.
+#[derive(Clone)]
+struct Config {
+    factory: Factory,
+    propagator: Option<Arc<dyn Propagator>>,
+    metrics: Option<Arc<dyn MetricsSink>>,
+}
+
+impl fmt::Debug for Telemetry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Telemetry")
+            .field("enabled", &self.is_enabled())
+            .field("propagate", &self.propagator().is_some())
+            .field("metrics", &self.metrics().is_some())
+            .finish()
+    }
+}
+
+/// The span factory of a [Telemetry]
+#[derive(Clone)]
+enum Factory {
+    Disabled,
+    Default,
+    Custom(Arc<dyn SpanFactory>),
+}
+
+impl Factory {
+    fn get(&self) -> Option<&dyn SpanFactory> {
+        match self {
+            Self::Disabled => None,
+            Self::Default => Some(&DefaultSpanFactory),
+            Self::Custom(factory) => Some(factory.as_ref()),
+        }
+    }
+}
+
+impl Telemetry {
+    /// No spans are created
+    pub fn disabled() -> Self {
+        Self::new(Factory::Disabled)
+    }
+
+    /// Spans are created by the given factory
+    pub fn with_factory(factory: impl SpanFactory) -> Self {
+        Self::new(Factory::Custom(Arc::new(factory)))
+    }
+
+    fn new(factory: Factory) -> Self {
+        Self(Some(Arc::new(Config {
+            factory,
+            propagator: None,
+            metrics: None,
+        })))
+    }
+
+    /// The configuration, to modify it
+    fn config_mut(&mut self) -> &mut Config {
+        let config = self.0.get_or_insert_with(|| {
+            Arc::new(Config {
+                factory: Factory::Default,
+                propagator: None,
+                metrics: None,
+            })
+        });
+        Arc::make_mut(config)
+    }
+
+    /// Pass the measurements of all interactions to the given sink
+    ///
+    /// This works independently of spans, so it also works for [Telemetry::disabled].
+    pub fn with_metrics(mut self, metrics: impl MetricsSink) -> Self {
+        self.config_mut().metrics = Some(Arc::new(metrics));
+        self
+    }
+
+    /// Propagate the trace context from clients to servers using the given propagator
+    ///
+    /// This has no effect if spans are disabled.
+    pub fn with_propagator(mut self, propagator: impl Propagator) -> Self {
+        self.config_mut().propagator = Some(Arc::new(propagator));
+        self
+    }
+
+    /// Whether spans are created
+    pub fn is_enabled(&self) -> bool {
+        self.factory().is_some()
+    }
+
+    fn factory(&self) -> Option<&dyn SpanFactory> {
+        match &self.0 {
+            Some(config) => config.factory.get(),
+            None => Some(&DefaultSpanFactory),
+        }
+    }
+
+    fn propagator(&self) -> Option<&Arc<dyn Propagator>> {
+        self.0.as_ref()?.propagator.as_ref()
+    }
+
+    fn metrics(&self) -> Option<&Arc<dyn MetricsSink>> {
+        self.0.as_ref()?.metrics.as_ref()
+    }
+
+    /// Create the span for an interaction with message `M`
+    pub(crate) fn start<S: Service, M: Msg<S>>(&self, side: Side) -> CallSpan {
+        self.start_call::<S, M>(side, None)
+    }
+
+    fn start_call<S: Service, M: Msg<S>>(
+        &self,
+        side: Side,
+        client_request_id: Option<u64>,
+    ) -> CallSpan {
+        let factory = self.factory().filter(|factory| factory.is_enabled());
+        if factory.is_none() && self.metrics().is_none() {
+            return CallSpan(None);
+        }
+        let info = RequestInfo::new::<S, M>(side, client_request_id);
+        let span = match factory {
+            Some(factory) => factory.make_span(&info),
+            None => Span::none(),
+        };
+        let request_id = (!span.is_disabled()).then_some(info.request_id);
+        let metrics = self
+            .metrics()
+            .cloned()
+            .map(|sink| CallMetrics::start(sink, info));
+        CallSpan(Some(Arc::new(Call {
+            span,
+            request_id,
+            items: AtomicU64::new(0),
+            metrics,
+        })))
+    }
+
+    /// Create the server span for an interaction with message `M`, as a child of the
+    /// trace context in the metadata sent by the client
+    pub(crate) fn start_server<S: Service, M: Msg<S>>(&self, metadata: &Metadata) -> CallSpan {
+        if metadata.is_empty() {
+            return self.start_call::<S, M>(Side::Server, None);
+        }
+        let client_request_id = metadata
+            .get(RequestInfo::CLIENT_REQUEST_ID)
+            .and_then(|id| id.parse().ok());
+        let call = self.start_call::<S, M>(Side::Server, client_request_id);
+        if let (Some(propagator), Some(span)) = (self.propagator(), call.recorded_span()) {
+            if let Some(parent) = TraceContext::from_metadata(metadata) {
+                propagator.set_parent(span, parent);
+            }
+        }
+        call
+    }
+
+    /// Whether [Telemetry::metadata] adds anything for a client call
+    pub(crate) fn has_metadata(&self, call: &CallSpan) -> bool {
+        call.recorded_span().is_some()
+    }
+
+    /// Add the request id and the trace context of a client call to the metadata sent
+    /// along with the request
+    pub(crate) fn metadata(&self, call: &CallSpan, mut metadata: Metadata) -> Metadata {
+        let call = match &call.0 {
+            Some(call) => call,
+            None => return metadata,
+        };
+        if let Some(id) = call.request_id {
+            metadata.insert(RequestInfo::CLIENT_REQUEST_ID, id.to_string());
+        }
+        if let (Some(propagator), false) = (self.propagator(), call.span.is_disabled()) {
+            if let Some(context) = propagator.context(&call.span) {
+                context.to_metadata(&mut metadata);
+            }
+        }
+        metadata
+    }
+}
+
+/// The span of a single interaction, the number of response items so far, and its
+/// measurements
+///
+/// None if the span is disabled and there are no metrics. The call is behind a single
+/// pointer, since it is stored in the futures of every interaction.
+#[derive(Debug, Clone)]
+pub(crate) struct CallSpan(Option<Arc<Call>>);
+
+#[derive(Debug)]
+struct Call {
+    span: Span,
+    /// Id of the request, None if the span is not recorded
+    request_id: Option<u64>,
+    /// Number of response items, only counted if the span is recorded
+    items: AtomicU64,
+    metrics: Option<CallMetrics>,
+}
+
+impl CallSpan {
+    pub(crate) fn span(&self) -> Span {
+        match &self.0 {
+            Some(call) => call.span.clone(),
+            None => Span::none(),
+        }
+    }
+
+    /// Enter the span, if there is one
+    pub(crate) fn enter(&self) -> Option<Entered<'_>> {
+        Some(self.0.as_ref()?.span.enter())
+    }
+
+    /// The span, if it is recorded
+    fn recorded_span(&self) -> Option<&Span> {
+        let call = self.0.as_ref()?;
+        call.request_id.map(|_| &call.span)
+    }
+
+    /// Count a response item, with its encoded size if known
+    pub(crate) fn item(&self, bytes: Option<usize>) {
+        let call = match &self.0 {
+            Some(call) => call,
+            None => return,
+        };
+        if call.request_id.is_some() {
+            let items = call.items.fetch_add(1, Ordering::Relaxed) + 1;
+            call.span.record("items", items);
+        }
+        if let Some(metrics) = &call.metrics {
+            metrics.item(bytes);
+        }
+    }
+
+    /// Record that the interaction succeeded
+    pub(crate) fn ok(&self) {
+        if let Some(call) = &self.0 {
+            call.span.record("outcome", "ok");
+            if let Some(metrics) = &call.metrics {
+                metrics.finish(None);
+            }
+        }
+    }
+
+    /// Record that the interaction failed
+    pub(crate) fn err(&self, cause: &(impl fmt::Debug + VariantName)) {
+        if let Some(call) = &self.0 {
+            call.span.record("outcome", field::debug(cause));
+            if let Some(metrics) = &call.metrics {
+                metrics.finish(Some(cause.variant_name()));
+            }
+        }
+    }
+
+    /// Record the outcome of the interaction, and pass the result through
+    pub(crate) fn finish<T, E: fmt::Debug + VariantName>(
+        &self,
+        result: Result<T, E>,
+    ) -> Result<T, E> {
+        match &result {
+            Ok(_) => self.ok(),
+            Err(cause) => self.err(cause),
+        }
+        result
+    }
+
+    /// Record the outcome if the interaction failed, and pass the result through
+    pub(crate) fn check<T, E: fmt::Debug + VariantName>(
+        &self,
+        result: Result<T, E>,
+    ) -> Result<T, E> {
+        if let Err(cause) = &result {
+            self.err(cause);
+        }
+        result
+    }
+}
+
+/// The last path segment of a type name, without generic arguments
+pub(crate) fn short_type_name<T>() -> &'static str {
+    let name = std::any::type_name::<T>();
+    let name = name.split('<').next().unwrap_or(name);
+    name.rsplit("::").next().unwrap_or(name)
+}
@@ -0,0 +1,768 @@
+//! Conformance test suite for transports
+//!
+//! This module contains a small test service and a set of checks that exercise a
+//! [ServiceConnection] and [ServiceEndpoint] pair in the same way as the built-in
+//! transports are tested. A third party transport can use this to prove that it
+//! behaves like the built-in ones.
+//!
+//! The easiest way to use this is [run_all]. Here it is used with the flume transport,
+//! which needs the `flume-transport` feature:
+//!
+//! ```
+//! # #[cfg(feature = "flume-transport")]
+//! # async fn example() -> Result<(), quic_rpc::testing::ConformanceError> {
+//! use quic_rpc::testing::{self, TestRequest, TestResponse};
+//!
+//! let (server, client) = quic_rpc::transport::flume::connection::<TestRequest, TestResponse>(1);
+//! testing::run_all(server, client).await?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! To run individual checks, create a [Conformance] instance.
+use crate::{
+    client::RpcClientError,
+    message::{BidiStreaming, ClientStreaming, ServerStreaming},
+    message::{BidiStreamingMsg, ClientStreamingMsg, Msg, RpcMsg, ServerStreamingMsg},
+    server::{RpcChannel, RpcServerError},
+    RpcClient, RpcServer, Service, ServiceConnection, ServiceEndpoint,
+};
+use futures::{future, stream, Future, SinkExt, Stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet},
+    error, fmt, result,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+/// Time after which a check is considered to hang
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Echo the payload
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Echo(pub Vec<u8>);
+
+/// Response to [Echo]
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct EchoResponse(pub Vec<u8>);
+
+/// Never respond. The server records when the interaction with the given tag ends.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Hang(pub u64);
+
+/// Response to [Hang], never sent
+#[derive(Debug, Serialize, Deserialize)]
+pub struct HangResponse;
+
+/// Close the channel without responding
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Close;
+
+/// Response to [Close], never sent
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CloseResponse;
+
+/// Collect all updates and respond with them once the updates end
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Collect;
+
+/// Update for [Collect]
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CollectUpdate(pub u64);
+
+/// Response to [Collect]
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CollectResponse(pub Vec<u64>);
+
+/// Respond with a stream of `n` items with a payload of `size` bytes.
+///
+/// The server records how many items it has produced for the given tag, and when
+/// the interaction ends.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Count {
+    /// Tag for the interaction
+    pub tag: u64,
+    /// Number of items
+    pub n: u64,
+    /// Size of the payload of each item
+    pub size: usize,
+}
+
+/// Response to [Count]
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CountResponse {
+    /// Index of the item
+    pub index: u64,
+    /// Payload
+    pub data: Vec<u8>,
+}
+
+/// Respond to each update with the same value. The server records when the
+/// interaction with the given tag ends.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Pipe(pub u64);
+
+/// Update for [Pipe]
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PipeUpdate(pub u64);
+
+/// Response to [Pipe]
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PipeResponse(pub u64);
+
+/// Request enum of the [TestService]
+#[allow(missing_docs)]
+#[derive(Debug, Serialize, Deserialize)]
+pub enum TestRequest {
+    Echo(Echo),
+    Hang(Hang),
+    Close(Close),
+    Collect(Collect),
+    CollectUpdate(CollectUpdate),
+    Count(Count),
+    Pipe(Pipe),
+    PipeUpdate(PipeUpdate),
+}
+
+/// Response enum of the [TestService]
+#[allow(missing_docs)]
+#[derive(Debug, Serialize, Deserialize)]
+pub enum TestResponse {
+    Echo(EchoResponse),
+    Hang(HangResponse),
+    Close(CloseResponse),
+    Collect(CollectResponse),
+    Count(CountResponse),
+    Pipe(PipeResponse),
+}
+
+/// Conversions between the message types and the enum
+macro_rules! enum_conversions {
+    ($enum:ident { $($variant:ident($ty:ty)),* $(,)? }) => {
+        $(
+            impl From<$ty> for $enum {
+                fn from(value: $ty) -> Self {
+                    $enum::$variant(value)
+                }
+            }
+
+            impl TryFrom<$enum> for $ty {
+                type Error = $enum;
+
+                fn try_from(value: $enum) -> result::Result<Self, Self::Error> {
+                    match value {
+                        $enum::$variant(value) => Ok(value),
+                        other => Err(other),
+                    }
+                }
+            }
+        )*
+    };
+}
+
+enum_conversions!(TestRequest {
+    Echo(Echo),
+    Hang(Hang),
+    Close(Close),
+    Collect(Collect),
+    CollectUpdate(CollectUpdate),
+    Count(Count),
+    Pipe(Pipe),
+    PipeUpdate(PipeUpdate),
+});
+
+enum_conversions!(TestResponse {
+    Echo(EchoResponse),
+    Hang(HangResponse),
+    Close(CloseResponse),
+    Collect(CollectResponse),
+    Count(CountResponse),
+    Pipe(PipeResponse),
+});
+
+/// The service used by the conformance checks
+#[derive(Debug, Clone)]
+pub struct TestService;
+
+impl Service for TestService {
+    type Req = TestRequest;
+    type Res = TestResponse;
+}
+
+impl RpcMsg<TestService> for Echo {
+    type Response = EchoResponse;
+}
+
+impl RpcMsg<TestService> for Hang {
+    type Response = HangResponse;
+}
+
+impl RpcMsg<TestService> for Close {
+    type Response = CloseResponse;
+}
+
+impl Msg<TestService> for Collect {
+    type Pattern = ClientStreaming;
+}
+
+impl ClientStreamingMsg<TestService> for Collect {
+    type Update = CollectUpdate;
+    type Response = CollectResponse;
+}
+
+impl Msg<TestService> for Count {
+    type Pattern = ServerStreaming;
+}
+
+impl ServerStreamingMsg<TestService> for Count {
+    type Response = CountResponse;
+}
+
+impl Msg<TestService> for Pipe {
+    type Pattern = BidiStreaming;
+}
+
+impl BidiStreamingMsg<TestService> for Pipe {
+    type Update = PipeUpdate;
+    type Response = PipeResponse;
+}
+
+/// What the server has observed, for the checks that can not be observed by the client
+#[derive(Debug, Default)]
+struct State {
+    /// tags of interactions that have ended
+    finished: HashSet<u64>,
+    /// number of items produced for a [Count] interaction
+    produced: HashMap<u64, u64>,
+}
+
+#[derive(Debug, Clone, Default)]
+struct Handler(Arc<Mutex<State>>);
+
+impl Handler {
+    async fn echo(self, req: Echo) -> EchoResponse {
+        EchoResponse(req.0)
+    }
+
+    async fn hang(self, _req: Hang) -> HangResponse {
+        future::pending().await
+    }
+
+    async fn collect(
+        self,
+        _req: Collect,
+        updates: impl Stream<Item = CollectUpdate>,
+    ) -> CollectResponse {
+        CollectResponse(updates.map(|x| x.0).collect().await)
+    }
+
+    fn count(self, req: Count) -> impl Stream<Item = CountResponse> {
+        stream::iter(0..req.n).map(move |index| {
+            *self.0.lock().unwrap().produced.entry(req.tag).or_default() += 1;
+            CountResponse {
+                index,
+                data: vec![0; req.size],
+            }
+        })
+    }
+
+    fn pipe(
+        self,
+        _req: Pipe,
+        updates: impl Stream<Item = PipeUpdate>,
+    ) -> impl Stream<Item = PipeResponse> {
+        updates.map(|x| PipeResponse(x.0))
+    }
+
+    async fn handle<C: ServiceEndpoint<TestService>>(
+        self,
+        req: TestRequest,
+        chan: RpcChannel<TestService, C>,
+    ) -> result::Result<(), RpcServerError<C>> {
+        use TestRequest::*;
+        let state = self.0.clone();
+        let tag = match &req {
+            Hang(req) => Some(req.0),
+            Count(req) => Some(req.tag),
+            Pipe(req) => Some(req.0),
+            _ => None,
+        };
+        #[rustfmt::skip]
+        let res = match req {
+            Echo(msg) => chan.rpc(msg, self, Self::echo).await,
+            Hang(msg) => chan.rpc(msg, self, Self::hang).await,
+            Close(_) => {
+                // close without responding
+                drop(chan);
+                Ok(())
+            }
+            Collect(msg) => chan.client_streaming(msg, self, Self::collect).await,
+            Count(msg) => chan.server_streaming(msg, self, Self::count).await,
+            Pipe(msg) => chan.bidi_streaming(msg, self, Self::pipe).await,
+            CollectUpdate(_) | PipeUpdate(_) => Err(RpcServerError::UnexpectedStartMessage),
+        };
+        if let Some(tag) = tag {
+            state.lock().unwrap().finished.insert(tag);
+        }
+        res
+    }
+}
+
+/// Error returned when a conformance check fails
+#[derive(Debug)]
+pub struct ConformanceError {
+    check: &'static str,
+    message: String,
+}
+
+impl ConformanceError {
+    fn new(check: &'static str, message: impl Into<String>) -> Self {
+        Self {
+            check,
+            message: message.into(),
+        }
+    }
+
+    /// The name of the check that failed
+    pub fn check(&self) -> &'static str {
+        self.check
+    }
+}
+
+impl fmt::Display for ConformanceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "conformance check {} failed: {}",
+            self.check, self.message
+        )
+    }
+}
+
+impl error::Error for ConformanceError {}
+
+type Result<T> = result::Result<T, ConformanceError>;
+
+/// Fail the check if the condition does not hold
+fn ensure(check: &'static str, cond: bool, message: impl FnOnce() -> String) -> Result<()> {
+    if cond {
+        Ok(())
+    } else {
+        Err(ConformanceError::new(check, message()))
+    }
+}
+
+/// Fail the check if the future does not complete in time
+async fn timeout<T>(check: &'static str, f: impl Future<Output = T>) -> Result<T> {
+    tokio::time::timeout(TIMEOUT, f)
+        .await
+        .map_err(|_| ConformanceError::new(check, "timed out"))
+}
+
+/// Map any error to a check failure
+fn check_err<T, E: fmt::Debug>(check: &'static str, res: result::Result<T, E>) -> Result<T> {
+    res.map_err(|e| ConformanceError::new(check, format!("{e:?}")))
+}
+
+/// A conformance test setup for a server endpoint and a client connection
+///
+/// Creating this spawns a server for the [TestService] on the server endpoint, so it
+/// must be called from within a tokio runtime. The server is stopped when this is dropped.
+#[derive(Debug)]
+pub struct Conformance<C> {
+    client: RpcClient<TestService, C>,
+    state: Arc<Mutex<State>>,
+    server: tokio::task::JoinHandle<()>,
+    next_tag: AtomicU64,
+}
+
+impl<C> Drop for Conformance<C> {
+    fn drop(&mut self) {
+        self.server.abort();
+    }
+}
+
+impl<C: ServiceConnection<TestService>> Conformance<C> {
+    /// Create a new conformance test setup
+    pub fn new<S: ServiceEndpoint<TestService>>(server: S, client: C) -> Self {
+        let handler = Handler::default();
+        let state = handler.0.clone();
+        let server = RpcServer::<TestService, S>::new(server);
+        let server = tokio::spawn(async move {
+            loop {
+                let (req, chan) = match server.accept().await {
+                    Ok(x) => x,
+                    Err(RpcServerError::Accept(cause)) => {
+                        tracing::debug!("Server terminated: {:?}", cause);
+                        break;
+                    }
+                    Err(cause) => {
+                        tracing::debug!("Error accepting channel: {:?}", cause);
+                        continue;
+                    }
+                };
+                tokio::spawn(handler.clone().handle(req, chan));
+            }
+        });
+        Self {
+            client: RpcClient::new(client),
+            state,
+            server,
+            next_tag: AtomicU64::new(0),
+        }
+    }
+
+    fn tag(&self) -> u64 {
+        self.next_tag.fetch_add(1, Ordering::SeqCst)
+    }
+
+    fn produced(&self, tag: u64) -> u64 {
+        let state = self.state.lock().unwrap();
+        state.produced.get(&tag).copied().unwrap_or_default()
+    }
+
+    /// Wait until the server has seen the end of the interaction with the given tag
+    async fn finished(&self, check: &'static str, tag: u64) -> Result<()> {
+        timeout(check, async {
+            while !self.state.lock().unwrap().finished.contains(&tag) {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .map_err(|_| ConformanceError::new(check, "server interaction did not end"))
+    }
+
+    /// Run all checks
+    pub async fn run_all(&self) -> Result<()> {
+        self.ordering().await?;
+        self.backpressure().await?;
+        self.early_close().await?;
+        self.drop_semantics().await?;
+        self.finish().await?;
+        self.concurrent_channels().await?;
+        self.large_messages().await?;
+        Ok(())
+    }
+
+    /// Messages arrive in the order in which they were sent, for all streaming patterns
+    pub async fn ordering(&self) -> Result<()> {
+        const CHECK: &str = "ordering";
+        const N: u64 = 1000;
+        timeout(CHECK, async {
+            // client streaming
+            let (mut send, recv) = check_err(CHECK, self.client.client_streaming(Collect).await)?;
+            for i in 0..N {
+                check_err(CHECK, send.send(CollectUpdate(i)).await)?;
+            }
+            drop(send);
+            let res = check_err(CHECK, recv.await)?;
+            ensure(CHECK, res.0 == (0..N).collect::<Vec<_>>(), || {
+                format!("client streaming updates out of order: {:?}", res.0)
+            })?;
+
+            // server streaming
+            let count = Count {
+                tag: self.tag(),
+                n: N,
+                size: 0,
+            };
+            let recv = check_err(CHECK, self.client.server_streaming(count).await)?;
+            let res = recv.map(|x| x.map(|x| x.index)).collect::<Vec<_>>().await;
+            let res = check_err(
+                CHECK,
+                res.into_iter().collect::<result::Result<Vec<_>, _>>(),
+            )?;
+            ensure(CHECK, res == (0..N).collect::<Vec<_>>(), || {
+                format!("server streaming responses out of order: {res:?}")
+            })?;
+
+            // bidi streaming, sending and receiving at the same time
+            let (mut send, recv) = check_err(CHECK, self.client.bidi(Pipe(self.tag())).await)?;
+            let send = async move {
+                for i in 0..N {
+                    send.send(PipeUpdate(i)).await?;
+                }
+                Ok(())
+            };
+            let recv = recv.map(|x| x.map(|x| x.0)).collect::<Vec<_>>();
+            let (send_res, res) = future::join(send, recv).await;
+            check_err::<_, C::SendError>(CHECK, send_res)?;
+            let res = check_err(
+                CHECK,
+                res.into_iter().collect::<result::Result<Vec<_>, _>>(),
+            )?;
+            ensure(CHECK, res == (0..N).collect::<Vec<_>>(), || {
+                format!("bidi streaming responses out of order: {res:?}")
+            })
+        })
+        .await?
+    }
+
+    /// A server that produces responses faster than the client reads them is slowed down
+    pub async fn backpressure(&self) -> Result<()> {
+        const CHECK: &str = "backpressure";
+        let tag = self.tag();
+        let count = Count {
+            tag,
+            n: u64::MAX,
+            size: 1024,
+        };
+        let mut recv = check_err(CHECK, self.client.server_streaming(count).await)?;
+        let first = timeout(CHECK, recv.next()).await?;
+        ensure(CHECK, matches!(first, Some(Ok(_))), || {
+            format!("expected an item, got {first:?}")
+        })?;
+        // without reading, the server must stop producing at some point
+        timeout(CHECK, async {
+            let mut last = self.produced(tag);
+            loop {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                let current = self.produced(tag);
+                if current == last {
+                    break;
+                }
+                last = current;
+            }
+        })
+        .await
+        .map_err(|_| ConformanceError::new(CHECK, "server does not stop producing"))?;
+        // reading resumes where we left off
+        for index in 1..100 {
+            let item = timeout(CHECK, recv.next()).await?;
+            let item = check_err(CHECK, item.ok_or("stream ended"))?;
+            let item = check_err(CHECK, item)?;
+            ensure(CHECK, item.index == index, || {
+                format!("expected item {index}, got {}", item.index)
+            })?;
+        }
+        drop(recv);
+        self.finished(CHECK, tag).await
+    }
+
+    /// A server that closes a channel without responding is seen as an early close
+    pub async fn early_close(&self) -> Result<()> {
+        const CHECK: &str = "early_close";
+        timeout(CHECK, async {
+            // rpc without response
+            let res = self.client.rpc(Close).await;
+            ensure(
+                CHECK,
+                matches!(res, Err(RpcClientError::EarlyClose)),
+                || format!("expected early close, got {res:?}"),
+            )?;
+
+            // server stream ends after the last item
+            let count = Count {
+                tag: self.tag(),
+                n: 3,
+                size: 0,
+            };
+            let recv = check_err(CHECK, self.client.server_streaming(count).await)?;
+            let n = recv.count().await;
+            ensure(CHECK, n == 3, || format!("expected 3 items, got {n}"))?;
+
+            // bidi stream ends if there are no updates
+            let (send, recv) = check_err(CHECK, self.client.bidi(Pipe(self.tag())).await)?;
+            drop(send);
+            let n = recv.count().await;
+            ensure(CHECK, n == 0, || format!("expected no items, got {n}"))
+        })
+        .await?
+    }
+
+    /// Dropping the client side of an interaction ends it on the server side, and dropping
+    /// an update sink finishes the updates.
+    pub async fn drop_semantics(&self) -> Result<()> {
+        const CHECK: &str = "drop_semantics";
+        // rpc: dropping the call cancels the interaction
+        let tag = self.tag();
+        let res =
+            tokio::time::timeout(Duration::from_millis(100), self.client.rpc(Hang(tag))).await;
+        ensure(CHECK, res.is_err(), || {
+            format!("expected no response, got {res:?}")
+        })?;
+        self.finished(CHECK, tag).await?;
+
+        // client streaming: dropping the sink finishes the updates
+        timeout(CHECK, async {
+            let (mut send, recv) = check_err(CHECK, self.client.client_streaming(Collect).await)?;
+            for i in 0..3 {
+                check_err(CHECK, send.send(CollectUpdate(i)).await)?;
+            }
+            drop(send);
+            let res = check_err(CHECK, recv.await)?;
+            ensure(CHECK, res.0 == vec![0, 1, 2], || {
+                format!("expected all updates, got {:?}", res.0)
+            })
+        })
+        .await??;
+
+        // server streaming: dropping the stream cancels the interaction
+        let tag = self.tag();
+        let count = Count {
+            tag,
+            n: u64::MAX,
+            size: 0,
+        };
+        let mut recv = check_err(CHECK, self.client.server_streaming(count).await)?;
+        for _ in 0..10 {
+            timeout(CHECK, recv.next()).await?;
+        }
+        drop(recv);
+        self.finished(CHECK, tag).await?;
+
+        // bidi streaming: dropping the sink finishes the updates and ends the responses
+        let tag = self.tag();
+        let (mut send, recv) = check_err(CHECK, self.client.bidi(Pipe(tag)).await)?;
+        let send = async move {
+            for i in 0..3 {
+                send.send(PipeUpdate(i)).await?;
+            }
+            Ok(())
+        };
+        let recv = recv.map(|x| x.map(|x| x.0)).collect::<Vec<_>>();
+        let (send_res, res) = timeout(CHECK, future::join(send, recv)).await?;
+        check_err::<_, C::SendError>(CHECK, send_res)?;
+        let res = check_err(
+            CHECK,
+            res.into_iter().collect::<result::Result<Vec<_>, _>>(),
+        )?;
+        ensure(CHECK, res == vec![0, 1, 2], || {
+            format!("expected all responses, got {res:?}")
+        })?;
+        self.finished(CHECK, tag).await?;
+
+        // bidi streaming: dropping both sides cancels the interaction
+        let tag = self.tag();
+        let (mut send, mut recv) = check_err(CHECK, self.client.bidi(Pipe(tag)).await)?;
+        timeout(CHECK, async {
+            check_err(CHECK, send.send(PipeUpdate(0)).await)?;
+            let item = recv.next().await;
+            ensure(CHECK, matches!(item, Some(Ok(PipeResponse(0)))), || {
+                format!("expected a response, got {item:?}")
+            })
+        })
+        .await??;
+        drop(send);
+        drop(recv);
+        self.finished(CHECK, tag).await
+    }
+
+    /// Finishing the updates keeps the response side of the channel open
+    pub async fn finish(&self) -> Result<()> {
+        const CHECK: &str = "finish";
+        timeout(CHECK, async {
+            // client streaming
+            let (mut send, recv) = check_err(CHECK, self.client.client_streaming(Collect).await)?;
+            for i in 0..3 {
+                check_err(CHECK, send.send(CollectUpdate(i)).await)?;
+            }
+            check_err(CHECK, send.finish().await)?;
+            let res = check_err(CHECK, recv.await)?;
+            ensure(CHECK, res.0 == vec![0, 1, 2], || {
+                format!("expected all updates, got {:?}", res.0)
+            })?;
+
+            // bidi streaming
+            let tag = self.tag();
+            let (mut send, recv) = check_err(CHECK, self.client.bidi(Pipe(tag)).await)?;
+            let send = async move {
+                for i in 0..3 {
+                    send.send(PipeUpdate(i)).await?;
+                }
+                send.finish().await
+            };
+            let recv = recv.map(|x| x.map(|x| x.0)).collect::<Vec<_>>();
+            let (send_res, res) = future::join(send, recv).await;
+            check_err::<_, C::SendError>(CHECK, send_res)?;
+            let res = check_err(
+                CHECK,
+                res.into_iter().collect::<result::Result<Vec<_>, _>>(),
+            )?;
+            ensure(CHECK, res == vec![0, 1, 2], || {
+                format!("expected all responses, got {res:?}")
+            })?;
+            self.finished(CHECK, tag).await
+        })
+        .await?
+    }
+
+    /// Many channels can be used at the same time
+    pub async fn concurrent_channels(&self) -> Result<()> {
+        const CHECK: &str = "concurrent_channels";
+        const N: usize = 32;
+        let rpcs = (0..N).map(|i| async move {
+            let data = vec![i as u8; i * 100];
+            let res = check_err(CHECK, self.client.rpc(Echo(data.clone())).await)?;
+            ensure(CHECK, res.0 == data, || format!("wrong echo for call {i}"))
+        });
+        let pipes = (0..N as u64).map(|i| async move {
+            let (mut send, mut recv) = check_err(CHECK, self.client.bidi(Pipe(self.tag())).await)?;
+            for j in 0..10 {
+                check_err(CHECK, send.send(PipeUpdate(i * 100 + j)).await)?;
+                let item = recv.next().await;
+                ensure(
+                    CHECK,
+                    matches!(item, Some(Ok(PipeResponse(x))) if x == i * 100 + j),
+                    || format!("wrong response for pipe {i}: {item:?}"),
+                )?;
+            }
+            Ok(())
+        });
+        let (rpcs, pipes) = timeout(
+            CHECK,
+            future::join(future::try_join_all(rpcs), future::try_join_all(pipes)),
+        )
+        .await?;
+        rpcs?;
+        pipes?;
+        Ok(())
+    }
+
+    /// Messages of up to 8 MiB can be sent in both directions
+    pub async fn large_messages(&self) -> Result<()> {
+        const CHECK: &str = "large_messages";
+        timeout(CHECK, async {
+            for size in [0, 1, 1 << 16, 1 << 20, 8 << 20] {
+                let data = (0..size).map(|i| i as u8).collect::<Vec<_>>();
+                let res = check_err(CHECK, self.client.rpc(Echo(data.clone())).await)?;
+                ensure(CHECK, res.0 == data, || {
+                    format!("echo of {size} bytes does not match")
+                })?;
+            }
+            let count = Count {
+                tag: self.tag(),
+                n: 4,
+                size: 1 << 20,
+            };
+            let recv = check_err(CHECK, self.client.server_streaming(count).await)?;
+            let res = recv.collect::<Vec<_>>().await;
+            let res = check_err(
+                CHECK,
+                res.into_iter().collect::<result::Result<Vec<_>, _>>(),
+            )?;
+            ensure(CHECK, res.len() == 4, || {
+                format!("expected 4 items, got {}", res.len())
+            })?;
+            ensure(CHECK, res.iter().all(|x| x.data.len() == 1 << 20), || {
+                "wrong item size".into()
+            })
+        })
+        .await?
+    }
+}
+
+/// Run all conformance checks on a server endpoint and a client connection
+///
+/// The client connection must be connected to the server endpoint.
+pub async fn run_all<S, C>(server: S, client: C) -> Result<()>
+where
+    S: ServiceEndpoint<TestService>,
+    C: ServiceConnection<TestService>,
+{
+    Conformance::new(server, client).run_all().await
+}
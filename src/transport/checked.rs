@@ -0,0 +1,369 @@
+//! Transport wrapper that checks that all messages survive serialization
+//!
+//! The [flume](crate::transport::flume) transport never serializes messages, so
+//! problems like a `#[serde(skip)]` field or an ambiguous untagged enum will only
+//! show up once a message crosses a real network transport.
+//!
+//! [CheckedConnection] and [CheckedServerEndpoint] wrap any other transport and round-trip
+//! every message through the same bincode encoding that is used on the wire. The send side
+//! fails with [SendError::SerializeError] if a message can not be serialized, and the receive
+//! side yields the deserialized copy of each message, or [RecvError::DeserializeError] if it
+//! can not be deserialized.
+//!
+//! This is meant for tests. To create a checked in-memory transport, use [connection].
+use super::{
+    Connection, ConnectionCommon, ConnectionErrors, LocalAddr, Metadata, PeerInfo, ServerEndpoint,
+    SizeLimits, Status,
+};
+use crate::RpcMessage;
+use bincode::Options;
+use futures::{future, Sink, Stream, TryFutureExt};
+use pin_project::pin_project;
+use std::{
+    error, fmt,
+    marker::PhantomData,
+    pin::Pin,
+    result,
+    task::{Context, Poll},
+};
+
+/// The maximum size of a serialized message, same as for the quinn transport.
+const MAX_FRAME_LENGTH: usize = 1024 * 1024 * 16;
+
+/// The bincode options used by the quinn transport
+fn bincode_options() -> impl Options {
+    bincode::DefaultOptions::new().with_fixint_encoding()
+}
+
+/// A connection that checks that all messages survive serialization
+#[derive(Debug, Clone)]
+pub struct CheckedConnection<C> {
+    inner: C,
+}
+
+impl<C> CheckedConnection<C> {
+    /// Wrap a connection
+    pub fn new(inner: C) -> Self {
+        Self { inner }
+    }
+
+    /// Get back the inner connection
+    pub fn into_inner(self) -> C {
+        self.inner
+    }
+}
+
+/// A server endpoint that checks that all messages survive serialization
+#[derive(Debug, Clone)]
+pub struct CheckedServerEndpoint<C> {
+    inner: C,
+}
+
+impl<C> CheckedServerEndpoint<C> {
+    /// Wrap a server endpoint
+    pub fn new(inner: C) -> Self {
+        Self { inner }
+    }
+
+    /// Get back the inner server endpoint
+    pub fn into_inner(self) -> C {
+        self.inner
+    }
+}
+
+/// Send sink for checked channels
+#[pin_project]
+pub struct SendSink<C: ConnectionCommon<In, Out>, In, Out>(
+    #[pin] C::SendSink,
+    PhantomData<(In, Out)>,
+);
+
+impl<C: ConnectionCommon<In, Out>, In, Out> fmt::Debug for SendSink<C, In, Out> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SendSink").finish()
+    }
+}
+
+impl<C: ConnectionCommon<In, Out>, In: RpcMessage, Out: RpcMessage> Sink<Out>
+    for SendSink<C, In, Out>
+{
+    type Error = self::SendError<C>;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.project().0.poll_ready(cx).map_err(SendError::Inner)
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: Out) -> Result<(), Self::Error> {
+        let data = bincode_options()
+            .serialize(&item)
+            .map_err(SendError::SerializeError)?;
+        if data.len() > MAX_FRAME_LENGTH {
+            return Err(SendError::SizeError(data.len()));
+        }
+        self.project().0.start_send(item).map_err(SendError::Inner)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.project().0.poll_flush(cx).map_err(SendError::Inner)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.project().0.poll_close(cx).map_err(SendError::Inner)
+    }
+}
+
+/// Receive stream for checked channels
+#[pin_project]
+pub struct RecvStream<C: ConnectionCommon<In, Out>, In, Out>(
+    #[pin] C::RecvStream,
+    PhantomData<(In, Out)>,
+);
+
+impl<C: ConnectionCommon<In, Out>, In, Out> fmt::Debug for RecvStream<C, In, Out> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RecvStream").finish()
+    }
+}
+
+impl<C: ConnectionCommon<In, Out>, In: RpcMessage, Out: RpcMessage> Stream
+    for RecvStream<C, In, Out>
+{
+    type Item = result::Result<In, self::RecvError<C>>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.project().0.poll_next(cx).map(|item| {
+            item.map(|item| {
+                let item = item.map_err(RecvError::Inner)?;
+                // do the full round trip, so the receiver sees exactly what it would see
+                // on a transport that serializes
+                let data = bincode_options()
+                    .serialize(&item)
+                    .map_err(RecvError::DeserializeError)?;
+                bincode_options()
+                    .deserialize(&data)
+                    .map_err(RecvError::DeserializeError)
+            })
+        })
+    }
+}
+
+/// Send error for checked channels
+#[derive(Debug)]
+pub enum SendError<C: ConnectionErrors> {
+    /// Error from the inner transport
+    Inner(C::SendError),
+    /// Error when bincode serializing the message
+    SerializeError(bincode::Error),
+    /// The serialized message is larger than the maximum frame size
+    SizeError(usize),
+}
+
+impl<C: ConnectionErrors> fmt::Display for SendError<C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self, f)
+    }
+}
+
+impl<C: ConnectionErrors> error::Error for SendError<C> {}
+
+/// Receive error for checked channels
+#[derive(Debug)]
+pub enum RecvError<C: ConnectionErrors> {
+    /// Error from the inner transport
+    Inner(C::RecvError),
+    /// Error when bincode deserializing the message
+    DeserializeError(bincode::Error),
+}
+
+impl<C: ConnectionErrors> fmt::Display for RecvError<C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self, f)
+    }
+}
+
+impl<C: ConnectionErrors> error::Error for RecvError<C> {}
+
+type Socket<C, In, Out> = (SendSink<C, In, Out>, RecvStream<C, In, Out>);
+
+#[allow(clippy::type_complexity)]
+fn wrap_socket<C: ConnectionCommon<In, Out>, In, Out>(
+    (send, recv): (C::SendSink, C::RecvStream),
+) -> Socket<C, In, Out> {
+    (SendSink(send, PhantomData), RecvStream(recv, PhantomData))
+}
+
+/// Future returned by open_bi
+#[allow(clippy::type_complexity)]
+pub type OpenBiFuture<C, In, Out> = future::MapOk<
+    <C as Connection<In, Out>>::OpenBiFut,
+    fn(
+        (
+            <C as ConnectionCommon<In, Out>>::SendSink,
+            <C as ConnectionCommon<In, Out>>::RecvStream,
+        ),
+    ) -> Socket<C, In, Out>,
+>;
+
+/// Future returned by accept_bi
+#[allow(clippy::type_complexity)]
+pub type AcceptBiFuture<C, In, Out> = future::MapOk<
+    <C as ServerEndpoint<In, Out>>::AcceptBiFut,
+    fn(
+        (
+            <C as ConnectionCommon<In, Out>>::SendSink,
+            <C as ConnectionCommon<In, Out>>::RecvStream,
+        ),
+    ) -> Socket<C, In, Out>,
+>;
+
+impl<C: ConnectionErrors> ConnectionErrors for CheckedConnection<C> {
+    type SendError = self::SendError<C>;
+    type RecvError = self::RecvError<C>;
+    type OpenError = C::OpenError;
+}
+
+impl<C: Connection<In, Out>, In: RpcMessage, Out: RpcMessage> ConnectionCommon<In, Out>
+    for CheckedConnection<C>
+{
+    type SendSink = self::SendSink<C, In, Out>;
+    type RecvStream = self::RecvStream<C, In, Out>;
+
+    fn limit_message_size(
+        send: &mut Self::SendSink,
+        recv: &mut Self::RecvStream,
+        limits: SizeLimits,
+    ) -> bool {
+        C::limit_message_size(&mut send.0, &mut recv.0, limits)
+    }
+
+    fn received_size(recv: &Self::RecvStream) -> Option<usize> {
+        C::received_size(&recv.0)
+    }
+
+    fn sent_size(send: &Self::SendSink) -> Option<usize> {
+        C::sent_size(&send.0)
+    }
+
+    fn cancel(send: &mut Self::SendSink) {
+        C::cancel(&mut send.0)
+    }
+
+    fn is_cancelled(error: &Self::RecvError) -> bool {
+        matches!(error, RecvError::Inner(inner) if C::is_cancelled(inner))
+    }
+
+    fn rejection(error: &Self::RecvError) -> Option<Status> {
+        match error {
+            RecvError::Inner(inner) => C::rejection(inner),
+            _ => None,
+        }
+    }
+}
+
+impl<C: Connection<In, Out>, In: RpcMessage, Out: RpcMessage> Connection<In, Out>
+    for CheckedConnection<C>
+{
+    type OpenBiFut = OpenBiFuture<C, In, Out>;
+
+    fn open_bi(&self) -> Self::OpenBiFut {
+        self.inner.open_bi().map_ok(wrap_socket::<C, In, Out>)
+    }
+
+    fn open_bi_with_metadata(&self, metadata: Metadata) -> Self::OpenBiFut {
+        self.inner
+            .open_bi_with_metadata(metadata)
+            .map_ok(wrap_socket::<C, In, Out>)
+    }
+
+    fn carries_metadata(&self) -> bool {
+        self.inner.carries_metadata()
+    }
+}
+
+impl<C: ConnectionErrors> ConnectionErrors for CheckedServerEndpoint<C> {
+    type SendError = self::SendError<C>;
+    type RecvError = self::RecvError<C>;
+    type OpenError = C::OpenError;
+}
+
+impl<C: ServerEndpoint<In, Out>, In: RpcMessage, Out: RpcMessage> ConnectionCommon<In, Out>
+    for CheckedServerEndpoint<C>
+{
+    type SendSink = self::SendSink<C, In, Out>;
+    type RecvStream = self::RecvStream<C, In, Out>;
+
+    fn limit_message_size(
+        send: &mut Self::SendSink,
+        recv: &mut Self::RecvStream,
+        limits: SizeLimits,
+    ) -> bool {
+        C::limit_message_size(&mut send.0, &mut recv.0, limits)
+    }
+
+    fn received_size(recv: &Self::RecvStream) -> Option<usize> {
+        C::received_size(&recv.0)
+    }
+
+    fn sent_size(send: &Self::SendSink) -> Option<usize> {
+        C::sent_size(&send.0)
+    }
+
+    fn cancel(send: &mut Self::SendSink) {
+        C::cancel(&mut send.0)
+    }
+
+    fn is_cancelled(error: &Self::RecvError) -> bool {
+        matches!(error, RecvError::Inner(inner) if C::is_cancelled(inner))
+    }
+
+    fn rejection(error: &Self::RecvError) -> Option<Status> {
+        match error {
+            RecvError::Inner(inner) => C::rejection(inner),
+            _ => None,
+        }
+    }
+}
+
+impl<C: ServerEndpoint<In, Out>, In: RpcMessage, Out: RpcMessage> ServerEndpoint<In, Out>
+    for CheckedServerEndpoint<C>
+{
+    type AcceptBiFut = AcceptBiFuture<C, In, Out>;
+
+    fn accept_bi(&self) -> Self::AcceptBiFut {
+        self.inner.accept_bi().map_ok(wrap_socket::<C, In, Out>)
+    }
+
+    fn local_addr(&self) -> &[LocalAddr] {
+        self.inner.local_addr()
+    }
+
+    fn metadata(recv: &Self::RecvStream) -> Metadata {
+        C::metadata(&recv.0)
+    }
+
+    fn peer(recv: &Self::RecvStream) -> PeerInfo {
+        C::peer(&recv.0)
+    }
+
+    fn reject(send: Self::SendSink, recv: Self::RecvStream, status: Status) {
+        C::reject(send.0, recv.0, status)
+    }
+}
+
+/// Create a checked flume server endpoint and a connected checked flume client channel.
+///
+/// `buffer` the size of the buffer for each channel. Keep this at a low value to get backpressure
+#[cfg(feature = "flume-transport")]
+#[allow(clippy::type_complexity)]
+pub fn connection<Req: RpcMessage, Res: RpcMessage>(
+    buffer: usize,
+) -> (
+    CheckedServerEndpoint<super::flume::FlumeServerEndpoint<Req, Res>>,
+    CheckedConnection<super::flume::FlumeConnection<Res, Req>>,
+) {
+    let (server, client) = super::flume::connection(buffer);
+    (
+        CheckedServerEndpoint::new(server),
+        CheckedConnection::new(client),
+    )
+}
@@ -1,5 +1,8 @@
 //! Transport that combines two other transports
-use super::{Connection, ConnectionCommon, ConnectionErrors, LocalAddr, ServerEndpoint};
+use super::{
+    Connection, ConnectionCommon, ConnectionErrors, LocalAddr, Metadata, PeerInfo, ServerEndpoint,
+    SizeLimits, Status,
+};
 use crate::RpcMessage;
 use futures::{
     future::{self, BoxFuture},
@@ -308,20 +311,78 @@ impl<A: Connection<In, Out>, B: Connection<In, Out>, In: RpcMessage, Out: RpcMes
 {
     type RecvStream = self::RecvStream<A, B, In, Out>;
     type SendSink = self::SendSink<A, B, In, Out>;
+
+    fn limit_message_size(
+        send: &mut Self::SendSink,
+        recv: &mut Self::RecvStream,
+        limits: SizeLimits,
+    ) -> bool {
+        match (send, recv) {
+            (SendSink::A(send), RecvStream::A(recv)) => A::limit_message_size(send, recv, limits),
+            (SendSink::B(send), RecvStream::B(recv)) => B::limit_message_size(send, recv, limits),
+            // the sides of a channel always come from the same endpoint
+            _ => true,
+        }
+    }
+
+    fn received_size(recv: &Self::RecvStream) -> Option<usize> {
+        match recv {
+            RecvStream::A(recv) => A::received_size(recv),
+            RecvStream::B(recv) => B::received_size(recv),
+        }
+    }
+
+    fn sent_size(send: &Self::SendSink) -> Option<usize> {
+        match send {
+            SendSink::A(send) => A::sent_size(send),
+            SendSink::B(send) => B::sent_size(send),
+        }
+    }
+
+    fn cancel(send: &mut Self::SendSink) {
+        match send {
+            SendSink::A(send) => A::cancel(send),
+            SendSink::B(send) => B::cancel(send),
+        }
+    }
+
+    fn is_cancelled(error: &Self::RecvError) -> bool {
+        match error {
+            RecvError::A(error) => A::is_cancelled(error),
+            RecvError::B(error) => B::is_cancelled(error),
+        }
+    }
+
+    fn rejection(error: &Self::RecvError) -> Option<Status> {
+        match error {
+            RecvError::A(error) => A::rejection(error),
+            RecvError::B(error) => B::rejection(error),
+        }
+    }
 }
 
 impl<A: Connection<In, Out>, B: Connection<In, Out>, In: RpcMessage, Out: RpcMessage>
     Connection<In, Out> for CombinedConnection<A, B, In, Out>
 {
     fn open_bi(&self) -> OpenBiFuture<A, B, In, Out> {
+        self.open_bi_with_metadata(Metadata::default())
+    }
+
+    fn open_bi_with_metadata(&self, metadata: Metadata) -> OpenBiFuture<A, B, In, Out> {
         let this = self.clone();
         async {
             // try a first, then b
             if let Some(a) = this.a {
-                let (send, recv) = a.open_bi().await.map_err(OpenBiError::A)?;
+                let (send, recv) = a
+                    .open_bi_with_metadata(metadata)
+                    .await
+                    .map_err(OpenBiError::A)?;
                 Ok((SendSink::A(send), RecvStream::A(recv)))
             } else if let Some(b) = this.b {
-                let (send, recv) = b.open_bi().await.map_err(OpenBiError::B)?;
+                let (send, recv) = b
+                    .open_bi_with_metadata(metadata)
+                    .await
+                    .map_err(OpenBiError::B)?;
                 Ok((SendSink::B(send), RecvStream::B(recv)))
             } else {
                 future::err(OpenBiError::NoChannel).await
@@ -330,6 +391,15 @@ impl<A: Connection<In, Out>, B: Connection<In, Out>, In: RpcMessage, Out: RpcMes
         .boxed()
     }
 
+    /// Whether the connection that channels are opened on carries metadata.
+    fn carries_metadata(&self) -> bool {
+        match (&self.a, &self.b) {
+            (Some(a), _) => a.carries_metadata(),
+            (None, Some(b)) => b.carries_metadata(),
+            (None, None) => false,
+        }
+    }
+
     type OpenBiFut = OpenBiFuture<A, B, In, Out>;
 }
 
@@ -346,6 +416,54 @@ impl<A: ServerEndpoint<In, Out>, B: ServerEndpoint<In, Out>, In: RpcMessage, Out
 {
     type RecvStream = self::RecvStream<A, B, In, Out>;
     type SendSink = self::SendSink<A, B, In, Out>;
+
+    fn limit_message_size(
+        send: &mut Self::SendSink,
+        recv: &mut Self::RecvStream,
+        limits: SizeLimits,
+    ) -> bool {
+        match (send, recv) {
+            (SendSink::A(send), RecvStream::A(recv)) => A::limit_message_size(send, recv, limits),
+            (SendSink::B(send), RecvStream::B(recv)) => B::limit_message_size(send, recv, limits),
+            // the sides of a channel always come from the same endpoint
+            _ => true,
+        }
+    }
+
+    fn received_size(recv: &Self::RecvStream) -> Option<usize> {
+        match recv {
+            RecvStream::A(recv) => A::received_size(recv),
+            RecvStream::B(recv) => B::received_size(recv),
+        }
+    }
+
+    fn sent_size(send: &Self::SendSink) -> Option<usize> {
+        match send {
+            SendSink::A(send) => A::sent_size(send),
+            SendSink::B(send) => B::sent_size(send),
+        }
+    }
+
+    fn cancel(send: &mut Self::SendSink) {
+        match send {
+            SendSink::A(send) => A::cancel(send),
+            SendSink::B(send) => B::cancel(send),
+        }
+    }
+
+    fn is_cancelled(error: &Self::RecvError) -> bool {
+        match error {
+            RecvError::A(error) => A::is_cancelled(error),
+            RecvError::B(error) => B::is_cancelled(error),
+        }
+    }
+
+    fn rejection(error: &Self::RecvError) -> Option<Status> {
+        match error {
+            RecvError::A(error) => A::rejection(error),
+            RecvError::B(error) => B::rejection(error),
+        }
+    }
 }
 
 impl<A: ServerEndpoint<In, Out>, B: ServerEndpoint<In, Out>, In: RpcMessage, Out: RpcMessage>
@@ -392,6 +510,29 @@ impl<A: ServerEndpoint<In, Out>, B: ServerEndpoint<In, Out>, In: RpcMessage, Out
     fn local_addr(&self) -> &[LocalAddr] {
         &self.local_addr
     }
+
+    fn metadata(recv: &Self::RecvStream) -> Metadata {
+        match recv {
+            RecvStream::A(recv) => A::metadata(recv),
+            RecvStream::B(recv) => B::metadata(recv),
+        }
+    }
+
+    fn peer(recv: &Self::RecvStream) -> PeerInfo {
+        match recv {
+            RecvStream::A(recv) => A::peer(recv),
+            RecvStream::B(recv) => B::peer(recv),
+        }
+    }
+
+    fn reject(send: Self::SendSink, recv: Self::RecvStream, status: Status) {
+        match (send, recv) {
+            (SendSink::A(send), RecvStream::A(recv)) => A::reject(send, recv, status),
+            (SendSink::B(send), RecvStream::B(recv)) => B::reject(send, recv, status),
+            // the sides of a channel always come from the same endpoint
+            _ => {}
+        }
+    }
 }
 
 #[cfg(test)]
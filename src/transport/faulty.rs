@@ -0,0 +1,653 @@
+//! Transport wrapper that injects faults
+//!
+//! [FaultyConnection] and [FaultyServerEndpoint] wrap any other transport and inject
+//! faults according to a [FaultConfig]. They can fail opening or accepting channels,
+//! drop or delay messages, close channels in the middle of an interaction, and corrupt
+//! received messages by flipping a bit in their bincode encoding.
+//!
+//! All randomness comes from a simple pseudo random number generator that is seeded
+//! from [FaultConfig::seed], so a test that interacts with a faulty transport in a
+//! deterministic order will see the same faults on every run.
+//!
+//! This is meant for tests.
+use super::{
+    Connection, ConnectionCommon, ConnectionErrors, LocalAddr, Metadata, PeerInfo, ServerEndpoint,
+    SizeLimits, Status,
+};
+use crate::RpcMessage;
+use bincode::Options;
+use futures::{ready, Future, Sink, Stream};
+use pin_project::pin_project;
+use std::{
+    error, fmt,
+    pin::Pin,
+    result,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::Duration,
+};
+
+/// Configuration for the faults to inject
+///
+/// All rates are probabilities between 0.0 (never) and 1.0 (always). The default
+/// configuration does not inject any faults.
+#[derive(Debug, Clone, Default)]
+pub struct FaultConfig {
+    seed: u64,
+    open_failure_rate: f64,
+    accept_failure_rate: f64,
+    drop_rate: f64,
+    delay_rate: f64,
+    max_delay: Duration,
+    close_rate: f64,
+    corrupt_rate: f64,
+}
+
+impl FaultConfig {
+    /// Seed for the pseudo random number generator.
+    ///
+    /// Default is 0
+    pub fn seed(mut self, value: u64) -> Self {
+        self.seed = value;
+        self
+    }
+
+    /// Probability that opening a channel fails with [OpenError::Injected].
+    ///
+    /// Default is 0.0
+    pub fn open_failure_rate(mut self, value: f64) -> Self {
+        self.open_failure_rate = value;
+        self
+    }
+
+    /// Probability that an accepted channel is dropped and [OpenError::Injected] is
+    /// returned instead.
+    ///
+    /// Default is 0.0
+    pub fn accept_failure_rate(mut self, value: f64) -> Self {
+        self.accept_failure_rate = value;
+        self
+    }
+
+    /// Probability that a sent message is silently dropped.
+    ///
+    /// Default is 0.0
+    pub fn drop_rate(mut self, value: f64) -> Self {
+        self.drop_rate = value;
+        self
+    }
+
+    /// Probability that a sent message is delayed, and the maximum delay.
+    ///
+    /// The actual delay is chosen uniformly between zero and `max_delay`.
+    ///
+    /// Default is no delay
+    pub fn delay(mut self, rate: f64, max_delay: Duration) -> Self {
+        self.delay_rate = rate;
+        self.max_delay = max_delay;
+        self
+    }
+
+    /// Probability that a channel is closed when sending or receiving a message.
+    ///
+    /// Closing on the send side fails the send with [SendError::Closed] and drops the
+    /// inner send sink. Closing on the receive side ends the receive stream.
+    ///
+    /// Default is 0.0
+    pub fn close_rate(mut self, value: f64) -> Self {
+        self.close_rate = value;
+        self
+    }
+
+    /// Probability that a received message is corrupted.
+    ///
+    /// A corrupted message is encoded with the same bincode options as the quinn transport,
+    /// a single bit of the encoding is flipped, and the result is decoded again. Depending on
+    /// the bit, this yields [RecvError::DeserializeError] or a silently altered message.
+    ///
+    /// Default is 0.0
+    pub fn corrupt_rate(mut self, value: f64) -> Self {
+        self.corrupt_rate = value;
+        self
+    }
+}
+
+/// The bincode options used by the quinn transport
+fn bincode_options() -> impl Options {
+    bincode::DefaultOptions::new().with_fixint_encoding()
+}
+
+/// Flip a random bit in the encoding of a message and decode it again
+fn corrupt<T: RpcMessage>(item: &T, rng: &mut Rng) -> bincode::Result<T> {
+    let mut bytes = bincode_options().serialize(item)?;
+    if !bytes.is_empty() {
+        let bit = rng.next_u64() % (bytes.len() as u64 * 8);
+        bytes[(bit / 8) as usize] ^= 1 << (bit % 8);
+    }
+    bincode_options().deserialize(&bytes)
+}
+
+/// Pseudo random number generator (splitmix64)
+#[derive(Debug, Clone)]
+struct Rng(u64);
+
+impl Rng {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// a float in the range `[0, 1)`
+    fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// returns true with probability `rate`
+    fn roll(&mut self, rate: f64) -> bool {
+        rate > 0.0 && self.next_f64() < rate
+    }
+
+    /// split off an independent generator
+    fn fork(&mut self) -> Self {
+        Self(self.next_u64())
+    }
+}
+
+/// State shared by a connection or endpoint and all its clones
+#[derive(Debug, Clone)]
+struct Faults {
+    config: FaultConfig,
+    rng: Arc<Mutex<Rng>>,
+}
+
+impl Faults {
+    fn new(config: FaultConfig) -> Self {
+        let rng = Arc::new(Mutex::new(Rng(config.seed)));
+        Self { config, rng }
+    }
+
+    fn roll(&self, rate: f64) -> bool {
+        self.rng.lock().unwrap().roll(rate)
+    }
+
+    /// Per channel state, with its own generator so that channels used in a fixed
+    /// order get the same faults regardless of how they are interleaved
+    fn channel(&self) -> ChannelFaults {
+        ChannelFaults {
+            config: self.config.clone(),
+            rng: self.rng.lock().unwrap().fork(),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct ChannelFaults {
+    config: FaultConfig,
+    rng: Rng,
+}
+
+impl ChannelFaults {
+    fn delay(&mut self) -> Option<Duration> {
+        if self.rng.roll(self.config.delay_rate) {
+            Some(self.config.max_delay.mul_f64(self.rng.next_f64()))
+        } else {
+            None
+        }
+    }
+}
+
+/// A connection that injects faults
+#[derive(Debug, Clone)]
+pub struct FaultyConnection<C> {
+    inner: C,
+    faults: Faults,
+}
+
+impl<C> FaultyConnection<C> {
+    /// Wrap a connection
+    pub fn new(inner: C, config: FaultConfig) -> Self {
+        Self {
+            inner,
+            faults: Faults::new(config),
+        }
+    }
+
+    /// Get back the inner connection
+    pub fn into_inner(self) -> C {
+        self.inner
+    }
+}
+
+/// A server endpoint that injects faults
+#[derive(Debug, Clone)]
+pub struct FaultyServerEndpoint<C> {
+    inner: C,
+    faults: Faults,
+}
+
+impl<C> FaultyServerEndpoint<C> {
+    /// Wrap a server endpoint
+    pub fn new(inner: C, config: FaultConfig) -> Self {
+        Self {
+            inner,
+            faults: Faults::new(config),
+        }
+    }
+
+    /// Get back the inner server endpoint
+    pub fn into_inner(self) -> C {
+        self.inner
+    }
+}
+
+/// Send sink for faulty channels
+pub struct SendSink<C: ConnectionCommon<In, Out>, In, Out> {
+    /// the inner sink, None once the channel has been closed
+    inner: Option<C::SendSink>,
+    /// a delayed message, to be sent once the delay has elapsed
+    delayed: Option<(Pin<Box<tokio::time::Sleep>>, Box<Out>)>,
+    faults: ChannelFaults,
+}
+
+impl<C: ConnectionCommon<In, Out>, In, Out> fmt::Debug for SendSink<C, In, Out> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SendSink")
+            .field("closed", &self.inner.is_none())
+            .field("delayed", &self.delayed.is_some())
+            .finish()
+    }
+}
+
+impl<C: ConnectionCommon<In, Out>, In, Out> SendSink<C, In, Out> {
+    /// Wait for a delayed message, if any, and hand it to the inner sink
+    fn poll_delayed(&mut self, cx: &mut Context<'_>) -> Poll<result::Result<(), SendError<C>>> {
+        if let Some((sleep, _)) = &mut self.delayed {
+            ready!(sleep.as_mut().poll(cx));
+            let inner = self.inner.as_mut().ok_or(SendError::Closed)?;
+            ready!(Pin::new(&mut *inner).poll_ready(cx)).map_err(SendError::Inner)?;
+            let (_, item) = self.delayed.take().unwrap();
+            Pin::new(inner)
+                .start_send(*item)
+                .map_err(SendError::Inner)?;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<C: ConnectionCommon<In, Out>, In, Out> Sink<Out> for SendSink<C, In, Out> {
+    type Error = self::SendError<C>;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        let this = self.get_mut();
+        ready!(this.poll_delayed(cx))?;
+        let inner = this.inner.as_mut().ok_or(SendError::Closed)?;
+        Pin::new(inner).poll_ready(cx).map_err(SendError::Inner)
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: Out) -> Result<(), Self::Error> {
+        let this = self.get_mut();
+        if this.inner.is_none() {
+            return Err(SendError::Closed);
+        }
+        if this.faults.rng.roll(this.faults.config.close_rate) {
+            this.inner = None;
+            return Err(SendError::Closed);
+        }
+        if this.faults.rng.roll(this.faults.config.drop_rate) {
+            return Ok(());
+        }
+        if let Some(delay) = this.faults.delay() {
+            this.delayed = Some((Box::pin(tokio::time::sleep(delay)), Box::new(item)));
+            return Ok(());
+        }
+        let inner = this.inner.as_mut().unwrap();
+        Pin::new(inner).start_send(item).map_err(SendError::Inner)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        let this = self.get_mut();
+        ready!(this.poll_delayed(cx))?;
+        let inner = this.inner.as_mut().ok_or(SendError::Closed)?;
+        Pin::new(inner).poll_flush(cx).map_err(SendError::Inner)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        let this = self.get_mut();
+        ready!(this.poll_delayed(cx))?;
+        match this.inner.as_mut() {
+            Some(inner) => Pin::new(inner).poll_close(cx).map_err(SendError::Inner),
+            None => Poll::Ready(Ok(())),
+        }
+    }
+}
+
+/// Receive stream for faulty channels
+pub struct RecvStream<C: ConnectionCommon<In, Out>, In, Out> {
+    /// the inner stream, None once the channel has been closed
+    inner: Option<C::RecvStream>,
+    faults: ChannelFaults,
+}
+
+impl<C: ConnectionCommon<In, Out>, In, Out> fmt::Debug for RecvStream<C, In, Out> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RecvStream")
+            .field("closed", &self.inner.is_none())
+            .finish()
+    }
+}
+
+impl<C: ConnectionCommon<In, Out>, In: RpcMessage, Out> Stream for RecvStream<C, In, Out> {
+    type Item = result::Result<In, self::RecvError<C>>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        let inner = match this.inner.as_mut() {
+            Some(inner) => inner,
+            None => return Poll::Ready(None),
+        };
+        match ready!(Pin::new(inner).poll_next(cx)) {
+            Some(Ok(item)) => {
+                if this.faults.rng.roll(this.faults.config.close_rate) {
+                    this.inner = None;
+                    Poll::Ready(None)
+                } else if this.faults.rng.roll(this.faults.config.corrupt_rate) {
+                    let item = corrupt(&item, &mut this.faults.rng);
+                    Poll::Ready(Some(item.map_err(RecvError::DeserializeError)))
+                } else {
+                    Poll::Ready(Some(Ok(item)))
+                }
+            }
+            Some(Err(cause)) => Poll::Ready(Some(Err(RecvError::Inner(cause)))),
+            None => Poll::Ready(None),
+        }
+    }
+}
+
+/// Open or accept error for faulty channels
+#[derive(Debug)]
+pub enum OpenError<C: ConnectionErrors> {
+    /// Error from the inner transport
+    Inner(C::OpenError),
+    /// Injected failure
+    Injected,
+}
+
+impl<C: ConnectionErrors> fmt::Display for OpenError<C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self, f)
+    }
+}
+
+impl<C: ConnectionErrors> error::Error for OpenError<C> {}
+
+/// Send error for faulty channels
+#[derive(Debug)]
+pub enum SendError<C: ConnectionErrors> {
+    /// Error from the inner transport
+    Inner(C::SendError),
+    /// The channel was closed by an injected fault
+    Closed,
+}
+
+impl<C: ConnectionErrors> fmt::Display for SendError<C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self, f)
+    }
+}
+
+impl<C: ConnectionErrors> error::Error for SendError<C> {}
+
+/// Receive error for faulty channels
+#[derive(Debug)]
+pub enum RecvError<C: ConnectionErrors> {
+    /// Error from the inner transport
+    Inner(C::RecvError),
+    /// A message corrupted by an injected fault could not be deserialized
+    DeserializeError(bincode::Error),
+}
+
+impl<C: ConnectionErrors> fmt::Display for RecvError<C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self, f)
+    }
+}
+
+impl<C: ConnectionErrors> error::Error for RecvError<C> {}
+
+type Socket<C, In, Out> = (SendSink<C, In, Out>, RecvStream<C, In, Out>);
+
+fn wrap_socket<C: ConnectionCommon<In, Out>, In, Out>(
+    (send, recv): (C::SendSink, C::RecvStream),
+    faults: &Faults,
+) -> Socket<C, In, Out> {
+    let send = SendSink {
+        inner: Some(send),
+        delayed: None,
+        faults: faults.channel(),
+    };
+    let recv = RecvStream {
+        inner: Some(recv),
+        faults: faults.channel(),
+    };
+    (send, recv)
+}
+
+/// Future returned by open_bi
+#[pin_project]
+pub struct OpenBiFuture<C: Connection<In, Out>, In, Out> {
+    /// the inner future, None if the open fails due to an injected fault
+    #[pin]
+    inner: Option<C::OpenBiFut>,
+    faults: Faults,
+}
+
+impl<C: Connection<In, Out>, In, Out> fmt::Debug for OpenBiFuture<C, In, Out> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("OpenBiFuture").finish()
+    }
+}
+
+impl<C: Connection<In, Out>, In, Out> Future for OpenBiFuture<C, In, Out> {
+    type Output = result::Result<Socket<C, In, Out>, OpenError<C>>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+        match this.inner.as_pin_mut() {
+            Some(inner) => inner
+                .poll(cx)
+                .map_ok(|socket| wrap_socket::<C, In, Out>(socket, this.faults))
+                .map_err(OpenError::Inner),
+            None => Poll::Ready(Err(OpenError::Injected)),
+        }
+    }
+}
+
+/// Future returned by accept_bi
+#[pin_project]
+pub struct AcceptBiFuture<C: ServerEndpoint<In, Out>, In, Out> {
+    #[pin]
+    inner: C::AcceptBiFut,
+    faults: Faults,
+}
+
+impl<C: ServerEndpoint<In, Out>, In, Out> fmt::Debug for AcceptBiFuture<C, In, Out> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AcceptBiFuture").finish()
+    }
+}
+
+impl<C: ServerEndpoint<In, Out>, In, Out> Future for AcceptBiFuture<C, In, Out> {
+    type Output = result::Result<Socket<C, In, Out>, OpenError<C>>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+        let socket = ready!(this.inner.poll(cx)).map_err(OpenError::Inner)?;
+        if this.faults.roll(this.faults.config.accept_failure_rate) {
+            // drop the accepted channel, so the remote sees it closed
+            drop(socket);
+            return Poll::Ready(Err(OpenError::Injected));
+        }
+        Poll::Ready(Ok(wrap_socket::<C, In, Out>(socket, this.faults)))
+    }
+}
+
+impl<C: ConnectionErrors> ConnectionErrors for FaultyConnection<C> {
+    type SendError = self::SendError<C>;
+    type RecvError = self::RecvError<C>;
+    type OpenError = self::OpenError<C>;
+}
+
+impl<C: ConnectionCommon<In, Out>, In: RpcMessage, Out: RpcMessage> ConnectionCommon<In, Out>
+    for FaultyConnection<C>
+{
+    type SendSink = self::SendSink<C, In, Out>;
+    type RecvStream = self::RecvStream<C, In, Out>;
+
+    fn limit_message_size(
+        send: &mut Self::SendSink,
+        recv: &mut Self::RecvStream,
+        limits: SizeLimits,
+    ) -> bool {
+        match (&mut send.inner, &mut recv.inner) {
+            (Some(send), Some(recv)) => C::limit_message_size(send, recv, limits),
+            // a channel that has been closed by a fault has nothing to limit
+            _ => true,
+        }
+    }
+
+    fn received_size(recv: &Self::RecvStream) -> Option<usize> {
+        recv.inner.as_ref().and_then(C::received_size)
+    }
+
+    fn sent_size(send: &Self::SendSink) -> Option<usize> {
+        send.inner.as_ref().and_then(C::sent_size)
+    }
+
+    fn cancel(send: &mut Self::SendSink) {
+        if let Some(inner) = &mut send.inner {
+            C::cancel(inner)
+        }
+    }
+
+    fn is_cancelled(error: &Self::RecvError) -> bool {
+        matches!(error, RecvError::Inner(inner) if C::is_cancelled(inner))
+    }
+
+    fn rejection(error: &Self::RecvError) -> Option<Status> {
+        match error {
+            RecvError::Inner(inner) => C::rejection(inner),
+            _ => None,
+        }
+    }
+}
+
+impl<C: Connection<In, Out>, In: RpcMessage, Out: RpcMessage> Connection<In, Out>
+    for FaultyConnection<C>
+{
+    type OpenBiFut = OpenBiFuture<C, In, Out>;
+
+    fn open_bi(&self) -> Self::OpenBiFut {
+        self.open_bi_with_metadata(Metadata::default())
+    }
+
+    fn open_bi_with_metadata(&self, metadata: Metadata) -> Self::OpenBiFut {
+        let inner = if self.faults.roll(self.faults.config.open_failure_rate) {
+            None
+        } else {
+            Some(self.inner.open_bi_with_metadata(metadata))
+        };
+        OpenBiFuture {
+            inner,
+            faults: self.faults.clone(),
+        }
+    }
+
+    fn carries_metadata(&self) -> bool {
+        self.inner.carries_metadata()
+    }
+}
+
+impl<C: ConnectionErrors> ConnectionErrors for FaultyServerEndpoint<C> {
+    type SendError = self::SendError<C>;
+    type RecvError = self::RecvError<C>;
+    type OpenError = self::OpenError<C>;
+}
+
+impl<C: ConnectionCommon<In, Out>, In: RpcMessage, Out: RpcMessage> ConnectionCommon<In, Out>
+    for FaultyServerEndpoint<C>
+{
+    type SendSink = self::SendSink<C, In, Out>;
+    type RecvStream = self::RecvStream<C, In, Out>;
+
+    fn limit_message_size(
+        send: &mut Self::SendSink,
+        recv: &mut Self::RecvStream,
+        limits: SizeLimits,
+    ) -> bool {
+        match (&mut send.inner, &mut recv.inner) {
+            (Some(send), Some(recv)) => C::limit_message_size(send, recv, limits),
+            // a channel that has been closed by a fault has nothing to limit
+            _ => true,
+        }
+    }
+
+    fn received_size(recv: &Self::RecvStream) -> Option<usize> {
+        recv.inner.as_ref().and_then(C::received_size)
+    }
+
+    fn sent_size(send: &Self::SendSink) -> Option<usize> {
+        send.inner.as_ref().and_then(C::sent_size)
+    }
+
+    fn cancel(send: &mut Self::SendSink) {
+        if let Some(inner) = &mut send.inner {
+            C::cancel(inner)
+        }
+    }
+
+    fn is_cancelled(error: &Self::RecvError) -> bool {
+        matches!(error, RecvError::Inner(inner) if C::is_cancelled(inner))
+    }
+
+    fn rejection(error: &Self::RecvError) -> Option<Status> {
+        match error {
+            RecvError::Inner(inner) => C::rejection(inner),
+            _ => None,
+        }
+    }
+}
+
+impl<C: ServerEndpoint<In, Out>, In: RpcMessage, Out: RpcMessage> ServerEndpoint<In, Out>
+    for FaultyServerEndpoint<C>
+{
+    type AcceptBiFut = AcceptBiFuture<C, In, Out>;
+
+    fn accept_bi(&self) -> Self::AcceptBiFut {
+        AcceptBiFuture {
+            inner: self.inner.accept_bi(),
+            faults: self.faults.clone(),
+        }
+    }
+
+    fn local_addr(&self) -> &[LocalAddr] {
+        self.inner.local_addr()
+    }
+
+    fn metadata(recv: &Self::RecvStream) -> Metadata {
+        recv.inner.as_ref().map(C::metadata).unwrap_or_default()
+    }
+
+    fn peer(recv: &Self::RecvStream) -> PeerInfo {
+        recv.inner.as_ref().map(C::peer).unwrap_or_default()
+    }
+
+    /// A channel that has been closed by a fault is just dropped.
+    fn reject(send: Self::SendSink, recv: Self::RecvStream, status: Status) {
+        if let (Some(send), Some(recv)) = (send.inner, recv.inner) {
+            C::reject(send, recv, status)
+        }
+    }
+}
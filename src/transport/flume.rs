@@ -2,20 +2,44 @@
 //!
 //! [flume]: https://docs.rs/flume/
 use crate::{
-    transport::{Connection, ConnectionErrors, LocalAddr, ServerEndpoint},
+    transport::{
+        Connection, ConnectionErrors, ConnectionHealth, LocalAddr, Metadata, ServerEndpoint,
+        StatsCounters, StatsSnapshot, Status, TransportStats,
+    },
     RpcMessage,
 };
 use core::fmt;
-use futures::{Future, FutureExt, Sink, SinkExt, Stream, StreamExt};
-use std::{error, fmt::Display, marker::PhantomData, pin::Pin, result, task::Poll};
+use futures::{future, ready, Future, FutureExt, Sink, SinkExt, Stream, StreamExt};
+use std::{
+    any::Any,
+    collections::HashMap,
+    error,
+    fmt::Display,
+    marker::PhantomData,
+    pin::Pin,
+    result,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc, Mutex,
+    },
+    task::Poll,
+    time::Duration,
+};
 
 use super::ConnectionCommon;
 
 /// Error when receiving from a channel
 ///
-/// This type has zero inhabitants, so it is always safe to unwrap a result with this error type.
+/// Memory channels can not fail, the only errors are the cancel signal of the other side,
+/// see [ConnectionCommon::cancel], and the rejection of a channel by the server, see
+/// [ServerEndpoint::reject].
 #[derive(Debug)]
-pub enum RecvError {}
+pub enum RecvError {
+    /// The other side cancelled the interaction
+    Cancelled,
+    /// The server rejected the channel with this status
+    Rejected(Status),
+}
 
 impl fmt::Display for RecvError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
@@ -24,7 +48,57 @@ impl fmt::Display for RecvError {
 }
 
 /// Sink for memory channels
-pub struct SendSink<T: RpcMessage>(flume::r#async::SendSink<'static, T>);
+///
+/// Closing the sink disconnects it, so the receiver sees the end of the stream even
+/// if the sink is not dropped. Dropping the sink has the same effect.
+pub struct SendSink<T: RpcMessage>(
+    Option<flume::r#async::SendSink<'static, T>>,
+    /// set before disconnecting to tell the receiver why the interaction ended
+    EndSignal,
+);
+
+/// State shared by both sides of a channel: the metadata sent by the client, and
+/// per direction the error a receiver reports instead of the end of the stream
+///
+/// Both directions of a channel share one allocation, the index selects the direction.
+#[derive(Clone)]
+struct EndSignal(Arc<Shared>, usize);
+
+struct Shared {
+    /// The error as a grpc status code, 0 for none
+    ends: [AtomicU32; 2],
+    metadata: Metadata,
+}
+
+/// The grpc status code for a cancelled interaction
+const CANCELLED: u32 = 1;
+
+impl EndSignal {
+    /// The signals for the two directions of a channel
+    fn pair(metadata: Metadata) -> (Self, Self) {
+        let shared = Arc::new(Shared {
+            ends: Default::default(),
+            metadata,
+        });
+        (Self(shared.clone(), 0), Self(shared, 1))
+    }
+
+    fn set(&self, error: RecvError) {
+        let code = match error {
+            RecvError::Cancelled => CANCELLED,
+            RecvError::Rejected(status) => status.code(),
+        };
+        self.0.ends[self.1].store(code, Ordering::Release);
+    }
+
+    fn take(&self) -> Option<RecvError> {
+        match self.0.ends[self.1].swap(0, Ordering::Acquire) {
+            0 => None,
+            CANCELLED => Some(RecvError::Cancelled),
+            code => Status::from_code(code.into()).map(RecvError::Rejected),
+        }
+    }
+}
 
 impl<T: RpcMessage> fmt::Debug for SendSink<T> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
@@ -32,6 +106,12 @@ impl<T: RpcMessage> fmt::Debug for SendSink<T> {
     }
 }
 
+impl<T: RpcMessage> SendSink<T> {
+    fn new(sink: flume::r#async::SendSink<'static, T>, end: EndSignal) -> Self {
+        Self(Some(sink), end)
+    }
+}
+
 impl<T: RpcMessage> Sink<T> for SendSink<T> {
     type Error = self::SendError;
 
@@ -39,38 +119,55 @@ impl<T: RpcMessage> Sink<T> for SendSink<T> {
         mut self: Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
     ) -> Poll<Result<(), Self::Error>> {
-        self.0
-            .poll_ready_unpin(cx)
-            .map_err(|_| SendError::ReceiverDropped)
+        match &mut self.0 {
+            Some(sink) => sink
+                .poll_ready_unpin(cx)
+                .map_err(|_| SendError::ReceiverDropped),
+            None => Poll::Ready(Err(SendError::ReceiverDropped)),
+        }
     }
 
     fn start_send(mut self: Pin<&mut Self>, item: T) -> Result<(), Self::Error> {
-        self.0
-            .start_send_unpin(item)
-            .map_err(|_| SendError::ReceiverDropped)
+        match &mut self.0 {
+            Some(sink) => sink
+                .start_send_unpin(item)
+                .map_err(|_| SendError::ReceiverDropped),
+            None => Err(SendError::ReceiverDropped),
+        }
     }
 
     fn poll_flush(
         mut self: Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
     ) -> Poll<Result<(), Self::Error>> {
-        self.0
-            .poll_flush_unpin(cx)
-            .map_err(|_| SendError::ReceiverDropped)
+        match &mut self.0 {
+            Some(sink) => sink
+                .poll_flush_unpin(cx)
+                .map_err(|_| SendError::ReceiverDropped),
+            None => Poll::Ready(Ok(())),
+        }
     }
 
     fn poll_close(
         mut self: Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
     ) -> Poll<Result<(), Self::Error>> {
-        self.0
-            .poll_close_unpin(cx)
-            .map_err(|_| SendError::ReceiverDropped)
+        if let Some(sink) = &mut self.0 {
+            ready!(sink.poll_close_unpin(cx)).map_err(|_| SendError::ReceiverDropped)?;
+            // drop the sender, so the receiver sees the end of the stream
+            self.0 = None;
+        }
+        Poll::Ready(Ok(()))
     }
 }
 
 /// Stream for memory channels
-pub struct RecvStream<T: RpcMessage>(flume::r#async::RecvStream<'static, T>);
+pub struct RecvStream<T: RpcMessage> {
+    inner: flume::r#async::RecvStream<'static, T>,
+    /// why the sender ended the interaction and the client metadata, shared with the
+    /// [SendSink]
+    end: EndSignal,
+}
 
 impl<T: RpcMessage> fmt::Debug for RecvStream<T> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
@@ -85,9 +182,10 @@ impl<T: RpcMessage> Stream for RecvStream<T> {
         mut self: Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
     ) -> Poll<Option<Self::Item>> {
-        match self.0.poll_next_unpin(cx) {
+        match self.inner.poll_next_unpin(cx) {
             Poll::Ready(Some(v)) => Poll::Ready(Some(Ok(v))),
-            Poll::Ready(None) => Poll::Ready(None),
+            // report the cancellation or rejection once, then end the stream
+            Poll::Ready(None) => Poll::Ready(self.end.take().map(Err)),
             Poll::Pending => Poll::Pending,
         }
     }
@@ -95,17 +193,27 @@ impl<T: RpcMessage> Stream for RecvStream<T> {
 
 impl error::Error for RecvError {}
 
+/// Disconnect the sink, after telling the receiver why
+fn end<T: RpcMessage>(send: &mut SendSink<T>, error: RecvError) {
+    send.1.set(error);
+    send.0 = None;
+}
+
 /// A flume based server endpoint.
 ///
 /// Created using [connection].
 pub struct FlumeServerEndpoint<In: RpcMessage, Out: RpcMessage> {
     stream: flume::Receiver<(SendSink<Out>, RecvStream<In>)>,
+    local_addr: [LocalAddr; 1],
+    stats: Arc<StatsCounters>,
 }
 
 impl<In: RpcMessage, Out: RpcMessage> Clone for FlumeServerEndpoint<In, Out> {
     fn clone(&self) -> Self {
         Self {
             stream: self.stream.clone(),
+            local_addr: self.local_addr.clone(),
+            stats: self.stats.clone(),
         }
     }
 }
@@ -114,6 +222,7 @@ impl<In: RpcMessage, Out: RpcMessage> fmt::Debug for FlumeServerEndpoint<In, Out
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("FlumeServerEndpoint")
             .field("stream", &self.stream)
+            .field("local_addr", &self.local_addr)
             .finish()
     }
 }
@@ -132,6 +241,7 @@ type Socket<In, Out> = (self::SendSink<Out>, self::RecvStream<In>);
 pub struct OpenBiFuture<In: RpcMessage, Out: RpcMessage> {
     inner: flume::r#async::SendFut<'static, Socket<Out, In>>,
     res: Option<Socket<In, Out>>,
+    stats: Arc<StatsCounters>,
 }
 
 impl<In: RpcMessage, Out: RpcMessage> fmt::Debug for OpenBiFuture<In, Out> {
@@ -141,10 +251,15 @@ impl<In: RpcMessage, Out: RpcMessage> fmt::Debug for OpenBiFuture<In, Out> {
 }
 
 impl<In: RpcMessage, Out: RpcMessage> OpenBiFuture<In, Out> {
-    fn new(inner: flume::r#async::SendFut<'static, Socket<Out, In>>, res: Socket<In, Out>) -> Self {
+    fn new(
+        inner: flume::r#async::SendFut<'static, Socket<Out, In>>,
+        res: Socket<In, Out>,
+        stats: Arc<StatsCounters>,
+    ) -> Self {
         Self {
             inner,
             res: Some(res),
+            stats,
         }
     }
 }
@@ -157,11 +272,13 @@ impl<In: RpcMessage, Out: RpcMessage> Future for OpenBiFuture<In, Out> {
         cx: &mut std::task::Context<'_>,
     ) -> std::task::Poll<Self::Output> {
         match self.inner.poll_unpin(cx) {
-            Poll::Ready(Ok(())) => self
-                .res
-                .take()
-                .map(|x| Poll::Ready(Ok(x)))
-                .unwrap_or(Poll::Pending),
+            Poll::Ready(Ok(())) => match self.res.take() {
+                Some(res) => {
+                    self.stats.opened.fetch_add(1, Ordering::Relaxed);
+                    Poll::Ready(Ok(res))
+                }
+                None => Poll::Pending,
+            },
             Poll::Ready(Err(_)) => Poll::Ready(Err(self::OpenBiError::RemoteDropped)),
             Poll::Pending => Poll::Pending,
         }
@@ -171,6 +288,7 @@ impl<In: RpcMessage, Out: RpcMessage> Future for OpenBiFuture<In, Out> {
 /// Future returned by [FlumeServerEndpoint::accept_bi]
 pub struct AcceptBiFuture<In: RpcMessage, Out: RpcMessage> {
     wrapped: flume::r#async::RecvFut<'static, (SendSink<Out>, RecvStream<In>)>,
+    stats: Arc<StatsCounters>,
     _p: PhantomData<(In, Out)>,
 }
 
@@ -185,7 +303,10 @@ impl<In: RpcMessage, Out: RpcMessage> Future for AcceptBiFuture<In, Out> {
 
     fn poll(mut self: Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> Poll<Self::Output> {
         match self.wrapped.poll_unpin(cx) {
-            Poll::Ready(Ok((send, recv))) => Poll::Ready(Ok((send, recv))),
+            Poll::Ready(Ok((send, recv))) => {
+                self.stats.accepted.fetch_add(1, Ordering::Relaxed);
+                Poll::Ready(Ok((send, recv)))
+            }
             Poll::Ready(Err(_)) => Poll::Ready(Err(AcceptBiError::RemoteDropped)),
             Poll::Pending => Poll::Pending,
         }
@@ -195,6 +316,28 @@ impl<In: RpcMessage, Out: RpcMessage> Future for AcceptBiFuture<In, Out> {
 impl<In: RpcMessage, Out: RpcMessage> ConnectionCommon<In, Out> for FlumeServerEndpoint<In, Out> {
     type SendSink = SendSink<Out>;
     type RecvStream = RecvStream<In>;
+
+    fn cancel(send: &mut Self::SendSink) {
+        end(send, RecvError::Cancelled)
+    }
+
+    fn is_cancelled(error: &Self::RecvError) -> bool {
+        matches!(error, RecvError::Cancelled)
+    }
+
+    fn rejection(error: &Self::RecvError) -> Option<Status> {
+        match error {
+            RecvError::Rejected(status) => Some(*status),
+            RecvError::Cancelled => None,
+        }
+    }
+}
+
+/// Counts channels. The accept queue is the buffer given to [connection].
+impl<In: RpcMessage, Out: RpcMessage> TransportStats for FlumeServerEndpoint<In, Out> {
+    fn stats(&self) -> StatsSnapshot {
+        self.stats.snapshot().with_queue(&self.stream)
+    }
 }
 
 impl<In: RpcMessage, Out: RpcMessage> ServerEndpoint<In, Out> for FlumeServerEndpoint<In, Out> {
@@ -203,12 +346,23 @@ impl<In: RpcMessage, Out: RpcMessage> ServerEndpoint<In, Out> for FlumeServerEnd
     fn accept_bi(&self) -> Self::AcceptBiFut {
         AcceptBiFuture {
             wrapped: self.stream.clone().into_recv_async(),
+            stats: self.stats.clone(),
             _p: PhantomData,
         }
     }
 
     fn local_addr(&self) -> &[LocalAddr] {
-        &[LocalAddr::Mem]
+        &self.local_addr
+    }
+
+    fn metadata(recv: &Self::RecvStream) -> Metadata {
+        recv.end.0.metadata.clone()
+    }
+
+    /// The client receives the status as [RecvError::Rejected].
+    fn reject(mut send: Self::SendSink, recv: Self::RecvStream, status: Status) {
+        end(&mut send, RecvError::Rejected(status));
+        drop(recv);
     }
 }
 
@@ -220,26 +374,77 @@ impl<In: RpcMessage, Out: RpcMessage> ConnectionErrors for FlumeConnection<In, O
     type OpenError = self::OpenBiError;
 }
 
+/// A flume connection is healthy as long as the server endpoint exists.
+impl<In: RpcMessage, Out: RpcMessage> ConnectionHealth for FlumeConnection<In, Out> {
+    type PingFut = future::Ready<result::Result<Duration, Self::OpenError>>;
+
+    fn ping(&self) -> Self::PingFut {
+        future::ready(if self.sink.is_disconnected() {
+            Err(OpenBiError::RemoteDropped)
+        } else {
+            Ok(Duration::ZERO)
+        })
+    }
+
+    fn is_healthy(&self) -> bool {
+        !self.sink.is_disconnected()
+    }
+}
+
 impl<In: RpcMessage, Out: RpcMessage> ConnectionCommon<In, Out> for FlumeConnection<In, Out> {
     type SendSink = SendSink<Out>;
     type RecvStream = RecvStream<In>;
+
+    fn cancel(send: &mut Self::SendSink) {
+        end(send, RecvError::Cancelled)
+    }
+
+    fn is_cancelled(error: &Self::RecvError) -> bool {
+        matches!(error, RecvError::Cancelled)
+    }
+
+    fn rejection(error: &Self::RecvError) -> Option<Status> {
+        match error {
+            RecvError::Rejected(status) => Some(*status),
+            RecvError::Cancelled => None,
+        }
+    }
 }
 
 impl<In: RpcMessage, Out: RpcMessage> Connection<In, Out> for FlumeConnection<In, Out> {
     type OpenBiFut = OpenBiFuture<In, Out>;
 
     fn open_bi(&self) -> Self::OpenBiFut {
+        self.open_bi_with_metadata(Metadata::default())
+    }
+
+    fn carries_metadata(&self) -> bool {
+        true
+    }
+
+    fn open_bi_with_metadata(&self, metadata: Metadata) -> Self::OpenBiFut {
         let (local_send, remote_recv) = flume::bounded::<Out>(128);
         let (remote_send, local_recv) = flume::bounded::<In>(128);
+        let (local_end, remote_end) = EndSignal::pair(metadata);
         let remote_chan = (
-            SendSink(remote_send.into_sink()),
-            RecvStream(remote_recv.into_stream()),
+            SendSink::new(remote_send.into_sink(), remote_end.clone()),
+            RecvStream {
+                inner: remote_recv.into_stream(),
+                end: local_end.clone(),
+            },
         );
         let local_chan = (
-            SendSink(local_send.into_sink()),
-            RecvStream(local_recv.into_stream()),
+            SendSink::new(local_send.into_sink(), local_end),
+            RecvStream {
+                inner: local_recv.into_stream(),
+                end: remote_end,
+            },
         );
-        OpenBiFuture::new(self.sink.clone().into_send_async(remote_chan), local_chan)
+        OpenBiFuture::new(
+            self.sink.clone().into_send_async(remote_chan),
+            local_chan,
+            self.stats.clone(),
+        )
     }
 }
 
@@ -248,16 +453,25 @@ impl<In: RpcMessage, Out: RpcMessage> Connection<In, Out> for FlumeConnection<In
 /// Created using [connection].
 pub struct FlumeConnection<In: RpcMessage, Out: RpcMessage> {
     sink: flume::Sender<(SendSink<In>, RecvStream<Out>)>,
+    stats: Arc<StatsCounters>,
 }
 
 impl<In: RpcMessage, Out: RpcMessage> Clone for FlumeConnection<In, Out> {
     fn clone(&self) -> Self {
         Self {
             sink: self.sink.clone(),
+            stats: self.stats.clone(),
         }
     }
 }
 
+/// Counts opened channels.
+impl<In: RpcMessage, Out: RpcMessage> TransportStats for FlumeConnection<In, Out> {
+    fn stats(&self) -> StatsSnapshot {
+        self.stats.snapshot()
+    }
+}
+
 impl<In: RpcMessage, Out: RpcMessage> fmt::Debug for FlumeConnection<In, Out> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("FlumeClientChannel")
@@ -335,7 +549,154 @@ impl std::error::Error for CreateChannelError {}
 /// `buffer` the size of the buffer for each channel. Keep this at a low value to get backpressure
 pub fn connection<Req: RpcMessage, Res: RpcMessage>(
     buffer: usize,
+) -> (FlumeServerEndpoint<Req, Res>, FlumeConnection<Res, Req>) {
+    connection_with_addr(buffer, LocalAddr::Mem(None))
+}
+
+fn connection_with_addr<Req: RpcMessage, Res: RpcMessage>(
+    buffer: usize,
+    local_addr: LocalAddr,
 ) -> (FlumeServerEndpoint<Req, Res>, FlumeConnection<Res, Req>) {
     let (sink, stream) = flume::bounded(buffer);
-    (FlumeServerEndpoint { stream }, FlumeConnection { sink })
+    (
+        FlumeServerEndpoint {
+            stream,
+            local_addr: [local_addr],
+            stats: Default::default(),
+        },
+        FlumeConnection {
+            sink,
+            stats: Default::default(),
+        },
+    )
+}
+
+/// Error when binding or connecting to a named endpoint in a [Registry].
+#[derive(Debug, Clone)]
+pub enum RegistryError {
+    /// Another live endpoint is already bound under this name
+    AddrInUse(String),
+    /// No live endpoint is bound under this name
+    NotFound(String),
+    /// The endpoint bound under this name uses different message types
+    TypeMismatch(String),
+}
+
+impl Display for RegistryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self, f)
+    }
+}
+
+impl std::error::Error for RegistryError {}
+
+/// An entry in a [Registry]: the client side of a bound endpoint, with the types erased.
+struct RegistryEntry {
+    connection: Box<dyn Any + Send + Sync>,
+    /// Checks if the server side of the endpoint is still alive.
+    ///
+    /// This is a monomorphized fn pointer so we can check liveness without knowing the types.
+    is_bound: fn(&(dyn Any + Send + Sync)) -> bool,
+}
+
+impl fmt::Debug for RegistryEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RegistryEntry")
+            .field("is_bound", &(self.is_bound)(self.connection.as_ref()))
+            .finish()
+    }
+}
+
+fn is_bound<Req: RpcMessage, Res: RpcMessage>(connection: &(dyn Any + Send + Sync)) -> bool {
+    connection
+        .downcast_ref::<FlumeConnection<Res, Req>>()
+        .map(|c| !c.sink.is_disconnected())
+        .unwrap_or_default()
+}
+
+/// A registry of named in-memory endpoints.
+///
+/// Instead of creating a connected pair using [connection] and passing the client side
+/// around, a server endpoint can be bound under a name using [Registry::bind], and clients
+/// can connect to it by name using [Registry::connect]. The local address of a bound
+/// endpoint is [LocalAddr::Mem] with the name, displayed as `mem://<name>`.
+///
+/// Names can be given with or without the `mem://` prefix.
+///
+/// The registry is cheap to clone. All clones share the same set of names, so it can be
+/// handed to all subsystems that need to talk to each other.
+///
+/// The registry keeps a client side of each bound endpoint, so [ServerEndpoint::accept_bi]
+/// on a bound endpoint will not fail when all clients are dropped. Use [Registry::unbind]
+/// to remove the name. A name whose server endpoint has been dropped can be bound again.
+#[derive(Debug, Clone, Default)]
+pub struct Registry {
+    entries: Arc<Mutex<HashMap<String, RegistryEntry>>>,
+}
+
+impl Registry {
+    /// Create a new, empty registry.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create a flume server endpoint and bind it under the given name.
+    ///
+    /// `buffer` the size of the buffer for each channel. Keep this at a low value to get backpressure
+    pub fn bind<Req: RpcMessage, Res: RpcMessage>(
+        &self,
+        name: &str,
+        buffer: usize,
+    ) -> result::Result<FlumeServerEndpoint<Req, Res>, RegistryError> {
+        let name = Self::strip_scheme(name);
+        let mut entries = self.entries.lock().unwrap();
+        if let Some(entry) = entries.get(name) {
+            if (entry.is_bound)(entry.connection.as_ref()) {
+                return Err(RegistryError::AddrInUse(name.to_string()));
+            }
+        }
+        let (server, client) =
+            connection_with_addr::<Req, Res>(buffer, LocalAddr::Mem(Some(name.to_string())));
+        entries.insert(
+            name.to_string(),
+            RegistryEntry {
+                connection: Box::new(client),
+                is_bound: is_bound::<Req, Res>,
+            },
+        );
+        Ok(server)
+    }
+
+    /// Connect to the server endpoint bound under the given name.
+    pub fn connect<Req: RpcMessage, Res: RpcMessage>(
+        &self,
+        name: &str,
+    ) -> result::Result<FlumeConnection<Res, Req>, RegistryError> {
+        let name = Self::strip_scheme(name);
+        let entries = self.entries.lock().unwrap();
+        let entry = entries
+            .get(name)
+            .filter(|entry| (entry.is_bound)(entry.connection.as_ref()))
+            .ok_or_else(|| RegistryError::NotFound(name.to_string()))?;
+        entry
+            .connection
+            .downcast_ref::<FlumeConnection<Res, Req>>()
+            .cloned()
+            .ok_or_else(|| RegistryError::TypeMismatch(name.to_string()))
+    }
+
+    /// Remove the name from the registry.
+    ///
+    /// Existing connections stay usable. Once they are all dropped, the server endpoint
+    /// will stop accepting channels.
+    ///
+    /// Returns true if the name was bound.
+    pub fn unbind(&self, name: &str) -> bool {
+        let name = Self::strip_scheme(name);
+        self.entries.lock().unwrap().remove(name).is_some()
+    }
+
+    fn strip_scheme(name: &str) -> &str {
+        name.strip_prefix("mem://").unwrap_or(name)
+    }
 }
@@ -2,32 +2,58 @@
 //!
 //! [hyper]: https://crates.io/crates/hyper/
 use std::{
-    convert::Infallible, error, fmt, io, marker::PhantomData, net::SocketAddr, pin::Pin, result,
-    sync::Arc, task::Poll,
+    convert::Infallible,
+    error, fmt, io,
+    marker::PhantomData,
+    net::SocketAddr,
+    pin::Pin,
+    result,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    task::Poll,
+    time::{Duration, Instant},
 };
 
-use crate::transport::{Connection, ConnectionErrors, LocalAddr, ServerEndpoint};
+use crate::transport::{
+    Connection, ConnectionErrors, ConnectionHealth, LocalAddr, Metadata, PeerInfo, RawStreams,
+    ServerEndpoint, SizeLimits, StatsCounters, StatsSnapshot, Status, TransportStats,
+};
 use crate::RpcMessage;
-use bytes::Bytes;
+use bytes::{Buf, Bytes, BytesMut};
 use flume::{r#async::RecvFut, Receiver, Sender};
-use futures::{future::FusedFuture, Future, FutureExt, Sink, SinkExt, StreamExt};
+use futures::{
+    future::{BoxFuture, FusedFuture},
+    ready, Future, FutureExt, Sink, SinkExt, StreamExt,
+};
 use hyper::{
     client::{connect::Connect, HttpConnector, ResponseFuture},
     server::conn::{AddrIncoming, AddrStream},
     service::{make_service_fn, service_fn},
-    Body, Client, Request, Response, Server, StatusCode, Uri,
+    Body, Client, Method, Request, Response, Server, StatusCode, Uri,
 };
 use pin_project::pin_project;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
 use tokio::sync::mpsc;
 use tokio::task::JoinHandle;
 use tracing::{debug, event, trace, Level};
 
-use super::ConnectionCommon;
+use super::{util::OpenChannel, ConnectionCommon};
+
+/// Length prefix of the frame a server sends to reject a channel, followed by the status
+/// code. Messages are never this large, see [ChannelConfig::max_payload_size].
+const REJECTION_FRAME: u32 = u32::MAX;
+/// Length prefix of the frame that cancels an interaction, see [ConnectionCommon::cancel]
+const CANCEL_FRAME: u32 = u32::MAX - 1;
 
 struct HyperConnectionInner {
     client: Box<dyn Requester>,
     config: Arc<ChannelConfig>,
     uri: Uri,
+    /// Result of the last request, shared with the open futures
+    healthy: Arc<AtomicBool>,
+    stats: Arc<StatsCounters>,
 }
 
 /// Hyper based connection to a server
@@ -72,12 +98,17 @@ impl<In: RpcMessage, Out: RpcMessage> HyperConnection<In, Out> {
             .http2_initial_stream_window_size(Some(config.max_frame_size))
             .http2_max_frame_size(Some(config.max_frame_size))
             .http2_max_send_buf_size(config.max_frame_size.try_into().unwrap())
+            .http2_keep_alive_interval(config.keep_alive_interval)
+            .http2_keep_alive_timeout(config.keep_alive_timeout)
+            .http2_keep_alive_while_idle(true)
             .build(connector);
         Self {
             inner: Arc::new(HyperConnectionInner {
                 client: Box::new(client),
                 uri,
                 config,
+                healthy: Arc::new(AtomicBool::new(true)),
+                stats: Default::default(),
             }),
             _p: PhantomData,
         }
@@ -108,10 +139,13 @@ impl<In: RpcMessage, Out: RpcMessage> Clone for HyperConnection<In, Out> {
 /// receives whole messages of the [`In`] and [`Out`] types.
 type Socket<In, Out> = (self::SendSink<Out>, self::RecvStream<In>);
 
-/// A flume sender and receiver tuple.
-type InternalChannel<In> = (
-    Receiver<result::Result<In, RecvError>>,
+/// A flume sender and receiver tuple, the metadata from the request headers and the
+/// address of the client.
+type InternalChannel = (
+    Receiver<result::Result<Bytes, hyper::Error>>,
     Sender<io::Result<Bytes>>,
+    Metadata,
+    SocketAddr,
 );
 
 /// Error when setting a channel configuration
@@ -139,6 +173,8 @@ pub struct ChannelConfig {
     /// The maximum frame size to use.
     max_frame_size: u32,
     max_payload_size: usize,
+    keep_alive_interval: Option<Duration>,
+    keep_alive_timeout: Duration,
 }
 
 impl ChannelConfig {
@@ -159,6 +195,24 @@ impl ChannelConfig {
         self.max_payload_size = value;
         Ok(self)
     }
+
+    /// Set the interval for HTTP/2 keep alive.
+    ///
+    /// Keep alive uses HTTP/2 `PING` frames. If the peer does not answer one within the keep
+    /// alive timeout, the underlying connection is closed, so the next request fails.
+    /// Default is `None`, which disables keep alive.
+    pub fn keep_alive_interval(mut self, value: Option<Duration>) -> Self {
+        self.keep_alive_interval = value;
+        self
+    }
+
+    /// Set the timeout for HTTP/2 keep alive.
+    ///
+    /// Default is 20 seconds.
+    pub fn keep_alive_timeout(mut self, value: Duration) -> Self {
+        self.keep_alive_timeout = value;
+        self
+    }
 }
 
 impl Default for ChannelConfig {
@@ -166,6 +220,8 @@ impl Default for ChannelConfig {
         Self {
             max_frame_size: 0xFFFFFF,
             max_payload_size: 0xFFFFFF,
+            keep_alive_interval: None,
+            keep_alive_timeout: Duration::from_secs(20),
         }
     }
 }
@@ -181,7 +237,7 @@ impl Default for ChannelConfig {
 #[derive(Debug)]
 pub struct HyperServerEndpoint<In: RpcMessage, Out: RpcMessage> {
     /// The channel.
-    channel: Receiver<InternalChannel<In>>,
+    channel: Receiver<InternalChannel>,
     /// The configuration.
     config: Arc<ChannelConfig>,
     /// The sender to stop the server.
@@ -194,6 +250,8 @@ pub struct HyperServerEndpoint<In: RpcMessage, Out: RpcMessage> {
     /// This is useful when the listen address uses a random port, `:0`, to find out which
     /// port was bound by the kernel.
     local_addr: [LocalAddr; 1],
+    /// Counters, shared by all clones
+    stats: Arc<StatsCounters>,
     /// Phantom data for in and out
     _p: PhantomData<(In, Out)>,
 }
@@ -219,7 +277,7 @@ impl<In: RpcMessage, Out: RpcMessage> HyperServerEndpoint<In, Out> {
             async move {
                 let one_req_service = service_fn(move |req: Request<Body>| {
                     // This closure is an FnMut as well, so clone accept_tx once more.
-                    Self::handle_one_http2_request(req, accept_tx.clone())
+                    Self::handle_one_http2_request(req, accept_tx.clone(), remote_addr)
                 });
                 Ok::<_, Infallible>(one_req_service)
             }
@@ -233,6 +291,8 @@ impl<In: RpcMessage, Out: RpcMessage> HyperServerEndpoint<In, Out> {
             .http2_initial_stream_window_size(Some(config.max_frame_size))
             .http2_max_frame_size(Some(config.max_frame_size))
             .http2_max_send_buf_size(config.max_frame_size.try_into().unwrap())
+            .http2_keep_alive_interval(config.keep_alive_interval)
+            .http2_keep_alive_timeout(config.keep_alive_timeout)
             .serve(service);
         let local_addr = server.local_addr();
 
@@ -248,6 +308,7 @@ impl<In: RpcMessage, Out: RpcMessage> HyperServerEndpoint<In, Out> {
             config: Arc::new(config),
             stop_tx,
             local_addr: [LocalAddr::Socket(local_addr)],
+            stats: Default::default(),
             _p: PhantomData,
         })
     }
@@ -258,12 +319,26 @@ impl<In: RpcMessage, Out: RpcMessage> HyperServerEndpoint<In, Out> {
     /// response and sends them to the [`ServerChannel`].
     async fn handle_one_http2_request(
         req: Request<Body>,
-        accept_tx: Sender<InternalChannel<In>>,
+        accept_tx: Sender<InternalChannel>,
+        remote_addr: SocketAddr,
     ) -> Result<Response<Body>, String> {
-        let (req_tx, req_rx) = flume::bounded::<result::Result<In, RecvError>>(32);
+        if req.method() == Method::OPTIONS {
+            // a health check, see the ConnectionHealth impl for HyperConnection
+            return Response::builder()
+                .status(StatusCode::OK)
+                .body(Body::empty())
+                .map_err(|_| "unable to set body".into());
+        }
+        // headers that are not valid utf8 can not have been sent as metadata
+        let metadata = req
+            .headers()
+            .iter()
+            .filter_map(|(name, value)| Some((name.as_str(), value.to_str().ok()?)))
+            .collect();
+        let (req_tx, req_rx) = flume::bounded::<result::Result<Bytes, hyper::Error>>(32);
         let (res_tx, res_rx) = flume::bounded::<io::Result<Bytes>>(32);
         accept_tx
-            .send_async((req_rx, res_tx))
+            .send_async((req_rx, res_tx, metadata, remote_addr))
             .await
             .map_err(|_e| "unable to send")?;
 
@@ -277,51 +352,7 @@ impl<In: RpcMessage, Out: RpcMessage> HyperServerEndpoint<In, Out> {
     }
 }
 
-fn try_get_length_prefixed(buf: &[u8]) -> Option<&[u8]> {
-    if buf.len() < 4 {
-        return None;
-    }
-    let len = u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]]) as usize;
-    if buf.len() < 4 + len {
-        return None;
-    }
-    Some(&buf[4..4 + len])
-}
-
-/// Try forward all frames as deserialized messages from the buffer to the sender.
-///
-/// On success, returns the number of forwarded bytes.
-/// On forward error, returns the unit error.
-///
-/// Deserialization errors don't cause an error, they will be sent.
-/// On error the number of consumed bytes is not returned. There is nothing to do but
-/// to stop the forwarder since there is nowhere to forward to anymore.
-async fn try_forward_all<In: RpcMessage>(
-    buffer: &[u8],
-    req_tx: &Sender<Result<In, RecvError>>,
-) -> result::Result<usize, ()> {
-    let mut sent = 0;
-    while let Some(msg) = try_get_length_prefixed(&buffer[sent..]) {
-        sent += msg.len() + 4;
-        let item = bincode::deserialize::<In>(msg).map_err(RecvError::DeserializeError);
-        if let Err(_cause) = req_tx.send_async(item).await {
-            // The receiver is gone, so we can't send any more data.
-            //
-            // This is a normal way for an interaction to end, when the server side is done processing
-            // the request and drops the receiver.
-            //
-            // don't log the cause. It does not contain any useful information.
-            trace!("Flume receiver dropped");
-            return Err(());
-        }
-    }
-    Ok(sent)
-}
-
-/// Spawns a task which forwards requests from the network to a flume channel.
-///
-/// This task will read chunks from the network, split them into length prefixed
-/// frames, deserialize those frames, and send the result to the flume channel.
+/// Spawns a task which forwards chunks from the network to a flume channel.
 ///
 /// If there is a network error or the flume channel closes or the request
 /// stream is simply ended this task will terminate.
@@ -329,42 +360,41 @@ async fn try_forward_all<In: RpcMessage>(
 /// So it is fine to ignore the returned [`JoinHandle`].
 ///
 /// The HTTP2 request comes from *req* and the data is sent to `req_tx`.
-fn spawn_recv_forwarder<In: RpcMessage>(
+fn spawn_recv_forwarder(
     req: Body,
-    req_tx: Sender<result::Result<In, RecvError>>,
-) -> JoinHandle<result::Result<(), ()>> {
+    req_tx: Sender<result::Result<Bytes, hyper::Error>>,
+) -> JoinHandle<()> {
     tokio::spawn(async move {
         let mut stream = req;
-        let mut buf = Vec::new();
-
         while let Some(chunk) = stream.next().await {
-            match chunk.as_ref() {
+            let is_err = match &chunk {
                 Ok(chunk) => {
                     event!(Level::TRACE, "Server got {} bytes", chunk.len());
-                    if buf.is_empty() {
-                        // try to forward directly from buffer
-                        let sent = try_forward_all(chunk, &req_tx).await?;
-                        // add just the rest, if any
-                        buf.extend_from_slice(&chunk[sent..]);
-                    } else {
-                        // no choice but to add it all
-                        buf.extend_from_slice(chunk);
-                    }
+                    false
                 }
                 Err(cause) => {
                     // Indicates that the connection has been closed on the client side.
                     // This is a normal occurrence, e.g. when the client has raced the RPC
                     // call with something else and has droppped the future.
+                    //
+                    // Forward the error, so the receiver can distinguish this abort from
+                    // a clean end of the stream.
                     debug!("Network error: {}", cause);
-                    break;
+                    true
                 }
             };
-            let sent = try_forward_all(&buf, &req_tx).await?;
-            // remove the forwarded bytes.
-            // Frequently this will be the entire buffer, so no memcpy but just set the size to 0
-            buf.drain(..sent);
+            if req_tx.send_async(chunk).await.is_err() {
+                // The receiver is gone, so we can't send any more data.
+                //
+                // This is a normal way for an interaction to end, when the server side is done processing
+                // the request and drops the receiver.
+                trace!("Flume receiver dropped");
+                break;
+            }
+            if is_err {
+                break;
+            }
         }
-        Ok(())
     })
 }
 
@@ -378,6 +408,7 @@ impl<In: RpcMessage, Out: RpcMessage> Clone for HyperServerEndpoint<In, Out> {
             stop_tx: self.stop_tx.clone(),
             local_addr: self.local_addr.clone(),
             config: self.config.clone(),
+            stats: self.stats.clone(),
             _p: PhantomData,
         }
     }
@@ -385,29 +416,172 @@ impl<In: RpcMessage, Out: RpcMessage> Clone for HyperServerEndpoint<In, Out> {
 
 /// Receive stream for hyper channels.
 ///
-/// This is a newtype wrapper around a [`flume::async::RecvStream`] of deserialized
-/// messages.
+/// The streams of channels split the chunks received from the network into length
+/// prefixed frames and deserialize them, so the bytes after a request can also be read
+/// as a raw stream, see [RawStreams].
+///
+/// Since the stream does the framing itself, it is no longer [Clone]: clones would each
+/// get some of the chunks and see broken frames.
 pub struct RecvStream<Res: RpcMessage> {
-    recv: flume::r#async::RecvStream<'static, result::Result<Res, RecvError>>,
+    source: Source<Res>,
+    /// Bytes that have been received but not yet decoded
+    buffer: BytesMut,
+    /// Metadata from the request headers, empty on the client side
+    metadata: Metadata,
+    /// Address of the client, None on the client side
+    remote_addr: Option<SocketAddr>,
+    stats: Arc<StatsCounters>,
+    /// Limit for the encoded size of messages, see [ConnectionCommon::limit_message_size]
+    max_size: Option<usize>,
+    /// Limit for the encoded size of all messages, see [ChannelConfig::max_payload_size]
+    max_payload_size: usize,
+    /// Bytes of a message that was too large, which are dropped as they arrive
+    discard: usize,
+    /// Encoded size of the last message
+    last_size: usize,
+    _open: Option<Arc<OpenChannel>>,
+    _p: PhantomData<Res>,
+}
+
+/// Where a [RecvStream] gets its messages from
+enum Source<Res: RpcMessage> {
+    /// Messages that have already been decoded, see [RecvStream::new]
+    Decoded(flume::r#async::RecvStream<'static, result::Result<Res, RecvError>>),
+    /// Chunks of the body, see [RecvStream::from_chunks]
+    Chunks(flume::r#async::RecvStream<'static, result::Result<Bytes, hyper::Error>>),
 }
 
 impl<Res: RpcMessage> RecvStream<Res> {
-    /// Creates a new [`RecvStream`] from a [`flume::Receiver`].
+    /// Creates a new [`RecvStream`] from a [`flume::Receiver`] of decoded messages.
+    ///
+    /// Such a stream can not be turned into a raw stream, and the size limits of
+    /// [ConnectionCommon::limit_message_size] do not apply to it.
     pub fn new(recv: flume::Receiver<result::Result<Res, RecvError>>) -> Self {
+        Self::with_source(Source::Decoded(recv.into_stream()))
+    }
+
+    /// Creates a new [`RecvStream`] from a [`flume::Receiver`] of the raw chunks of a body.
+    ///
+    /// Messages are limited to the default [ChannelConfig::max_payload_size].
+    pub fn from_chunks(recv: flume::Receiver<result::Result<Bytes, hyper::Error>>) -> Self {
+        Self::with_source(Source::Chunks(recv.into_stream()))
+    }
+
+    fn with_source(source: Source<Res>) -> Self {
         Self {
-            recv: recv.into_stream(),
+            source,
+            buffer: BytesMut::new(),
+            metadata: Metadata::default(),
+            remote_addr: None,
+            stats: Default::default(),
+            max_size: None,
+            max_payload_size: ChannelConfig::default().max_payload_size,
+            discard: 0,
+            last_size: 0,
+            _open: None,
+            _p: PhantomData,
         }
     }
 
-    // we can not write into_inner, since all we got is a stream of already
-    // framed and deserialize messages. Might want to change that...
-}
-
-impl<In: RpcMessage> Clone for RecvStream<In> {
-    fn clone(&self) -> Self {
+    /// A stream that counts into the stats of a connection or endpoint
+    fn with_stats(
+        recv: flume::Receiver<result::Result<Bytes, hyper::Error>>,
+        config: &ChannelConfig,
+        stats: Arc<StatsCounters>,
+        open: Arc<OpenChannel>,
+    ) -> Self {
         Self {
-            recv: self.recv.clone(),
+            max_payload_size: config.max_payload_size,
+            stats,
+            _open: Some(open),
+            ..Self::from_chunks(recv)
+        }
+    }
+
+    /// Get a [RawRecvStream] that starts with the bytes that have already been received,
+    /// but not yet decoded as messages.
+    ///
+    /// Reading from the raw stream fails if this stream was created from decoded
+    /// messages with [RecvStream::new].
+    pub fn into_raw(self) -> RawRecvStream {
+        let recv = match self.source {
+            Source::Chunks(recv) => Some(recv),
+            Source::Decoded(_) => None,
+        };
+        RawRecvStream {
+            recv,
+            buffer: self.buffer,
+        }
+    }
+
+    /// Poll the next chunk of the body. Streams of decoded messages have no chunks.
+    fn poll_chunk(
+        &mut self,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<Option<result::Result<Bytes, hyper::Error>>> {
+        match &mut self.source {
+            Source::Chunks(recv) => recv.poll_next_unpin(cx),
+            Source::Decoded(_) => Poll::Ready(None),
+        }
+    }
+
+    /// Take the next complete frame from the buffer, if any
+    ///
+    /// The length prefix is checked against the limits before the frame is buffered. A
+    /// frame that is too large fails, and its bytes are dropped as they arrive.
+    fn try_take_frame(&mut self) -> result::Result<Option<BytesMut>, RecvError> {
+        if self.discard > 0 {
+            let n = self.discard.min(self.buffer.len());
+            self.buffer.advance(n);
+            self.discard -= n;
+            if self.discard > 0 {
+                return Ok(None);
+            }
         }
+        if self.buffer.len() < 4 {
+            return Ok(None);
+        }
+        let len = u32::from_be_bytes([
+            self.buffer[0],
+            self.buffer[1],
+            self.buffer[2],
+            self.buffer[3],
+        ]);
+        if len == CANCEL_FRAME {
+            self.buffer.advance(4);
+            return Err(RecvError::Cancelled);
+        }
+        if len == REJECTION_FRAME {
+            if self.buffer.len() < 8 {
+                return Ok(None);
+            }
+            let code = u32::from_be_bytes([
+                self.buffer[4],
+                self.buffer[5],
+                self.buffer[6],
+                self.buffer[7],
+            ]);
+            self.buffer.advance(8);
+            return Err(match Status::from_code(code.into()) {
+                Some(status) => RecvError::Rejected(status),
+                // a status this version does not know, handled like any frame over the limit
+                None => RecvError::SizeError(len as usize),
+            });
+        }
+        let len = len as usize;
+        let max_size = self.max_size.map_or(self.max_payload_size, |max_size| {
+            max_size.min(self.max_payload_size)
+        });
+        if len > max_size {
+            self.last_size = len;
+            self.discard = 4 + len;
+            return Err(RecvError::SizeError(len));
+        }
+        if self.buffer.len() < 4 + len {
+            return Ok(None);
+        }
+        let mut frame = self.buffer.split_to(4 + len);
+        Ok(Some(frame.split_off(4)))
     }
 }
 
@@ -418,33 +592,123 @@ impl<Res: RpcMessage> futures::Stream for RecvStream<Res> {
         mut self: Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
     ) -> Poll<Option<Self::Item>> {
-        self.recv.poll_next_unpin(cx)
+        if let Source::Decoded(recv) = &mut self.source {
+            return recv.poll_next_unpin(cx);
+        }
+        loop {
+            if let Some(frame) = self.try_take_frame()? {
+                self.stats.frame_received(frame.len());
+                self.last_size = frame.len();
+                let item = bincode::deserialize::<Res>(&frame).map_err(|cause| {
+                    self.stats.serialization_error();
+                    RecvError::DeserializeError(cause)
+                });
+                return Poll::Ready(Some(item));
+            }
+            match ready!(self.poll_chunk(cx)) {
+                Some(Ok(chunk)) => self.buffer.extend_from_slice(&chunk),
+                Some(Err(cause)) => return Poll::Ready(Some(Err(RecvError::NetworkError(cause)))),
+                None => return Poll::Ready(None),
+            }
+        }
+    }
+}
+
+/// Raw receive stream for hyper channels, see [RawStreams]
+///
+/// Yields the bytes that were already buffered when the typed [RecvStream] was turned into
+/// a raw stream, followed by the bytes received from the network.
+pub struct RawRecvStream {
+    /// The chunks of the body, None if the typed stream had decoded messages
+    recv: Option<flume::r#async::RecvStream<'static, result::Result<Bytes, hyper::Error>>>,
+    buffer: BytesMut,
+}
+
+impl fmt::Debug for RawRecvStream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RawRecvStream")
+            .field("buffered", &self.buffer.len())
+            .finish()
+    }
+}
+
+impl AsyncRead for RawRecvStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        while self.buffer.is_empty() {
+            let recv = self.recv.as_mut().ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "stream of decoded messages has no raw bytes",
+                )
+            })?;
+            match ready!(recv.poll_next_unpin(cx)) {
+                Some(Ok(chunk)) => self.buffer.extend_from_slice(&chunk),
+                Some(Err(cause)) => {
+                    return Poll::Ready(Err(io::Error::new(io::ErrorKind::Other, cause)))
+                }
+                // end of stream
+                None => return Poll::Ready(Ok(())),
+            }
+        }
+        let n = self.buffer.len().min(buf.remaining());
+        buf.put_slice(&self.buffer.split_to(n));
+        Poll::Ready(Ok(()))
     }
 }
 
 /// Send sink for hyper channels
+///
+/// Closing the sink ends the body, so the receiver sees the end of the stream even
+/// if the sink is not dropped. Dropping the sink has the same effect.
 pub struct SendSink<Out: RpcMessage> {
-    sink: flume::r#async::SendSink<'static, io::Result<Bytes>>,
+    /// The sink for the body, None once the sink has been closed
+    sink: Option<flume::r#async::SendSink<'static, io::Result<Bytes>>>,
     config: Arc<ChannelConfig>,
+    stats: Arc<StatsCounters>,
+    /// Limit for the encoded size of messages, see [ConnectionCommon::limit_message_size]
+    max_size: Option<usize>,
+    /// Encoded size of the last message
+    last_size: usize,
+    _open: Arc<OpenChannel>,
     _p: PhantomData<Out>,
 }
 
 impl<Out: RpcMessage> SendSink<Out> {
-    fn new(sender: flume::Sender<io::Result<Bytes>>, config: Arc<ChannelConfig>) -> Self {
+    fn new(
+        sender: flume::Sender<io::Result<Bytes>>,
+        config: Arc<ChannelConfig>,
+        stats: Arc<StatsCounters>,
+        open: Arc<OpenChannel>,
+    ) -> Self {
         Self {
-            sink: sender.into_sink(),
+            sink: Some(sender.into_sink()),
             config,
+            stats,
+            max_size: None,
+            last_size: 0,
+            _open: open,
             _p: PhantomData,
         }
     }
-    fn serialize(&self, item: Out) -> Result<Bytes, SendError> {
+    fn serialize(&mut self, item: Out) -> Result<Bytes, SendError> {
         let mut data = Vec::with_capacity(1024);
         data.extend_from_slice(&[0u8; 4]);
-        bincode::serialize_into(&mut data, &item).map_err(SendError::SerializeError)?;
+        bincode::serialize_into(&mut data, &item).map_err(|cause| {
+            self.stats.serialization_error();
+            SendError::SerializeError(cause)
+        })?;
         let len = data.len() - 4;
-        if len > self.config.max_payload_size {
+        if len > self.config.max_payload_size
+            || self.max_size.map_or(false, |max_size| len > max_size)
+        {
             return Err(SendError::SizeError(len));
         }
+        self.stats.frame_sent(len);
+        self.last_size = len;
         let len: u32 = len.try_into().expect("max_payload_size fits into u32");
         data[0..4].copy_from_slice(&len.to_be_bytes());
         Ok(data.into())
@@ -453,9 +717,21 @@ impl<Out: RpcMessage> SendSink<Out> {
     /// Consumes the [`SendSink`] and returns the underlying [`flume::async::SendSink`].
     ///
     /// This is useful if you want to send raw [bytes::Bytes] without framing
-    /// directly to the channel.
+    /// directly to the channel. Closing the sink ends the body, so if the sink has
+    /// already been closed, the returned sink is disconnected.
     pub fn into_inner(self) -> flume::r#async::SendSink<'static, io::Result<Bytes>> {
-        self.sink
+        self.sink.unwrap_or_else(|| flume::bounded(0).0.into_sink())
+    }
+
+    /// Consumes the [`SendSink`] and returns a [RawSendSink] to write bytes without framing.
+    pub fn into_raw(self) -> RawSendSink {
+        RawSendSink { sink: self.sink }
+    }
+
+    fn sink(
+        &mut self,
+    ) -> Result<&mut flume::r#async::SendSink<'static, io::Result<Bytes>>, SendError> {
+        self.sink.as_mut().ok_or(SendError::ReceiverDropped)
     }
 }
 
@@ -466,7 +742,7 @@ impl<Out: RpcMessage> Sink<Out> for SendSink<Out> {
         mut self: Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
     ) -> Poll<Result<(), Self::Error>> {
-        self.sink
+        self.sink()?
             .poll_ready_unpin(cx)
             .map_err(|_| SendError::ReceiverDropped)
     }
@@ -481,7 +757,7 @@ impl<Out: RpcMessage> Sink<Out> for SendSink<Out> {
             ),
         };
         // attempt sending
-        self.sink
+        self.sink()?
             .start_send_unpin(send)
             .map_err(|_| SendError::ReceiverDropped)?;
         res
@@ -491,18 +767,84 @@ impl<Out: RpcMessage> Sink<Out> for SendSink<Out> {
         mut self: Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
     ) -> Poll<Result<(), Self::Error>> {
-        self.sink
-            .poll_flush_unpin(cx)
-            .map_err(|_| SendError::ReceiverDropped)
+        match &mut self.sink {
+            Some(sink) => sink
+                .poll_flush_unpin(cx)
+                .map_err(|_| SendError::ReceiverDropped),
+            None => Poll::Ready(Ok(())),
+        }
     }
 
     fn poll_close(
         mut self: Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
     ) -> Poll<Result<(), Self::Error>> {
+        if let Some(sink) = &mut self.sink {
+            ready!(sink.poll_close_unpin(cx)).map_err(|_| SendError::ReceiverDropped)?;
+            // drop the sender, so the body ends
+            self.sink = None;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Raw send sink for hyper channels, see [RawStreams]
+///
+/// Every write is sent as a chunk of the body. Shutting down the sink ends the body.
+pub struct RawSendSink {
+    /// The sink for the body, None once the sink has been shut down
+    sink: Option<flume::r#async::SendSink<'static, io::Result<Bytes>>>,
+}
+
+impl fmt::Debug for RawSendSink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RawSendSink").finish()
+    }
+}
+
+impl RawSendSink {
+    fn sink(&mut self) -> io::Result<&mut flume::r#async::SendSink<'static, io::Result<Bytes>>> {
         self.sink
-            .poll_close_unpin(cx)
-            .map_err(|_| SendError::ReceiverDropped)
+            .as_mut()
+            .ok_or_else(|| io::ErrorKind::BrokenPipe.into())
+    }
+}
+
+impl AsyncWrite for RawSendSink {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let sink = self.sink()?;
+        ready!(sink.poll_ready_unpin(cx)).map_err(|_| io::ErrorKind::BrokenPipe)?;
+        sink.start_send_unpin(Ok(Bytes::copy_from_slice(buf)))
+            .map_err(|_| io::ErrorKind::BrokenPipe)?;
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        match &mut self.sink {
+            Some(sink) => sink
+                .poll_flush_unpin(cx)
+                .map_err(|_| io::ErrorKind::BrokenPipe.into()),
+            None => Poll::Ready(Ok(())),
+        }
+    }
+
+    fn poll_shutdown(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        if let Some(sink) = &mut self.sink {
+            ready!(sink.poll_close_unpin(cx)).map_err(|_| io::ErrorKind::BrokenPipe)?;
+            // drop the sender, so the body ends
+            self.sink = None;
+        }
+        Poll::Ready(Ok(()))
     }
 }
 
@@ -530,6 +872,12 @@ impl error::Error for SendError {}
 pub enum RecvError {
     /// Error when bincode deserializing the message.
     DeserializeError(bincode::Error),
+    /// The message is larger than the limit of the channel.
+    SizeError(usize),
+    /// The server rejected the channel with this status.
+    Rejected(Status),
+    /// The other side cancelled the interaction.
+    Cancelled,
     /// Hyper network error.
     NetworkError(hyper::Error),
 }
@@ -575,6 +923,8 @@ pub struct OpenBiFuture<In, Out> {
             OpenBiError,
         >,
     >,
+    stats: Arc<StatsCounters>,
+    healthy: Arc<AtomicBool>,
     _p: PhantomData<(In, Out)>,
 }
 
@@ -589,9 +939,13 @@ impl<In: RpcMessage, Out: RpcMessage> OpenBiFuture<In, Out> {
             ),
             OpenBiError,
         >,
+        stats: Arc<StatsCounters>,
+        healthy: Arc<AtomicBool>,
     ) -> Self {
         Self {
             chan: Some(value),
+            stats,
+            healthy,
             _p: PhantomData,
         }
     }
@@ -609,16 +963,25 @@ impl<In: RpcMessage, Out: RpcMessage> Future for OpenBiFuture<In, Out> {
             Some(Ok((fut, _, _))) => match fut.poll_unpin(cx) {
                 Poll::Ready(Ok(res)) => {
                     event!(Level::TRACE, "OpenBiFuture got response");
+                    this.healthy.store(true, Ordering::Relaxed);
                     let (_, out_tx, config) = this.chan.take().unwrap().unwrap();
-                    let (in_tx, in_rx) = flume::bounded::<result::Result<In, RecvError>>(32);
+                    let (in_tx, in_rx) = flume::bounded::<result::Result<Bytes, hyper::Error>>(32);
                     spawn_recv_forwarder(res.into_body(), in_tx);
 
-                    let out_tx = self::SendSink::new(out_tx, config);
-                    let in_rx = self::RecvStream::new(in_rx);
+                    let open = this.stats.open_channel();
+                    let in_rx = self::RecvStream::with_stats(
+                        in_rx,
+                        &config,
+                        this.stats.clone(),
+                        open.clone(),
+                    );
+                    let out_tx = self::SendSink::new(out_tx, config, this.stats.clone(), open);
+                    this.stats.opened.fetch_add(1, Ordering::Relaxed);
                     Poll::Ready(Ok((out_tx, in_rx)))
                 }
                 Poll::Ready(Err(cause)) => {
                     event!(Level::TRACE, "OpenBiFuture got error {}", cause);
+                    this.healthy.store(false, Ordering::Relaxed);
                     this.chan.take();
                     Poll::Ready(Err(OpenBiError::Hyper(cause)))
                 }
@@ -661,33 +1024,21 @@ impl error::Error for AcceptBiError {}
 #[allow(clippy::type_complexity)]
 #[pin_project]
 pub struct AcceptBiFuture<In: RpcMessage, Out: RpcMessage> {
-    chan: Option<(
-        RecvFut<
-            'static,
-            (
-                Receiver<result::Result<In, RecvError>>,
-                Sender<io::Result<Bytes>>,
-            ),
-        >,
-        Arc<ChannelConfig>,
-    )>,
+    chan: Option<(RecvFut<'static, InternalChannel>, Arc<ChannelConfig>)>,
+    stats: Arc<StatsCounters>,
     _p: PhantomData<(In, Out)>,
 }
 
 impl<In: RpcMessage, Out: RpcMessage> AcceptBiFuture<In, Out> {
     #[allow(clippy::type_complexity)]
     fn new(
-        fut: RecvFut<
-            'static,
-            (
-                Receiver<result::Result<In, RecvError>>,
-                Sender<io::Result<Bytes>>,
-            ),
-        >,
+        fut: RecvFut<'static, InternalChannel>,
         config: Arc<ChannelConfig>,
+        stats: Arc<StatsCounters>,
     ) -> Self {
         Self {
             chan: Some((fut, config)),
+            stats,
             _p: PhantomData,
         }
     }
@@ -703,12 +1054,20 @@ impl<In: RpcMessage, Out: RpcMessage> Future for AcceptBiFuture<In, Out> {
         let this = self.project();
         match this.chan {
             Some((fut, _)) => match fut.poll_unpin(cx) {
-                Poll::Ready(Ok((recv, send))) => {
+                Poll::Ready(Ok((recv, send, metadata, remote_addr))) => {
                     let (_, config) = this.chan.take().unwrap();
-                    Poll::Ready(Ok((
-                        self::SendSink::new(send, config),
-                        self::RecvStream::new(recv),
-                    )))
+                    let open = this.stats.open_channel();
+                    let mut recv = self::RecvStream::with_stats(
+                        recv,
+                        &config,
+                        this.stats.clone(),
+                        open.clone(),
+                    );
+                    let send = self::SendSink::new(send, config, this.stats.clone(), open);
+                    recv.metadata = metadata;
+                    recv.remote_addr = Some(remote_addr);
+                    this.stats.accepted.fetch_add(1, Ordering::Relaxed);
+                    Poll::Ready(Ok((send, recv)))
                 }
                 Poll::Ready(Err(_cause)) => {
                     this.chan.take();
@@ -739,10 +1098,14 @@ where
 }
 
 impl<In: RpcMessage, Out: RpcMessage> HyperConnection<In, Out> {
-    fn open_bi(&self) -> OpenBiFuture<In, Out> {
+    fn open_bi_inner(&self, metadata: Metadata) -> OpenBiFuture<In, Out> {
         event!(Level::TRACE, "open_bi {}", self.inner.uri);
         let (out_tx, out_rx) = flume::bounded::<io::Result<Bytes>>(32);
-        let req: Result<Request<Body>, OpenBiError> = Request::post(&self.inner.uri)
+        let mut req = Request::post(&self.inner.uri);
+        for (name, value) in metadata.iter() {
+            req = req.header(name, value);
+        }
+        let req: Result<Request<Body>, OpenBiError> = req
             .body(Body::wrap_stream(out_rx.into_stream()))
             .map_err(OpenBiError::HyperHttp);
         let res = req.map(|req| {
@@ -752,7 +1115,7 @@ impl<In: RpcMessage, Out: RpcMessage> HyperConnection<In, Out> {
                 self.inner.config.clone(),
             )
         });
-        OpenBiFuture::new(res)
+        OpenBiFuture::new(res, self.inner.stats.clone(), self.inner.healthy.clone())
     }
 }
 
@@ -764,17 +1127,99 @@ impl<In: RpcMessage, Out: RpcMessage> ConnectionErrors for HyperConnection<In, O
     type OpenError = OpenBiError;
 }
 
+/// [ConnectionHealth::ping] is not an HTTP/2 `PING` frame, but a full `OPTIONS` request on a
+/// new HTTP/2 stream. The server answers it from its request handler without creating a
+/// channel, so the round trip includes the server's request handling, but not the service.
+///
+/// [ConnectionHealth::is_healthy] reflects the outcome of the last request, either a ping or
+/// opening a channel. It does not do any io, so a connection that is idle stays healthy until
+/// the next request fails. To have hyper notice a dead peer while idle, configure
+/// [ChannelConfig::keep_alive_interval]: once keep alive closes the connection, the next
+/// request fails.
+impl<In: RpcMessage, Out: RpcMessage> ConnectionHealth for HyperConnection<In, Out> {
+    type PingFut = BoxFuture<'static, result::Result<Duration, OpenBiError>>;
+
+    fn ping(&self) -> Self::PingFut {
+        let inner = self.inner.clone();
+        async move {
+            let start = Instant::now();
+            let req = Request::options(&inner.uri)
+                .body(Body::empty())
+                .map_err(OpenBiError::HyperHttp)?;
+            let res = inner.client.request(req).await;
+            inner.healthy.store(res.is_ok(), Ordering::Relaxed);
+            res.map_err(OpenBiError::Hyper)?;
+            Ok(start.elapsed())
+        }
+        .boxed()
+    }
+
+    fn is_healthy(&self) -> bool {
+        self.inner.healthy.load(Ordering::Relaxed)
+    }
+}
+
+/// Counts channels, messages and serialization errors. Every channel is an HTTP/2 stream,
+/// so the open channels are the open HTTP/2 streams.
+impl<In: RpcMessage, Out: RpcMessage> TransportStats for HyperConnection<In, Out> {
+    fn stats(&self) -> StatsSnapshot {
+        self.inner.stats.snapshot()
+    }
+}
+
 impl<In: RpcMessage, Out: RpcMessage> ConnectionCommon<In, Out> for HyperConnection<In, Out> {
     type RecvStream = self::RecvStream<In>;
 
     type SendSink = self::SendSink<Out>;
+
+    fn limit_message_size(
+        send: &mut Self::SendSink,
+        recv: &mut Self::RecvStream,
+        limits: SizeLimits,
+    ) -> bool {
+        limit_message_size(send, recv, limits)
+    }
+
+    fn received_size(recv: &Self::RecvStream) -> Option<usize> {
+        Some(recv.last_size)
+    }
+
+    fn sent_size(send: &Self::SendSink) -> Option<usize> {
+        Some(send.last_size)
+    }
+
+    /// Ends the body with a cancel frame, which the other side receives as
+    /// [RecvError::Cancelled].
+    fn cancel(send: &mut Self::SendSink) {
+        cancel(send)
+    }
+
+    fn is_cancelled(error: &Self::RecvError) -> bool {
+        matches!(error, RecvError::Cancelled)
+    }
+
+    fn rejection(error: &Self::RecvError) -> Option<Status> {
+        match error {
+            RecvError::Rejected(status) => Some(*status),
+            _ => None,
+        }
+    }
 }
 
 impl<In: RpcMessage, Out: RpcMessage> Connection<In, Out> for HyperConnection<In, Out> {
     type OpenBiFut = OpenBiFuture<In, Out>;
 
     fn open_bi(&self) -> Self::OpenBiFut {
-        self.open_bi()
+        self.open_bi_inner(Metadata::default())
+    }
+
+    /// The metadata is sent as http headers, so keys have to be valid header names.
+    fn open_bi_with_metadata(&self, metadata: Metadata) -> Self::OpenBiFut {
+        self.open_bi_inner(metadata)
+    }
+
+    fn carries_metadata(&self) -> bool {
+        true
     }
 }
 
@@ -789,6 +1234,83 @@ impl<In: RpcMessage, Out: RpcMessage> ConnectionErrors for HyperServerEndpoint<I
 impl<In: RpcMessage, Out: RpcMessage> ConnectionCommon<In, Out> for HyperServerEndpoint<In, Out> {
     type RecvStream = self::RecvStream<In>;
     type SendSink = self::SendSink<Out>;
+
+    fn limit_message_size(
+        send: &mut Self::SendSink,
+        recv: &mut Self::RecvStream,
+        limits: SizeLimits,
+    ) -> bool {
+        limit_message_size(send, recv, limits)
+    }
+
+    fn received_size(recv: &Self::RecvStream) -> Option<usize> {
+        Some(recv.last_size)
+    }
+
+    fn sent_size(send: &Self::SendSink) -> Option<usize> {
+        Some(send.last_size)
+    }
+
+    /// Ends the body with a cancel frame, which the other side receives as
+    /// [RecvError::Cancelled].
+    fn cancel(send: &mut Self::SendSink) {
+        cancel(send)
+    }
+
+    fn is_cancelled(error: &Self::RecvError) -> bool {
+        matches!(error, RecvError::Cancelled)
+    }
+
+    fn rejection(error: &Self::RecvError) -> Option<Status> {
+        match error {
+            RecvError::Rejected(status) => Some(*status),
+            _ => None,
+        }
+    }
+}
+
+/// End the body of a channel with a cancel frame
+fn cancel<Out: RpcMessage>(send: &mut SendSink<Out>) {
+    if let Some(sink) = send.sink.take() {
+        let frame = Bytes::copy_from_slice(&CANCEL_FRAME.to_be_bytes());
+        send_last_frame(sink.sender(), frame);
+    }
+}
+
+/// Send the last frame of a body without waiting
+///
+/// If the body channel is full, the frame is sent from a task, which keeps the body open
+/// until the frame is queued. So the frame is not lost, and the other side does not see
+/// a clean end of the body instead.
+fn send_last_frame(sender: &flume::Sender<io::Result<Bytes>>, frame: Bytes) {
+    // if the body is gone, there is nobody to tell anyway
+    if let Err(flume::TrySendError::Full(frame)) = sender.try_send(Ok(frame)) {
+        if let Ok(handle) = tokio::runtime::Handle::try_current() {
+            let sender = sender.clone();
+            handle.spawn(async move { sender.send_async(frame).await.ok() });
+        }
+    }
+}
+
+/// Apply the size limits to both sides of a channel
+fn limit_message_size<In: RpcMessage, Out: RpcMessage>(
+    send: &mut SendSink<Out>,
+    recv: &mut RecvStream<In>,
+    limits: SizeLimits,
+) -> bool {
+    send.max_size = limits.send;
+    recv.max_size = limits.recv;
+    limits
+        .recv
+        .map_or(true, |max_size| recv.last_size <= max_size)
+}
+
+/// Counts channels, messages and serialization errors. The accept queue holds the requests
+/// received on all connections, and is bounded.
+impl<In: RpcMessage, Out: RpcMessage> TransportStats for HyperServerEndpoint<In, Out> {
+    fn stats(&self) -> StatsSnapshot {
+        self.stats.snapshot().with_queue(&self.channel)
+    }
 }
 
 impl<In: RpcMessage, Out: RpcMessage> ServerEndpoint<In, Out> for HyperServerEndpoint<In, Out> {
@@ -799,6 +1321,54 @@ impl<In: RpcMessage, Out: RpcMessage> ServerEndpoint<In, Out> for HyperServerEnd
     }
 
     fn accept_bi(&self) -> Self::AcceptBiFut {
-        AcceptBiFuture::new(self.channel.clone().into_recv_async(), self.config.clone())
+        AcceptBiFuture::new(
+            self.channel.clone().into_recv_async(),
+            self.config.clone(),
+            self.stats.clone(),
+        )
+    }
+
+    /// All request headers with valid utf8 values, including any added by proxies.
+    fn metadata(recv: &Self::RecvStream) -> Metadata {
+        recv.metadata.clone()
+    }
+
+    /// The remote address of the http connection.
+    fn peer(recv: &Self::RecvStream) -> PeerInfo {
+        PeerInfo {
+            remote_addr: recv.remote_addr,
+            ..Default::default()
+        }
+    }
+
+    /// The response has already been started when a channel is accepted, so the status
+    /// can not be sent as the HTTP status. The response body ends with a rejection frame
+    /// instead, which the client receives as [RecvError::Rejected].
+    fn reject(send: Self::SendSink, recv: Self::RecvStream, status: Status) {
+        if let Some(sink) = &send.sink {
+            let mut frame = Vec::with_capacity(8);
+            frame.extend_from_slice(&REJECTION_FRAME.to_be_bytes());
+            frame.extend_from_slice(&status.code().to_be_bytes());
+            send_last_frame(sink.sender(), frame.into());
+        }
+        drop(recv);
+    }
+}
+
+impl<In: RpcMessage, Out: RpcMessage> RawStreams<In, Out> for HyperConnection<In, Out> {
+    type RawSend = RawSendSink;
+    type RawRecv = RawRecvStream;
+
+    fn into_raw(send: Self::SendSink, recv: Self::RecvStream) -> (Self::RawSend, Self::RawRecv) {
+        (send.into_raw(), recv.into_raw())
+    }
+}
+
+impl<In: RpcMessage, Out: RpcMessage> RawStreams<In, Out> for HyperServerEndpoint<In, Out> {
+    type RawSend = RawSendSink;
+    type RawRecv = RawRecvStream;
+
+    fn into_raw(send: Self::SendSink, recv: Self::RecvStream) -> (Self::RawSend, Self::RawRecv) {
+        (send.into_raw(), recv.into_raw())
     }
 }
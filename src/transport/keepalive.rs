@@ -0,0 +1,217 @@
+//! Connection wrapper that keeps track of the health of a connection
+//!
+//! [KeepAliveConnection] wraps any connection that implements [ConnectionHealth]. It
+//! pings the connection in the background according to a [KeepAliveConfig], and marks
+//! the connection as unhealthy once pings keep failing. This is useful e.g. for a load
+//! balancer that wants to evict dead connections before using them.
+use super::{
+    Connection, ConnectionCommon, ConnectionErrors, ConnectionHealth, Metadata, SizeLimits,
+    StatsSnapshot, Status, TransportStats,
+};
+use crate::RpcMessage;
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::task::JoinHandle;
+
+/// Configuration for the background pings
+#[derive(Debug, Clone)]
+pub struct KeepAliveConfig {
+    interval: Duration,
+    timeout: Duration,
+    max_failures: u32,
+}
+
+impl Default for KeepAliveConfig {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(15),
+            timeout: Duration::from_secs(10),
+            max_failures: 2,
+        }
+    }
+}
+
+impl KeepAliveConfig {
+    /// Time between two pings.
+    ///
+    /// Default is 15 seconds
+    pub fn interval(mut self, value: Duration) -> Self {
+        self.interval = value;
+        self
+    }
+
+    /// Time after which a ping that did not complete counts as failed.
+    ///
+    /// Default is 10 seconds
+    pub fn timeout(mut self, value: Duration) -> Self {
+        self.timeout = value;
+        self
+    }
+
+    /// Number of consecutive failed pings after which the connection is unhealthy.
+    ///
+    /// A successful ping makes the connection healthy again. Default is 2
+    pub fn max_failures(mut self, value: u32) -> Self {
+        self.max_failures = value.max(1);
+        self
+    }
+}
+
+/// Aborts the ping task once the last clone of the connection is dropped
+#[derive(Debug)]
+struct PingTask(JoinHandle<()>);
+
+impl Drop for PingTask {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+/// A connection that is pinged in the background
+///
+/// Creating this spawns a tokio task that pings a clone of the inner connection. The
+/// task is stopped once the connection and all its clones are dropped.
+#[derive(Clone)]
+pub struct KeepAliveConnection<C> {
+    inner: C,
+    healthy: Arc<AtomicBool>,
+    _task: Arc<PingTask>,
+}
+
+impl<C: fmt::Debug> fmt::Debug for KeepAliveConnection<C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("KeepAliveConnection")
+            .field("inner", &self.inner)
+            .field("healthy", &self.healthy.load(Ordering::Relaxed))
+            .finish()
+    }
+}
+
+impl<C: ConnectionHealth> KeepAliveConnection<C> {
+    /// Wrap a connection
+    pub fn new(inner: C, config: KeepAliveConfig) -> Self {
+        let healthy = Arc::new(AtomicBool::new(true));
+        let task = tokio::spawn(Self::ping_loop(inner.clone(), config, healthy.clone()));
+        Self {
+            inner,
+            healthy,
+            _task: Arc::new(PingTask(task)),
+        }
+    }
+
+    async fn ping_loop(inner: C, config: KeepAliveConfig, healthy: Arc<AtomicBool>) {
+        let mut failures = 0;
+        loop {
+            tokio::time::sleep(config.interval).await;
+            match tokio::time::timeout(config.timeout, inner.ping()).await {
+                Ok(Ok(rtt)) => {
+                    tracing::trace!("ping succeeded, rtt {:?}", rtt);
+                    failures = 0;
+                    healthy.store(true, Ordering::Relaxed);
+                }
+                Ok(Err(cause)) => {
+                    tracing::debug!("ping failed: {}", cause);
+                    failures += 1;
+                }
+                Err(_) => {
+                    tracing::debug!("ping timed out");
+                    failures += 1;
+                }
+            }
+            if failures >= config.max_failures {
+                healthy.store(false, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+impl<C> KeepAliveConnection<C> {
+    /// Get a reference to the inner connection
+    pub fn inner(&self) -> &C {
+        &self.inner
+    }
+}
+
+impl<C: ConnectionErrors> ConnectionErrors for KeepAliveConnection<C> {
+    type SendError = C::SendError;
+    type RecvError = C::RecvError;
+    type OpenError = C::OpenError;
+}
+
+impl<C: ConnectionCommon<In, Out>, In: RpcMessage, Out: RpcMessage> ConnectionCommon<In, Out>
+    for KeepAliveConnection<C>
+{
+    type SendSink = C::SendSink;
+    type RecvStream = C::RecvStream;
+
+    fn limit_message_size(
+        send: &mut Self::SendSink,
+        recv: &mut Self::RecvStream,
+        limits: SizeLimits,
+    ) -> bool {
+        C::limit_message_size(send, recv, limits)
+    }
+
+    fn received_size(recv: &Self::RecvStream) -> Option<usize> {
+        C::received_size(recv)
+    }
+
+    fn sent_size(send: &Self::SendSink) -> Option<usize> {
+        C::sent_size(send)
+    }
+
+    fn cancel(send: &mut Self::SendSink) {
+        C::cancel(send)
+    }
+
+    fn is_cancelled(error: &Self::RecvError) -> bool {
+        C::is_cancelled(error)
+    }
+
+    fn rejection(error: &Self::RecvError) -> Option<Status> {
+        C::rejection(error)
+    }
+}
+
+impl<C: Connection<In, Out>, In: RpcMessage, Out: RpcMessage> Connection<In, Out>
+    for KeepAliveConnection<C>
+{
+    type OpenBiFut = C::OpenBiFut;
+
+    fn open_bi(&self) -> Self::OpenBiFut {
+        self.inner.open_bi()
+    }
+
+    fn open_bi_with_metadata(&self, metadata: Metadata) -> Self::OpenBiFut {
+        self.inner.open_bi_with_metadata(metadata)
+    }
+
+    fn carries_metadata(&self) -> bool {
+        self.inner.carries_metadata()
+    }
+}
+
+/// Healthy if the background pings succeed and the inner connection is healthy.
+impl<C: ConnectionHealth> ConnectionHealth for KeepAliveConnection<C> {
+    type PingFut = C::PingFut;
+
+    fn ping(&self) -> Self::PingFut {
+        self.inner.ping()
+    }
+
+    fn is_healthy(&self) -> bool {
+        self.healthy.load(Ordering::Relaxed) && self.inner.is_healthy()
+    }
+}
+
+impl<C: TransportStats> TransportStats for KeepAliveConnection<C> {
+    fn stats(&self) -> StatsSnapshot {
+        self.inner.stats()
+    }
+}
@@ -1,18 +1,36 @@
 //! Transports for quic-rpc
 use crate::RpcError;
 use futures::{Future, Sink, Stream};
+use serde::{Deserialize, Serialize};
+#[cfg(any(
+    feature = "flume-transport",
+    feature = "quinn-transport",
+    feature = "hyper-transport"
+))]
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::{
+    collections::BTreeMap,
     fmt::{self, Debug, Display},
     net::SocketAddr,
+    time::Duration,
 };
+use tokio::io::{AsyncRead, AsyncWrite};
+#[cfg(feature = "checked-transport")]
+pub mod checked;
 #[cfg(feature = "combined-transport")]
 pub mod combined;
+#[cfg(feature = "faulty-transport")]
+pub mod faulty;
 #[cfg(feature = "flume-transport")]
 pub mod flume;
 #[cfg(feature = "hyper-transport")]
 pub mod hyper;
+#[cfg(feature = "keepalive")]
+pub mod keepalive;
 #[cfg(feature = "quinn-transport")]
 pub mod quinn;
+#[cfg(feature = "record-transport")]
+pub mod record;
 
 pub mod misc;
 
@@ -37,6 +55,88 @@ pub trait ConnectionCommon<In, Out>: ConnectionErrors {
     type RecvStream: Stream<Item = Result<In, Self::RecvError>> + Send + Unpin + 'static;
     /// Send side of a bidirectional typed channel
     type SendSink: Sink<Out, Error = Self::SendError> + Send + Unpin + 'static;
+
+    /// Limit the encoded size of the messages of a channel.
+    ///
+    /// This is called once it is known which message a channel is for, with the limits the
+    /// message declares, see [Msg::MAX_REQUEST_SIZE](crate::message::Msg::MAX_REQUEST_SIZE).
+    /// Sending a larger message then fails with a send error, and receiving one fails with
+    /// a receive error. Returns false if the last message received before the call, such
+    /// as the request on the server side, is larger than the receive limit.
+    ///
+    /// Transports should check the size of a message before buffering it, so a peer can
+    /// not make the receiver buffer more than the limit.
+    ///
+    /// Transports that do not encode messages ignore the limits. The default
+    /// implementation does nothing.
+    fn limit_message_size(
+        send: &mut Self::SendSink,
+        recv: &mut Self::RecvStream,
+        limits: SizeLimits,
+    ) -> bool {
+        let _ = (send, recv, limits);
+        true
+    }
+
+    /// Encoded size of the last message received on `recv`, in bytes.
+    ///
+    /// This is used for the [metrics](crate::metrics) of response items. Transports that
+    /// do not encode messages return `None`, which is also the default implementation.
+    fn received_size(recv: &Self::RecvStream) -> Option<usize> {
+        let _ = recv;
+        None
+    }
+
+    /// Encoded size of the last message sent on `send`, in bytes.
+    ///
+    /// See [Self::received_size].
+    fn sent_size(send: &Self::SendSink) -> Option<usize> {
+        let _ = send;
+        None
+    }
+
+    /// Tell the other side of a channel that the interaction has been cancelled.
+    ///
+    /// The client calls this when a call is dropped before it has ended, and the server
+    /// sees the signal as a receive error for which [Self::is_cancelled] is true. Sending
+    /// on `send` fails afterwards. The default implementation does nothing. The server
+    /// then notices that the client went away once the channel is dropped, as the end of
+    /// the stream. For rpc and server streaming calls this also ends the interaction with
+    /// [RpcServerError::Cancelled](crate::server::RpcServerError::Cancelled). For calls
+    /// with updates the end of the stream is the end of the updates, so the server only
+    /// notices when sending the response fails.
+    fn cancel(send: &mut Self::SendSink) {
+        let _ = send;
+    }
+
+    /// Whether a receive error is the signal of [Self::cancel], as opposed to a failure
+    /// of the channel. The default implementation returns false.
+    fn is_cancelled(error: &Self::RecvError) -> bool {
+        let _ = error;
+        false
+    }
+
+    /// The status the server rejected the channel with, if `error` is caused by
+    /// [ServerEndpoint::reject].
+    ///
+    /// The client uses this to report rejections as typed errors, e.g.
+    /// [RpcClientError::IncompatibleVersion](crate::client::RpcClientError::IncompatibleVersion).
+    /// The default implementation returns None.
+    fn rejection(error: &Self::RecvError) -> Option<Status> {
+        let _ = error;
+        None
+    }
+}
+
+/// Limits on the encoded size of the messages of a channel, in bytes
+///
+/// See [ConnectionCommon::limit_message_size].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SizeLimits {
+    /// Limit for the messages sent through the channel, None for no limit
+    pub send: Option<usize>,
+    /// Limit for the messages received from the channel, None for no limit
+    pub recv: Option<usize>,
 }
 
 /// A connection to a specific remote machine
@@ -48,6 +148,23 @@ pub trait Connection<In, Out>: ConnectionCommon<In, Out> {
         + Send;
     /// Open a channel to the remote
     fn open_bi(&self) -> Self::OpenBiFut;
+
+    /// Open a channel to the remote, and send metadata along with it
+    ///
+    /// The server gets the metadata using [`ServerEndpoint::metadata`]. The default
+    /// implementation drops the metadata.
+    fn open_bi_with_metadata(&self, metadata: Metadata) -> Self::OpenBiFut {
+        drop(metadata);
+        self.open_bi()
+    }
+
+    /// Whether [Self::open_bi_with_metadata] sends the metadata to the server
+    ///
+    /// Implementations that override [Self::open_bi_with_metadata] should return true.
+    /// The default implementation returns false.
+    fn carries_metadata(&self) -> bool {
+        false
+    }
 }
 
 /// A server endpoint that listens for connections
@@ -65,6 +182,277 @@ pub trait ServerEndpoint<In, Out>: ConnectionCommon<In, Out> {
 
     /// The local addresses this endpoint is bound to.
     fn local_addr(&self) -> &[LocalAddr];
+
+    /// The metadata the client sent when opening the channel of `recv`
+    ///
+    /// Depending on the transport, this is only available once the first message has
+    /// been received. The default implementation returns empty metadata.
+    fn metadata(recv: &Self::RecvStream) -> Metadata {
+        let _ = recv;
+        Metadata::default()
+    }
+
+    /// What the transport knows about the client of the channel of `recv`
+    ///
+    /// The default implementation returns no information.
+    fn peer(recv: &Self::RecvStream) -> PeerInfo {
+        let _ = recv;
+        PeerInfo::default()
+    }
+
+    /// Close a channel that is not handed to a handler, telling the client why
+    ///
+    /// How much of the status reaches the client depends on the transport, see the
+    /// implementations. The default implementation just drops the channel.
+    fn reject(send: Self::SendSink, recv: Self::RecvStream, status: Status) {
+        let _ = (send, recv, status);
+    }
+}
+
+/// What a transport knows about the client of a channel
+///
+/// Fields the transport does not know are empty.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct PeerInfo {
+    /// The address of the client
+    pub remote_addr: Option<SocketAddr>,
+    /// The DER encoded certificate chain the client presented, leaf first
+    pub certificates: Vec<Vec<u8>>,
+}
+
+/// Why a server refused to handle a channel, see [ServerEndpoint::reject]
+///
+/// The codes are the same as the corresponding grpc status codes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum Status {
+    /// The client did not present valid credentials
+    Unauthenticated,
+    /// The client is not allowed to do this
+    PermissionDenied,
+    /// The client exceeded a limit of the server, e.g. on the number of connections
+    ResourceExhausted,
+    /// The client is not compatible with the server, e.g. it was built for a different
+    /// version of the service
+    FailedPrecondition,
+}
+
+impl Status {
+    /// The numeric code sent on the wire
+    pub fn code(self) -> u32 {
+        match self {
+            Status::PermissionDenied => 7,
+            Status::ResourceExhausted => 8,
+            Status::FailedPrecondition => 9,
+            Status::Unauthenticated => 16,
+        }
+    }
+
+    /// The status for a numeric code, if it is known
+    pub fn from_code(code: u64) -> Option<Self> {
+        match code {
+            7 => Some(Status::PermissionDenied),
+            8 => Some(Status::ResourceExhausted),
+            9 => Some(Status::FailedPrecondition),
+            16 => Some(Status::Unauthenticated),
+            _ => None,
+        }
+    }
+}
+
+impl Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self, f)
+    }
+}
+
+/// Key value pairs that are sent once per channel, before the first message
+///
+/// This is used for data about a request that is not part of the request itself,
+/// like a trace context. How it is carried depends on the transport: the flume
+/// transport passes it in memory, the quinn transport sends it in front of the first
+/// message if both sides enable it with `metadata_frame`, and the hyper transport sends
+/// it as http headers. Transports that can not carry metadata drop it.
+///
+/// Keys should be lowercase ascii, and values printable ascii, so they are valid
+/// http header names and values.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Metadata(BTreeMap<String, String>);
+
+impl Metadata {
+    /// Empty metadata
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the value for a key, returning the previous value
+    pub fn insert(&mut self, key: impl Into<String>, value: impl Into<String>) -> Option<String> {
+        self.0.insert(key.into(), value.into())
+    }
+
+    /// The value for a key
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).map(String::as_str)
+    }
+
+    /// Remove the value for a key
+    pub fn remove(&mut self, key: &str) -> Option<String> {
+        self.0.remove(key)
+    }
+
+    /// Whether there are no entries
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Iterate over the entries, ordered by key
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.0.iter().map(|(k, v)| (k.as_str(), v.as_str()))
+    }
+}
+
+impl<K: Into<String>, V: Into<String>> FromIterator<(K, V)> for Metadata {
+    fn from_iter<I: IntoIterator<Item = (K, V)>>(iter: I) -> Self {
+        Self(
+            iter.into_iter()
+                .map(|(k, v)| (k.into(), v.into()))
+                .collect(),
+        )
+    }
+}
+
+/// Channels that can be turned into raw byte streams.
+///
+/// This allows exchanging typed messages first, and then sending and receiving bytes
+/// directly, without framing and serialization. Bytes that have already been received
+/// but not yet decoded as messages are not lost.
+///
+/// Implemented for the quinn and hyper transports. The in-memory flume transport does not
+/// carry bytes, so it does not implement this.
+pub trait RawStreams<In, Out>: ConnectionCommon<In, Out> {
+    /// Raw send side of a channel
+    type RawSend: AsyncWrite + Send + Unpin + 'static;
+    /// Raw receive side of a channel
+    type RawRecv: AsyncRead + Send + Unpin + 'static;
+
+    /// Turn the typed sides of a channel into raw byte streams.
+    ///
+    /// Messages that have been sent should be flushed before calling this.
+    fn into_raw(send: Self::SendSink, recv: Self::RecvStream) -> (Self::RawSend, Self::RawRecv);
+}
+
+/// A connection that can check the liveness of the underlying transport.
+///
+/// This works without a user defined ping message. What a ping does depends on the
+/// transport, see the implementations.
+pub trait ConnectionHealth: ConnectionErrors {
+    /// The future returned by [ConnectionHealth::ping]
+    type PingFut: Future<Output = Result<Duration, Self::OpenError>> + Send;
+
+    /// Check that the connection is alive, returning the round trip time.
+    fn ping(&self) -> Self::PingFut;
+
+    /// Whether the connection is believed to be alive, without doing any io.
+    fn is_healthy(&self) -> bool;
+}
+
+/// A connection or server endpoint that counts what it is doing.
+///
+/// This is meant for diagnosing stalls and backpressure without attaching a debugger.
+/// The counters are shared by all clones of a connection or endpoint, and are never reset.
+pub trait TransportStats {
+    /// The current values of the counters
+    fn stats(&self) -> StatsSnapshot;
+}
+
+/// The counters of a connection or server endpoint, see [TransportStats]
+///
+/// Counters that a transport does not track are zero. Bytes and frames are those of
+/// typed messages, so traffic after a channel is turned into raw streams is not counted.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct StatsSnapshot {
+    /// Number of channels opened
+    pub opened: u64,
+    /// Number of channels accepted
+    pub accepted: u64,
+    /// Number of typed channels that are currently open
+    pub open_channels: u64,
+    /// Number of bytes sent, including framing
+    pub bytes_sent: u64,
+    /// Number of bytes received, including framing
+    pub bytes_received: u64,
+    /// Number of messages sent
+    pub frames_sent: u64,
+    /// Number of messages received
+    pub frames_received: u64,
+    /// Number of messages that could not be serialized or deserialized
+    pub serialization_errors: u64,
+    /// Number of times the underlying connection was re-established
+    pub reconnects: u64,
+    /// Number of incoming channels waiting to be accepted
+    pub accept_queue_len: usize,
+    /// Capacity of the queue of incoming channels, None if unbounded
+    pub accept_queue_capacity: Option<usize>,
+}
+
+/// The counters behind a [StatsSnapshot], shared by a connection or endpoint and its channels
+#[cfg(any(
+    feature = "flume-transport",
+    feature = "quinn-transport",
+    feature = "hyper-transport"
+))]
+#[derive(Debug, Default)]
+pub(crate) struct StatsCounters {
+    pub(crate) opened: AtomicU64,
+    pub(crate) accepted: AtomicU64,
+    pub(crate) open_channels: AtomicU64,
+    pub(crate) bytes_sent: AtomicU64,
+    pub(crate) bytes_received: AtomicU64,
+    pub(crate) frames_sent: AtomicU64,
+    pub(crate) frames_received: AtomicU64,
+    pub(crate) serialization_errors: AtomicU64,
+    pub(crate) reconnects: AtomicU64,
+}
+
+#[cfg(any(
+    feature = "flume-transport",
+    feature = "quinn-transport",
+    feature = "hyper-transport"
+))]
+impl StatsCounters {
+    /// Snapshot of the counters, without an accept queue
+    pub(crate) fn snapshot(&self) -> StatsSnapshot {
+        let get = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        StatsSnapshot {
+            opened: get(&self.opened),
+            accepted: get(&self.accepted),
+            open_channels: get(&self.open_channels),
+            bytes_sent: get(&self.bytes_sent),
+            bytes_received: get(&self.bytes_received),
+            frames_sent: get(&self.frames_sent),
+            frames_received: get(&self.frames_received),
+            serialization_errors: get(&self.serialization_errors),
+            reconnects: get(&self.reconnects),
+            accept_queue_len: 0,
+            accept_queue_capacity: None,
+        }
+    }
+}
+
+#[cfg(any(
+    feature = "flume-transport",
+    feature = "quinn-transport",
+    feature = "hyper-transport"
+))]
+impl StatsSnapshot {
+    /// Add the depth of the accept queue
+    pub(crate) fn with_queue<T>(mut self, queue: &::flume::Receiver<T>) -> Self {
+        self.accept_queue_len = queue.len();
+        self.accept_queue_capacity = queue.capacity();
+        self
+    }
 }
 
 /// The kinds of local addresses a [ServerEndpoint] can be bound to.
@@ -77,15 +465,16 @@ pub trait ServerEndpoint<In, Out>: ConnectionCommon<In, Out> {
 pub enum LocalAddr {
     /// A local socket.
     Socket(SocketAddr),
-    /// An in-memory address.
-    Mem,
+    /// An in-memory address, optionally bound under a name.
+    Mem(Option<String>),
 }
 
 impl Display for LocalAddr {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             LocalAddr::Socket(sockaddr) => write!(f, "{sockaddr}"),
-            LocalAddr::Mem => write!(f, "mem"),
+            LocalAddr::Mem(None) => write!(f, "mem"),
+            LocalAddr::Mem(Some(name)) => write!(f, "mem://{name}"),
         }
     }
 }
@@ -1,34 +1,64 @@
 //! QUIC transport implementation based on [quinn](https://crates.io/crates/quinn)
 use crate::{
-    transport::{Connection, ConnectionErrors, LocalAddr, ServerEndpoint},
+    transport::{
+        Connection, ConnectionErrors, ConnectionHealth, LocalAddr, Metadata, PeerInfo, RawStreams,
+        ServerEndpoint, SizeLimits, StatsCounters, StatsSnapshot, Status, TransportStats,
+    },
     RpcMessage,
 };
+use bincode::Options;
+use bytes::BytesMut;
 use futures::channel::oneshot;
-use futures::{Future, FutureExt, Sink, SinkExt, Stream, StreamExt};
+use futures::{future, ready, Future, FutureExt, Sink, SinkExt, Stream, StreamExt};
 use pin_project::pin_project;
 use serde::de::DeserializeOwned;
 use serde::Serialize;
-use std::net::SocketAddr;
-use std::sync::Arc;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc, Mutex,
+};
 use std::task::{Context, Poll};
-use std::{fmt, io, marker::PhantomData, pin::Pin, result};
+use std::time::Instant;
+use std::{fmt, io, marker::PhantomData, pin::Pin, result, time::Duration};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
 use tracing::{debug_span, Instrument};
 
 use super::{
-    util::{FramedBincodeRead, FramedBincodeWrite},
+    util::{FramedBincodeRead, FramedBincodeWrite, OpenChannel},
     ConnectionCommon,
 };
 
 type Socket<In, Out> = (SendSink<Out>, RecvStream<In>);
 
 const MAX_FRAME_LENGTH: usize = 1024 * 1024 * 16;
+/// Code to reset a stream with when the interaction is cancelled, see
+/// [ConnectionCommon::cancel]. This is the grpc code for a cancelled call, which is not
+/// used by any [Status]. Peers that do not use this crate can reset with it to cancel.
+pub const CANCEL_CODE: u32 = 1;
+
+/// Bincode options for messages and the metadata frame
+fn encoding() -> impl Options {
+    bincode::DefaultOptions::new().with_fixint_encoding()
+}
 
 #[derive(Debug)]
 struct ServerEndpointInner {
     endpoint: Option<quinn::Endpoint>,
     task: Option<tokio::task::JoinHandle<()>>,
     local_addr: [LocalAddr; 1],
-    receiver: flume::Receiver<SocketInner>,
+    receiver: AcceptReceiver,
+    stats: Arc<StatsCounters>,
+}
+
+/// The queue the endpoint accepts substreams from
+#[derive(Debug)]
+enum AcceptReceiver {
+    /// Substreams that were checked against the [ServerLimits] of the endpoint
+    Admitted(flume::Receiver<Incoming>),
+    /// Substreams passed in as they are, see [QuinnServerEndpoint::handle_substreams]
+    Substreams(flume::Receiver<SocketInner>),
 }
 
 impl Drop for ServerEndpointInner {
@@ -52,9 +82,15 @@ impl Drop for ServerEndpointInner {
 }
 
 /// A server endpoint using a quinn connection
+///
+/// By default, every substream carries just the length prefixed messages. To receive
+/// [Metadata] from clients, enable [QuinnServerEndpoint::metadata_frame] here and
+/// [QuinnConnection::metadata_frame] on the clients.
 #[derive(Debug)]
 pub struct QuinnServerEndpoint<In: RpcMessage, Out: RpcMessage> {
     inner: Arc<ServerEndpointInner>,
+    /// Whether substreams start with a metadata frame
+    metadata_frame: bool,
     _phantom: PhantomData<(In, Out)>,
 }
 
@@ -62,7 +98,14 @@ impl<In: RpcMessage, Out: RpcMessage> QuinnServerEndpoint<In, Out> {
     /// handles RPC requests from a connection
     ///
     /// to cleanly shutdown the handler, drop the receiver side of the sender.
-    async fn connection_handler(connection: quinn::Connection, sender: flume::Sender<SocketInner>) {
+    async fn connection_handler(
+        connection: quinn::Connection,
+        sender: flume::Sender<Incoming>,
+        limits: ServerLimits,
+        _permit: Option<ConnectionPermit>,
+    ) {
+        let peer = Arc::new(peer_info(&connection));
+        let mut substreams = SubstreamLimits::new(&limits);
         loop {
             tracing::debug!("Awaiting incoming bidi substream on existing connection...");
             let bidi_stream = match connection.accept_bi().await {
@@ -76,38 +119,71 @@ impl<In: RpcMessage, Out: RpcMessage> QuinnServerEndpoint<In, Out> {
                     break;
                 }
             };
-            tracing::debug!("Sending substream to be handled... {}", bidi_stream.0.id());
-            if sender.send_async(bidi_stream).await.is_err() {
+            let incoming = match substreams.admit(bidi_stream, &peer) {
+                Some(incoming) => incoming,
+                None => continue,
+            };
+            if sender.send_async(incoming).await.is_err() {
                 tracing::debug!("Receiver dropped");
                 break;
             }
         }
     }
 
-    async fn endpoint_handler(endpoint: quinn::Endpoint, sender: flume::Sender<SocketInner>) {
+    async fn endpoint_handler(
+        endpoint: quinn::Endpoint,
+        sender: flume::Sender<Incoming>,
+        limits: ServerLimits,
+    ) {
+        let acceptor = Arc::new(Acceptor::new(sender, limits));
         loop {
             tracing::debug!("Waiting for incoming connection...");
             let connecting = match endpoint.accept().await {
                 Some(connecting) => connecting,
                 None => break,
             };
-            tracing::debug!("Awaiting connection from connect...");
-            let conection = match connecting.await {
-                Ok(conection) => conection,
-                Err(e) => {
-                    tracing::warn!("Error accepting connection: {}", e);
-                    continue;
-                }
-            };
-            tracing::debug!(
-                "Connection established from {:?}",
-                conection.remote_address()
-            );
-            tracing::debug!("Spawning connection handler...");
-            tokio::spawn(Self::connection_handler(conection, sender.clone()));
+            // complete the handshake in the background, so a slow client does not hold up
+            // the connections of others
+            let acceptor = acceptor.clone();
+            tokio::spawn(async move {
+                tracing::debug!("Awaiting connection from connect...");
+                let connection = match connecting.await {
+                    Ok(connection) => connection,
+                    Err(e) => {
+                        tracing::warn!("Error accepting connection: {}", e);
+                        return;
+                    }
+                };
+                Self::handle_connection(connection, acceptor).await
+            });
         }
     }
 
+    /// Checks the connection limit for a new connection, and handles its substreams
+    async fn handle_connection(connection: quinn::Connection, acceptor: Arc<Acceptor>) {
+        tracing::debug!(
+            "Connection established from {:?}",
+            connection.remote_address()
+        );
+        let permit = match acceptor.limits.max_connections_per_ip {
+            Some(max) => {
+                let ip = connection.remote_address().ip();
+                match ConnectionPermit::acquire(&acceptor.connections, ip, max) {
+                    Some(permit) => Some(permit),
+                    None => {
+                        tracing::debug!("Too many connections from {}, closing", ip);
+                        let code = quinn::VarInt::from_u32(Status::ResourceExhausted.code());
+                        connection.close(code, b"too many connections");
+                        return;
+                    }
+                }
+            }
+            None => None,
+        };
+        let sender = acceptor.queue.sender();
+        Self::connection_handler(connection, sender, acceptor.limits.clone(), permit).await
+    }
+
     /// Create a new server channel, given a quinn endpoint.
     ///
     /// The endpoint must be a server endpoint.
@@ -115,20 +191,42 @@ impl<In: RpcMessage, Out: RpcMessage> QuinnServerEndpoint<In, Out> {
     /// The server channel will take care of listening on the endpoint and spawning
     /// handlers for new connections.
     pub fn new(endpoint: quinn::Endpoint) -> io::Result<Self> {
+        Self::with_limits(endpoint, ServerLimits::default())
+    }
+
+    /// Create a new server channel, given a quinn endpoint and limits for its clients.
+    ///
+    /// Connections and substreams that exceed the limits are closed with
+    /// [Status::ResourceExhausted], see [rejection_status].
+    pub fn with_limits(endpoint: quinn::Endpoint, limits: ServerLimits) -> io::Result<Self> {
         let local_addr = endpoint.local_addr()?;
-        let (sender, receiver) = flume::bounded(16);
-        let task = tokio::spawn(Self::endpoint_handler(endpoint.clone(), sender));
+        let (sender, receiver) = flume::bounded(limits.accept_queue_capacity);
+        let task = tokio::spawn(Self::endpoint_handler(endpoint.clone(), sender, limits));
         Ok(Self {
             inner: Arc::new(ServerEndpointInner {
                 endpoint: Some(endpoint),
                 task: Some(task),
                 local_addr: [LocalAddr::Socket(local_addr)],
-                receiver,
+                receiver: AcceptReceiver::Admitted(receiver),
+                stats: Default::default(),
             }),
+            metadata_frame: false,
             _phantom: PhantomData,
         })
     }
 
+    /// Whether substreams start with a frame of [Metadata] sent by the client.
+    ///
+    /// This changes the wire format, so it has to match the setting of the clients,
+    /// see [QuinnConnection::metadata_frame]. Substreams passed to
+    /// [Self::handle_substreams] must have been opened with the same setting.
+    ///
+    /// Default is `false`, so [ServerEndpoint::metadata] is always empty.
+    pub fn metadata_frame(mut self, value: bool) -> Self {
+        self.metadata_frame = value;
+        self
+    }
+
     /// Create a new server channel, given just a source of incoming connections
     ///
     /// This is useful if you want to manage the quinn endpoint yourself,
@@ -137,11 +235,24 @@ impl<In: RpcMessage, Out: RpcMessage> QuinnServerEndpoint<In, Out> {
         incoming: flume::Receiver<quinn::Connection>,
         local_addr: SocketAddr,
     ) -> Self {
-        let (sender, receiver) = flume::bounded(16);
+        Self::handle_connections_with_limits(incoming, local_addr, ServerLimits::default())
+    }
+
+    /// Create a new server channel, given just a source of incoming connections and
+    /// limits for its clients.
+    ///
+    /// Like [Self::handle_connections], with the limits applied as in [Self::with_limits].
+    pub fn handle_connections_with_limits(
+        incoming: flume::Receiver<quinn::Connection>,
+        local_addr: SocketAddr,
+        limits: ServerLimits,
+    ) -> Self {
+        let (sender, receiver) = flume::bounded(limits.accept_queue_capacity);
         let task = tokio::spawn(async move {
             // just grab all connections and spawn a handler for each one
+            let acceptor = Arc::new(Acceptor::new(sender, limits));
             while let Ok(connection) = incoming.recv_async().await {
-                tokio::spawn(Self::connection_handler(connection, sender.clone()));
+                tokio::spawn(Self::handle_connection(connection, acceptor.clone()));
             }
         });
         Self {
@@ -149,8 +260,10 @@ impl<In: RpcMessage, Out: RpcMessage> QuinnServerEndpoint<In, Out> {
                 endpoint: None,
                 task: Some(task),
                 local_addr: [LocalAddr::Socket(local_addr)],
-                receiver,
+                receiver: AcceptReceiver::Admitted(receiver),
+                stats: Default::default(),
             }),
+            metadata_frame: false,
             _phantom: PhantomData,
         }
     }
@@ -159,6 +272,8 @@ impl<In: RpcMessage, Out: RpcMessage> QuinnServerEndpoint<In, Out> {
     ///
     /// This is useful if you want to manage the quinn endpoint yourself,
     /// use multiple endpoints, or use an endpoint for multiple protocols.
+    ///
+    /// The connection of a substream is not known, so there is no [PeerInfo] for it.
     pub fn handle_substreams(
         receiver: flume::Receiver<SocketInner>,
         local_addr: SocketAddr,
@@ -168,8 +283,54 @@ impl<In: RpcMessage, Out: RpcMessage> QuinnServerEndpoint<In, Out> {
                 endpoint: None,
                 task: None,
                 local_addr: [LocalAddr::Socket(local_addr)],
-                receiver,
+                receiver: AcceptReceiver::Substreams(receiver),
+                stats: Default::default(),
             }),
+            metadata_frame: false,
+            _phantom: PhantomData,
+        }
+    }
+
+    /// Create a new server channel, given just a source of incoming substreams and
+    /// limits for them.
+    ///
+    /// Like [Self::handle_substreams]. The connections of the substreams are not known,
+    /// so [ServerLimits::max_connections_per_ip] does not apply, and the other limits
+    /// treat all substreams as if they came from a single connection. Without any other
+    /// limits, or a fair queue, the substreams are accepted from `incoming` directly.
+    pub fn handle_substreams_with_limits(
+        incoming: flume::Receiver<SocketInner>,
+        local_addr: SocketAddr,
+        limits: ServerLimits,
+    ) -> Self {
+        if !limits.has_substream_limits() {
+            return Self::handle_substreams(incoming, local_addr);
+        }
+        let (sender, receiver) = flume::bounded(limits.accept_queue_capacity);
+        let task = tokio::spawn(async move {
+            let acceptor = Acceptor::new(sender, limits);
+            let sender = acceptor.queue.sender();
+            let mut substreams = SubstreamLimits::new(&acceptor.limits);
+            let peer = Arc::new(PeerInfo::default());
+            while let Ok(socket) = incoming.recv_async().await {
+                let incoming = match substreams.admit(socket, &peer) {
+                    Some(incoming) => incoming,
+                    None => continue,
+                };
+                if sender.send_async(incoming).await.is_err() {
+                    break;
+                }
+            }
+        });
+        Self {
+            inner: Arc::new(ServerEndpointInner {
+                endpoint: None,
+                task: Some(task),
+                local_addr: [LocalAddr::Socket(local_addr)],
+                receiver: AcceptReceiver::Admitted(receiver),
+                stats: Default::default(),
+            }),
+            metadata_frame: false,
             _phantom: PhantomData,
         }
     }
@@ -179,6 +340,7 @@ impl<In: RpcMessage, Out: RpcMessage> Clone for QuinnServerEndpoint<In, Out> {
     fn clone(&self) -> Self {
         Self {
             inner: self.inner.clone(),
+            metadata_frame: self.metadata_frame,
             _phantom: PhantomData,
         }
     }
@@ -195,22 +357,528 @@ impl<In: RpcMessage, Out: RpcMessage> ConnectionErrors for QuinnServerEndpoint<I
 impl<In: RpcMessage, Out: RpcMessage> ConnectionCommon<In, Out> for QuinnServerEndpoint<In, Out> {
     type RecvStream = self::RecvStream<In>;
     type SendSink = self::SendSink<Out>;
+
+    fn limit_message_size(
+        send: &mut Self::SendSink,
+        recv: &mut Self::RecvStream,
+        limits: SizeLimits,
+    ) -> bool {
+        limit_message_size(send, recv, limits)
+    }
+
+    fn received_size(recv: &Self::RecvStream) -> Option<usize> {
+        Some(recv.last_size)
+    }
+
+    fn sent_size(send: &Self::SendSink) -> Option<usize> {
+        Some(send.last_size)
+    }
+
+    /// Resets the send stream with a dedicated code, so the other side can tell the
+    /// cancellation apart from a lost connection.
+    fn cancel(send: &mut Self::SendSink) {
+        cancel(send)
+    }
+
+    fn is_cancelled(error: &Self::RecvError) -> bool {
+        is_cancelled(error)
+    }
+
+    fn rejection(error: &Self::RecvError) -> Option<Status> {
+        rejection_status(error)
+    }
+}
+
+/// Counts channels, messages and serialization errors. The accept queue holds the
+/// substreams accepted on all connections, and is bounded.
+impl<In: RpcMessage, Out: RpcMessage> TransportStats for QuinnServerEndpoint<In, Out> {
+    fn stats(&self) -> StatsSnapshot {
+        let stats = self.inner.stats.snapshot();
+        match &self.inner.receiver {
+            AcceptReceiver::Admitted(receiver) => stats.with_queue(receiver),
+            AcceptReceiver::Substreams(receiver) => stats.with_queue(receiver),
+        }
+    }
 }
 
 impl<In: RpcMessage, Out: RpcMessage> ServerEndpoint<In, Out> for QuinnServerEndpoint<In, Out> {
     type AcceptBiFut = AcceptBiFuture<In, Out>;
 
     fn accept_bi(&self) -> Self::AcceptBiFut {
-        AcceptBiFuture(self.inner.receiver.clone().into_recv_async(), PhantomData)
+        let recv = match &self.inner.receiver {
+            AcceptReceiver::Admitted(receiver) => {
+                AcceptRecv::Admitted(receiver.clone().into_recv_async())
+            }
+            AcceptReceiver::Substreams(receiver) => {
+                AcceptRecv::Substream(receiver.clone().into_recv_async())
+            }
+        };
+        AcceptBiFuture(
+            recv,
+            self.inner.stats.clone(),
+            self.metadata_frame,
+            PhantomData,
+        )
     }
 
     fn local_addr(&self) -> &[LocalAddr] {
         &self.inner.local_addr
     }
+
+    fn metadata(recv: &Self::RecvStream) -> Metadata {
+        recv.metadata.clone().unwrap_or_default()
+    }
+
+    /// The remote address and the certificate chain of the quinn connection. The
+    /// certificates are only available if the server config requests client certificates.
+    fn peer(recv: &Self::RecvStream) -> PeerInfo {
+        recv.peer.as_deref().cloned().unwrap_or_default()
+    }
+
+    /// Resets the send stream and stops the receive stream with the status code, see
+    /// [rejection_status].
+    fn reject(send: Self::SendSink, recv: Self::RecvStream, status: Status) {
+        let code = quinn::VarInt::from_u32(status.code());
+        send.into_inner().reset(code).ok();
+        recv.into_inner().stop(code).ok();
+    }
+}
+
+/// The status a server rejected a channel with, if `error` is caused by a rejection
+///
+/// This covers channels rejected by [ServerEndpoint::reject], and channels and
+/// connections over the [ServerLimits] of the server.
+pub fn rejection_status(error: &io::Error) -> Option<Status> {
+    let inner = error.get_ref()?;
+    let code = match (inner.downcast_ref(), inner.downcast_ref()) {
+        (Some(quinn::ReadError::Reset(code)), _) | (_, Some(quinn::WriteError::Stopped(code))) => {
+            *code
+        }
+        (Some(quinn::ReadError::ConnectionLost(lost)), _)
+        | (_, Some(quinn::WriteError::ConnectionLost(lost))) => match lost {
+            quinn::ConnectionError::ApplicationClosed(close) => close.error_code,
+            _ => return None,
+        },
+        _ => return None,
+    };
+    Status::from_code(code.into_inner())
+}
+
+/// Apply the size limits to both sides of a channel
+fn limit_message_size<In, Out>(
+    send: &mut SendSink<Out>,
+    recv: &mut RecvStream<In>,
+    limits: SizeLimits,
+) -> bool {
+    send.max_size = limits.send;
+    // limit the codec, so larger frames are rejected before they are buffered
+    let max_frame_length = limits
+        .recv
+        .map_or(MAX_FRAME_LENGTH, |max_size| max_size.min(MAX_FRAME_LENGTH));
+    recv.inner.set_max_frame_length(max_frame_length);
+    limits
+        .recv
+        .map_or(true, |max_size| recv.last_size <= max_size)
+}
+
+/// Reset the send stream of a channel with [CANCEL_CODE]
+fn cancel<Out>(send: &mut SendSink<Out>) {
+    send.inner
+        .get_mut()
+        .reset(quinn::VarInt::from_u32(CANCEL_CODE))
+        .ok();
+}
+
+/// Whether `error` is caused by a reset with [CANCEL_CODE]
+fn is_cancelled(error: &io::Error) -> bool {
+    matches!(
+        error.get_ref().and_then(|inner| inner.downcast_ref()),
+        Some(quinn::ReadError::Reset(code)) if code.into_inner() == u64::from(CANCEL_CODE)
+    )
+}
+
+/// The error for a message that is larger than the limit of its channel
+fn too_large(size: usize, max_size: usize) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("message of {size} bytes exceeds the limit of {max_size} bytes"),
+    )
+}
+
+/// What we know about the client of a connection
+fn peer_info(connection: &quinn::Connection) -> PeerInfo {
+    let certificates = connection
+        .peer_identity()
+        .and_then(|identity| identity.downcast::<Vec<rustls::Certificate>>().ok())
+        .map(|chain| chain.into_iter().map(|cert| cert.0).collect())
+        .unwrap_or_default();
+    PeerInfo {
+        remote_addr: Some(connection.remote_address()),
+        certificates,
+    }
 }
 
 type SocketInner = (quinn::SendStream, quinn::RecvStream);
 
+/// A substream and the client of its connection
+struct Incoming {
+    socket: SocketInner,
+    /// `None` if the connection is not known
+    peer: Option<Arc<PeerInfo>>,
+    /// counts the substream against the limit of its connection
+    permit: Option<Arc<StreamPermit>>,
+}
+
+/// Limits that protect a [QuinnServerEndpoint] from misbehaving clients
+///
+/// Connections over the limit are closed, and substreams over the limit are reset, both
+/// with the code of [Status::ResourceExhausted]. By default there are no limits. The
+/// limits apply to endpoints created with [QuinnServerEndpoint::with_limits],
+/// [QuinnServerEndpoint::handle_connections_with_limits] and
+/// [QuinnServerEndpoint::handle_substreams_with_limits].
+#[derive(Debug, Clone)]
+pub struct ServerLimits {
+    max_connections_per_ip: Option<usize>,
+    max_concurrent_streams: Option<usize>,
+    max_streams_per_second: Option<u32>,
+    fair_queue_capacity: Option<usize>,
+    accept_queue_capacity: usize,
+}
+
+impl Default for ServerLimits {
+    fn default() -> Self {
+        Self {
+            max_connections_per_ip: None,
+            max_concurrent_streams: None,
+            max_streams_per_second: None,
+            fair_queue_capacity: None,
+            accept_queue_capacity: 16,
+        }
+    }
+}
+
+impl ServerLimits {
+    /// Maximum number of connections from a single IP address.
+    ///
+    /// Default is `None`, which allows any number of connections.
+    pub fn max_connections_per_ip(mut self, value: Option<usize>) -> Self {
+        self.max_connections_per_ip = value;
+        self
+    }
+
+    /// Maximum number of substreams of a connection that are open at the same time.
+    ///
+    /// A substream is open until both its [SendSink] and [RecvStream], or the
+    /// [RawSendStream] and [RawRecvStream] they were turned into, are dropped. Turning them
+    /// into their inner quinn streams releases the substream early. Default is `None`, which allows any number
+    /// of substreams.
+    pub fn max_concurrent_streams(mut self, value: Option<usize>) -> Self {
+        self.max_concurrent_streams = value;
+        self
+    }
+
+    /// Maximum number of new substreams per second and connection.
+    ///
+    /// Short bursts of up to this many substreams are allowed. Default is `None`, which
+    /// allows any rate.
+    pub fn max_streams_per_second(mut self, value: Option<u32>) -> Self {
+        self.max_streams_per_second = value.map(|value| value.max(1));
+        self
+    }
+
+    /// Accept substreams from all connections in turn, using a queue of this size for
+    /// each connection.
+    ///
+    /// By default all connections put their substreams in the accept queue, so a busy
+    /// connection can fill it and delay the substreams of all others. With a queue per
+    /// connection, only the connection whose queue is full has to wait. The substreams
+    /// are moved from the queues of the connections to the accept queue in turn, so the
+    /// accept queue still limits how many substreams wait in front of a new one, see
+    /// [Self::accept_queue_capacity]. Default is `None`, which uses just the accept queue.
+    pub fn fair_queue_capacity(mut self, value: Option<usize>) -> Self {
+        self.fair_queue_capacity = value.map(|value| value.max(1));
+        self
+    }
+
+    /// Size of the queue of substreams waiting to be accepted by the server.
+    ///
+    /// When the queue is full, the connections wait before accepting more substreams.
+    /// With [Self::fair_queue_capacity], a small accept queue makes the turns of the
+    /// connections take effect sooner, at the cost of less buffering. This is also the
+    /// capacity of the queue of [QuinnServerEndpoint::handle_substreams_with_limits], if
+    /// it has any limits. Default is 16.
+    pub fn accept_queue_capacity(mut self, value: usize) -> Self {
+        self.accept_queue_capacity = value.max(1);
+        self
+    }
+
+    /// Whether substreams have to be checked or queued before they are accepted
+    fn has_substream_limits(&self) -> bool {
+        self.max_concurrent_streams.is_some()
+            || self.max_streams_per_second.is_some()
+            || self.fair_queue_capacity.is_some()
+    }
+}
+
+/// What the handlers of new connections share
+struct Acceptor {
+    /// number of connections per IP address
+    connections: Arc<Mutex<HashMap<IpAddr, usize>>>,
+    queue: AcceptQueue,
+    limits: ServerLimits,
+}
+
+impl Acceptor {
+    fn new(sender: flume::Sender<Incoming>, limits: ServerLimits) -> Self {
+        let queue = match limits.fair_queue_capacity {
+            Some(capacity) => {
+                let (register, queues) = flume::unbounded();
+                tokio::spawn(fair_scheduler(queues, sender));
+                AcceptQueue::Fair { register, capacity }
+            }
+            None => AcceptQueue::Shared(sender),
+        };
+        Self {
+            connections: Default::default(),
+            queue,
+            limits,
+        }
+    }
+}
+
+/// Where the connection handlers put their substreams
+enum AcceptQueue {
+    /// All connections use the accept queue
+    Shared(flume::Sender<Incoming>),
+    /// Every connection gets its own queue, which is registered with the [fair_scheduler]
+    Fair {
+        register: flume::Sender<flume::Receiver<Incoming>>,
+        capacity: usize,
+    },
+}
+
+impl AcceptQueue {
+    /// The sender for a new connection
+    fn sender(&self) -> flume::Sender<Incoming> {
+        match self {
+            Self::Shared(sender) => sender.clone(),
+            Self::Fair { register, capacity } => {
+                let (sender, receiver) = flume::bounded(*capacity);
+                register.send(receiver).ok();
+                sender
+            }
+        }
+    }
+}
+
+/// Moves substreams from the queues of the connections to the accept queue, taking
+/// one from each connection in turn
+async fn fair_scheduler(
+    queues: flume::Receiver<flume::Receiver<Incoming>>,
+    sender: flume::Sender<Incoming>,
+) {
+    let mut queues = RoundRobin {
+        new: queues.into_stream(),
+        queues: Vec::new(),
+        next: 0,
+    };
+    while let Some(incoming) = queues.next().await {
+        if sender.send_async(incoming).await.is_err() {
+            break;
+        }
+    }
+}
+
+/// A stream that takes the items of a changing set of queues in turn
+struct RoundRobin {
+    /// queues of new connections
+    new: flume::r#async::RecvStream<'static, flume::Receiver<Incoming>>,
+    queues: Vec<flume::r#async::RecvStream<'static, Incoming>>,
+    /// the queue to look at first
+    next: usize,
+}
+
+impl Stream for RoundRobin {
+    type Item = Incoming;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        let mut done = false;
+        while let Poll::Ready(queue) = this.new.poll_next_unpin(cx) {
+            match queue {
+                Some(queue) => this.queues.push(queue.into_stream()),
+                None => {
+                    done = true;
+                    break;
+                }
+            }
+        }
+        let mut checked = 0;
+        while checked < this.queues.len() {
+            let i = (this.next + checked) % this.queues.len();
+            match this.queues[i].poll_next_unpin(cx) {
+                Poll::Ready(Some(incoming)) => {
+                    this.next = i + 1;
+                    return Poll::Ready(Some(incoming));
+                }
+                // the connection is gone. Start over, so no queue is skipped when the
+                // last queue moves into its place
+                Poll::Ready(None) => {
+                    this.queues.swap_remove(i);
+                    this.next = i;
+                    checked = 0;
+                }
+                Poll::Pending => checked += 1,
+            }
+        }
+        if done && this.queues.is_empty() {
+            Poll::Ready(None)
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+/// Keeps a connection counted against the limit of its IP address
+#[derive(Debug)]
+struct ConnectionPermit {
+    ip: IpAddr,
+    connections: Arc<Mutex<HashMap<IpAddr, usize>>>,
+}
+
+impl ConnectionPermit {
+    fn acquire(
+        connections: &Arc<Mutex<HashMap<IpAddr, usize>>>,
+        ip: IpAddr,
+        max: usize,
+    ) -> Option<Self> {
+        let mut map = connections.lock().unwrap();
+        let count = map.entry(ip).or_default();
+        if *count >= max {
+            return None;
+        }
+        *count += 1;
+        Some(Self {
+            ip,
+            connections: connections.clone(),
+        })
+    }
+}
+
+impl Drop for ConnectionPermit {
+    fn drop(&mut self) {
+        let mut map = self.connections.lock().unwrap();
+        if let Some(count) = map.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                map.remove(&self.ip);
+            }
+        }
+    }
+}
+
+/// Keeps a substream counted against the limit of its connection
+#[derive(Debug)]
+struct StreamPermit(Arc<AtomicUsize>);
+
+impl StreamPermit {
+    fn acquire(streams: &Arc<AtomicUsize>, max: usize) -> Option<Self> {
+        streams
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| {
+                (n < max).then_some(n + 1)
+            })
+            .ok()?;
+        Some(Self(streams.clone()))
+    }
+}
+
+impl Drop for StreamPermit {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Token bucket for the new substreams of a connection
+struct RateLimit {
+    per_second: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl RateLimit {
+    fn new(per_second: u32) -> Self {
+        Self {
+            per_second: per_second.into(),
+            tokens: per_second.into(),
+            last: Instant::now(),
+        }
+    }
+
+    fn try_acquire(&mut self) -> bool {
+        let now = Instant::now();
+        let refill = now.duration_since(self.last).as_secs_f64() * self.per_second;
+        self.tokens = (self.tokens + refill).min(self.per_second);
+        self.last = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// The limits of the substreams of a single connection
+struct SubstreamLimits {
+    /// number of open substreams
+    streams: Arc<AtomicUsize>,
+    max_concurrent_streams: Option<usize>,
+    rate: Option<RateLimit>,
+}
+
+impl SubstreamLimits {
+    fn new(limits: &ServerLimits) -> Self {
+        Self {
+            streams: Default::default(),
+            max_concurrent_streams: limits.max_concurrent_streams,
+            rate: limits.max_streams_per_second.map(RateLimit::new),
+        }
+    }
+
+    /// Check a new substream against the limits, and reset it if it exceeds them
+    fn admit(&mut self, socket: SocketInner, peer: &Arc<PeerInfo>) -> Option<Incoming> {
+        if !self.rate.as_mut().map_or(true, RateLimit::try_acquire) {
+            tracing::debug!("Too many new substreams, rejecting {}", socket.0.id());
+            reject_substream(socket);
+            return None;
+        }
+        let permit = match self.max_concurrent_streams {
+            Some(max) => match StreamPermit::acquire(&self.streams, max) {
+                Some(permit) => Some(Arc::new(permit)),
+                None => {
+                    tracing::debug!("Too many open substreams, rejecting {}", socket.0.id());
+                    reject_substream(socket);
+                    return None;
+                }
+            },
+            None => None,
+        };
+        tracing::debug!("Sending substream to be handled... {}", socket.0.id());
+        Some(Incoming {
+            socket,
+            peer: Some(peer.clone()),
+            permit,
+        })
+    }
+}
+
+/// Reset a substream that exceeds a limit
+fn reject_substream((mut send, mut recv): SocketInner) {
+    let code = quinn::VarInt::from_u32(Status::ResourceExhausted.code());
+    send.reset(code).ok();
+    recv.stop(code).ok();
+}
+
 #[derive(Debug)]
 struct ClientConnectionInner {
     /// The quinn endpoint, we just keep a clone of this for information
@@ -219,8 +887,15 @@ struct ClientConnectionInner {
     task: Option<tokio::task::JoinHandle<()>>,
     /// The channel to receive new connections
     sender: flume::Sender<oneshot::Sender<Result<SocketInner, quinn::ConnectionError>>>,
+    /// The current quinn connection, if any
+    connection: CurrentConnection,
+    /// Counters, shared with the connection handler task
+    stats: Arc<StatsCounters>,
 }
 
+/// The current quinn connection of a client, shared with the connection handler task
+type CurrentConnection = Arc<Mutex<Option<quinn::Connection>>>;
+
 impl Drop for ClientConnectionInner {
     fn drop(&mut self) {
         tracing::debug!("Dropping client connection");
@@ -245,8 +920,14 @@ impl Drop for ClientConnectionInner {
 }
 
 /// A connection using a quinn connection
+///
+/// By default, [Connection::open_bi_with_metadata] drops the metadata, so the
+/// substreams are compatible with servers that do not expect a metadata frame. See
+/// [QuinnConnection::metadata_frame].
 pub struct QuinnConnection<In: RpcMessage, Out: RpcMessage> {
     inner: Arc<ClientConnectionInner>,
+    /// Whether substreams start with a metadata frame
+    metadata_frame: bool,
     _phantom: PhantomData<(In, Out)>,
 }
 
@@ -300,7 +981,10 @@ impl<In: RpcMessage, Out: RpcMessage> QuinnConnection<In, Out> {
         addr: SocketAddr,
         name: String,
         requests: flume::Receiver<oneshot::Sender<Result<SocketInner, quinn::ConnectionError>>>,
+        current: CurrentConnection,
+        stats: Arc<StatsCounters>,
     ) {
+        let mut connected = false;
         'outer: loop {
             tracing::debug!("Connecting to {} as {}", addr, name);
             let connecting = match endpoint.connect(addr, &name) {
@@ -319,6 +1003,11 @@ impl<In: RpcMessage, Out: RpcMessage> QuinnConnection<In, Out> {
                     continue;
                 }
             };
+            if connected {
+                stats.reconnects.fetch_add(1, Ordering::Relaxed);
+            }
+            connected = true;
+            *current.lock().unwrap() = Some(connection.clone());
             loop {
                 tracing::debug!("Awaiting request for new bidi substream...");
                 let request = match requests.recv_async().await {
@@ -353,21 +1042,27 @@ impl<In: RpcMessage, Out: RpcMessage> QuinnConnection<In, Out> {
         addr: SocketAddr,
         name: String,
         requests: flume::Receiver<oneshot::Sender<Result<SocketInner, quinn::ConnectionError>>>,
+        current: CurrentConnection,
+        stats: Arc<StatsCounters>,
     ) {
-        Self::reconnect_handler_inner(endpoint, addr, name, requests).await;
+        Self::reconnect_handler_inner(endpoint, addr, name, requests, current, stats).await;
         tracing::info!("Reconnect handler finished");
     }
 
     /// Create a new channel
     pub fn from_connection(connection: quinn::Connection) -> Self {
         let (sender, receiver) = flume::bounded(16);
+        let current = Arc::new(Mutex::new(Some(connection.clone())));
         let task = tokio::spawn(Self::single_connection_handler(connection, receiver));
         Self {
             inner: Arc::new(ClientConnectionInner {
                 endpoint: None,
                 task: Some(task),
                 sender,
+                connection: current,
+                stats: Default::default(),
             }),
+            metadata_frame: false,
             _phantom: PhantomData,
         }
     }
@@ -375,21 +1070,40 @@ impl<In: RpcMessage, Out: RpcMessage> QuinnConnection<In, Out> {
     /// Create a new channel
     pub fn new(endpoint: quinn::Endpoint, addr: SocketAddr, name: String) -> Self {
         let (sender, receiver) = flume::bounded(16);
+        let current = CurrentConnection::default();
+        let stats = Arc::<StatsCounters>::default();
         let task = tokio::spawn(Self::reconnect_handler(
             endpoint.clone(),
             addr,
             name,
             receiver,
+            current.clone(),
+            stats.clone(),
         ));
         Self {
             inner: Arc::new(ClientConnectionInner {
                 endpoint: Some(endpoint),
                 task: Some(task),
                 sender,
+                connection: current,
+                stats,
             }),
+            metadata_frame: false,
             _phantom: PhantomData,
         }
     }
+
+    /// Whether substreams start with a frame of [Metadata], for things like
+    /// authentication, trace contexts and the version handshake.
+    ///
+    /// This changes the wire format, so the server has to enable
+    /// [QuinnServerEndpoint::metadata_frame] as well.
+    ///
+    /// Default is `false`, which drops the metadata.
+    pub fn metadata_frame(mut self, value: bool) -> Self {
+        self.metadata_frame = value;
+        self
+    }
 }
 
 impl<In: RpcMessage, Out: RpcMessage> fmt::Debug for QuinnConnection<In, Out> {
@@ -404,6 +1118,7 @@ impl<In: RpcMessage, Out: RpcMessage> Clone for QuinnConnection<In, Out> {
     fn clone(&self) -> Self {
         Self {
             inner: self.inner.clone(),
+            metadata_frame: self.metadata_frame,
             _phantom: PhantomData,
         }
     }
@@ -417,29 +1132,116 @@ impl<In: RpcMessage, Out: RpcMessage> ConnectionErrors for QuinnConnection<In, O
     type OpenError = quinn::ConnectionError;
 }
 
+/// Uses the state of the current quinn connection, without any io.
+///
+/// A ping returns the current round trip time estimate, or the reason why the connection
+/// was closed. To detect a dead peer, configure a keep alive interval and an idle timeout
+/// in the quinn [TransportConfig](quinn::TransportConfig), so the connection gets closed
+/// once the peer stops responding.
+///
+/// While the initial connection is being established, the connection is not healthy and
+/// a ping fails with [quinn::ConnectionError::TimedOut].
+impl<In: RpcMessage, Out: RpcMessage> ConnectionHealth for QuinnConnection<In, Out> {
+    type PingFut = future::Ready<result::Result<Duration, quinn::ConnectionError>>;
+
+    fn ping(&self) -> Self::PingFut {
+        let connection = self.inner.connection.lock().unwrap();
+        future::ready(match connection.as_ref() {
+            Some(connection) => match connection.close_reason() {
+                Some(reason) => Err(reason),
+                None => Ok(connection.rtt()),
+            },
+            None => Err(quinn::ConnectionError::TimedOut),
+        })
+    }
+
+    fn is_healthy(&self) -> bool {
+        let connection = self.inner.connection.lock().unwrap();
+        matches!(connection.as_ref(), Some(connection) if connection.close_reason().is_none())
+    }
+}
+
+/// Counts channels, messages, serialization errors and reconnects. A connection created
+/// with [QuinnConnection::from_connection] never reconnects.
+impl<In: RpcMessage, Out: RpcMessage> TransportStats for QuinnConnection<In, Out> {
+    fn stats(&self) -> StatsSnapshot {
+        self.inner.stats.snapshot()
+    }
+}
+
 impl<In: RpcMessage, Out: RpcMessage> ConnectionCommon<In, Out> for QuinnConnection<In, Out> {
     type SendSink = self::SendSink<Out>;
     type RecvStream = self::RecvStream<In>;
+
+    fn limit_message_size(
+        send: &mut Self::SendSink,
+        recv: &mut Self::RecvStream,
+        limits: SizeLimits,
+    ) -> bool {
+        limit_message_size(send, recv, limits)
+    }
+
+    fn received_size(recv: &Self::RecvStream) -> Option<usize> {
+        Some(recv.last_size)
+    }
+
+    fn sent_size(send: &Self::SendSink) -> Option<usize> {
+        Some(send.last_size)
+    }
+
+    /// Resets the send stream with a dedicated code, so the other side can tell the
+    /// cancellation apart from a lost connection.
+    fn cancel(send: &mut Self::SendSink) {
+        cancel(send)
+    }
+
+    fn is_cancelled(error: &Self::RecvError) -> bool {
+        is_cancelled(error)
+    }
+
+    fn rejection(error: &Self::RecvError) -> Option<Status> {
+        rejection_status(error)
+    }
 }
 
 impl<In: RpcMessage, Out: RpcMessage> Connection<In, Out> for QuinnConnection<In, Out> {
     type OpenBiFut = OpenBiFuture<In, Out>;
 
     fn open_bi(&self) -> Self::OpenBiFut {
+        self.open_bi_with_metadata(Metadata::default())
+    }
+
+    /// The metadata is sent as the first frame of the channel if
+    /// [QuinnConnection::metadata_frame] is enabled, and dropped otherwise.
+    fn open_bi_with_metadata(&self, metadata: Metadata) -> Self::OpenBiFut {
         let (sender, receiver) = oneshot::channel();
         OpenBiFuture(
             OpenBiFutureState::Sending(self.inner.sender.clone().into_send_async(sender), receiver),
+            Some(metadata).filter(|_| self.metadata_frame),
+            self.inner.stats.clone(),
             PhantomData,
         )
     }
+
+    fn carries_metadata(&self) -> bool {
+        self.metadata_frame
+    }
 }
 
 /// A sink that wraps a quinn SendStream with length delimiting and bincode
 ///
 /// If you want to send bytes directly, use [SendSink::into_inner] to get the
 /// underlying [quinn::SendStream].
-#[pin_project]
-pub struct SendSink<Out>(#[pin] FramedBincodeWrite<quinn::SendStream, Out>);
+pub struct SendSink<Out> {
+    inner: FramedBincodeWrite<quinn::SendStream, Out>,
+    stats: Arc<StatsCounters>,
+    /// limit for the encoded size of messages, see [ConnectionCommon::limit_message_size]
+    max_size: Option<usize>,
+    /// encoded size of the last message
+    last_size: usize,
+    _open: Arc<OpenChannel>,
+    _permit: Option<Arc<StreamPermit>>,
+}
 
 impl<Out> fmt::Debug for SendSink<Out> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
@@ -448,46 +1250,82 @@ impl<Out> fmt::Debug for SendSink<Out> {
 }
 
 impl<Out: Serialize> SendSink<Out> {
-    fn new(inner: quinn::SendStream) -> Self {
+    fn new(inner: quinn::SendStream, stats: Arc<StatsCounters>, open: Arc<OpenChannel>) -> Self {
         let inner = FramedBincodeWrite::new(inner, MAX_FRAME_LENGTH);
-        Self(inner)
+        Self {
+            inner,
+            stats,
+            max_size: None,
+            last_size: 0,
+            _open: open,
+            _permit: None,
+        }
+    }
+
+    /// A sink that sends the metadata frame before the first message
+    fn with_metadata(
+        inner: quinn::SendStream,
+        metadata: &Metadata,
+        stats: Arc<StatsCounters>,
+        open: Arc<OpenChannel>,
+    ) -> io::Result<Self> {
+        let mut this = Self::new(inner, stats, open);
+        let frame = encoding()
+            .serialize(metadata)
+            .map_err(|cause| io::Error::new(io::ErrorKind::InvalidInput, cause))?;
+        this.inner.start_send_frame(frame.into())?;
+        Ok(this)
     }
 }
 
 impl<Out> SendSink<Out> {
     /// Get the underlying [quinn::SendStream], which implements
     /// [tokio::io::AsyncWrite] and can be used to send bytes directly.
+    ///
+    /// Once both halves are turned into quinn streams, the channel no longer counts as
+    /// open and no longer takes up a place in [ServerLimits::max_concurrent_streams].
+    /// Use [RawStreams::into_raw] to keep both.
     pub fn into_inner(self) -> quinn::SendStream {
-        self.0.into_inner()
+        self.inner.into_inner()
     }
 }
 
-impl<Out: Serialize> Sink<Out> for SendSink<Out> {
+impl<Out: Serialize + Unpin> Sink<Out> for SendSink<Out> {
     type Error = io::Error;
 
     fn poll_ready(
-        self: Pin<&mut Self>,
+        mut self: Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
     ) -> std::task::Poll<Result<(), Self::Error>> {
-        self.project().0.poll_ready_unpin(cx)
+        SinkExt::<Out>::poll_ready_unpin(&mut self.inner, cx)
     }
 
-    fn start_send(self: Pin<&mut Self>, item: Out) -> Result<(), Self::Error> {
-        self.project().0.start_send_unpin(item)
+    fn start_send(mut self: Pin<&mut Self>, item: Out) -> Result<(), Self::Error> {
+        // serialize here instead of in the framing, so we can count frames and errors
+        let frame = encoding().serialize(&item).map_err(|cause| {
+            self.stats.serialization_error();
+            io::Error::new(io::ErrorKind::InvalidData, cause)
+        })?;
+        if let Some(max_size) = self.max_size.filter(|max_size| frame.len() > *max_size) {
+            return Err(too_large(frame.len(), max_size));
+        }
+        self.stats.frame_sent(frame.len());
+        self.last_size = frame.len();
+        self.inner.start_send_frame(frame.into())
     }
 
     fn poll_flush(
-        self: Pin<&mut Self>,
+        mut self: Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
     ) -> std::task::Poll<Result<(), Self::Error>> {
-        self.project().0.poll_flush_unpin(cx)
+        SinkExt::<Out>::poll_flush_unpin(&mut self.inner, cx)
     }
 
     fn poll_close(
-        self: Pin<&mut Self>,
+        mut self: Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
     ) -> std::task::Poll<Result<(), Self::Error>> {
-        self.project().0.poll_close_unpin(cx)
+        SinkExt::<Out>::poll_close_unpin(&mut self.inner, cx)
     }
 }
 
@@ -495,8 +1333,19 @@ impl<Out: Serialize> Sink<Out> for SendSink<Out> {
 ///
 /// If you want to receive bytes directly, use [RecvStream::into_inner] to get
 /// the underlying [quinn::RecvStream].
-#[pin_project]
-pub struct RecvStream<In>(#[pin] FramedBincodeRead<quinn::RecvStream, In>);
+pub struct RecvStream<In> {
+    inner: FramedBincodeRead<quinn::RecvStream, In>,
+    /// metadata sent by the client, None on the server side until the metadata frame
+    /// has been read
+    metadata: Option<Metadata>,
+    /// the client of the connection, None on the client side
+    peer: Option<Arc<PeerInfo>>,
+    stats: Arc<StatsCounters>,
+    /// encoded size of the last message
+    last_size: usize,
+    _open: Arc<OpenChannel>,
+    _permit: Option<Arc<StreamPermit>>,
+}
 
 impl<In> fmt::Debug for RecvStream<In> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
@@ -505,28 +1354,206 @@ impl<In> fmt::Debug for RecvStream<In> {
 }
 
 impl<In: DeserializeOwned> RecvStream<In> {
-    fn new(inner: quinn::RecvStream) -> Self {
+    fn new(inner: quinn::RecvStream, stats: Arc<StatsCounters>, open: Arc<OpenChannel>) -> Self {
         let inner = FramedBincodeRead::new(inner, MAX_FRAME_LENGTH);
-        Self(inner)
+        Self {
+            inner,
+            metadata: Some(Metadata::default()),
+            peer: None,
+            stats,
+            last_size: 0,
+            _open: open,
+            _permit: None,
+        }
+    }
+
+    /// A server side stream, that reads the metadata frame before the first message if
+    /// `metadata_frame` is set
+    fn with_peer(
+        inner: quinn::RecvStream,
+        peer: Option<Arc<PeerInfo>>,
+        stats: Arc<StatsCounters>,
+        open: Arc<OpenChannel>,
+        metadata_frame: bool,
+    ) -> Self {
+        let this = Self::new(inner, stats, open);
+        Self {
+            metadata: this.metadata.filter(|_| !metadata_frame),
+            peer,
+            ..this
+        }
     }
 }
 
 impl<In> RecvStream<In> {
     /// Get the underlying [quinn::RecvStream], which implements
     /// [tokio::io::AsyncRead] and can be used to receive bytes directly.
+    ///
+    /// Once both halves are turned into quinn streams, the channel no longer counts as
+    /// open and no longer takes up a place in [ServerLimits::max_concurrent_streams].
+    /// Use [RawStreams::into_raw] to keep both.
     pub fn into_inner(self) -> quinn::RecvStream {
-        self.0.into_inner()
+        self.inner.into_inner()
+    }
+}
+
+impl<In> RecvStream<In> {
+    /// Get a [RawRecvStream] that starts with the bytes that have already been received,
+    /// but not yet decoded as messages.
+    ///
+    /// Unlike [RecvStream::into_inner], the raw stream keeps counting as an open channel
+    /// and holds on to its place in [ServerLimits::max_concurrent_streams].
+    pub fn into_raw(self) -> RawRecvStream {
+        let (inner, buffer) = self.inner.into_parts();
+        RawRecvStream {
+            buffer,
+            inner,
+            _open: self._open,
+            _permit: self._permit,
+        }
     }
 }
 
-impl<In: DeserializeOwned> Stream for RecvStream<In> {
+impl<In: DeserializeOwned + Unpin> Stream for RecvStream<In> {
     type Item = result::Result<In, io::Error>;
 
     fn poll_next(
-        self: Pin<&mut Self>,
+        mut self: Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
     ) -> std::task::Poll<Option<Self::Item>> {
-        self.project().0.poll_next_unpin(cx)
+        if self.metadata.is_none() {
+            let frame = match ready!(self.inner.poll_next_frame(cx)) {
+                Some(Ok(frame)) => frame,
+                Some(Err(cause)) => return Poll::Ready(Some(Err(cause))),
+                None => return Poll::Ready(None),
+            };
+            let metadata = encoding()
+                .deserialize(&frame)
+                .map_err(|cause| io::Error::new(io::ErrorKind::InvalidData, cause))?;
+            self.metadata = Some(metadata);
+        }
+        // deserialize here instead of in the framing, so we can count frames and errors
+        let frame = match ready!(self.inner.poll_next_frame(cx)) {
+            Some(Ok(frame)) => frame,
+            Some(Err(cause)) => return Poll::Ready(Some(Err(cause))),
+            None => return Poll::Ready(None),
+        };
+        self.stats.frame_received(frame.len());
+        self.last_size = frame.len();
+        let item = encoding().deserialize(&frame).map_err(|cause| {
+            self.stats.serialization_error();
+            io::Error::new(io::ErrorKind::InvalidData, cause)
+        });
+        Poll::Ready(Some(item))
+    }
+}
+
+/// A raw byte stream, see [RawStreams]
+///
+/// Yields the bytes that were already buffered when the typed [RecvStream] was turned into
+/// a raw stream, followed by the bytes of the underlying [quinn::RecvStream].
+pub struct RawRecvStream {
+    buffer: BytesMut,
+    inner: quinn::RecvStream,
+    _open: Arc<OpenChannel>,
+    _permit: Option<Arc<StreamPermit>>,
+}
+
+impl RawRecvStream {
+    /// Get the underlying [quinn::RecvStream] and the bytes that were buffered but not
+    /// yet read.
+    pub fn into_parts(self) -> (quinn::RecvStream, BytesMut) {
+        (self.inner, self.buffer)
+    }
+}
+
+impl fmt::Debug for RawRecvStream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RawRecvStream")
+            .field("buffered", &self.buffer.len())
+            .finish()
+    }
+}
+
+impl AsyncRead for RawRecvStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if !self.buffer.is_empty() {
+            let n = self.buffer.len().min(buf.remaining());
+            buf.put_slice(&self.buffer.split_to(n));
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+/// A raw byte sink, see [RawStreams]
+///
+/// Writes go directly to the underlying [quinn::SendStream].
+pub struct RawSendStream {
+    inner: quinn::SendStream,
+    _open: Arc<OpenChannel>,
+    _permit: Option<Arc<StreamPermit>>,
+}
+
+impl fmt::Debug for RawSendStream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RawSendStream").finish()
+    }
+}
+
+impl RawSendStream {
+    /// Get the underlying [quinn::SendStream]
+    pub fn into_inner(self) -> quinn::SendStream {
+        self.inner
+    }
+}
+
+impl AsyncWrite for RawSendStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+fn into_raw<In, Out>(send: SendSink<Out>, recv: RecvStream<In>) -> (RawSendStream, RawRecvStream) {
+    let send = RawSendStream {
+        inner: send.inner.into_inner(),
+        _open: send._open,
+        _permit: send._permit,
+    };
+    (send, recv.into_raw())
+}
+
+impl<In: RpcMessage, Out: RpcMessage> RawStreams<In, Out> for QuinnConnection<In, Out> {
+    type RawSend = RawSendStream;
+    type RawRecv = RawRecvStream;
+
+    fn into_raw(send: Self::SendSink, recv: Self::RecvStream) -> (Self::RawSend, Self::RawRecv) {
+        into_raw(send, recv)
+    }
+}
+
+impl<In: RpcMessage, Out: RpcMessage> RawStreams<In, Out> for QuinnServerEndpoint<In, Out> {
+    type RawSend = RawSendStream;
+    type RawRecv = RawRecvStream;
+
+    fn into_raw(send: Self::SendSink, recv: Self::RecvStream) -> (Self::RawSend, Self::RawRecv) {
+        into_raw(send, recv)
     }
 }
 
@@ -561,7 +1588,13 @@ impl OpenBiFutureState {
 
 /// Future returned by open_bi
 #[pin_project]
-pub struct OpenBiFuture<In, Out>(OpenBiFutureState, PhantomData<(In, Out)>);
+pub struct OpenBiFuture<In, Out>(
+    OpenBiFutureState,
+    /// The metadata to send, None if there is no metadata frame
+    Option<Metadata>,
+    Arc<StatsCounters>,
+    PhantomData<(In, Out)>,
+);
 
 impl<In: RpcMessage, Out: RpcMessage> fmt::Debug for OpenBiFuture<In, Out> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
@@ -587,8 +1620,26 @@ impl<In: RpcMessage, Out: RpcMessage> Future for OpenBiFuture<In, Out> {
             },
             OpenBiFutureState::Receiving(mut fut) => match fut.poll_unpin(cx) {
                 Poll::Ready(Ok(Ok((send, recv)))) => {
-                    let send = SendSink::new(send);
-                    let recv = RecvStream::new(recv);
+                    let open = self.2.open_channel();
+                    let send = match self.1.take() {
+                        Some(metadata) => {
+                            match SendSink::with_metadata(
+                                send,
+                                &metadata,
+                                self.2.clone(),
+                                open.clone(),
+                            ) {
+                                Ok(send) => send,
+                                Err(cause) => {
+                                    tracing::warn!("open_bi: unable to send metadata: {}", cause);
+                                    return Poll::Ready(Err(quinn::ConnectionError::LocallyClosed));
+                                }
+                            }
+                        }
+                        None => SendSink::new(send, self.2.clone(), open.clone()),
+                    };
+                    let recv = RecvStream::new(recv, self.2.clone(), open);
+                    self.2.opened.fetch_add(1, Ordering::Relaxed);
                     Poll::Ready(Ok((send, recv)))
                 }
                 Poll::Ready(Ok(Err(cause))) => Poll::Ready(Err(cause)),
@@ -606,7 +1657,9 @@ impl<In: RpcMessage, Out: RpcMessage> Future for OpenBiFuture<In, Out> {
 /// Future returned by accept_bi
 #[pin_project]
 pub struct AcceptBiFuture<In, Out>(
-    #[pin] flume::r#async::RecvFut<'static, SocketInner>,
+    #[pin] AcceptRecv,
+    Arc<StatsCounters>,
+    bool,
     PhantomData<(In, Out)>,
 );
 
@@ -616,6 +1669,28 @@ impl<In: RpcMessage, Out: RpcMessage> fmt::Debug for AcceptBiFuture<In, Out> {
     }
 }
 
+/// Receives the next substream from the [AcceptReceiver]
+#[pin_project(project = AcceptRecvProj)]
+enum AcceptRecv {
+    Admitted(#[pin] flume::r#async::RecvFut<'static, Incoming>),
+    Substream(#[pin] flume::r#async::RecvFut<'static, SocketInner>),
+}
+
+impl Future for AcceptRecv {
+    type Output = result::Result<Incoming, flume::RecvError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        match self.project() {
+            AcceptRecvProj::Admitted(recv) => recv.poll(cx),
+            AcceptRecvProj::Substream(recv) => recv.poll(cx).map_ok(|socket| Incoming {
+                socket,
+                peer: None,
+                permit: None,
+            }),
+        }
+    }
+}
+
 impl<In: RpcMessage, Out: RpcMessage> Future for AcceptBiFuture<In, Out> {
     type Output = result::Result<self::Socket<In, Out>, self::OpenBiError>;
 
@@ -623,13 +1698,24 @@ impl<In: RpcMessage, Out: RpcMessage> Future for AcceptBiFuture<In, Out> {
         self: Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
     ) -> std::task::Poll<Self::Output> {
-        self.project().0.poll_unpin(cx).map(|conn| {
-            let (send, recv) = conn.map_err(|e| {
+        let this = self.project();
+        let stats = this.1;
+        let metadata_frame = *this.2;
+        this.0.poll(cx).map(|conn| {
+            let Incoming {
+                socket: (send, recv),
+                peer,
+                permit,
+            } = conn.map_err(|e| {
                 tracing::warn!("accept_bi: error receiving connection: {}", e);
                 quinn::ConnectionError::LocallyClosed
             })?;
-            let send = SendSink::new(send);
-            let recv = RecvStream::new(recv);
+            let open = stats.open_channel();
+            let mut send = SendSink::new(send, stats.clone(), open.clone());
+            send._permit = permit.clone();
+            let mut recv = RecvStream::with_peer(recv, peer, stats.clone(), open, metadata_frame);
+            recv._permit = permit;
+            stats.accepted.fetch_add(1, Ordering::Relaxed);
             Ok((send, recv))
         })
     }
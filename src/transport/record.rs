@@ -0,0 +1,657 @@
+//! Transport wrapper that records all messages, and replay of recordings
+//!
+//! [RecordingConnection] and [RecordingServerEndpoint] wrap any other transport and
+//! write every message sent or received on any channel to a [Recorder], together with
+//! the channel it belongs to, its direction and the time since the recorder was created.
+//!
+//! A recording can be loaded using [Recording::load], and then be served on any
+//! [ServerEndpoint] using [Recording::replay]. The replay matches each incoming channel
+//! to a recorded channel with the same first request, and answers with the recorded
+//! responses. Timing is not reproduced.
+//!
+//! This allows capturing a session against a real server, e.g. using the quinn transport,
+//! and replaying it in tests using the flume transport.
+//!
+//! # File format
+//!
+//! A recording is a sequence of entries. Each entry consists of the channel id (u64),
+//! the elapsed time in microseconds (u64), the direction (u8, 0 for client to server
+//! and 1 for server to client), the payload length (u32) and the payload. All integers
+//! are little endian. The payload is the message encoded with the same bincode encoding
+//! that is used by the quinn transport.
+use super::{
+    Connection, ConnectionCommon, ConnectionErrors, LocalAddr, Metadata, PeerInfo, ServerEndpoint,
+    SizeLimits, Status,
+};
+use crate::RpcMessage;
+use bincode::Options;
+use futures::{ready, stream::FuturesUnordered, Future, Sink, SinkExt, Stream, StreamExt};
+use pin_project::pin_project;
+use serde::{de::DeserializeOwned, Serialize};
+use std::{
+    fmt,
+    io::{self, Read, Write},
+    pin::Pin,
+    result,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+/// The maximum size of a recorded message, same as for the quinn transport.
+const MAX_FRAME_LENGTH: usize = 1024 * 1024 * 16;
+
+/// The bincode options used by the quinn transport
+fn bincode_options() -> impl Options {
+    bincode::DefaultOptions::new().with_fixint_encoding()
+}
+
+/// Direction of a recorded message
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// A request or update sent from the client to the server
+    ClientToServer,
+    /// A response sent from the server to the client
+    ServerToClient,
+}
+
+/// A single recorded message
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    /// Id of the channel, in the order in which channels were opened or accepted
+    pub channel: u64,
+    /// Time since the recorder was created
+    pub elapsed: Duration,
+    /// Direction of the message
+    pub direction: Direction,
+    /// The bincode encoded message
+    pub payload: Vec<u8>,
+}
+
+impl Entry {
+    /// Decode the payload
+    pub fn decode<T: DeserializeOwned>(&self) -> bincode::Result<T> {
+        bincode_options().deserialize(&self.payload)
+    }
+
+    fn write(&self, mut writer: impl Write) -> io::Result<()> {
+        let direction: u8 = match self.direction {
+            Direction::ClientToServer => 0,
+            Direction::ServerToClient => 1,
+        };
+        let elapsed = u64::try_from(self.elapsed.as_micros()).unwrap_or(u64::MAX);
+        let len = u32::try_from(self.payload.len())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "payload too large"))?;
+        writer.write_all(&self.channel.to_le_bytes())?;
+        writer.write_all(&elapsed.to_le_bytes())?;
+        writer.write_all(&[direction])?;
+        writer.write_all(&len.to_le_bytes())?;
+        writer.write_all(&self.payload)
+    }
+
+    /// Read an entry, returns `None` at the end of the recording
+    fn read(mut reader: impl Read) -> io::Result<Option<Self>> {
+        let mut channel = [0u8; 8];
+        // a clean end of file is only allowed at an entry boundary
+        match reader.read(&mut channel[..1])? {
+            0 => return Ok(None),
+            _ => reader.read_exact(&mut channel[1..])?,
+        }
+        let mut elapsed = [0u8; 8];
+        reader.read_exact(&mut elapsed)?;
+        let mut direction = [0u8; 1];
+        reader.read_exact(&mut direction)?;
+        let direction = match direction[0] {
+            0 => Direction::ClientToServer,
+            1 => Direction::ServerToClient,
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "invalid direction",
+                ))
+            }
+        };
+        let mut len = [0u8; 4];
+        reader.read_exact(&mut len)?;
+        let len = u32::from_le_bytes(len) as usize;
+        if len > MAX_FRAME_LENGTH {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "payload too large",
+            ));
+        }
+        let mut payload = vec![0u8; len];
+        reader.read_exact(&mut payload)?;
+        Ok(Some(Self {
+            channel: u64::from_le_bytes(channel),
+            elapsed: Duration::from_micros(u64::from_le_bytes(elapsed)),
+            direction,
+            payload,
+        }))
+    }
+}
+
+struct RecorderInner {
+    writer: Box<dyn Write + Send>,
+    /// the first error that happened when writing, if any
+    error: Option<io::Error>,
+}
+
+/// Destination for recorded messages
+///
+/// This can be shared between multiple recording connections or endpoints. Errors
+/// when writing do not affect the recorded channels, but are reported by
+/// [Recorder::finish].
+#[derive(Clone)]
+pub struct Recorder {
+    inner: Arc<Mutex<RecorderInner>>,
+    start: Instant,
+    next_channel: Arc<AtomicU64>,
+}
+
+impl fmt::Debug for Recorder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Recorder")
+            .field("start", &self.start)
+            .field("next_channel", &self.next_channel)
+            .finish()
+    }
+}
+
+impl Recorder {
+    /// Create a new recorder that writes to the given writer
+    pub fn new(writer: impl Write + Send + 'static) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(RecorderInner {
+                writer: Box::new(writer),
+                error: None,
+            })),
+            start: Instant::now(),
+            next_channel: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// Flush the writer, and return the first error that happened while recording
+    pub fn finish(&self) -> io::Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(cause) = inner.error.take() {
+            return Err(cause);
+        }
+        inner.writer.flush()
+    }
+
+    fn next_channel(&self) -> u64 {
+        self.next_channel.fetch_add(1, Ordering::SeqCst)
+    }
+
+    fn record(&self, channel: u64, direction: Direction, msg: &impl Serialize) {
+        let elapsed = self.start.elapsed();
+        let mut inner = self.inner.lock().unwrap();
+        if inner.error.is_some() {
+            return;
+        }
+        let res = bincode_options()
+            .serialize(msg)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+            .and_then(|payload| {
+                let entry = Entry {
+                    channel,
+                    elapsed,
+                    direction,
+                    payload,
+                };
+                entry.write(&mut inner.writer)
+            });
+        if let Err(cause) = res {
+            tracing::warn!("Error recording message: {}", cause);
+            inner.error = Some(cause);
+        }
+    }
+}
+
+/// A connection that records all messages
+#[derive(Debug, Clone)]
+pub struct RecordingConnection<C> {
+    inner: C,
+    recorder: Recorder,
+}
+
+impl<C> RecordingConnection<C> {
+    /// Wrap a connection
+    pub fn new(inner: C, recorder: Recorder) -> Self {
+        Self { inner, recorder }
+    }
+
+    /// Get back the inner connection
+    pub fn into_inner(self) -> C {
+        self.inner
+    }
+}
+
+/// A server endpoint that records all messages
+#[derive(Debug, Clone)]
+pub struct RecordingServerEndpoint<C> {
+    inner: C,
+    recorder: Recorder,
+}
+
+impl<C> RecordingServerEndpoint<C> {
+    /// Wrap a server endpoint
+    pub fn new(inner: C, recorder: Recorder) -> Self {
+        Self { inner, recorder }
+    }
+
+    /// Get back the inner server endpoint
+    pub fn into_inner(self) -> C {
+        self.inner
+    }
+}
+
+/// Send sink for recording channels
+pub struct SendSink<C: ConnectionCommon<In, Out>, In, Out> {
+    inner: C::SendSink,
+    recorder: Recorder,
+    channel: u64,
+    direction: Direction,
+}
+
+impl<C: ConnectionCommon<In, Out>, In, Out> fmt::Debug for SendSink<C, In, Out> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SendSink")
+            .field("channel", &self.channel)
+            .finish()
+    }
+}
+
+impl<C: ConnectionCommon<In, Out>, In, Out: Serialize> Sink<Out> for SendSink<C, In, Out> {
+    type Error = C::SendError;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.get_mut().inner).poll_ready(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: Out) -> Result<(), Self::Error> {
+        let this = self.get_mut();
+        this.recorder.record(this.channel, this.direction, &item);
+        Pin::new(&mut this.inner).start_send(item)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.get_mut().inner).poll_close(cx)
+    }
+}
+
+/// Receive stream for recording channels
+pub struct RecvStream<C: ConnectionCommon<In, Out>, In, Out> {
+    inner: C::RecvStream,
+    recorder: Recorder,
+    channel: u64,
+    direction: Direction,
+}
+
+impl<C: ConnectionCommon<In, Out>, In, Out> fmt::Debug for RecvStream<C, In, Out> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RecvStream")
+            .field("channel", &self.channel)
+            .finish()
+    }
+}
+
+impl<C: ConnectionCommon<In, Out>, In: Serialize, Out> Stream for RecvStream<C, In, Out> {
+    type Item = result::Result<In, C::RecvError>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        let item = ready!(Pin::new(&mut this.inner).poll_next(cx));
+        if let Some(Ok(item)) = &item {
+            this.recorder.record(this.channel, this.direction, item);
+        }
+        Poll::Ready(item)
+    }
+}
+
+type Socket<C, In, Out> = (SendSink<C, In, Out>, RecvStream<C, In, Out>);
+
+/// Wrap a channel. `outgoing` is the direction of messages sent on this side.
+fn wrap_socket<C: ConnectionCommon<In, Out>, In, Out>(
+    (send, recv): (C::SendSink, C::RecvStream),
+    recorder: &Recorder,
+    outgoing: Direction,
+) -> Socket<C, In, Out> {
+    let channel = recorder.next_channel();
+    let incoming = match outgoing {
+        Direction::ClientToServer => Direction::ServerToClient,
+        Direction::ServerToClient => Direction::ClientToServer,
+    };
+    let send = SendSink {
+        inner: send,
+        recorder: recorder.clone(),
+        channel,
+        direction: outgoing,
+    };
+    let recv = RecvStream {
+        inner: recv,
+        recorder: recorder.clone(),
+        channel,
+        direction: incoming,
+    };
+    (send, recv)
+}
+
+/// Future returned by open_bi
+#[pin_project]
+pub struct OpenBiFuture<C: Connection<In, Out>, In, Out> {
+    #[pin]
+    inner: C::OpenBiFut,
+    recorder: Recorder,
+}
+
+impl<C: Connection<In, Out>, In, Out> fmt::Debug for OpenBiFuture<C, In, Out> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("OpenBiFuture").finish()
+    }
+}
+
+impl<C: Connection<In, Out>, In, Out> Future for OpenBiFuture<C, In, Out> {
+    type Output = result::Result<Socket<C, In, Out>, C::OpenError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+        this.inner.poll(cx).map_ok(|socket| {
+            wrap_socket::<C, In, Out>(socket, this.recorder, Direction::ClientToServer)
+        })
+    }
+}
+
+/// Future returned by accept_bi
+#[pin_project]
+pub struct AcceptBiFuture<C: ServerEndpoint<In, Out>, In, Out> {
+    #[pin]
+    inner: C::AcceptBiFut,
+    recorder: Recorder,
+}
+
+impl<C: ServerEndpoint<In, Out>, In, Out> fmt::Debug for AcceptBiFuture<C, In, Out> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AcceptBiFuture").finish()
+    }
+}
+
+impl<C: ServerEndpoint<In, Out>, In, Out> Future for AcceptBiFuture<C, In, Out> {
+    type Output = result::Result<Socket<C, In, Out>, C::OpenError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+        this.inner.poll(cx).map_ok(|socket| {
+            wrap_socket::<C, In, Out>(socket, this.recorder, Direction::ServerToClient)
+        })
+    }
+}
+
+impl<C: ConnectionErrors> ConnectionErrors for RecordingConnection<C> {
+    type SendError = C::SendError;
+    type RecvError = C::RecvError;
+    type OpenError = C::OpenError;
+}
+
+impl<C: ConnectionCommon<In, Out>, In: RpcMessage, Out: RpcMessage> ConnectionCommon<In, Out>
+    for RecordingConnection<C>
+{
+    type SendSink = self::SendSink<C, In, Out>;
+    type RecvStream = self::RecvStream<C, In, Out>;
+
+    fn limit_message_size(
+        send: &mut Self::SendSink,
+        recv: &mut Self::RecvStream,
+        limits: SizeLimits,
+    ) -> bool {
+        C::limit_message_size(&mut send.inner, &mut recv.inner, limits)
+    }
+
+    fn received_size(recv: &Self::RecvStream) -> Option<usize> {
+        C::received_size(&recv.inner)
+    }
+
+    fn sent_size(send: &Self::SendSink) -> Option<usize> {
+        C::sent_size(&send.inner)
+    }
+
+    fn cancel(send: &mut Self::SendSink) {
+        C::cancel(&mut send.inner)
+    }
+
+    fn is_cancelled(error: &Self::RecvError) -> bool {
+        C::is_cancelled(error)
+    }
+
+    fn rejection(error: &Self::RecvError) -> Option<Status> {
+        C::rejection(error)
+    }
+}
+
+impl<C: Connection<In, Out>, In: RpcMessage, Out: RpcMessage> Connection<In, Out>
+    for RecordingConnection<C>
+{
+    type OpenBiFut = OpenBiFuture<C, In, Out>;
+
+    fn open_bi(&self) -> Self::OpenBiFut {
+        OpenBiFuture {
+            inner: self.inner.open_bi(),
+            recorder: self.recorder.clone(),
+        }
+    }
+
+    fn open_bi_with_metadata(&self, metadata: Metadata) -> Self::OpenBiFut {
+        OpenBiFuture {
+            inner: self.inner.open_bi_with_metadata(metadata),
+            recorder: self.recorder.clone(),
+        }
+    }
+
+    fn carries_metadata(&self) -> bool {
+        self.inner.carries_metadata()
+    }
+}
+
+impl<C: ConnectionErrors> ConnectionErrors for RecordingServerEndpoint<C> {
+    type SendError = C::SendError;
+    type RecvError = C::RecvError;
+    type OpenError = C::OpenError;
+}
+
+impl<C: ConnectionCommon<In, Out>, In: RpcMessage, Out: RpcMessage> ConnectionCommon<In, Out>
+    for RecordingServerEndpoint<C>
+{
+    type SendSink = self::SendSink<C, In, Out>;
+    type RecvStream = self::RecvStream<C, In, Out>;
+
+    fn limit_message_size(
+        send: &mut Self::SendSink,
+        recv: &mut Self::RecvStream,
+        limits: SizeLimits,
+    ) -> bool {
+        C::limit_message_size(&mut send.inner, &mut recv.inner, limits)
+    }
+
+    fn received_size(recv: &Self::RecvStream) -> Option<usize> {
+        C::received_size(&recv.inner)
+    }
+
+    fn sent_size(send: &Self::SendSink) -> Option<usize> {
+        C::sent_size(&send.inner)
+    }
+
+    fn cancel(send: &mut Self::SendSink) {
+        C::cancel(&mut send.inner)
+    }
+
+    fn is_cancelled(error: &Self::RecvError) -> bool {
+        C::is_cancelled(error)
+    }
+
+    fn rejection(error: &Self::RecvError) -> Option<Status> {
+        C::rejection(error)
+    }
+}
+
+impl<C: ServerEndpoint<In, Out>, In: RpcMessage, Out: RpcMessage> ServerEndpoint<In, Out>
+    for RecordingServerEndpoint<C>
+{
+    type AcceptBiFut = AcceptBiFuture<C, In, Out>;
+
+    fn accept_bi(&self) -> Self::AcceptBiFut {
+        AcceptBiFuture {
+            inner: self.inner.accept_bi(),
+            recorder: self.recorder.clone(),
+        }
+    }
+
+    fn local_addr(&self) -> &[LocalAddr] {
+        self.inner.local_addr()
+    }
+
+    fn metadata(recv: &Self::RecvStream) -> Metadata {
+        C::metadata(&recv.inner)
+    }
+
+    fn peer(recv: &Self::RecvStream) -> PeerInfo {
+        C::peer(&recv.inner)
+    }
+
+    fn reject(send: Self::SendSink, recv: Self::RecvStream, status: Status) {
+        C::reject(send.inner, recv.inner, status)
+    }
+}
+
+/// A loaded recording
+#[derive(Debug, Clone, Default)]
+pub struct Recording {
+    entries: Vec<Entry>,
+}
+
+impl Recording {
+    /// Load a recording
+    pub fn load(mut reader: impl Read) -> io::Result<Self> {
+        let mut entries = Vec::new();
+        while let Some(entry) = Entry::read(&mut reader)? {
+            entries.push(entry);
+        }
+        Ok(Self { entries })
+    }
+
+    /// All recorded messages, in the order in which they were recorded
+    pub fn entries(&self) -> &[Entry] {
+        &self.entries
+    }
+
+    /// The recorded messages, grouped by channel and ordered by channel id
+    fn channels(&self) -> Vec<Vec<&Entry>> {
+        let mut ids = self.entries.iter().map(|e| e.channel).collect::<Vec<_>>();
+        ids.sort_unstable();
+        ids.dedup();
+        ids.into_iter()
+            .map(|id| self.entries.iter().filter(|e| e.channel == id).collect())
+            .collect()
+    }
+
+    /// Answer incoming channels on `endpoint` from the recording.
+    ///
+    /// Each incoming channel is matched to the first unused recorded channel with the
+    /// same first request. The recorded responses are then sent in the recorded order,
+    /// each after the recorded requests and updates that preceded it have been received.
+    /// Channels that can not be matched, or that diverge from the recording, are closed.
+    ///
+    /// This runs until accepting a channel fails.
+    pub async fn replay<In, Out, E>(&self, endpoint: E) -> result::Result<(), E::OpenError>
+    where
+        In: RpcMessage,
+        Out: RpcMessage,
+        E: ServerEndpoint<In, Out>,
+    {
+        let channels = self.channels();
+        let used = Mutex::new(vec![false; channels.len()]);
+        let mut tasks = FuturesUnordered::new();
+        loop {
+            tokio::select! {
+                res = endpoint.accept_bi() => {
+                    let (send, recv) = res?;
+                    tasks.push(replay_channel::<In, Out, E>(&channels, &used, send, recv));
+                }
+                Some(()) = tasks.next(), if !tasks.is_empty() => {}
+            }
+        }
+    }
+}
+
+async fn replay_channel<In, Out, E>(
+    channels: &[Vec<&Entry>],
+    used: &Mutex<Vec<bool>>,
+    mut send: E::SendSink,
+    mut recv: E::RecvStream,
+) where
+    In: RpcMessage,
+    Out: RpcMessage,
+    E: ServerEndpoint<In, Out>,
+{
+    let first = match recv.next().await {
+        Some(Ok(msg)) => msg,
+        _ => return,
+    };
+    let Ok(first) = bincode_options().serialize(&first) else {
+        return;
+    };
+    // find the first unused recorded channel that starts with the same request
+    let script = {
+        let mut used = used.lock().unwrap();
+        let found = channels.iter().enumerate().find(|(i, entries)| {
+            !used[*i]
+                && entries.first().map_or(false, |e| {
+                    e.direction == Direction::ClientToServer && e.payload == first
+                })
+        });
+        match found {
+            Some((i, entries)) => {
+                used[i] = true;
+                entries
+            }
+            None => {
+                tracing::warn!("No recorded channel matches the request");
+                return;
+            }
+        }
+    };
+    for entry in &script[1..] {
+        match entry.direction {
+            Direction::ClientToServer => {
+                let msg = match recv.next().await {
+                    Some(Ok(msg)) => msg,
+                    _ => return,
+                };
+                if bincode_options().serialize(&msg).ok().as_ref() != Some(&entry.payload) {
+                    tracing::warn!("Channel diverged from the recording");
+                    return;
+                }
+            }
+            Direction::ServerToClient => {
+                let msg: Out = match entry.decode() {
+                    Ok(msg) => msg,
+                    Err(cause) => {
+                        tracing::warn!("Unable to decode recorded response: {}", cause);
+                        return;
+                    }
+                };
+                if send.send(msg).await.is_err() {
+                    return;
+                }
+            }
+        }
+    }
+    send.close().await.ok();
+}
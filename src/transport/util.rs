@@ -1,15 +1,62 @@
 use std::{
+    io,
     pin::Pin,
+    sync::{atomic::Ordering, Arc},
     task::{self, Poll},
 };
 
 use bincode::Options;
+use bytes::{Bytes, BytesMut};
 use futures::{Sink, SinkExt, Stream, StreamExt};
 use pin_project::pin_project;
 use serde::{de::DeserializeOwned, Serialize};
 use tokio::io::{AsyncRead, AsyncWrite};
 use tokio_util::codec::LengthDelimitedCodec;
 
+use super::StatsCounters;
+
+/// Length of the length prefix of a frame
+const LENGTH_PREFIX: u64 = 4;
+
+impl StatsCounters {
+    /// Count a message frame that was sent, given the length of its payload
+    pub fn frame_sent(&self, len: usize) {
+        self.frames_sent.fetch_add(1, Ordering::Relaxed);
+        self.bytes_sent
+            .fetch_add(len as u64 + LENGTH_PREFIX, Ordering::Relaxed);
+    }
+
+    /// Count a message frame that was received, given the length of its payload
+    pub fn frame_received(&self, len: usize) {
+        self.frames_received.fetch_add(1, Ordering::Relaxed);
+        self.bytes_received
+            .fetch_add(len as u64 + LENGTH_PREFIX, Ordering::Relaxed);
+    }
+
+    /// Count a message that could not be serialized or deserialized
+    pub fn serialization_error(&self) {
+        self.serialization_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a channel as open until the returned guard is dropped
+    ///
+    /// The guard is shared by both sides of the channel.
+    pub fn open_channel(self: &Arc<Self>) -> Arc<OpenChannel> {
+        self.open_channels.fetch_add(1, Ordering::Relaxed);
+        Arc::new(OpenChannel(self.clone()))
+    }
+}
+
+/// Keeps a channel counted in [StatsCounters::open_channels] while it is alive
+#[derive(Debug)]
+pub struct OpenChannel(Arc<StatsCounters>);
+
+impl Drop for OpenChannel {
+    fn drop(&mut self) {
+        self.0.open_channels.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
 type BincodeEncoding =
     bincode::config::WithOtherIntEncoding<bincode::DefaultOptions, bincode::config::FixintEncoding>;
 /// Wrapper that wraps a bidirectional binary stream in a length delimited codec and bincode with fast fixint encoding
@@ -51,6 +98,34 @@ impl<T, In> FramedBincodeRead<T, In> {
     pub fn into_inner(self) -> T {
         self.0.into_inner().into_inner()
     }
+
+    /// Get the underlying binary stream and the bytes that have already been read
+    /// from it, but not yet decoded
+    pub fn into_parts(self) -> (T, BytesMut) {
+        let mut framed = self.0.into_inner();
+        let buffer = framed.read_buffer_mut().split();
+        (framed.into_inner(), buffer)
+    }
+
+    /// Set the maximum length of a frame
+    ///
+    /// A frame with a larger length prefix fails before any of it is buffered.
+    pub fn set_max_frame_length(&mut self, max_frame_length: usize) {
+        self.0
+            .get_mut()
+            .decoder_mut()
+            .set_max_frame_length(max_frame_length);
+    }
+}
+
+impl<T: AsyncRead + Unpin, In> FramedBincodeRead<T, In> {
+    /// Poll for the next frame, without decoding it as a message
+    pub fn poll_next_frame(
+        &mut self,
+        cx: &mut task::Context<'_>,
+    ) -> Poll<Option<io::Result<BytesMut>>> {
+        self.0.get_mut().poll_next_unpin(cx)
+    }
 }
 
 impl<T: AsyncRead, In: DeserializeOwned> Stream for FramedBincodeRead<T, In> {
@@ -100,6 +175,22 @@ impl<T, Out> FramedBincodeWrite<T, Out> {
     pub fn into_inner(self) -> T {
         self.0.into_inner().into_inner()
     }
+
+    /// Get a mutable reference to the underlying binary stream
+    ///
+    /// Frames that have not been flushed yet are still buffered in the framing.
+    pub fn get_mut(&mut self) -> &mut T {
+        self.0.get_mut().get_mut()
+    }
+}
+
+impl<T: AsyncWrite + Unpin, Out> FramedBincodeWrite<T, Out> {
+    /// Queue a frame that is not encoded as a message
+    ///
+    /// The frame is sent together with the next message.
+    pub fn start_send_frame(&mut self, frame: Bytes) -> io::Result<()> {
+        self.0.get_mut().start_send_unpin(frame)
+    }
 }
 
 impl<T: AsyncWrite, Out: Serialize> Sink<Out> for FramedBincodeWrite<T, Out> {
@@ -0,0 +1,354 @@
+//! Version handshake between clients and servers
+//!
+//! Clients and servers built against different versions of a service do not agree on
+//! the encoding of the messages. Since messages are encoded by their position in the
+//! request and response enums, this shows up as decode errors or downcast errors at
+//! best, and as the wrong request at worst.
+//!
+//! The handshake is opt-in on both sides. A service that implements [Versioned]
+//! describes its request and response enums in a [Schema]. A client created with
+//! [RpcClient::with_handshake](crate::RpcClient::with_handshake) sends the name of the
+//! service and the [Fingerprint] of the schema as metadata with every channel. A server
+//! created with [RpcServer::with_handshake](crate::RpcServer::with_handshake) compares
+//! them with its own before handing out the channel. On a mismatch, it logs the
+//! [IncompatibleVersion] and rejects the channel with
+//! [Status::FailedPrecondition](crate::transport::Status::FailedPrecondition), and
+//! [RpcServer::accept](crate::RpcServer::accept) goes on with the next channel, so a
+//! stale client does not stop the server. The client call fails with
+//! [RpcClientError::IncompatibleVersion](crate::client::RpcClientError::IncompatibleVersion),
+//! or the `IncompatibleVersion` variant of the item error of streaming calls.
+//!
+//! Once the server checks the version, channels without a version are rejected the same
+//! way. The version is sent as [Metadata], so the transport has to carry it, e.g. the
+//! quinn transport only does with `metadata_frame` enabled on both sides. Otherwise the
+//! server could not tell a client without a version from one whose version was dropped.
+//!
+//! The [rpc_service](crate::rpc_service) macro implements [Versioned] for the services
+//! it generates. Its schema contains the names of the variants and of the message types
+//! in them, and the serde formats of the messages, see [Schema::with_formats]. So
+//! changing the fields of a message changes the fingerprint as well.
+use crate::{transport::Metadata, Service};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_reflection::{ContainerFormat, Format, Named, Tracer, TracerConfig, VariantFormat};
+use std::{collections::BTreeMap, error, fmt, fmt::Write, str::FromStr};
+
+/// A variant of a request or response enum
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Variant {
+    /// Name of the variant
+    pub name: String,
+    /// Name of the type of the field of the variant, without the module path
+    pub ty: String,
+}
+
+impl Variant {
+    /// A variant with a field of type `ty`
+    pub fn new(name: impl Into<String>, ty: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            ty: ty.into(),
+        }
+    }
+}
+
+/// The request and response enums of a service
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Schema {
+    /// The variants of the request enum, in declaration order
+    pub request: Vec<Variant>,
+    /// The variants of the response enum, in declaration order
+    pub response: Vec<Variant>,
+    /// The serde formats of the enums and the types they contain, by type name
+    ///
+    /// Empty if the schema only describes the variants. See [Self::with_formats].
+    pub formats: BTreeMap<String, String>,
+}
+
+impl Schema {
+    /// Add the serde formats of the request and response enums of service `S`
+    ///
+    /// The formats are found by tracing the deserialize implementations of the enums with
+    /// [serde_reflection], and are written in a notation of this crate, e.g.
+    /// `struct { id: u64, data: Option<bytes> }`. Enums nested in the messages are only
+    /// traced as far as their first variant. If tracing fails, e.g. because a message
+    /// rejects the values tracing makes up, the formats are left out and a warning is
+    /// logged.
+    pub fn with_formats<S: Service>(mut self) -> Self {
+        match trace::<S::Req, S::Res>() {
+            Ok(formats) => self.formats = formats,
+            Err(cause) => tracing::warn!("Unable to trace the formats of the messages: {}", cause),
+        }
+        self
+    }
+
+    /// The fingerprint of the schema
+    ///
+    /// This is a 64 bit FNV-1a hash of the variants and the formats, so it is the same on
+    /// all platforms and compiler versions.
+    pub fn fingerprint(&self) -> Fingerprint {
+        let mut hash = 0xcbf2_9ce4_8422_2325u64;
+        let enums = [("request", &self.request), ("response", &self.response)];
+        let variants = enums.into_iter().flat_map(|(kind, variants)| {
+            let parts = variants
+                .iter()
+                .flat_map(|v| [v.name.as_str(), v.ty.as_str()]);
+            std::iter::once(kind).chain(parts)
+        });
+        // schemas without formats keep the fingerprint they had before formats were added
+        let formats = (!self.formats.is_empty())
+            .then_some("formats")
+            .into_iter()
+            .chain(
+                self.formats
+                    .iter()
+                    .flat_map(|(ty, f)| [ty.as_str(), f.as_str()]),
+            );
+        // each part is terminated by a zero byte, so parts can not run into each other
+        for part in variants.chain(formats) {
+            for byte in part.bytes().chain(std::iter::once(0)) {
+                hash ^= u64::from(byte);
+                hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+            }
+        }
+        Fingerprint(hash)
+    }
+}
+
+/// Trace the formats of the types in the enums `Req` and `Res`, see [Schema::with_formats]
+fn trace<Req, Res>() -> serde_reflection::Result<BTreeMap<String, String>>
+where
+    Req: DeserializeOwned,
+    Res: DeserializeOwned,
+{
+    let mut tracer = Tracer::new(TracerConfig::default());
+    tracer.trace_simple_type::<Req>()?;
+    tracer.trace_simple_type::<Res>()?;
+    // nested enums are incomplete, but their traced variants are the same on every run
+    let registry = tracer.registry_unchecked();
+    Ok(registry
+        .iter()
+        .map(|(name, container)| {
+            let mut text = String::new();
+            write_container(&mut text, container);
+            (name.clone(), text)
+        })
+        .collect())
+}
+
+fn write_container(out: &mut String, container: &ContainerFormat) {
+    match container {
+        ContainerFormat::UnitStruct => out.push_str("struct"),
+        ContainerFormat::NewTypeStruct(format) => {
+            out.push_str("struct");
+            write_tuple(out, std::slice::from_ref(format.as_ref()));
+        }
+        ContainerFormat::TupleStruct(formats) => {
+            out.push_str("struct");
+            write_tuple(out, formats);
+        }
+        ContainerFormat::Struct(fields) => {
+            out.push_str("struct ");
+            write_fields(out, fields);
+        }
+        ContainerFormat::Enum(variants) => {
+            out.push_str("enum {");
+            for (i, variant) in variants.values().enumerate() {
+                out.push_str(if i == 0 { " " } else { ", " });
+                out.push_str(&variant.name);
+                match &variant.value {
+                    VariantFormat::Variable(_) => out.push_str(" ?"),
+                    VariantFormat::Unit => {}
+                    VariantFormat::NewType(format) => {
+                        write_tuple(out, std::slice::from_ref(format.as_ref()))
+                    }
+                    VariantFormat::Tuple(formats) => write_tuple(out, formats),
+                    VariantFormat::Struct(fields) => {
+                        out.push(' ');
+                        write_fields(out, fields);
+                    }
+                }
+            }
+            out.push_str(" }");
+        }
+    }
+}
+
+fn write_fields(out: &mut String, fields: &[Named<Format>]) {
+    out.push('{');
+    for (i, field) in fields.iter().enumerate() {
+        out.push_str(if i == 0 { " " } else { ", " });
+        out.push_str(&field.name);
+        out.push_str(": ");
+        write_format(out, &field.value);
+    }
+    out.push_str(" }");
+}
+
+fn write_tuple(out: &mut String, formats: &[Format]) {
+    out.push('(');
+    for (i, format) in formats.iter().enumerate() {
+        if i > 0 {
+            out.push_str(", ");
+        }
+        write_format(out, format);
+    }
+    out.push(')');
+}
+
+fn write_format(out: &mut String, format: &Format) {
+    let primitive = match format {
+        Format::Variable(_) => "?",
+        Format::TypeName(name) => name,
+        Format::Unit => "()",
+        Format::Bool => "bool",
+        Format::I8 => "i8",
+        Format::I16 => "i16",
+        Format::I32 => "i32",
+        Format::I64 => "i64",
+        Format::I128 => "i128",
+        Format::U8 => "u8",
+        Format::U16 => "u16",
+        Format::U32 => "u32",
+        Format::U64 => "u64",
+        Format::U128 => "u128",
+        Format::F32 => "f32",
+        Format::F64 => "f64",
+        Format::Char => "char",
+        Format::Str => "str",
+        Format::Bytes => "bytes",
+        Format::Option(format) => {
+            out.push_str("Option<");
+            write_format(out, format);
+            out.push('>');
+            return;
+        }
+        Format::Seq(format) => {
+            out.push('[');
+            write_format(out, format);
+            out.push(']');
+            return;
+        }
+        Format::Map { key, value } => {
+            out.push_str("Map<");
+            write_format(out, key);
+            out.push_str(", ");
+            write_format(out, value);
+            out.push('>');
+            return;
+        }
+        Format::Tuple(formats) => {
+            write_tuple(out, formats);
+            return;
+        }
+        Format::TupleArray { content, size } => {
+            out.push('[');
+            write_format(out, content);
+            write!(out, "; {size}]").ok();
+            return;
+        }
+    };
+    out.push_str(primitive);
+}
+
+/// A hash of the [Schema] of a service
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Fingerprint(pub u64);
+
+/// As 16 lowercase hex digits
+impl fmt::Display for Fingerprint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:016x}", self.0)
+    }
+}
+
+impl FromStr for Fingerprint {
+    type Err = std::num::ParseIntError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        u64::from_str_radix(s, 16).map(Self)
+    }
+}
+
+/// A service that can take part in the version handshake
+pub trait Versioned: Service {
+    /// Name of the service
+    const NAME: &'static str;
+
+    /// Describe the request and response enums of the service
+    fn schema() -> Schema;
+}
+
+/// The name and fingerprint of a service, as exchanged in the handshake
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Version {
+    /// Name of the service
+    pub service: String,
+    /// Fingerprint of the schema of the service
+    pub fingerprint: Fingerprint,
+}
+
+impl Version {
+    /// The metadata key for the name of the service
+    pub const SERVICE: &'static str = "rpc-service";
+    /// The metadata key for the fingerprint
+    pub const FINGERPRINT: &'static str = "rpc-fingerprint";
+
+    /// The version of the service `S`
+    pub fn of<S: Versioned>() -> Self {
+        Self {
+            service: S::NAME.to_string(),
+            fingerprint: S::schema().fingerprint(),
+        }
+    }
+
+    /// Get the version from the `rpc-service` and `rpc-fingerprint` entries of the
+    /// metadata
+    pub fn from_metadata(metadata: &Metadata) -> Option<Self> {
+        Some(Self {
+            service: metadata.get(Self::SERVICE)?.to_string(),
+            fingerprint: metadata.get(Self::FINGERPRINT)?.parse().ok()?,
+        })
+    }
+
+    /// Set the `rpc-service` and `rpc-fingerprint` entries of the metadata
+    pub fn to_metadata(&self, metadata: &mut Metadata) {
+        metadata.insert(Self::SERVICE, self.service.clone());
+        metadata.insert(Self::FINGERPRINT, self.fingerprint.to_string());
+    }
+}
+
+/// As `service/fingerprint`
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.service, self.fingerprint)
+    }
+}
+
+/// The client of a channel was built for a different version of the service, or did not
+/// send a version
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IncompatibleVersion {
+    /// The version of the server
+    pub server: Version,
+    /// The version the client sent, None if it did not send one
+    pub client: Option<Version>,
+}
+
+impl fmt::Display for IncompatibleVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.client {
+            Some(client) => write!(
+                f,
+                "incompatible version: server has {}, client has {}",
+                self.server, client
+            ),
+            None => write!(
+                f,
+                "incompatible version: server has {}, client sent none",
+                self.server
+            ),
+        }
+    }
+}
+
+impl error::Error for IncompatibleVersion {}
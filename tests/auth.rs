@@ -0,0 +1,114 @@
+use quic_rpc::auth::{Identity, Policy, TokenFile};
+
+#[test]
+fn token_file_parse() {
+    let tokens = TokenFile::parse("# comment\n\nsecret alice\n  other bob  \n").unwrap();
+    // the tokens are not printed
+    assert_eq!(format!("{tokens:?}"), "TokenFile { tokens: 2 }");
+    let err = TokenFile::parse("secret alice\nsecret\n").unwrap_err();
+    assert_eq!(err.to_string(), "line 2: expected a token and a name");
+}
+
+#[test]
+fn policy_roles() {
+    let policy = Policy::new()
+        .allow("admin", "*")
+        .allow("user", "Get")
+        .allow("mallory", "Get")
+        .grant("carol", "Put")
+        .assign("alice", "admin")
+        .assign("bob", "user")
+        .assign("carol", "user");
+    let alice = Identity::new("alice");
+    let bob = Identity::new("bob");
+    let carol = Identity::new("carol");
+    let mallory = Identity::new("mallory");
+    assert!(policy.is_allowed(&alice, "Shutdown"));
+    assert!(policy.is_allowed(&bob, "Get"));
+    assert!(!policy.is_allowed(&bob, "Put"));
+    // methods can be granted to an identity directly
+    assert!(policy.is_allowed(&carol, "Get"));
+    assert!(policy.is_allowed(&carol, "Put"));
+    assert!(!policy.is_allowed(&bob, "Shutdown"));
+    // an identity does not get the methods of a role with the same name
+    assert!(!policy.is_allowed(&mallory, "Get"));
+}
+
+#[cfg(all(feature = "toml", feature = "serde_json"))]
+#[test]
+fn policy_files() {
+    let expected = Policy::new()
+        .allow("admin", "*")
+        .allow("user", "Get")
+        .grant("carol", "Put")
+        .assign("alice", "admin")
+        .assign("bob", "user");
+    let toml = r#"
+        [roles]
+        admin = ["*"]
+        user = ["Get"]
+
+        [identities]
+        alice = ["admin"]
+        bob = ["user"]
+
+        [grants]
+        carol = ["Put"]
+    "#;
+    assert_eq!(Policy::from_toml(toml).unwrap(), expected);
+    let json = r#"{
+        "roles": { "admin": ["*"], "user": ["Get"] },
+        "identities": { "alice": ["admin"], "bob": ["user"] },
+        "grants": { "carol": ["Put"] }
+    }"#;
+    assert_eq!(Policy::from_json(json).unwrap(), expected);
+    assert!(Policy::from_toml("[roles]\nadmin = \"*\"").is_err());
+}
+
+#[cfg(feature = "macros")]
+mod method_names {
+    use derive_more::{From, TryInto};
+    use quic_rpc::{declare_rpc, message::Msg, Service};
+    use serde::{Deserialize, Serialize};
+
+    mod admin {
+        #[derive(Debug, serde::Serialize, serde::Deserialize)]
+        pub struct Delete;
+    }
+
+    mod user {
+        #[derive(Debug, serde::Serialize, serde::Deserialize)]
+        pub struct Delete;
+    }
+
+    #[derive(Debug, Serialize, Deserialize, From, TryInto)]
+    enum Request {
+        AdminDelete(admin::Delete),
+        UserDelete(user::Delete),
+    }
+
+    #[derive(Debug, Serialize, Deserialize, From, TryInto)]
+    enum Response {
+        Deleted(bool),
+    }
+
+    #[derive(Debug, Clone)]
+    struct DeleteService;
+
+    impl Service for DeleteService {
+        type Req = Request;
+        type Res = Response;
+    }
+
+    declare_rpc!(DeleteService, admin::Delete, bool);
+    declare_rpc!(DeleteService, user::Delete, bool);
+
+    /// types with the same name in different modules are different methods
+    #[test]
+    fn method_names_are_unique() {
+        let admin = <admin::Delete as Msg<DeleteService>>::method_name();
+        let user = <user::Delete as Msg<DeleteService>>::method_name();
+        assert_ne!(admin, user);
+        assert!(admin.ends_with("admin::Delete"));
+    }
+}
@@ -0,0 +1,95 @@
+#![cfg(any(feature = "hyper-transport", feature = "quinn-transport"))]
+#![allow(dead_code)]
+use derive_more::{From, TryInto};
+use quic_rpc::{
+    declare_raw_streaming,
+    message::Msg,
+    metrics::Metrics,
+    server::RpcServerError,
+    telemetry::{Side, Telemetry},
+    transport::RawStreams,
+    RpcClient, RpcServer, Service, ServiceConnection, ServiceEndpoint,
+};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+/// echo the raw bytes back to the client
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Echo;
+
+/// request enum
+#[derive(Debug, Serialize, Deserialize, From, TryInto)]
+pub enum BlobRequest {
+    Echo(Echo),
+}
+
+/// response enum, there are no typed responses
+#[derive(Debug, Serialize, Deserialize)]
+pub enum BlobResponse {}
+
+#[derive(Debug, Clone)]
+pub struct BlobService;
+
+impl Service for BlobService {
+    type Req = BlobRequest;
+    type Res = BlobResponse;
+}
+
+declare_raw_streaming!(BlobService, Echo);
+
+impl BlobService {
+    pub async fn server<C>(server: RpcServer<BlobService, C>) -> Result<(), RpcServerError<C>>
+    where
+        C: ServiceEndpoint<BlobService> + RawStreams<BlobRequest, BlobResponse>,
+    {
+        loop {
+            let (req, chan) = server.accept().await?;
+            tokio::spawn(async move {
+                match req {
+                    BlobRequest::Echo(req) => {
+                        chan.raw_streaming(req, (), |_, _, mut send, mut recv| async move {
+                            tokio::io::copy(&mut recv, &mut send).await.ok();
+                            send.shutdown().await.ok();
+                        })
+                        .await
+                    }
+                }
+            });
+        }
+    }
+}
+
+/// send a blob right after the request, and check that it comes back unchanged
+pub async fn raw_echo_test<C>(client: C, size: usize) -> anyhow::Result<()>
+where
+    C: ServiceConnection<BlobService> + RawStreams<BlobResponse, BlobRequest>,
+{
+    let metrics = Metrics::new();
+    let client = RpcClient::<BlobService, C>::new(client)
+        .with_telemetry(Telemetry::disabled().with_metrics(metrics.clone()));
+    let data = (0..size).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+    let (mut send, mut recv) = client.raw_streaming(Echo).await?;
+    let writer = tokio::spawn({
+        let data = data.clone();
+        async move {
+            send.write_all(&data).await?;
+            send.shutdown().await?;
+            anyhow::Ok(())
+        }
+    });
+    let mut res = Vec::new();
+    recv.read_to_end(&mut res).await?;
+    writer.await??;
+    assert_eq!(res.len(), data.len());
+    assert!(res == data);
+    // the call lasts until the end of the raw receive side
+    let snapshot = metrics.snapshot();
+    let echo = snapshot
+        .get(Side::Client, <Echo as Msg<BlobService>>::method_name())
+        .unwrap();
+    assert_eq!(echo.in_flight, 0);
+    assert_eq!(echo.error_count(), 0);
+    assert_eq!(echo.latency.count(), 1);
+    assert_eq!(echo.bytes, size as u64);
+    Ok(())
+}
@@ -0,0 +1,153 @@
+#![cfg(all(feature = "checked-transport", feature = "flume-transport"))]
+use derive_more::{From, TryInto};
+use quic_rpc::{
+    client::RpcClientError,
+    declare_rpc,
+    server::RpcServerError,
+    transport::checked::{self, CheckedServerEndpoint, RecvError, SendError},
+    transport::flume::FlumeServerEndpoint,
+    RpcClient, RpcServer, Service,
+};
+use serde::{Deserialize, Serialize};
+
+mod math;
+use math::*;
+
+/// simple happy path test for all 4 patterns
+#[tokio::test]
+async fn checked_flume_channel_smoke() -> anyhow::Result<()> {
+    tracing_subscriber::fmt::try_init().ok();
+    let (server, client) = checked::connection::<ComputeRequest, ComputeResponse>(1);
+
+    let server = RpcServer::<ComputeService, _>::new(server);
+    let server_handle = tokio::task::spawn(ComputeService::server(server));
+    smoke_test(client).await?;
+
+    // dropping the client will cause the server to terminate
+    match server_handle.await? {
+        Err(RpcServerError::Accept(_)) => {}
+        e => panic!("unexpected termination result {e:?}"),
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn checked_flume_channel_errors() -> anyhow::Result<()> {
+    type SC = CheckedServerEndpoint<FlumeServerEndpoint<TestRequest, TestResponse>>;
+
+    /// request that looks serializable but isn't
+    #[derive(Debug, Serialize, Deserialize)]
+    pub struct NoSerRequest(NoSer);
+
+    /// request that looks deserializable but isn't
+    #[derive(Debug, Serialize, Deserialize)]
+    pub struct NoDeserRequest(NoDeser);
+
+    /// request with a field that does not make it over the wire
+    #[derive(Debug, Serialize, Deserialize)]
+    pub struct SkipRequest {
+        #[serde(skip)]
+        value: u64,
+    }
+
+    /// helper struct that implements serde::Serialize but errors on serialization
+    #[derive(Debug, Deserialize)]
+    pub struct NoSer;
+
+    impl serde::Serialize for NoSer {
+        fn serialize<S>(&self, _serializer: S) -> Result<S::Ok, S::Error>
+        where
+            S: serde::Serializer,
+        {
+            Err(serde::ser::Error::custom("nope"))
+        }
+    }
+
+    /// helper struct that implements serde::Deserialize but errors on deserialization
+    #[derive(Debug, Serialize)]
+    pub struct NoDeser;
+
+    impl<'de> serde::Deserialize<'de> for NoDeser {
+        fn deserialize<D>(_deserializer: D) -> Result<Self, D::Error>
+        where
+            D: serde::Deserializer<'de>,
+        {
+            Err(serde::de::Error::custom("nope"))
+        }
+    }
+
+    #[allow(clippy::enum_variant_names)]
+    #[derive(Debug, Serialize, Deserialize, From, TryInto)]
+    enum TestRequest {
+        NoSerRequest(NoSerRequest),
+        NoDeserRequest(NoDeserRequest),
+        SkipRequest(SkipRequest),
+    }
+
+    #[derive(Debug, Serialize, Deserialize, From, TryInto)]
+    enum TestResponse {
+        Unit(()),
+        U64(u64),
+    }
+
+    #[derive(Debug, Clone)]
+    struct TestService;
+
+    impl Service for TestService {
+        type Req = TestRequest;
+        type Res = TestResponse;
+    }
+
+    declare_rpc!(TestService, NoSerRequest, ());
+    declare_rpc!(TestService, NoDeserRequest, ());
+    declare_rpc!(TestService, SkipRequest, u64);
+
+    let (server, client) = checked::connection::<TestRequest, TestResponse>(1);
+    let server = RpcServer::<TestService, _>::new(server);
+    let (res_tx, res_rx) = flume::unbounded();
+    let server_handle = tokio::spawn(async move {
+        loop {
+            let res: Result<(), RpcServerError<SC>> = match server.accept().await {
+                Ok((TestRequest::SkipRequest(req), chan)) => {
+                    chan.rpc(req, (), |_, req| async move { req.value }).await
+                }
+                Ok((_, chan)) => {
+                    chan.rpc(SkipRequest { value: 0 }, (), |_, _| async { 0 })
+                        .await
+                }
+                Err(e) => Err(e),
+            };
+            if res_tx.send_async(res).await.is_err() {
+                break;
+            }
+        }
+    });
+    let client = RpcClient::<TestService, _>::new(client);
+
+    // not serializable - should fail immediately after opening a connection
+    let res = client.rpc(NoSerRequest(NoSer)).await;
+    assert!(matches!(
+        res,
+        Err(RpcClientError::Send(SendError::SerializeError(_)))
+    ));
+    assert!(matches!(
+        res_rx.recv_async().await?,
+        Err(RpcServerError::EarlyClose)
+    ));
+
+    // not deserializable - should fail on the server side
+    let res = client.rpc(NoDeserRequest(NoDeser)).await;
+    assert!(matches!(res, Err(RpcClientError::EarlyClose)));
+    assert!(matches!(
+        res_rx.recv_async().await?,
+        Err(RpcServerError::RecvError(RecvError::DeserializeError(_)))
+    ));
+
+    // skipped fields are lost, just like on a real wire
+    let res = client.rpc(SkipRequest { value: 42 }).await?;
+    assert_eq!(res, 0);
+    assert!(matches!(res_rx.recv_async().await?, Ok(())));
+
+    server_handle.abort();
+    Ok(())
+}
@@ -0,0 +1,248 @@
+#![cfg(all(feature = "flume-transport", feature = "cli"))]
+mod math;
+use futures::{stream, StreamExt, TryStreamExt};
+use math::*;
+use quic_rpc::{
+    cli::{
+        self,
+        schema::{Frame, ServiceSchema},
+        CallError,
+    },
+    reflection::{MethodInfo, PatternKind, Reflect, ServiceInfo},
+    transport::{
+        flume,
+        hyper::{HyperConnection, HyperServerEndpoint},
+    },
+    RpcServer, Service,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+
+impl Reflect for ComputeService {
+    fn service_info() -> ServiceInfo {
+        let method =
+            |name: &str, request: &str, pattern, update: Option<&str>, response: &str| MethodInfo {
+                name: name.to_string(),
+                request: request.to_string(),
+                pattern,
+                update: update.map(ToString::to_string),
+                response: Some(response.to_string()),
+            };
+        ServiceInfo {
+            name: "ComputeService".to_string(),
+            methods: vec![
+                method("sqr", "Sqr", PatternKind::Rpc, None, "SqrResponse"),
+                method(
+                    "sum",
+                    "Sum",
+                    PatternKind::ClientStreaming,
+                    Some("SumUpdate"),
+                    "SumResponse",
+                ),
+                method(
+                    "fibonacci",
+                    "Fibonacci",
+                    PatternKind::ServerStreaming,
+                    None,
+                    "FibonacciResponse",
+                ),
+                method(
+                    "multiply",
+                    "Multiply",
+                    PatternKind::BidiStreaming,
+                    Some("MultiplyUpdate"),
+                    "MultiplyResponse",
+                ),
+            ],
+        }
+    }
+}
+
+#[tokio::test]
+async fn flume_cli_call() -> anyhow::Result<()> {
+    let (server, client) = flume::connection::<ComputeRequest, ComputeResponse>(1);
+    let server = RpcServer::<ComputeService, _>::new(server);
+    let server_handle = tokio::task::spawn(ComputeService::server(server));
+
+    let call = |method: &'static str, request: Value, updates: Vec<Value>| {
+        let client = client.clone();
+        async move {
+            cli::call::<ComputeService, _>(&client, method, request, stream::iter(updates))
+                .await?
+                .try_collect::<Vec<_>>()
+                .await
+        }
+    };
+
+    // by method name and by request type name
+    assert_eq!(call("sqr", json!(12), vec![]).await?, vec![json!(144)]);
+    assert_eq!(call("Sqr", json!(3), vec![]).await?, vec![json!(9)]);
+    assert_eq!(
+        call("sum", Value::Null, vec![json!(1), json!(2), json!(3)]).await?,
+        vec![json!(6)]
+    );
+    assert_eq!(
+        call("fibonacci", json!(5), vec![]).await?,
+        vec![json!(0), json!(1), json!(1), json!(2), json!(3)]
+    );
+    assert_eq!(
+        call("multiply", json!(2), vec![json!(1), json!(2)]).await?,
+        vec![json!(2), json!(4)]
+    );
+
+    assert!(matches!(
+        call("divide", json!(1), vec![]).await,
+        Err(CallError::UnknownMethod(_))
+    ));
+    assert!(matches!(
+        call("sqr", json!("twelve"), vec![]).await,
+        Err(CallError::Json(_))
+    ));
+    // an invalid update only fails the stream, after the request has been sent
+    let res =
+        cli::call::<ComputeService, _>(&client, "sum", Value::Null, stream::iter([json!("x")]))
+            .await?
+            .next()
+            .await;
+    assert!(matches!(res, Some(Err(CallError::Json(_)))));
+
+    server_handle.abort();
+    Ok(())
+}
+
+/// a message with all kinds of types, to check the schema against bincode
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct Everything {
+    name: String,
+    id: [u8; 4],
+    tags: Vec<(u16, char)>,
+    limit: Option<i64>,
+    weights: BTreeMap<u32, f64>,
+    flag: bool,
+    nothing: (),
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub enum KitchenRequest {
+    Everything(Everything),
+    Ping,
+    Pair(u8, i128),
+    Named { text: String, bytes: Vec<u8> },
+}
+
+#[derive(Debug, Clone)]
+pub struct KitchenService;
+
+impl Service for KitchenService {
+    type Req = KitchenRequest;
+    type Res = KitchenRequest;
+}
+
+impl Reflect for KitchenService {
+    fn service_info() -> ServiceInfo {
+        ServiceInfo {
+            name: "KitchenService".to_string(),
+            methods: vec![],
+        }
+    }
+}
+
+fn bincode(value: &impl Serialize) -> Vec<u8> {
+    use bincode::Options;
+    bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .serialize(value)
+        .unwrap()
+}
+
+#[test]
+fn schema_matches_bincode() -> anyhow::Result<()> {
+    let schema = ServiceSchema::of::<KitchenService>()?;
+    // the schema is stored as json
+    let schema: ServiceSchema = serde_json::from_str(&serde_json::to_string(&schema)?)?;
+    let messages = [
+        KitchenRequest::Everything(Everything {
+            name: "all".to_string(),
+            id: [1, 2, 3, 4],
+            tags: vec![(7, 'x'), (8, 'ü')],
+            limit: Some(-3),
+            weights: [(1, 0.5), (2, 2.0)].into_iter().collect(),
+            flag: true,
+            nothing: (),
+        }),
+        KitchenRequest::Ping,
+        KitchenRequest::Pair(1, -12),
+        KitchenRequest::Named {
+            text: "text".to_string(),
+            bytes: vec![0, 255],
+        },
+    ];
+    for message in messages {
+        let value = serde_json::to_value(&message)?;
+        let (variant, inner) = match &value {
+            Value::String(variant) => (variant.clone(), Value::Null),
+            Value::Object(map) => map
+                .iter()
+                .next()
+                .map(|(k, v)| (k.clone(), v.clone()))
+                .unwrap(),
+            other => anyhow::bail!("unexpected json {other}"),
+        };
+        let frame = schema.encode_request(&variant, inner)?;
+        assert_eq!(frame.0, bincode(&message), "encoding {message:?}");
+        assert_eq!(schema.decode_response(&frame)?, value);
+    }
+    assert!(schema.encode_request("Pair", json!([1])).is_err());
+    assert!(schema.encode_request("Unknown", Value::Null).is_err());
+    assert!(schema.decode_response(&Frame(vec![9, 0, 0, 0])).is_err());
+    Ok(())
+}
+
+/// a client without the service types calls the service using its schema
+#[tokio::test]
+async fn hyper_cli_schema_call() -> anyhow::Result<()> {
+    let addr = "127.0.0.1:3013".parse()?;
+    let channel = HyperServerEndpoint::<ComputeRequest, ComputeResponse>::serve(&addr)?;
+    let server = RpcServer::<ComputeService, _>::new(channel);
+    let server_handle = tokio::task::spawn(async move {
+        loop {
+            ComputeService::server(server.clone()).await?;
+        }
+        #[allow(unreachable_code)]
+        anyhow::Ok(())
+    });
+    let schema = ServiceSchema::of::<ComputeService>()?;
+    let client = HyperConnection::<Frame, Frame>::new("http://127.0.0.1:3013".parse()?);
+
+    let call = |method: &'static str, request: Value, updates: Vec<Value>| {
+        let client = client.clone();
+        let schema = schema.clone();
+        async move {
+            cli::call_with_schema(&client, &schema, method, request, stream::iter(updates))
+                .await?
+                .try_collect::<Vec<_>>()
+                .await
+        }
+    };
+    assert_eq!(call("sqr", json!(12), vec![]).await?, vec![json!(144)]);
+    assert_eq!(
+        call("sum", Value::Null, vec![json!(1), json!(2), json!(3)]).await?,
+        vec![json!(6)]
+    );
+    assert_eq!(
+        call("fibonacci", json!(5), vec![]).await?,
+        vec![json!(0), json!(1), json!(1), json!(2), json!(3)]
+    );
+    assert_eq!(
+        call("multiply", json!(2), vec![json!(1), json!(2)]).await?,
+        vec![json!(2), json!(4)]
+    );
+    assert!(matches!(
+        call("sqr", json!("twelve"), vec![]).await,
+        Err(CallError::Json(_))
+    ));
+
+    server_handle.abort();
+    Ok(())
+}
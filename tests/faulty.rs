@@ -0,0 +1,247 @@
+#![cfg(all(feature = "faulty-transport", feature = "flume-transport"))]
+use std::time::Duration;
+
+use futures::{SinkExt, StreamExt};
+use quic_rpc::{
+    client::{ClientStreamingItemError, RpcClientError},
+    server::RpcServerError,
+    transport::{
+        faulty::{self, FaultConfig, FaultyConnection, FaultyServerEndpoint},
+        flume::{self, FlumeConnection, FlumeServerEndpoint},
+    },
+    RpcClient, RpcServer,
+};
+
+mod math;
+use math::*;
+
+type SC = FaultyServerEndpoint<FlumeServerEndpoint<ComputeRequest, ComputeResponse>>;
+type CC = FaultyConnection<FlumeConnection<ComputeResponse, ComputeRequest>>;
+
+/// create a flume connection with faults on the client and server side
+fn faulty_connection(
+    server_config: FaultConfig,
+    client_config: FaultConfig,
+) -> (RpcServer<ComputeService, SC>, RpcClient<ComputeService, CC>) {
+    let (server, client) = flume::connection::<ComputeRequest, ComputeResponse>(1);
+    let server = FaultyServerEndpoint::new(server, server_config);
+    let client = FaultyConnection::new(client, client_config);
+    (RpcServer::new(server), RpcClient::new(client))
+}
+
+/// sequentially handle sqr and sum requests, reporting the result of each interaction
+async fn run_server(
+    server: RpcServer<ComputeService, SC>,
+    results: ::flume::Sender<Result<(), RpcServerError<SC>>>,
+) {
+    loop {
+        let res = match server.accept().await {
+            Ok((ComputeRequest::Sqr(req), chan)) => {
+                chan.rpc(req, (), |_, Sqr(x)| async move {
+                    SqrResponse(x as u128 * x as u128)
+                })
+                .await
+            }
+            Ok((ComputeRequest::Sum(req), chan)) => {
+                chan.client_streaming(req, (), |_, _, updates| async move {
+                    SumResponse(
+                        updates
+                            .fold(0, |sum, SumUpdate(x)| async move { sum + x as u128 })
+                            .await,
+                    )
+                })
+                .await
+            }
+            Ok(_) => Err(RpcServerError::UnexpectedStartMessage),
+            Err(cause) => Err(cause),
+        };
+        if results.send_async(res).await.is_err() {
+            break;
+        }
+    }
+}
+
+/// without faults, the wrapper is transparent
+#[tokio::test]
+async fn faulty_flume_channel_smoke() -> anyhow::Result<()> {
+    tracing_subscriber::fmt::try_init().ok();
+    let (server, client) = flume::connection::<ComputeRequest, ComputeResponse>(1);
+    let server = FaultyServerEndpoint::new(server, FaultConfig::default());
+    let client = FaultyConnection::new(client, FaultConfig::default());
+
+    let server = RpcServer::<ComputeService, _>::new(server);
+    let server_handle = tokio::task::spawn(ComputeService::server(server));
+    smoke_test(client).await?;
+
+    // dropping the client will cause the server to terminate
+    match server_handle.await? {
+        Err(RpcServerError::Accept(_)) => {}
+        e => panic!("unexpected termination result {e:?}"),
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn faulty_open_and_accept() -> anyhow::Result<()> {
+    // failing to open a channel never reaches the server
+    let (_server, client) = faulty_connection(
+        FaultConfig::default(),
+        FaultConfig::default().open_failure_rate(1.0),
+    );
+    let res = client.rpc(Sqr(2)).await;
+    assert!(matches!(
+        res,
+        Err(RpcClientError::Open(faulty::OpenError::Injected))
+    ));
+
+    // failing to accept a channel closes it for the client
+    let (server, client) = faulty_connection(
+        FaultConfig::default().accept_failure_rate(1.0),
+        FaultConfig::default(),
+    );
+    let (res_tx, res_rx) = ::flume::unbounded();
+    let server_handle = tokio::spawn(run_server(server, res_tx));
+    let res = client.rpc(Sqr(2)).await;
+    assert!(matches!(res, Err(RpcClientError::EarlyClose)));
+    assert!(matches!(
+        res_rx.recv_async().await?,
+        Err(RpcServerError::Accept(faulty::OpenError::Injected))
+    ));
+    server_handle.abort();
+    Ok(())
+}
+
+#[tokio::test]
+async fn faulty_early_close() -> anyhow::Result<()> {
+    // a dropped response looks like an early close to the client
+    let (server, client) = faulty_connection(
+        FaultConfig::default().drop_rate(1.0),
+        FaultConfig::default(),
+    );
+    let (res_tx, res_rx) = ::flume::unbounded();
+    let server_handle = tokio::spawn(run_server(server, res_tx));
+    let res = client.rpc(Sqr(2)).await;
+    assert!(matches!(res, Err(RpcClientError::EarlyClose)));
+    assert!(matches!(res_rx.recv_async().await?, Ok(())));
+    server_handle.abort();
+
+    // a channel closed while sending the request
+    let (_server, client) = faulty_connection(
+        FaultConfig::default(),
+        FaultConfig::default().close_rate(1.0),
+    );
+    let res = client.rpc(Sqr(2)).await;
+    assert!(matches!(
+        res,
+        Err(RpcClientError::Send(faulty::SendError::Closed))
+    ));
+
+    // a corrupted response
+    let (server, client) = faulty_connection(
+        FaultConfig::default(),
+        FaultConfig::default().corrupt_rate(1.0),
+    );
+    let (res_tx, res_rx) = ::flume::unbounded();
+    let server_handle = tokio::spawn(run_server(server, res_tx));
+    let res = client.rpc(Sqr(2)).await;
+    assert!(!matches!(res, Ok(SqrResponse(4))));
+    assert!(matches!(res_rx.recv_async().await?, Ok(())));
+    server_handle.abort();
+    Ok(())
+}
+
+/// corrupted updates must either alter the result or terminate a client streaming call
+/// with an error on both sides
+#[tokio::test]
+async fn faulty_update_stream_errors() -> anyhow::Result<()> {
+    let mut ok = 0;
+    let mut altered = 0;
+    let mut corrupted_updates = 0;
+    for seed in 0..32 {
+        let (server, client) = faulty_connection(
+            FaultConfig::default().seed(seed).corrupt_rate(0.2),
+            FaultConfig::default(),
+        );
+        let server_handle = tokio::spawn(async move {
+            // a corrupted request fails accept or turns into a different request,
+            // a corrupted update fails the handler or changes the sum
+            let (req, chan) = server.accept().await?;
+            let ComputeRequest::Sum(req) = req else {
+                return Err(RpcServerError::UnexpectedStartMessage);
+            };
+            let res = chan
+                .client_streaming(req, (), |_, _, updates| async move {
+                    SumResponse(
+                        updates
+                            .fold(0, |sum, SumUpdate(x)| async move { sum + x as u128 })
+                            .await,
+                    )
+                })
+                .await;
+            Ok::<_, RpcServerError<SC>>(res)
+        });
+        let (mut send, recv) = client.client_streaming(Sum).await?;
+        for i in 1..=5 {
+            if send.send(SumUpdate(i)).await.is_err() {
+                break;
+            }
+        }
+        drop(send);
+        let res = recv.await;
+        match server_handle.await? {
+            Ok(Ok(())) => match res? {
+                SumResponse(15) => ok += 1,
+                _ => altered += 1,
+            },
+            Ok(Err(
+                RpcServerError::RecvError(faulty::RecvError::DeserializeError(_))
+                | RpcServerError::UnexpectedUpdateMessage,
+            )) => {
+                assert!(matches!(res, Err(ClientStreamingItemError::EarlyClose)));
+                corrupted_updates += 1;
+            }
+            Ok(Err(e)) => panic!("unexpected handler result {e:?}"),
+            Err(
+                RpcServerError::RecvError(faulty::RecvError::DeserializeError(_))
+                | RpcServerError::UnexpectedStartMessage,
+            ) => {
+                assert!(matches!(res, Err(ClientStreamingItemError::EarlyClose)));
+            }
+            Err(e) => panic!("unexpected accept result {e:?}"),
+        }
+    }
+    assert!(ok > 0);
+    assert!(altered > 0);
+    assert!(corrupted_updates > 0);
+    Ok(())
+}
+
+/// the same seed produces the same faults
+#[tokio::test]
+async fn faulty_deterministic() -> anyhow::Result<()> {
+    async fn run(seed: u64) -> anyhow::Result<Vec<String>> {
+        let config = FaultConfig::default()
+            .seed(seed)
+            .drop_rate(0.1)
+            .close_rate(0.1)
+            .corrupt_rate(0.1)
+            .delay(0.2, Duration::from_millis(1));
+        let (server, client) = faulty_connection(config, FaultConfig::default());
+        let (res_tx, res_rx) = ::flume::unbounded();
+        let server_handle = tokio::spawn(run_server(server, res_tx));
+        let mut outcomes = Vec::new();
+        for i in 0..100 {
+            let res = client.rpc(Sqr(i)).await;
+            let server_res = res_rx.recv_async().await?;
+            outcomes.push(format!("{res:?} {server_res:?}"));
+        }
+        server_handle.abort();
+        Ok(outcomes)
+    }
+    let a = run(42).await?;
+    let b = run(42).await?;
+    assert_eq!(a, b);
+    assert!(a.iter().any(|x| x.starts_with("Ok")));
+    assert!(a.iter().any(|x| x.starts_with("Err")));
+    Ok(())
+}
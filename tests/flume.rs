@@ -1,7 +1,14 @@
 #![cfg(feature = "flume-transport")]
 mod math;
 use math::*;
-use quic_rpc::{server::RpcServerError, transport::flume, RpcClient, RpcServer};
+use quic_rpc::{
+    message::{Msg, RpcMsg},
+    server::RpcServerError,
+    transport::{
+        flume, Connection, ConnectionCommon, ConnectionErrors, ServerEndpoint, TransportStats,
+    },
+    RpcClient, RpcServer,
+};
 
 #[tokio::test]
 async fn flume_channel_bench() -> anyhow::Result<()> {
@@ -37,3 +44,247 @@ async fn flume_channel_smoke() -> anyhow::Result<()> {
     }
     Ok(())
 }
+
+#[tokio::test]
+async fn flume_channel_metadata() -> anyhow::Result<()> {
+    let (server, client) = flume::connection::<ComputeRequest, ComputeResponse>(1);
+    metadata_test(server, client).await
+}
+
+#[tokio::test]
+async fn flume_channel_auth() -> anyhow::Result<()> {
+    let (server, client) = flume::connection::<ComputeRequest, ComputeResponse>(1);
+    auth_test(server, client).await
+}
+
+#[tokio::test]
+async fn flume_channel_policy() -> anyhow::Result<()> {
+    let (server, client) = flume::connection::<ComputeRequest, ComputeResponse>(1);
+    policy_test(server, client).await
+}
+
+#[tokio::test]
+async fn flume_channel_stats() -> anyhow::Result<()> {
+    let (server, client) = flume::connection::<ComputeRequest, ComputeResponse>(2);
+    let _first = client.open_bi().await?;
+    let _second = client.open_bi().await?;
+    assert_eq!(client.stats().opened, 2);
+    let stats = server.stats();
+    assert_eq!(stats.accept_queue_len, 2);
+    assert_eq!(stats.accept_queue_capacity, Some(2));
+    let _accepted = server.accept_bi().await?;
+    let stats = server.stats();
+    assert_eq!(stats.accepted, 1);
+    assert_eq!(stats.accept_queue_len, 1);
+    Ok(())
+}
+
+/// smoke test for endpoints bound by name in a registry
+#[tokio::test]
+async fn flume_registry_smoke() -> anyhow::Result<()> {
+    tracing_subscriber::fmt::try_init().ok();
+    let registry = flume::Registry::new();
+    let server = registry.bind::<ComputeRequest, ComputeResponse>("compute", 1)?;
+    assert_eq!(server.local_addr()[0].to_string(), "mem://compute");
+    // binding the same name again fails while the endpoint is alive
+    assert!(matches!(
+        registry.bind::<ComputeRequest, ComputeResponse>("compute", 1),
+        Err(flume::RegistryError::AddrInUse(_))
+    ));
+    // connecting with the wrong types fails
+    assert!(matches!(
+        registry.connect::<ComputeResponse, ComputeRequest>("compute"),
+        Err(flume::RegistryError::TypeMismatch(_))
+    ));
+    assert!(matches!(
+        registry.connect::<ComputeRequest, ComputeResponse>("nothing"),
+        Err(flume::RegistryError::NotFound(_))
+    ));
+
+    let server = RpcServer::<ComputeService, _>::new(server);
+    let server_handle = tokio::task::spawn(ComputeService::server(server));
+    let client = registry.connect::<ComputeRequest, ComputeResponse>("mem://compute")?;
+    smoke_test(client).await?;
+
+    // unbinding and dropping all clients will cause the server to terminate
+    assert!(registry.unbind("compute"));
+    match server_handle.await? {
+        Err(RpcServerError::Accept(_)) => {}
+        e => panic!("unexpected termination result {e:?}"),
+    }
+    // the name is free again
+    registry.bind::<ComputeRequest, ComputeResponse>("compute", 1)?;
+    Ok(())
+}
+
+#[cfg(feature = "test-utils")]
+#[tokio::test]
+async fn flume_channel_conformance() -> anyhow::Result<()> {
+    use quic_rpc::testing::{self, TestRequest, TestResponse};
+    tracing_subscriber::fmt::try_init().ok();
+    let (server, client) = flume::connection::<TestRequest, TestResponse>(1);
+    testing::run_all(server, client).await?;
+    Ok(())
+}
+
+/// dropping the client future cancels the interaction on the server side
+#[tokio::test]
+async fn flume_rpc_cancellation() -> anyhow::Result<()> {
+    tracing_subscriber::fmt::try_init().ok();
+    let (server, client) = flume::connection::<ComputeRequest, ComputeResponse>(1);
+    let server = RpcServer::<ComputeService, _>::new(server);
+    let server_handle = tokio::task::spawn(async move {
+        let (req, chan) = server.accept().await?;
+        let ComputeRequest::Sqr(req) = req else {
+            panic!("unexpected request");
+        };
+        let token = chan.cancellation_token();
+        let res = chan
+            .rpc(req, (), |_, _| futures::future::pending::<SqrResponse>())
+            .await;
+        anyhow::Ok((res, token.is_cancelled()))
+    });
+    let client = RpcClient::<ComputeService, _>::new(client);
+    let res = tokio::time::timeout(std::time::Duration::from_millis(100), client.rpc(Sqr(2))).await;
+    assert!(res.is_err());
+    let (res, cancelled) = server_handle.await??;
+    assert!(matches!(res, Err(RpcServerError::Cancelled)));
+    assert!(cancelled);
+    Ok(())
+}
+
+#[tokio::test]
+async fn flume_connection_health() -> anyhow::Result<()> {
+    use quic_rpc::transport::ConnectionHealth;
+    let (server, client) = flume::connection::<ComputeRequest, ComputeResponse>(1);
+    assert!(client.is_healthy());
+    assert!(client.ping().await.is_ok());
+    drop(server);
+    assert!(!client.is_healthy());
+    assert!(client.ping().await.is_err());
+    Ok(())
+}
+
+#[cfg(feature = "keepalive")]
+#[tokio::test]
+async fn flume_keepalive() -> anyhow::Result<()> {
+    use quic_rpc::transport::{
+        keepalive::{KeepAliveConfig, KeepAliveConnection},
+        ConnectionHealth,
+    };
+    use std::time::Duration;
+    let (server, client) = flume::connection::<ComputeRequest, ComputeResponse>(1);
+    let config = KeepAliveConfig::default()
+        .interval(Duration::from_millis(10))
+        .max_failures(2);
+    let client = KeepAliveConnection::new(client, config);
+    let server = RpcServer::<ComputeService, _>::new(server);
+    let server_handle = tokio::task::spawn(ComputeService::server(server));
+    smoke_test(client.clone()).await?;
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert!(client.is_healthy());
+    server_handle.abort();
+    let _ = server_handle.await;
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert!(!client.is_healthy());
+    Ok(())
+}
+
+/// dropping the client side of each pattern cancels the interaction on the server side
+#[tokio::test]
+async fn flume_channel_cancel() -> anyhow::Result<()> {
+    tracing_subscriber::fmt::try_init().ok();
+    let (server, client) = flume::connection::<ComputeRequest, ComputeResponse>(1);
+    cancel_test(server, client).await
+}
+
+/// A flume connection that keeps the default [ConnectionCommon::cancel], so dropping a call
+/// only closes the channel
+#[derive(Debug, Clone)]
+struct DefaultCancel(flume::FlumeConnection<ComputeResponse, ComputeRequest>);
+
+impl ConnectionErrors for DefaultCancel {
+    type OpenError = flume::OpenBiError;
+    type SendError = flume::SendError;
+    type RecvError = flume::RecvError;
+}
+
+impl ConnectionCommon<ComputeResponse, ComputeRequest> for DefaultCancel {
+    type SendSink = flume::SendSink<ComputeRequest>;
+    type RecvStream = flume::RecvStream<ComputeResponse>;
+}
+
+impl Connection<ComputeResponse, ComputeRequest> for DefaultCancel {
+    type OpenBiFut = flume::OpenBiFuture<ComputeResponse, ComputeRequest>;
+
+    fn open_bi(&self) -> Self::OpenBiFut {
+        self.0.open_bi()
+    }
+}
+
+/// without a cancel signal, the end of the stream cancels rpc and server streaming calls
+#[tokio::test]
+async fn flume_channel_default_cancel() -> anyhow::Result<()> {
+    tracing_subscriber::fmt::try_init().ok();
+    let (server, client) = flume::connection::<ComputeRequest, ComputeResponse>(1);
+    let server = RpcServer::<ComputeService, _>::new(server);
+    let server_handle = tokio::task::spawn(async move {
+        let mut results = Vec::new();
+        for _ in 0..2 {
+            let (req, chan) = server.accept().await?;
+            let token = chan.cancellation_token();
+            let res = match req {
+                ComputeRequest::Sqr(req) => {
+                    chan.rpc(req, (), |_, _| futures::future::pending::<SqrResponse>())
+                        .await
+                }
+                ComputeRequest::Fibonacci(req) => {
+                    chan.server_streaming(req, (), |_, _| {
+                        futures::stream::pending::<FibonacciResponse>()
+                    })
+                    .await
+                }
+                _ => anyhow::bail!("unexpected request"),
+            };
+            results.push((res, token.is_cancelled()));
+        }
+        anyhow::Ok(results)
+    });
+    let client = RpcClient::<ComputeService, _>::new(DefaultCancel(client));
+    let res = tokio::time::timeout(std::time::Duration::from_millis(100), client.rpc(Sqr(2))).await;
+    assert!(res.is_err());
+    let responses = client.server_streaming(Fibonacci(10)).await?;
+    drop(responses);
+    let results =
+        tokio::time::timeout(std::time::Duration::from_secs(1), server_handle).await???;
+    for (res, cancelled) in results {
+        assert!(matches!(res, Err(RpcServerError::Cancelled)), "{res:?}");
+        assert!(cancelled);
+    }
+    Ok(())
+}
+
+/// the size limits and name of a rpc message, with both message traits in scope
+fn rpc_limits<S: quic_rpc::Service, M: Msg<S> + RpcMsg<S>>(
+) -> [(Option<usize>, Option<usize>, &'static str); 2] {
+    [
+        (M::MAX_REQUEST_SIZE, M::MAX_RESPONSE_SIZE, M::method_name()),
+        (
+            M::RPC_MAX_REQUEST_SIZE,
+            M::RPC_MAX_RESPONSE_SIZE,
+            M::rpc_method_name(),
+        ),
+    ]
+}
+
+/// the items of [Msg] and [RpcMsg] can be used on a rpc message without naming the trait
+#[test]
+fn rpc_msg_items_are_not_ambiguous() {
+    let [msg, rpc] = rpc_limits::<LimitedService, Echo>();
+    assert_eq!(msg, rpc);
+    assert_eq!(msg.0, Some(64));
+    assert_eq!(msg.1, Some(64));
+    let [msg, rpc] = rpc_limits::<ComputeService, Sqr>();
+    assert_eq!(msg, rpc);
+    assert_eq!(msg.0, None);
+}
@@ -0,0 +1,177 @@
+#![cfg(all(feature = "flume-transport", feature = "health"))]
+use derive_more::{From, TryInto};
+use futures::StreamExt;
+use quic_rpc::{
+    health::{Check, HealthReporter, HealthRequest, HealthService, ServingStatus, Watch},
+    message::{Msg, RpcMsg, ServerStreaming, ServerStreamingMsg},
+    transport::flume,
+    RpcClient, RpcServer, Service,
+};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Ping;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Pong;
+
+/// a service that mounts the health messages next to its own
+#[derive(Debug, Serialize, Deserialize, From, TryInto)]
+pub enum AppRequest {
+    Ping(Ping),
+    Check(Check),
+    Watch(Watch),
+}
+
+#[derive(Debug, Serialize, Deserialize, From, TryInto)]
+pub enum AppResponse {
+    Pong(Pong),
+    Health(ServingStatus),
+}
+
+#[derive(Debug, Clone)]
+pub struct AppService;
+
+impl Service for AppService {
+    type Req = AppRequest;
+    type Res = AppResponse;
+}
+
+impl RpcMsg<AppService> for Ping {
+    type Response = Pong;
+}
+
+impl RpcMsg<AppService> for Check {
+    type Response = ServingStatus;
+}
+
+impl Msg<AppService> for Watch {
+    type Pattern = ServerStreaming;
+}
+
+impl ServerStreamingMsg<AppService> for Watch {
+    type Response = ServingStatus;
+}
+
+fn check(service: &str) -> Check {
+    Check {
+        service: service.to_string(),
+    }
+}
+
+fn watch(service: &str) -> Watch {
+    Watch {
+        service: service.to_string(),
+    }
+}
+
+async fn serve_app(
+    server: RpcServer<AppService, flume::FlumeServerEndpoint<AppRequest, AppResponse>>,
+    health: HealthReporter,
+) -> anyhow::Result<()> {
+    loop {
+        let (req, chan) = server.accept().await?;
+        let health = health.clone();
+        // watch requests run until the client goes away, so handle each request in a task
+        tokio::task::spawn(async move {
+            match req {
+                AppRequest::Ping(req) => chan.rpc(req, (), |_, _| async { Pong }).await,
+                AppRequest::Check(req) => health.handle(req.into(), chan).await,
+                AppRequest::Watch(req) => health.handle(req.into(), chan).await,
+            }
+        });
+    }
+}
+
+async fn serve_health(
+    server: RpcServer<HealthService, flume::FlumeServerEndpoint<HealthRequest, ServingStatus>>,
+    health: HealthReporter,
+) -> anyhow::Result<()> {
+    loop {
+        let (req, chan) = server.accept().await?;
+        tokio::task::spawn(health.clone().handle(req, chan));
+    }
+}
+
+#[tokio::test]
+async fn flume_health_mounted() -> anyhow::Result<()> {
+    let (server, client) = flume::connection::<AppRequest, AppResponse>(1);
+    let health = HealthReporter::new();
+    let server_handle = tokio::task::spawn(serve_app(RpcServer::new(server), health.clone()));
+    let client = RpcClient::<AppService, _>::new(client);
+
+    // the service itself still works
+    client.rpc(Ping).await?;
+
+    assert_eq!(client.rpc(check("")).await?, ServingStatus::Serving);
+    assert_eq!(
+        client.rpc(check("storage")).await?,
+        ServingStatus::ServiceUnknown
+    );
+
+    let mut updates = client.server_streaming(watch("storage")).await?;
+    assert_eq!(
+        updates.next().await.transpose()?,
+        Some(ServingStatus::ServiceUnknown)
+    );
+    health.set_status("storage", ServingStatus::NotServing);
+    assert_eq!(
+        updates.next().await.transpose()?,
+        Some(ServingStatus::NotServing)
+    );
+    // setting the same status again does not produce an update
+    health.set_status("storage", ServingStatus::NotServing);
+    health.set_status("storage", ServingStatus::Serving);
+    assert_eq!(
+        updates.next().await.transpose()?,
+        Some(ServingStatus::Serving)
+    );
+    assert_eq!(client.rpc(check("storage")).await?, ServingStatus::Serving);
+
+    health.clear_status("storage");
+    assert_eq!(
+        updates.next().await.transpose()?,
+        Some(ServingStatus::ServiceUnknown)
+    );
+
+    server_handle.abort();
+    Ok(())
+}
+
+#[tokio::test]
+async fn flume_health_standalone() -> anyhow::Result<()> {
+    let (server, client) = flume::connection(1);
+    let health = HealthReporter::new();
+    let server_handle = tokio::task::spawn(serve_health(RpcServer::new(server), health.clone()));
+    let client = RpcClient::<HealthService, _>::new(client);
+
+    health.set_status("", ServingStatus::NotServing);
+    assert_eq!(client.rpc(check("")).await?, ServingStatus::NotServing);
+    let mut updates = client.server_streaming(watch("")).await?;
+    assert_eq!(
+        updates.next().await.transpose()?,
+        Some(ServingStatus::NotServing)
+    );
+    health.set_status("", ServingStatus::Serving);
+    assert_eq!(
+        updates.next().await.transpose()?,
+        Some(ServingStatus::Serving)
+    );
+
+    // setting another service does not produce an update for an unknown service
+    let mut updates = client.server_streaming(watch("storage")).await?;
+    assert_eq!(
+        updates.next().await.transpose()?,
+        Some(ServingStatus::ServiceUnknown)
+    );
+    health.set_status("cache", ServingStatus::Serving);
+    health.clear_status("storage");
+    health.set_status("storage", ServingStatus::NotServing);
+    assert_eq!(
+        updates.next().await.transpose()?,
+        Some(ServingStatus::NotServing)
+    );
+
+    server_handle.abort();
+    Ok(())
+}
@@ -14,6 +14,7 @@ use quic_rpc::{
 use serde::{Deserialize, Serialize};
 use tokio::task::JoinHandle;
 
+mod blob;
 mod math;
 use math::*;
 mod util;
@@ -298,3 +299,177 @@ async fn hyper_channel_errors() -> anyhow::Result<()> {
     let _ = server_handle.await;
     Ok(())
 }
+
+/// raw bytes after a typed request
+#[tokio::test]
+async fn hyper_raw_streaming() -> anyhow::Result<()> {
+    let addr: SocketAddr = "127.0.0.1:3004".parse()?;
+    let uri: Uri = "http://127.0.0.1:3004".parse()?;
+    let server = HyperServerEndpoint::serve(&addr)?;
+    let server_handle = tokio::task::spawn(blob::BlobService::server(RpcServer::new(server)));
+    let client = HyperConnection::new(uri);
+    blob::raw_echo_test(client.clone(), 0).await?;
+    blob::raw_echo_test(client, 1024 * 1024 * 4).await?;
+    server_handle.abort();
+    Ok(())
+}
+
+#[tokio::test]
+async fn hyper_connection_health() -> anyhow::Result<()> {
+    use quic_rpc::transport::{Connection, ConnectionHealth};
+    let addr: SocketAddr = "127.0.0.1:3005".parse()?;
+    let uri: Uri = "http://127.0.0.1:3005".parse()?;
+    let server = HyperServerEndpoint::<ComputeRequest, ComputeResponse>::serve(&addr)?;
+    let client = HyperConnection::<ComputeResponse, ComputeRequest>::new(uri);
+    assert!(client.ping().await.is_ok());
+    assert!(client.is_healthy());
+    // a ping does not create a channel
+    let server = RpcServer::<ComputeService, _>::new(server);
+    let accept = tokio::time::timeout(std::time::Duration::from_millis(100), server.accept());
+    assert!(accept.await.is_err());
+    // dropping the server shuts it down
+    drop(server);
+    tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    // a failed request marks the connection unhealthy without a ping
+    assert!(client.is_healthy());
+    assert!(client.open_bi().await.is_err());
+    assert!(!client.is_healthy());
+    assert!(client.ping().await.is_err());
+    assert!(!client.is_healthy());
+    Ok(())
+}
+
+#[tokio::test]
+async fn hyper_channel_metadata() -> anyhow::Result<()> {
+    let addr: SocketAddr = "127.0.0.1:3006".parse()?;
+    let uri: Uri = "http://127.0.0.1:3006".parse()?;
+    let server = HyperServerEndpoint::serve(&addr)?;
+    let client = HyperConnection::new(uri);
+    metadata_test(server, client).await
+}
+
+#[tokio::test]
+async fn hyper_channel_stats() -> anyhow::Result<()> {
+    let addr: SocketAddr = "127.0.0.1:3007".parse()?;
+    let uri: Uri = "http://127.0.0.1:3007".parse()?;
+    let server = HyperServerEndpoint::serve(&addr)?;
+    let client = HyperConnection::new(uri);
+    stats_test(server, client, 32).await
+}
+
+#[tokio::test]
+async fn hyper_channel_size_limit() -> anyhow::Result<()> {
+    let addr: SocketAddr = "127.0.0.1:3009".parse()?;
+    let uri: Uri = "http://127.0.0.1:3009".parse()?;
+    let server = HyperServerEndpoint::serve(&addr)?;
+    let client = HyperConnection::new(uri);
+    size_limit_test(server, client).await
+}
+
+/// a rejected channel ends with the status instead of a network error
+#[tokio::test]
+async fn hyper_channel_reject() -> anyhow::Result<()> {
+    use futures::{SinkExt, StreamExt};
+    use quic_rpc::transport::{Connection, ServerEndpoint, Status};
+    let addr: SocketAddr = "127.0.0.1:3010".parse()?;
+    let uri: Uri = "http://127.0.0.1:3010".parse()?;
+    let server = HyperServerEndpoint::<ComputeRequest, ComputeResponse>::serve(&addr)?;
+    let client = HyperConnection::<ComputeResponse, ComputeRequest>::new(uri);
+    let server_handle = tokio::spawn(async move {
+        let (send, mut recv) = server.accept_bi().await?;
+        recv.next().await;
+        HyperServerEndpoint::reject(send, recv, Status::FailedPrecondition);
+        anyhow::Ok(server)
+    });
+    let (mut send, mut recv) = client.open_bi().await?;
+    send.send(Sqr(3).into()).await?;
+    assert!(matches!(
+        recv.next().await,
+        Some(Err(RecvError::Rejected(Status::FailedPrecondition)))
+    ));
+    server_handle.await??;
+    Ok(())
+}
+
+#[tokio::test]
+async fn hyper_channel_handshake() -> anyhow::Result<()> {
+    let addr: SocketAddr = "127.0.0.1:3012".parse()?;
+    let uri: Uri = "http://127.0.0.1:3012".parse()?;
+    let server = HyperServerEndpoint::serve(&addr)?;
+    let client = HyperConnection::new(uri.clone());
+    let old = HyperConnection::new(uri);
+    handshake_test(server, client, old).await
+}
+
+/// a stream created from decoded messages yields them as they are, and has no raw bytes
+#[tokio::test]
+async fn hyper_recv_decoded() -> anyhow::Result<()> {
+    use futures::StreamExt;
+    use tokio::io::AsyncReadExt;
+    let (tx, rx) = flume::bounded(4);
+    let mut recv = hyper::RecvStream::<ComputeResponse>::new(rx);
+    tx.send_async(Ok(ComputeResponse::SqrResponse(SqrResponse(4))))
+        .await?;
+    assert!(matches!(
+        recv.next().await,
+        Some(Ok(ComputeResponse::SqrResponse(SqrResponse(4))))
+    ));
+    let mut raw = recv.into_raw();
+    assert!(raw.read(&mut [0; 4]).await.is_err());
+    Ok(())
+}
+
+#[tokio::test]
+async fn hyper_recv_frame_limit() -> anyhow::Result<()> {
+    use futures::StreamExt;
+    let (tx, rx) = flume::bounded(4);
+    let mut recv = hyper::RecvStream::<ComputeResponse>::from_chunks(rx);
+    // the length prefix alone is enough to reject a frame above the payload limit
+    let len: u32 = 0x1000000;
+    tx.send_async(Ok(len.to_be_bytes().to_vec().into())).await?;
+    assert!(matches!(
+        recv.next().await,
+        Some(Err(RecvError::SizeError(size))) if size == len as usize
+    ));
+    // the bytes of the rejected frame are dropped, and the next frame is received
+    tx.send_async(Ok(vec![0; len as usize].into())).await?;
+    let msg = bincode::serialize(&ComputeResponse::SqrResponse(SqrResponse(4)))?;
+    let mut frame = (msg.len() as u32).to_be_bytes().to_vec();
+    frame.extend_from_slice(&msg);
+    tx.send_async(Ok(frame.into())).await?;
+    assert!(matches!(
+        recv.next().await,
+        Some(Ok(ComputeResponse::SqrResponse(SqrResponse(4))))
+    ));
+    Ok(())
+}
+
+#[tokio::test]
+async fn hyper_channel_auth() -> anyhow::Result<()> {
+    let addr: SocketAddr = "127.0.0.1:3008".parse()?;
+    let uri: Uri = "http://127.0.0.1:3008".parse()?;
+    let server = HyperServerEndpoint::serve(&addr)?;
+    let client = HyperConnection::new(uri);
+    auth_test(server, client).await
+}
+
+#[cfg(feature = "test-utils")]
+#[tokio::test]
+async fn hyper_channel_conformance() -> anyhow::Result<()> {
+    use quic_rpc::testing::{self, TestRequest, TestResponse};
+    let addr: SocketAddr = "127.0.0.1:3003".parse()?;
+    let uri: Uri = "http://127.0.0.1:3003".parse()?;
+    let server = HyperServerEndpoint::<TestRequest, TestResponse>::serve(&addr)?;
+    let client = HyperConnection::new(uri);
+    testing::run_all(server, client).await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn hyper_channel_cancel() -> anyhow::Result<()> {
+    let addr: SocketAddr = "127.0.0.1:3011".parse()?;
+    let uri: Uri = "http://127.0.0.1:3011".parse()?;
+    let server = HyperServerEndpoint::serve(&addr)?;
+    let client = HyperConnection::new(uri);
+    cancel_test(server, client).await
+}
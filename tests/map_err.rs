@@ -0,0 +1,143 @@
+#![cfg(feature = "flume-transport")]
+use derive_more::{From, TryInto};
+use futures::{stream, SinkExt, StreamExt};
+use quic_rpc::{
+    declare_bidi_streaming, declare_client_streaming, declare_server_streaming, transport::flume,
+    RpcClient, RpcServer, Service,
+};
+use serde::{Deserialize, Serialize};
+
+/// serializable error type used on the wire
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct WireError(String);
+
+impl From<anyhow::Error> for WireError {
+    fn from(e: anyhow::Error) -> Self {
+        Self(e.to_string())
+    }
+}
+
+/// count from 0, failing at n
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CountTo(u64);
+
+/// sum the updates, failing on 0
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Sum;
+
+/// double the updates, failing on 0
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Double;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Number(u64);
+
+#[derive(Debug, Serialize, Deserialize, From, TryInto)]
+pub enum FallibleRequest {
+    CountTo(CountTo),
+    Sum(Sum),
+    Double(Double),
+    Number(Number),
+}
+
+#[derive(Debug, Serialize, Deserialize, From, TryInto)]
+pub enum FallibleResponse {
+    Number(Result<u64, WireError>),
+}
+
+#[derive(Debug, Clone)]
+pub struct FallibleService;
+
+impl Service for FallibleService {
+    type Req = FallibleRequest;
+    type Res = FallibleResponse;
+}
+
+declare_server_streaming!(FallibleService, CountTo, Result<u64, WireError>);
+declare_client_streaming!(FallibleService, Sum, Number, Result<u64, WireError>);
+declare_bidi_streaming!(FallibleService, Double, Number, Result<u64, WireError>);
+
+fn check(x: u64) -> anyhow::Result<u64> {
+    anyhow::ensure!(x != 0, "zero");
+    Ok(x)
+}
+
+async fn serve(
+    server: RpcServer<
+        FallibleService,
+        flume::FlumeServerEndpoint<FallibleRequest, FallibleResponse>,
+    >,
+) -> anyhow::Result<()> {
+    loop {
+        let (req, chan) = server.accept().await?;
+        match req {
+            FallibleRequest::CountTo(req) => {
+                chan.server_streaming_map_err(req, (), |_, CountTo(n)| {
+                    // the stream is terminated after the first error
+                    stream::iter(0..).map(move |i| {
+                        anyhow::ensure!(i != n, "failed at {i}");
+                        Ok(i)
+                    })
+                })
+                .await?
+            }
+            FallibleRequest::Sum(req) => {
+                chan.client_streaming_map_err(req, (), |_, _, updates| async move {
+                    let mut sum = 0;
+                    tokio::pin!(updates);
+                    while let Some(Number(x)) = updates.next().await {
+                        sum += check(x)?;
+                    }
+                    anyhow::Ok(sum)
+                })
+                .await?
+            }
+            FallibleRequest::Double(req) => {
+                chan.bidi_streaming_map_err(req, (), |_, _, updates| {
+                    updates.map(|Number(x)| check(x).map(|x| x * 2))
+                })
+                .await?
+            }
+            FallibleRequest::Number(_) => anyhow::bail!("unexpected request"),
+        }
+    }
+}
+
+#[tokio::test]
+async fn flume_streaming_map_err() -> anyhow::Result<()> {
+    let (server, client) = flume::connection::<FallibleRequest, FallibleResponse>(1);
+    let server_handle = tokio::task::spawn(serve(RpcServer::new(server)));
+    let client = RpcClient::<FallibleService, _>::new(client);
+
+    let items = client.server_streaming(CountTo(3)).await?;
+    let items = items.map(|x| x.unwrap()).collect::<Vec<_>>().await;
+    assert_eq!(
+        items,
+        vec![Ok(0), Ok(1), Ok(2), Err(WireError("failed at 3".into()))]
+    );
+
+    let (mut send, recv) = client.client_streaming(Sum).await?;
+    send.send(Number(1)).await?;
+    send.send(Number(2)).await?;
+    send.finish().await?;
+    assert_eq!(recv.await?, Ok(3));
+
+    let (mut send, recv) = client.client_streaming(Sum).await?;
+    send.send(Number(0)).await?;
+    send.finish().await?;
+    assert_eq!(recv.await?, Err(WireError("zero".into())));
+
+    let (mut send, mut recv) = client.bidi(Double).await?;
+    send.send(Number(1)).await?;
+    assert_eq!(recv.next().await.transpose()?, Some(Ok(2)));
+    send.send(Number(0)).await?;
+    assert_eq!(
+        recv.next().await.transpose()?,
+        Some(Err(WireError("zero".into())))
+    );
+    // the error terminated the stream
+    assert!(recv.next().await.is_none());
+
+    server_handle.abort();
+    Ok(())
+}
@@ -8,8 +8,14 @@ use async_stream::stream;
 use derive_more::{From, TryInto};
 use futures::{SinkExt, Stream, StreamExt, TryStreamExt};
 use quic_rpc::{
+    auth::{bearer_metadata, Identity, Policy, TokenFile},
+    client::RpcClientError,
     declare_bidi_streaming, declare_client_streaming, declare_rpc, declare_server_streaming,
-    server::RpcServerError, RpcClient, RpcServer, Service, ServiceConnection, ServiceEndpoint,
+    message::Msg,
+    server::{current_cancellation_token, FlushPolicy, RpcServerError},
+    transport::{Metadata, Status, TransportStats},
+    version::{Schema, Variant, Version, Versioned},
+    RpcClient, RpcServer, Service, ServiceConnection, ServiceEndpoint,
 };
 use serde::{Deserialize, Serialize};
 use std::{
@@ -86,6 +92,65 @@ declare_client_streaming!(ComputeService, Sum, SumUpdate, SumResponse);
 declare_server_streaming!(ComputeService, Fibonacci, FibonacciResponse);
 declare_bidi_streaming!(ComputeService, Multiply, MultiplyUpdate, MultiplyResponse);
 
+/// the name of a method of the compute service, see [Msg::method_name]
+pub fn method_name<M: Msg<ComputeService>>() -> &'static str {
+    M::method_name()
+}
+
+impl Versioned for ComputeService {
+    const NAME: &'static str = "ComputeService";
+
+    fn schema() -> Schema {
+        let variants = |names: &[&str]| names.iter().map(|n| Variant::new(*n, *n)).collect();
+        Schema {
+            request: variants(&[
+                "Sqr",
+                "Sum",
+                "SumUpdate",
+                "Fibonacci",
+                "Multiply",
+                "MultiplyUpdate",
+            ]),
+            response: variants(&[
+                "SqrResponse",
+                "SumResponse",
+                "FibonacciResponse",
+                "MultiplyResponse",
+            ]),
+            formats: Default::default(),
+        }
+        .with_formats::<Self>()
+    }
+}
+
+/// a client built for an older version of ComputeService, with the requests in a
+/// different order
+#[derive(Debug, Serialize, Deserialize, From, TryInto)]
+pub enum OldComputeRequest {
+    Sum(Sum),
+    Sqr(Sqr),
+}
+
+#[derive(Debug, Clone)]
+pub struct OldComputeService;
+
+impl Service for OldComputeService {
+    type Req = OldComputeRequest;
+    type Res = ComputeResponse;
+}
+
+declare_rpc!(OldComputeService, Sqr, SqrResponse);
+
+impl Versioned for OldComputeService {
+    const NAME: &'static str = "ComputeService";
+
+    fn schema() -> Schema {
+        let mut schema = ComputeService::schema();
+        schema.request = vec![Variant::new("Sum", "Sum"), Variant::new("Sqr", "Sqr")];
+        schema
+    }
+}
+
 impl ComputeService {
     async fn sqr(self, req: Sqr) -> SqrResponse {
         SqrResponse(req.0 as u128 * req.0 as u128)
@@ -132,7 +197,14 @@ impl ComputeService {
     pub async fn server<C: ServiceEndpoint<ComputeService>>(
         server: RpcServer<ComputeService, C>,
     ) -> result::Result<(), RpcServerError<C>> {
-        let s = server;
+        Self::server_with_flush_policy(server, FlushPolicy::EveryItem).await
+    }
+
+    pub async fn server_with_flush_policy<C: ServiceEndpoint<ComputeService>>(
+        server: RpcServer<ComputeService, C>,
+        flush_policy: FlushPolicy,
+    ) -> result::Result<(), RpcServerError<C>> {
+        let s = server.with_flush_policy(flush_policy);
         let service = ComputeService;
         loop {
             let (req, chan) = s.accept().await?;
@@ -239,6 +311,98 @@ pub async fn smoke_test<C: ServiceConnection<ComputeService>>(client: C) -> anyh
     Ok(())
 }
 
+/// Metadata sent when opening a channel is available to the server handler
+pub async fn metadata_test<S, C>(server: S, client: C) -> anyhow::Result<()>
+where
+    S: ServiceEndpoint<ComputeService>,
+    C: ServiceConnection<ComputeService>,
+{
+    let server = RpcServer::<ComputeService, S>::new(server);
+    let server_handle = tokio::task::spawn(async move {
+        let mut received = Vec::new();
+        for _ in 0..2 {
+            let (req, chan) = server.accept().await?;
+            received.push(chan.metadata().clone());
+            match req {
+                ComputeRequest::Sqr(req) => {
+                    chan.rpc(req, ComputeService, ComputeService::sqr).await?
+                }
+                _ => anyhow::bail!("unexpected request"),
+            }
+        }
+        anyhow::Ok(received)
+    });
+    let metadata: Metadata = [
+        (
+            "traceparent",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+        ),
+        ("x-tenant", "acme"),
+    ]
+    .into_iter()
+    .collect();
+    let (mut send, mut recv) = client
+        .open_bi_with_metadata(metadata.clone())
+        .await
+        .map_err(anyhow::Error::msg)?;
+    send.send(Sqr(3).into()).await.map_err(anyhow::Error::msg)?;
+    assert!(matches!(
+        recv.next().await,
+        Some(Ok(ComputeResponse::SqrResponse(SqrResponse(9))))
+    ));
+    // a plain channel has no metadata
+    let (mut send, mut recv) = client.open_bi().await.map_err(anyhow::Error::msg)?;
+    send.send(Sqr(4).into()).await.map_err(anyhow::Error::msg)?;
+    assert!(recv.next().await.is_some());
+    let received = server_handle.await??;
+    assert_eq!(received[0], metadata);
+    assert_eq!(received[1].get("x-tenant"), None);
+    Ok(())
+}
+
+/// Counters of a transport that sends messages as length prefixed bincode frames
+pub async fn stats_test<S, C>(server: S, client: C, queue_capacity: usize) -> anyhow::Result<()>
+where
+    S: ServiceEndpoint<ComputeService> + TransportStats,
+    C: ServiceConnection<ComputeService> + TransportStats,
+{
+    let (mut send, mut recv) = client.open_bi().await.map_err(anyhow::Error::msg)?;
+    send.send(Sqr(3).into()).await.map_err(anyhow::Error::msg)?;
+    let (mut server_send, mut server_recv) =
+        server.accept_bi().await.map_err(anyhow::Error::msg)?;
+    assert!(matches!(
+        server_recv.next().await,
+        Some(Ok(ComputeRequest::Sqr(Sqr(3))))
+    ));
+    server_send
+        .send(SqrResponse(9).into())
+        .await
+        .map_err(anyhow::Error::msg)?;
+    assert!(recv.next().await.is_some());
+
+    let client_stats = client.stats();
+    assert_eq!(client_stats.opened, 1);
+    assert_eq!(client_stats.open_channels, 1);
+    assert_eq!(client_stats.frames_sent, 1);
+    assert_eq!(client_stats.frames_received, 1);
+    // 4 bytes length prefix, 4 bytes variant and the value
+    assert_eq!(client_stats.bytes_sent, 4 + 4 + 8);
+    assert_eq!(client_stats.bytes_received, 4 + 4 + 16);
+    assert_eq!(client_stats.serialization_errors, 0);
+    let server_stats = server.stats();
+    assert_eq!(server_stats.accepted, 1);
+    assert_eq!(server_stats.open_channels, 1);
+    assert_eq!(server_stats.bytes_received, client_stats.bytes_sent);
+    assert_eq!(server_stats.bytes_sent, client_stats.bytes_received);
+    assert_eq!(server_stats.accept_queue_len, 0);
+    assert_eq!(server_stats.accept_queue_capacity, Some(queue_capacity));
+
+    drop((send, recv, server_send, server_recv));
+    assert_eq!(client.stats().open_channels, 0);
+    assert_eq!(server.stats().open_channels, 0);
+    Ok(())
+}
+
 fn clear_line() {
     print!("\r{}\r", " ".repeat(80));
 }
@@ -320,3 +484,292 @@ where
 fn sum_of_squares(n: u64) -> u128 {
     (0..n).map(|x| (x * x) as u128).sum()
 }
+
+/// check that only channels with a valid token are handed to the handler
+pub async fn auth_test<S, C>(server: S, client: C) -> anyhow::Result<()>
+where
+    S: ServiceEndpoint<ComputeService>,
+    C: ServiceConnection<ComputeService>,
+{
+    let tokens = TokenFile::parse("# token identity\nsecret alice\n")?;
+    let server = RpcServer::<ComputeService, S>::new(server).with_authenticator(tokens);
+    let server_handle = tokio::task::spawn(async move {
+        let mut identities = Vec::new();
+        for _ in 0..2 {
+            let (req, chan) = server.accept().await?;
+            identities.push(chan.identity().cloned());
+            match req {
+                ComputeRequest::Sqr(req) => {
+                    chan.rpc(req, ComputeService, ComputeService::sqr).await?
+                }
+                _ => anyhow::bail!("unexpected request"),
+            }
+        }
+        // hand back the server so it is not closed before the last response is read
+        anyhow::Ok((identities, server))
+    });
+    let valid = RpcClient::<ComputeService, C>::new(client.clone())
+        .with_metadata(bearer_metadata("secret"));
+    let invalid =
+        RpcClient::<ComputeService, C>::new(client.clone()).with_metadata(bearer_metadata("guess"));
+    let anonymous = RpcClient::<ComputeService, C>::new(client);
+    assert_eq!(valid.rpc(Sqr(3)).await?, SqrResponse(9));
+    // rejected channels are closed, the server keeps accepting
+    assert!(invalid.rpc(Sqr(4)).await.is_err());
+    assert!(anonymous.rpc(Sqr(5)).await.is_err());
+    assert_eq!(valid.rpc(Sqr(6)).await?, SqrResponse(36));
+    let (identities, _server) = server_handle.await??;
+    assert_eq!(identities, vec![Some(Identity::new("alice")); 2]);
+    Ok(())
+}
+
+/// check that the policy is enforced before the handler is called
+pub async fn policy_test<S, C>(server: S, client: C) -> anyhow::Result<()>
+where
+    S: ServiceEndpoint<ComputeService>,
+    C: ServiceConnection<ComputeService>,
+{
+    let tokens = TokenFile::parse("admin-token alice\nuser-token bob\n")?;
+    let policy = Policy::new()
+        .allow("admin", "*")
+        .allow("user", method_name::<Fibonacci>())
+        .assign("alice", "admin")
+        .assign("bob", "user");
+    let server = RpcServer::<ComputeService, S>::new(server)
+        .with_authenticator(tokens)
+        .with_policy(policy);
+    let server_handle = tokio::task::spawn(ComputeService::server(server));
+    let alice = RpcClient::<ComputeService, C>::new(client.clone())
+        .with_metadata(bearer_metadata("admin-token"));
+    let bob =
+        RpcClient::<ComputeService, C>::new(client).with_metadata(bearer_metadata("user-token"));
+    assert_eq!(alice.rpc(Sqr(3)).await?, SqrResponse(9));
+    let fib = bob.server_streaming(Fibonacci(5)).await?;
+    let fib = fib.map_ok(|x| x.0).try_collect::<Vec<_>>().await?;
+    assert_eq!(fib, vec![0, 1, 1, 2, 3]);
+    assert!(bob.rpc(Sqr(4)).await.is_err());
+    server_handle.abort();
+    Ok(())
+}
+
+/// echo a payload, with a small size limit
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Echo {
+    pub data: Vec<u8>,
+    pub response_len: usize,
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct EchoResponse(pub Vec<u8>);
+
+/// upload a payload, without a size limit
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Bulk(pub Vec<u8>);
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct BulkResponse(pub usize);
+
+#[derive(Debug, Serialize, Deserialize, From, TryInto)]
+pub enum LimitedRequest {
+    Echo(Echo),
+    Bulk(Bulk),
+}
+
+#[derive(Debug, Serialize, Deserialize, From, TryInto)]
+pub enum LimitedResponse {
+    EchoResponse(EchoResponse),
+    BulkResponse(BulkResponse),
+}
+
+#[derive(Debug, Clone)]
+pub struct LimitedService;
+
+impl Service for LimitedService {
+    type Req = LimitedRequest;
+    type Res = LimitedResponse;
+}
+
+declare_rpc!(
+    LimitedService,
+    Echo,
+    EchoResponse,
+    max_request_size = 64,
+    max_response_size = 64
+);
+declare_rpc!(LimitedService, Bulk, BulkResponse);
+
+impl LimitedService {
+    async fn echo(self, req: Echo) -> EchoResponse {
+        EchoResponse(vec![1; req.response_len])
+    }
+
+    async fn bulk(self, req: Bulk) -> BulkResponse {
+        BulkResponse(req.0.len())
+    }
+
+    pub async fn server<C: ServiceEndpoint<LimitedService>>(
+        server: RpcServer<LimitedService, C>,
+    ) -> result::Result<(), RpcServerError<C>> {
+        loop {
+            let (req, chan) = match server.accept().await {
+                Ok(accepted) => accepted,
+                // the client gave up before sending its request
+                Err(RpcServerError::EarlyClose) => continue,
+                Err(cause) => return Err(cause),
+            };
+            tokio::spawn(async move {
+                match req {
+                    LimitedRequest::Echo(req) => chan.rpc(req, LimitedService, Self::echo).await,
+                    LimitedRequest::Bulk(req) => chan.rpc(req, LimitedService, Self::bulk).await,
+                }
+            });
+        }
+    }
+}
+
+/// check that both sides enforce the message size limits
+pub async fn size_limit_test<S, C>(server: S, client: C) -> anyhow::Result<()>
+where
+    S: ServiceEndpoint<LimitedService>,
+    C: ServiceConnection<LimitedService>,
+{
+    let server = RpcServer::<LimitedService, S>::new(server);
+    let server_handle = tokio::task::spawn(LimitedService::server(server));
+    let echo = |len, response_len| Echo {
+        data: vec![0; len],
+        response_len,
+    };
+    let rpc = RpcClient::<LimitedService, C>::new(client.clone());
+    assert_eq!(rpc.rpc(echo(8, 8)).await?, EchoResponse(vec![1; 8]));
+    // too large to send
+    assert!(rpc.rpc(echo(100, 8)).await.is_err());
+    // too large to send back
+    assert!(rpc.rpc(echo(8, 100)).await.is_err());
+    // other messages are not limited
+    assert_eq!(rpc.rpc(Bulk(vec![0; 1000])).await?, BulkResponse(1000));
+    // a client that ignores the limit is rejected by the server
+    let (mut send, mut recv) = client.open_bi().await.map_err(anyhow::Error::msg)?;
+    send.send(echo(100, 8).into())
+        .await
+        .map_err(anyhow::Error::msg)?;
+    assert!(!matches!(recv.next().await, Some(Ok(_))));
+    assert_eq!(rpc.rpc(echo(8, 8)).await?, EchoResponse(vec![1; 8]));
+    server_handle.abort();
+    Ok(())
+}
+
+/// dropping the client side of each pattern cancels the interaction on the server side
+pub async fn cancel_test<S, C>(server: S, client: C) -> anyhow::Result<()>
+where
+    S: ServiceEndpoint<ComputeService>,
+    C: ServiceConnection<ComputeService>,
+{
+    let server = RpcServer::<ComputeService, S>::new(server);
+    let (started_send, mut started) = tokio::sync::mpsc::unbounded_channel();
+    let server_handle = tokio::task::spawn(async move {
+        let mut results = Vec::new();
+        for _ in 0..4 {
+            let (req, chan) = server.accept().await?;
+            let token = chan.cancellation_token();
+            let started = started_send.clone();
+            let res = match req {
+                ComputeRequest::Sqr(req) => {
+                    chan.rpc(req, started, |started, _| async move {
+                        started.send(current_cancellation_token()).ok();
+                        futures::future::pending::<SqrResponse>().await
+                    })
+                    .await
+                }
+                ComputeRequest::Sum(req) => {
+                    chan.client_streaming(req, started, |started, _, _| async move {
+                        started.send(current_cancellation_token()).ok();
+                        futures::future::pending::<SumResponse>().await
+                    })
+                    .await
+                }
+                ComputeRequest::Fibonacci(req) => {
+                    chan.server_streaming(req, started, |started, _| {
+                        started.send(current_cancellation_token()).ok();
+                        futures::stream::pending::<FibonacciResponse>()
+                    })
+                    .await
+                }
+                ComputeRequest::Multiply(req) => {
+                    chan.bidi_streaming(req, started, |started, _, _| {
+                        started.send(current_cancellation_token()).ok();
+                        futures::stream::pending::<MultiplyResponse>()
+                    })
+                    .await
+                }
+                _ => anyhow::bail!("unexpected request"),
+            };
+            results.push((res, token.is_cancelled()));
+        }
+        anyhow::Ok((results, server))
+    });
+    let client = RpcClient::<ComputeService, C>::new(client);
+    tokio::select! {
+        _ = client.rpc(Sqr(2)) => anyhow::bail!("rpc completed"),
+        token = started.recv() => assert!(token.flatten().is_some()),
+    }
+    let (send, recv) = client.client_streaming(Sum).await?;
+    assert!(started.recv().await.flatten().is_some());
+    drop(recv);
+    drop(send);
+    let responses = client.server_streaming(Fibonacci(10)).await?;
+    assert!(started.recv().await.flatten().is_some());
+    drop(responses);
+    let (send, recv) = client.bidi(Multiply(2)).await?;
+    assert!(started.recv().await.flatten().is_some());
+    drop(recv);
+    drop(send);
+    let (results, _server) = server_handle.await??;
+    for (res, cancelled) in results {
+        assert!(matches!(res, Err(RpcServerError::Cancelled)), "{res:?}");
+        assert!(cancelled);
+    }
+    Ok(())
+}
+
+/// clients of a different version, and clients without a version, are rejected before
+/// their requests are decoded, and the server goes on serving other clients
+pub async fn handshake_test<S, C, O>(server: S, client: C, old: O) -> anyhow::Result<()>
+where
+    S: ServiceEndpoint<ComputeService>,
+    C: ServiceConnection<ComputeService>,
+    O: ServiceConnection<OldComputeService>,
+{
+    let server = RpcServer::<ComputeService, S>::new(server).with_handshake();
+    let server_handle = tokio::task::spawn(async move {
+        for _ in 0..2 {
+            let (req, chan) = server.accept().await?;
+            match req {
+                ComputeRequest::Sqr(req) => {
+                    chan.rpc(req, ComputeService, ComputeService::sqr).await?
+                }
+                _ => anyhow::bail!("unexpected request"),
+            }
+        }
+        anyhow::Ok(server)
+    });
+    let new = RpcClient::<ComputeService, C>::new(client.clone()).with_handshake();
+    assert_eq!(new.rpc(Sqr(3)).await?, SqrResponse(9));
+    let unversioned = RpcClient::<ComputeService, C>::new(client);
+    match unversioned.rpc(Sqr(3)).await {
+        Err(RpcClientError::RecvError(error)) => {
+            assert_eq!(C::rejection(&error), Some(Status::FailedPrecondition));
+        }
+        other => panic!("expected a rejection, got {other:?}"),
+    }
+    let old = RpcClient::<OldComputeService, O>::new(old).with_handshake();
+    match old.rpc(Sqr(3)).await {
+        Err(RpcClientError::IncompatibleVersion(version)) => {
+            assert_eq!(version, Version::of::<OldComputeService>());
+        }
+        other => panic!("expected an incompatible version, got {other:?}"),
+    }
+    // the rejections do not stop the server
+    assert_eq!(new.rpc(Sqr(4)).await?, SqrResponse(16));
+    let _server = server_handle.await??;
+    Ok(())
+}
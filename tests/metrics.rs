@@ -0,0 +1,151 @@
+#![cfg(feature = "flume-transport")]
+mod math;
+use futures::StreamExt;
+use math::*;
+use quic_rpc::{
+    client::RpcClientError,
+    metrics::{LatencyHistogram, Metrics},
+    telemetry::{Side, Telemetry},
+    transport::flume,
+    RpcClient, RpcServer,
+};
+use std::time::Duration;
+
+#[tokio::test]
+async fn flume_metrics_snapshot() -> anyhow::Result<()> {
+    let metrics = Metrics::new();
+    // metrics work without spans
+    let telemetry = Telemetry::disabled().with_metrics(metrics.clone());
+
+    let (server, client) = flume::connection::<ComputeRequest, ComputeResponse>(1);
+    let server = RpcServer::<ComputeService, _>::new(server).with_telemetry(telemetry.clone());
+    let server_handle = tokio::task::spawn(ComputeService::server(server));
+    let client = RpcClient::<ComputeService, _>::new(client).with_telemetry(telemetry);
+
+    client.rpc(Sqr(3)).await?;
+    client.rpc(Sqr(4)).await?;
+    let items = client.server_streaming(Fibonacci(5)).await?;
+    assert_eq!(items.count().await, 5);
+    // dropping the stream before the end ends the interaction
+    let mut items = client.server_streaming(Fibonacci(5)).await?;
+    items.next().await;
+    drop(items);
+
+    let snapshot = metrics.snapshot();
+    let sqr = snapshot.get(Side::Client, method_name::<Sqr>()).unwrap();
+    assert_eq!(sqr.service, "ComputeService");
+    assert_eq!(sqr.pattern, "Rpc");
+    assert_eq!(sqr.calls, 2);
+    assert_eq!(sqr.in_flight, 0);
+    assert_eq!(sqr.error_count(), 0);
+    assert_eq!(sqr.latency.count(), 2);
+    assert_eq!(sqr.items, 0);
+    assert_eq!(
+        snapshot
+            .get(Side::Server, method_name::<Sqr>())
+            .unwrap()
+            .calls,
+        2
+    );
+
+    let fib = snapshot
+        .get(Side::Client, method_name::<Fibonacci>())
+        .unwrap();
+    assert_eq!(fib.calls, 2);
+    assert_eq!(fib.items, 6);
+    // flume does not encode messages, so the size of the items is not known
+    assert_eq!(fib.bytes, 0);
+    assert_eq!(fib.errors.get("Dropped"), Some(&1));
+    assert_eq!(fib.latency.count(), 2);
+
+    // the server ends and the next call fails
+    server_handle.abort();
+    let _ = server_handle.await;
+    metrics.reset();
+    let res = client.rpc(Sqr(5)).await;
+    assert!(matches!(res, Err(RpcClientError::Open(_))));
+    let snapshot = metrics.snapshot();
+    assert_eq!(snapshot.methods.len(), 1);
+    let sqr = snapshot.get(Side::Client, method_name::<Sqr>()).unwrap();
+    assert_eq!(sqr.errors.get("Open"), Some(&1));
+    Ok(())
+}
+
+#[test]
+fn latency_histogram() {
+    let mut histogram = LatencyHistogram::default();
+    assert_eq!(histogram.quantile(0.5), None);
+    assert_eq!(histogram.mean(), None);
+    for ms in [1, 2, 3, 4, 100] {
+        histogram.record(Duration::from_millis(ms));
+    }
+    assert_eq!(histogram.count(), 5);
+    assert_eq!(histogram.sum(), Duration::from_millis(110));
+    assert_eq!(histogram.mean(), Some(Duration::from_millis(22)));
+    assert_eq!(histogram.max(), Duration::from_millis(100));
+    // 3ms is in the bucket up to 10µs * 2^9 = 5.12ms
+    assert_eq!(histogram.quantile(0.5), Some(Duration::from_micros(5120)));
+    assert_eq!(histogram.quantile(1.0), Some(Duration::from_millis(100)));
+    assert_eq!(histogram.buckets().map(|(_, n)| n).sum::<u64>(), 5);
+    assert_eq!(histogram.buckets().last(), Some((None, 0)));
+    histogram.record(Duration::from_secs(3600));
+    assert_eq!(histogram.buckets().last(), Some((None, 1)));
+}
+
+#[cfg(feature = "metrics")]
+#[tokio::test]
+async fn flume_metrics_facade() -> anyhow::Result<()> {
+    use metrics_util::debugging::{DebugValue, DebuggingRecorder};
+    use quic_rpc::metrics::FacadeMetrics;
+
+    let recorder = DebuggingRecorder::new();
+    let snapshotter = recorder.snapshotter();
+    recorder.install()?;
+
+    let (server, client) = flume::connection::<ComputeRequest, ComputeResponse>(1);
+    let server = RpcServer::<ComputeService, _>::new(server)
+        .with_telemetry(Telemetry::disabled().with_metrics(FacadeMetrics));
+    let server_handle = tokio::task::spawn(ComputeService::server(server));
+    let client = RpcClient::<ComputeService, _>::new(client)
+        .with_telemetry(Telemetry::disabled().with_metrics(FacadeMetrics));
+    client.rpc(Sqr(3)).await?;
+    let items = client.server_streaming(Fibonacci(3)).await?;
+    assert_eq!(items.count().await, 3);
+
+    let values = snapshotter.snapshot().into_vec();
+    let value = |name: &str, side: &str, method: &str| {
+        values.iter().find_map(|(key, _, _, value)| {
+            let key = key.key();
+            let labels = key
+                .labels()
+                .map(|label| (label.key(), label.value()))
+                .collect::<Vec<_>>();
+            let matches = key.name() == name
+                && labels.contains(&("side", side))
+                && labels.contains(&("method", method));
+            matches.then_some(value)
+        })
+    };
+    assert_eq!(
+        value("quic_rpc_calls_total", "client", method_name::<Sqr>()),
+        Some(&DebugValue::Counter(1))
+    );
+    assert_eq!(
+        value("quic_rpc_calls_total", "server", method_name::<Sqr>()),
+        Some(&DebugValue::Counter(1))
+    );
+    assert_eq!(
+        value("quic_rpc_items_total", "client", method_name::<Fibonacci>()),
+        Some(&DebugValue::Counter(3))
+    );
+    assert_eq!(
+        value("quic_rpc_in_flight", "client", method_name::<Fibonacci>()),
+        Some(&DebugValue::Gauge(0.0.into()))
+    );
+    assert!(matches!(
+        value("quic_rpc_latency_seconds", "client", method_name::<Sqr>()),
+        Some(DebugValue::Histogram(latencies)) if latencies.len() == 1
+    ));
+    server_handle.abort();
+    Ok(())
+}
@@ -4,10 +4,12 @@ use std::{
     sync::Arc,
 };
 
-use quic_rpc::{RpcClient, RpcServer};
+use futures::{StreamExt, TryStreamExt};
+use quic_rpc::{server::FlushPolicy, RpcClient, RpcServer};
 use quinn::{ClientConfig, Endpoint, ServerConfig};
 use tokio::task::JoinHandle;
 
+mod blob;
 mod math;
 use math::*;
 mod util;
@@ -134,3 +136,722 @@ async fn quinn_channel_smoke() -> anyhow::Result<()> {
     server_handle.abort();
     Ok(())
 }
+
+/// batching responses must not change what the client sees
+#[tokio::test]
+async fn quinn_channel_batched_flush() -> anyhow::Result<()> {
+    tracing_subscriber::fmt::try_init().ok();
+    let Endpoints {
+        client,
+        server,
+        server_addr,
+    } = make_endpoints(12348)?;
+    let server_handle = tokio::task::spawn(async move {
+        let connection = quic_rpc::transport::quinn::QuinnServerEndpoint::new(server)?;
+        let server = RpcServer::<ComputeService, _>::new(connection);
+        let flush_policy = FlushPolicy::Batch { max_items: 16 };
+        ComputeService::server_with_flush_policy(server, flush_policy).await?;
+        anyhow::Ok(())
+    });
+    let client =
+        quic_rpc::transport::quinn::QuinnConnection::new(client, server_addr, "localhost".into());
+    smoke_test(client.clone()).await?;
+
+    // a long stream that is produced faster than it is sent
+    let client = RpcClient::<ComputeService, _>::new(client);
+    let items = client
+        .server_streaming(Fibonacci(100))
+        .await?
+        .map(|x| x.map(|FibonacciResponse(x)| x))
+        .try_collect::<Vec<_>>()
+        .await?;
+    assert_eq!(items.len(), 100);
+    assert_eq!(items[99], 218922995834555169026);
+    server_handle.abort();
+    Ok(())
+}
+
+/// handlers can flush responses that the flush policy would hold back
+#[tokio::test(flavor = "multi_thread")]
+async fn quinn_flush_handle() -> anyhow::Result<()> {
+    use std::{sync::mpsc, time::Duration};
+    tracing_subscriber::fmt::try_init().ok();
+    let Endpoints {
+        client,
+        server,
+        server_addr,
+    } = make_endpoints(12362)?;
+    let server = quic_rpc::transport::quinn::QuinnServerEndpoint::new(server)?;
+    let server = RpcServer::<ComputeService, _>::new(server)
+        .with_flush_policy(FlushPolicy::Batch { max_items: 1000 });
+    let (received_tx, received_rx) = mpsc::channel::<()>();
+    let server_handle = tokio::task::spawn(async move {
+        let (req, chan) = server.accept().await?;
+        let flush = chan.flush_handle();
+        let req = match req {
+            ComputeRequest::Fibonacci(req) => req,
+            _ => anyhow::bail!("unexpected request"),
+        };
+        chan.server_streaming(req, (), move |_, _| {
+            // the second response is always ready, so only an explicit flush gets the
+            // first one to the client before it
+            futures::stream::iter([0, 1]).map(move |i| {
+                if i == 0 {
+                    flush.flush();
+                    FibonacciResponse(0)
+                } else {
+                    let received = tokio::task::block_in_place(|| {
+                        received_rx.recv_timeout(Duration::from_secs(10))
+                    });
+                    FibonacciResponse(received.is_ok() as u128)
+                }
+            })
+        })
+        .await?;
+        anyhow::Ok(server)
+    });
+    let client =
+        quic_rpc::transport::quinn::QuinnConnection::new(client, server_addr, "localhost".into());
+    let client = RpcClient::<ComputeService, _>::new(client);
+    let mut items = client.server_streaming(Fibonacci(2)).await?;
+    assert!(matches!(items.next().await, Some(Ok(FibonacciResponse(0)))));
+    received_tx.send(())?;
+    assert!(matches!(items.next().await, Some(Ok(FibonacciResponse(1)))));
+    let _server = server_handle.await??;
+    Ok(())
+}
+
+/// raw bytes after a typed request
+#[tokio::test]
+async fn quinn_raw_streaming() -> anyhow::Result<()> {
+    tracing_subscriber::fmt::try_init().ok();
+    let Endpoints {
+        client,
+        server,
+        server_addr,
+    } = make_endpoints(12349)?;
+    let server = quic_rpc::transport::quinn::QuinnServerEndpoint::new(server)?;
+    let server_handle = tokio::task::spawn(blob::BlobService::server(RpcServer::new(server)));
+    let client =
+        quic_rpc::transport::quinn::QuinnConnection::new(client, server_addr, "localhost".into());
+    blob::raw_echo_test(client.clone(), 0).await?;
+    blob::raw_echo_test(client, 1024 * 1024 * 4).await?;
+    server_handle.abort();
+    Ok(())
+}
+
+#[tokio::test]
+async fn quinn_connection_health() -> anyhow::Result<()> {
+    use quic_rpc::transport::ConnectionHealth;
+    tracing_subscriber::fmt::try_init().ok();
+    let Endpoints {
+        client,
+        server,
+        server_addr,
+    } = make_endpoints(12350)?;
+    let server_handle = run_server(server.clone());
+    let client =
+        quic_rpc::transport::quinn::QuinnConnection::new(client, server_addr, "localhost".into());
+    smoke_test(client.clone()).await?;
+    assert!(client.is_healthy());
+    assert!(client.ping().await.is_ok());
+    // closing the server endpoint closes the connection
+    server_handle.abort();
+    server.close(0u32.into(), b"done");
+    server.wait_idle().await;
+    tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    assert!(!client.is_healthy());
+    assert!(client.ping().await.is_err());
+    Ok(())
+}
+
+#[tokio::test]
+async fn quinn_channel_metadata() -> anyhow::Result<()> {
+    tracing_subscriber::fmt::try_init().ok();
+    let Endpoints {
+        client,
+        server,
+        server_addr,
+    } = make_endpoints(12351)?;
+    let server = quic_rpc::transport::quinn::QuinnServerEndpoint::new(server)?.metadata_frame(true);
+    let client =
+        quic_rpc::transport::quinn::QuinnConnection::new(client, server_addr, "localhost".into())
+            .metadata_frame(true);
+    metadata_test(server, client).await
+}
+
+/// without the metadata frame, substreams carry just length prefixed messages
+#[tokio::test]
+async fn quinn_channel_wire_format() -> anyhow::Result<()> {
+    use quic_rpc::transport::{quinn::QuinnServerEndpoint, ServerEndpoint};
+    tracing_subscriber::fmt::try_init().ok();
+    let Endpoints {
+        client,
+        server,
+        server_addr,
+    } = make_endpoints(12365)?;
+    let server = QuinnServerEndpoint::<ComputeRequest, ComputeResponse>::new(server)?;
+    let connection = client.connect(server_addr, "localhost")?.await?;
+    let (mut send, _recv) = connection.open_bi().await?;
+    let message = bincode::serialize(&ComputeRequest::from(Sqr(4)))?;
+    send.write_all(&(message.len() as u32).to_be_bytes())
+        .await?;
+    send.write_all(&message).await?;
+    let (_send, mut recv) = server.accept_bi().await?;
+    assert!(QuinnServerEndpoint::<ComputeRequest, ComputeResponse>::metadata(&recv).is_empty());
+    assert!(matches!(
+        recv.next().await,
+        Some(Ok(ComputeRequest::Sqr(Sqr(4))))
+    ));
+    Ok(())
+}
+
+#[tokio::test]
+async fn quinn_channel_stats() -> anyhow::Result<()> {
+    tracing_subscriber::fmt::try_init().ok();
+    let Endpoints {
+        client,
+        server,
+        server_addr,
+    } = make_endpoints(12352)?;
+    let server = quic_rpc::transport::quinn::QuinnServerEndpoint::new(server)?;
+    let client =
+        quic_rpc::transport::quinn::QuinnConnection::new(client, server_addr, "localhost".into());
+    stats_test(server, client, 16).await
+}
+
+#[tokio::test]
+async fn quinn_channel_metrics() -> anyhow::Result<()> {
+    use quic_rpc::{
+        metrics::Metrics,
+        telemetry::{Side, Telemetry},
+    };
+    tracing_subscriber::fmt::try_init().ok();
+    let Endpoints {
+        client,
+        server,
+        server_addr,
+    } = make_endpoints(12366)?;
+    let metrics = Metrics::new();
+    let telemetry = Telemetry::disabled().with_metrics(metrics.clone());
+    let server = quic_rpc::transport::quinn::QuinnServerEndpoint::new(server)?;
+    let server = RpcServer::<ComputeService, _>::new(server).with_telemetry(telemetry.clone());
+    let server_handle = tokio::task::spawn(ComputeService::server(server));
+    let client =
+        quic_rpc::transport::quinn::QuinnConnection::new(client, server_addr, "localhost".into());
+    let client = RpcClient::<ComputeService, _>::new(client).with_telemetry(telemetry);
+    let items = client.server_streaming(Fibonacci(5)).await?;
+    assert_eq!(items.count().await, 5);
+    // the size of the items is the size of the frames on the wire
+    let snapshot = metrics.snapshot();
+    let client_fib = snapshot
+        .get(Side::Client, method_name::<Fibonacci>())
+        .unwrap();
+    let server_fib = snapshot
+        .get(Side::Server, method_name::<Fibonacci>())
+        .unwrap();
+    assert_eq!(client_fib.items, 5);
+    assert!(client_fib.bytes > 0);
+    assert_eq!(client_fib.bytes, server_fib.bytes);
+    server_handle.abort();
+    Ok(())
+}
+
+#[tokio::test]
+async fn quinn_channel_auth() -> anyhow::Result<()> {
+    tracing_subscriber::fmt::try_init().ok();
+    let Endpoints {
+        client,
+        server,
+        server_addr,
+    } = make_endpoints(12353)?;
+    let server = quic_rpc::transport::quinn::QuinnServerEndpoint::new(server)?.metadata_frame(true);
+    let client =
+        quic_rpc::transport::quinn::QuinnConnection::new(client, server_addr, "localhost".into())
+            .metadata_frame(true);
+    auth_test(server, client).await
+}
+
+#[tokio::test]
+async fn quinn_channel_policy() -> anyhow::Result<()> {
+    tracing_subscriber::fmt::try_init().ok();
+    let Endpoints {
+        client,
+        server,
+        server_addr,
+    } = make_endpoints(12355)?;
+    let server = quic_rpc::transport::quinn::QuinnServerEndpoint::new(server)?.metadata_frame(true);
+    let client =
+        quic_rpc::transport::quinn::QuinnConnection::new(client, server_addr, "localhost".into())
+            .metadata_frame(true);
+    policy_test(server, client).await
+}
+
+#[tokio::test]
+async fn quinn_channel_size_limit() -> anyhow::Result<()> {
+    tracing_subscriber::fmt::try_init().ok();
+    let Endpoints {
+        client,
+        server,
+        server_addr,
+    } = make_endpoints(12360)?;
+    let server = quic_rpc::transport::quinn::QuinnServerEndpoint::new(server)?;
+    let client =
+        quic_rpc::transport::quinn::QuinnConnection::new(client, server_addr, "localhost".into());
+    size_limit_test(server, client).await
+}
+
+/// the client can tell why a channel was rejected
+#[tokio::test]
+async fn quinn_channel_rejection_status() -> anyhow::Result<()> {
+    use futures::{SinkExt, StreamExt};
+    use quic_rpc::{
+        auth::{bearer_metadata, TokenFile},
+        transport::{quinn::rejection_status, Connection, Status},
+    };
+    tracing_subscriber::fmt::try_init().ok();
+    let Endpoints {
+        client,
+        server,
+        server_addr,
+    } = make_endpoints(12354)?;
+    let server = quic_rpc::transport::quinn::QuinnServerEndpoint::new(server)?;
+    let client =
+        quic_rpc::transport::quinn::QuinnConnection::<ComputeResponse, ComputeRequest>::new(
+            client,
+            server_addr,
+            "localhost".into(),
+        );
+    let server = RpcServer::<ComputeService, _>::new(server)
+        .with_authenticator(TokenFile::parse("secret alice")?);
+    let server_handle = tokio::task::spawn(ComputeService::server(server));
+    let (mut send, mut recv) = client
+        .open_bi_with_metadata(bearer_metadata("guess"))
+        .await?;
+    send.send(Sqr(3).into()).await?;
+    let error = recv.next().await.expect("stream reset").unwrap_err();
+    assert_eq!(rejection_status(&error), Some(Status::Unauthenticated));
+    server_handle.abort();
+    Ok(())
+}
+
+/// metadata such as bearer tokens only reaches the server with the metadata frame
+#[tokio::test]
+async fn quinn_carries_metadata() -> anyhow::Result<()> {
+    use quic_rpc::transport::{quinn::QuinnConnection, Connection};
+    let Endpoints {
+        client,
+        server_addr,
+        ..
+    } = make_endpoints(12369)?;
+    let plain = QuinnConnection::<ComputeResponse, ComputeRequest>::new(
+        client,
+        server_addr,
+        "localhost".into(),
+    );
+    assert!(!plain.carries_metadata());
+    assert!(plain.metadata_frame(true).carries_metadata());
+    Ok(())
+}
+
+/// clients of a different version are rejected before their requests are decoded, and
+/// the server goes on serving other clients
+#[tokio::test]
+async fn quinn_channel_handshake() -> anyhow::Result<()> {
+    use quic_rpc::transport::quinn::{QuinnConnection, QuinnServerEndpoint};
+    tracing_subscriber::fmt::try_init().ok();
+    let Endpoints {
+        client,
+        server,
+        server_addr,
+    } = make_endpoints(12361)?;
+    let server =
+        QuinnServerEndpoint::<ComputeRequest, ComputeResponse>::new(server)?.metadata_frame(true);
+    let new =
+        QuinnConnection::new(client.clone(), server_addr, "localhost".into()).metadata_frame(true);
+    let old = QuinnConnection::new(client, server_addr, "localhost".into()).metadata_frame(true);
+    handshake_test(server, new, old).await
+}
+
+/// substreams over the concurrency limit of a connection are reset
+#[tokio::test]
+async fn quinn_channel_stream_limit() -> anyhow::Result<()> {
+    use futures::SinkExt;
+    use quic_rpc::transport::{
+        quinn::{rejection_status, QuinnConnection, QuinnServerEndpoint, ServerLimits},
+        Connection, RawStreams, ServerEndpoint, Status,
+    };
+    tracing_subscriber::fmt::try_init().ok();
+    let Endpoints {
+        client,
+        server,
+        server_addr,
+    } = make_endpoints(12356)?;
+    let limits = ServerLimits::default().max_concurrent_streams(Some(1));
+    let server =
+        QuinnServerEndpoint::<ComputeRequest, ComputeResponse>::with_limits(server, limits)?;
+    let client = QuinnConnection::<ComputeResponse, ComputeRequest>::new(
+        client,
+        server_addr,
+        "localhost".into(),
+    );
+    let (mut send, _recv) = client.open_bi().await?;
+    send.send(Sqr(1).into()).await?;
+    let first = server.accept_bi().await?;
+    // the first substream is still open
+    let (mut send2, mut recv2) = client.open_bi().await?;
+    send2.send(Sqr(2).into()).await?;
+    let error = recv2.next().await.expect("stream reset").unwrap_err();
+    assert_eq!(rejection_status(&error), Some(Status::ResourceExhausted));
+    // raw streams keep the place of the substream
+    let (send, recv) = first;
+    let raw = QuinnServerEndpoint::into_raw(send, recv);
+    let (mut send2, mut recv2) = client.open_bi().await?;
+    send2.send(Sqr(2).into()).await?;
+    let error = recv2.next().await.expect("stream reset").unwrap_err();
+    assert_eq!(rejection_status(&error), Some(Status::ResourceExhausted));
+    // once it is closed, there is room for another one
+    drop(raw);
+    let (mut send3, _recv3) = client.open_bi().await?;
+    send3.send(Sqr(3).into()).await?;
+    let (_, mut recv) = server.accept_bi().await?;
+    assert!(matches!(
+        recv.next().await,
+        Some(Ok(ComputeRequest::Sqr(Sqr(3))))
+    ));
+    Ok(())
+}
+
+/// substreams passed in by the application are limited as well
+#[tokio::test]
+async fn quinn_substreams_stream_limit() -> anyhow::Result<()> {
+    use futures::SinkExt;
+    use quic_rpc::transport::{
+        quinn::{rejection_status, QuinnConnection, QuinnServerEndpoint, ServerLimits},
+        Connection, ServerEndpoint, Status,
+    };
+    tracing_subscriber::fmt::try_init().ok();
+    let Endpoints {
+        client,
+        server,
+        server_addr,
+    } = make_endpoints(12370)?;
+    let (sender, receiver) = flume::bounded(16);
+    let accept = tokio::spawn(async move {
+        let connection = server.accept().await.unwrap().await?;
+        while let Ok(substream) = connection.accept_bi().await {
+            sender.send_async(substream).await?;
+        }
+        anyhow::Ok(())
+    });
+    let limits = ServerLimits::default().max_concurrent_streams(Some(1));
+    let server =
+        QuinnServerEndpoint::<ComputeRequest, ComputeResponse>::handle_substreams_with_limits(
+            receiver,
+            server_addr,
+            limits,
+        );
+    let client = QuinnConnection::<ComputeResponse, ComputeRequest>::new(
+        client,
+        server_addr,
+        "localhost".into(),
+    );
+    let (mut send, _recv) = client.open_bi().await?;
+    send.send(Sqr(1).into()).await?;
+    let first = server.accept_bi().await?;
+    let (mut send2, mut recv2) = client.open_bi().await?;
+    send2.send(Sqr(2).into()).await?;
+    let error = recv2.next().await.expect("stream reset").unwrap_err();
+    assert_eq!(rejection_status(&error), Some(Status::ResourceExhausted));
+    drop(first);
+    let (mut send3, _recv3) = client.open_bi().await?;
+    send3.send(Sqr(3).into()).await?;
+    let (_, mut recv) = server.accept_bi().await?;
+    assert!(matches!(
+        recv.next().await,
+        Some(Ok(ComputeRequest::Sqr(Sqr(3))))
+    ));
+    accept.abort();
+    Ok(())
+}
+
+/// substreams passed in by the application are accepted from their queue directly,
+/// unless they have to be checked against limits
+#[tokio::test]
+async fn quinn_substreams_queue_capacity() -> anyhow::Result<()> {
+    use quic_rpc::transport::{
+        quinn::{QuinnServerEndpoint, ServerLimits},
+        TransportStats,
+    };
+    let addr = "127.0.0.1:12371".parse()?;
+    let limits = ServerLimits::default().accept_queue_capacity(4);
+    let (_sender, receiver) = flume::bounded(1);
+    let server =
+        QuinnServerEndpoint::<ComputeRequest, ComputeResponse>::handle_substreams_with_limits(
+            receiver,
+            addr,
+            limits.clone(),
+        );
+    assert_eq!(server.stats().accept_queue_capacity, Some(1));
+    let (_sender, receiver) = flume::bounded(1);
+    let server =
+        QuinnServerEndpoint::<ComputeRequest, ComputeResponse>::handle_substreams_with_limits(
+            receiver,
+            addr,
+            limits.max_concurrent_streams(Some(8)),
+        );
+    assert_eq!(server.stats().accept_queue_capacity, Some(4));
+    Ok(())
+}
+
+/// substreams over the rate limit of a connection are reset
+#[tokio::test]
+async fn quinn_channel_rate_limit() -> anyhow::Result<()> {
+    use quic_rpc::transport::quinn::{QuinnConnection, QuinnServerEndpoint, ServerLimits};
+    tracing_subscriber::fmt::try_init().ok();
+    let Endpoints {
+        client,
+        server,
+        server_addr,
+    } = make_endpoints(12357)?;
+    let limits = ServerLimits::default().max_streams_per_second(Some(2));
+    let server = QuinnServerEndpoint::with_limits(server, limits)?;
+    let server = RpcServer::<ComputeService, _>::new(server);
+    let server_handle = tokio::task::spawn(ComputeService::server(server));
+    let client = QuinnConnection::new(client, server_addr, "localhost".into());
+    let client = RpcClient::<ComputeService, _>::new(client);
+    assert_eq!(client.rpc(Sqr(2)).await?, SqrResponse(4));
+    assert_eq!(client.rpc(Sqr(3)).await?, SqrResponse(9));
+    assert!(client.rpc(Sqr(4)).await.is_err());
+    // the bucket refills over time
+    tokio::time::sleep(std::time::Duration::from_millis(600)).await;
+    assert_eq!(client.rpc(Sqr(5)).await?, SqrResponse(25));
+    server_handle.abort();
+    Ok(())
+}
+
+/// a busy connection does not delay the substreams of other connections
+#[tokio::test]
+async fn quinn_channel_fair_queue() -> anyhow::Result<()> {
+    use futures::SinkExt;
+    use quic_rpc::transport::{
+        quinn::{QuinnConnection, QuinnServerEndpoint, ServerLimits},
+        Connection, ServerEndpoint,
+    };
+    use std::time::Duration;
+    tracing_subscriber::fmt::try_init().ok();
+    let Endpoints {
+        client,
+        server,
+        server_addr,
+    } = make_endpoints(12359)?;
+    let limits = ServerLimits::default()
+        .fair_queue_capacity(Some(2))
+        .accept_queue_capacity(1);
+    let server =
+        QuinnServerEndpoint::<ComputeRequest, ComputeResponse>::with_limits(server, limits)?;
+    let busy = QuinnConnection::<ComputeResponse, ComputeRequest>::new(
+        client.clone(),
+        server_addr,
+        "localhost".into(),
+    );
+    let quiet = QuinnConnection::<ComputeResponse, ComputeRequest>::new(
+        client,
+        server_addr,
+        "localhost".into(),
+    );
+    let mut channels = Vec::new();
+    for i in 0..10 {
+        let (mut send, recv) = busy.open_bi().await?;
+        send.send(Sqr(i).into()).await?;
+        channels.push((send, recv));
+    }
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    let (mut send, _recv) = quiet.open_bi().await?;
+    send.send(Sqr(99).into()).await?;
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    let mut order = Vec::new();
+    for _ in 0..11 {
+        let (_, mut recv) = server.accept_bi().await?;
+        match recv.next().await {
+            Some(Ok(ComputeRequest::Sqr(Sqr(i)))) => order.push(i),
+            other => anyhow::bail!("unexpected request {other:?}"),
+        }
+    }
+    // without fair queuing, the quiet substream would be the last one
+    let position = order.iter().position(|&i| i == 99).unwrap();
+    assert!(position < 3, "accepted in order {order:?}");
+    Ok(())
+}
+
+/// fair queuing keeps the capacity of the accept queue
+#[tokio::test]
+async fn quinn_channel_fair_queue_capacity() -> anyhow::Result<()> {
+    use futures::SinkExt;
+    use quic_rpc::transport::{
+        quinn::{QuinnConnection, QuinnServerEndpoint, ServerLimits},
+        Connection, TransportStats,
+    };
+    use std::time::Duration;
+    tracing_subscriber::fmt::try_init().ok();
+    let Endpoints {
+        client,
+        server,
+        server_addr,
+    } = make_endpoints(12364)?;
+    let limits = ServerLimits::default().fair_queue_capacity(Some(2));
+    let server =
+        QuinnServerEndpoint::<ComputeRequest, ComputeResponse>::with_limits(server, limits)?;
+    let client = QuinnConnection::<ComputeResponse, ComputeRequest>::new(
+        client,
+        server_addr,
+        "localhost".into(),
+    );
+    let mut channels = Vec::new();
+    for i in 0..10 {
+        let (mut send, recv) = client.open_bi().await?;
+        send.send(Sqr(i).into()).await?;
+        channels.push((send, recv));
+    }
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    let stats = server.stats();
+    assert_eq!(stats.accept_queue_capacity, Some(16));
+    assert_eq!(stats.accept_queue_len, 10);
+    Ok(())
+}
+
+/// connections over the limit of an IP address are closed
+#[tokio::test]
+async fn quinn_channel_connection_limit() -> anyhow::Result<()> {
+    use quic_rpc::transport::quinn::{QuinnConnection, QuinnServerEndpoint, ServerLimits};
+    tracing_subscriber::fmt::try_init().ok();
+    let Endpoints {
+        client,
+        server,
+        server_addr,
+    } = make_endpoints(12358)?;
+    let limits = ServerLimits::default().max_connections_per_ip(Some(1));
+    let server = QuinnServerEndpoint::with_limits(server, limits)?;
+    let server = RpcServer::<ComputeService, _>::new(server);
+    let server_handle = tokio::task::spawn(ComputeService::server(server));
+    // every QuinnConnection has its own quinn connection
+    let first = QuinnConnection::new(client.clone(), server_addr, "localhost".into());
+    let first = RpcClient::<ComputeService, _>::new(first);
+    let second = QuinnConnection::new(client, server_addr, "localhost".into());
+    let second = RpcClient::<ComputeService, _>::new(second);
+    assert_eq!(first.rpc(Sqr(2)).await?, SqrResponse(4));
+    assert!(second.rpc(Sqr(3)).await.is_err());
+    assert_eq!(first.rpc(Sqr(4)).await?, SqrResponse(16));
+    server_handle.abort();
+    Ok(())
+}
+
+/// the limits also apply to connections that are accepted outside of the endpoint
+#[tokio::test]
+async fn quinn_handle_connections_limit() -> anyhow::Result<()> {
+    use quic_rpc::transport::quinn::{QuinnConnection, QuinnServerEndpoint, ServerLimits};
+    tracing_subscriber::fmt::try_init().ok();
+    let Endpoints {
+        client,
+        server,
+        server_addr,
+    } = make_endpoints(12363)?;
+    let (connections, incoming) = flume::bounded(1);
+    let accept_handle = tokio::task::spawn(async move {
+        while let Some(connecting) = server.accept().await {
+            if let Ok(connection) = connecting.await {
+                connections.send_async(connection).await?;
+            }
+        }
+        anyhow::Ok(())
+    });
+    let limits = ServerLimits::default().max_connections_per_ip(Some(1));
+    let server = QuinnServerEndpoint::handle_connections_with_limits(incoming, server_addr, limits);
+    let server = RpcServer::<ComputeService, _>::new(server);
+    let server_handle = tokio::task::spawn(ComputeService::server(server));
+    let first = QuinnConnection::new(client.clone(), server_addr, "localhost".into());
+    let first = RpcClient::<ComputeService, _>::new(first);
+    let second = QuinnConnection::new(client, server_addr, "localhost".into());
+    let second = RpcClient::<ComputeService, _>::new(second);
+    assert_eq!(first.rpc(Sqr(2)).await?, SqrResponse(4));
+    assert!(second.rpc(Sqr(3)).await.is_err());
+    assert_eq!(first.rpc(Sqr(4)).await?, SqrResponse(16));
+    server_handle.abort();
+    accept_handle.abort();
+    Ok(())
+}
+
+#[cfg(feature = "test-utils")]
+#[tokio::test]
+async fn quinn_channel_conformance() -> anyhow::Result<()> {
+    use quic_rpc::testing;
+    tracing_subscriber::fmt::try_init().ok();
+    let Endpoints {
+        client,
+        server,
+        server_addr,
+    } = make_endpoints(12347)?;
+    let server = quic_rpc::transport::quinn::QuinnServerEndpoint::new(server)?;
+    let client =
+        quic_rpc::transport::quinn::QuinnConnection::new(client, server_addr, "localhost".into());
+    testing::run_all(server, client).await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn quinn_channel_cancel() -> anyhow::Result<()> {
+    tracing_subscriber::fmt::try_init().ok();
+    let Endpoints {
+        client,
+        server,
+        server_addr,
+    } = make_endpoints(12367)?;
+    let server = quic_rpc::transport::quinn::QuinnServerEndpoint::new(server)?;
+    let client =
+        quic_rpc::transport::quinn::QuinnConnection::new(client, server_addr, "localhost".into());
+    cancel_test(server, client).await
+}
+
+/// losing the connection is a receive error, not a cancellation by the client
+#[tokio::test]
+async fn quinn_channel_connection_lost() -> anyhow::Result<()> {
+    use quic_rpc::{
+        server::RpcServerError,
+        transport::quinn::{QuinnServerEndpoint, CANCEL_CODE},
+    };
+    tracing_subscriber::fmt::try_init().ok();
+    let Endpoints {
+        client,
+        server,
+        server_addr,
+    } = make_endpoints(12368)?;
+    let server = RpcServer::<ComputeService, _>::new(QuinnServerEndpoint::new(server)?);
+    let (started_send, started) = tokio::sync::oneshot::channel();
+    let server_handle = tokio::task::spawn(async move {
+        let (req, chan) = server.accept().await?;
+        let ComputeRequest::Sqr(req) = req else {
+            anyhow::bail!("unexpected request");
+        };
+        let token = chan.cancellation_token();
+        let res = chan
+            .rpc(req, started_send, |started, _| async move {
+                started.send(()).ok();
+                futures::future::pending::<SqrResponse>().await
+            })
+            .await;
+        anyhow::Ok((res, token.is_cancelled()))
+    });
+    let connection = client.connect(server_addr, "localhost")?.await?;
+    let (mut send, _recv) = connection.open_bi().await?;
+    let message = bincode::serialize(&ComputeRequest::from(Sqr(4)))?;
+    send.write_all(&(message.len() as u32).to_be_bytes())
+        .await?;
+    send.write_all(&message).await?;
+    started.await?;
+    // closing with the cancel code does not make it a cancellation
+    connection.close(CANCEL_CODE.into(), b"");
+    let (res, cancelled) = server_handle.await??;
+    assert!(matches!(res, Err(RpcServerError::RecvError(_))), "{res:?}");
+    assert!(cancelled);
+    Ok(())
+}
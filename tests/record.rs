@@ -0,0 +1,77 @@
+#![cfg(all(feature = "record-transport", feature = "flume-transport"))]
+use std::{
+    io,
+    sync::{Arc, Mutex},
+};
+
+use quic_rpc::{
+    client::RpcClientError,
+    transport::{
+        flume,
+        record::{Direction, Recorder, Recording, RecordingConnection},
+    },
+    RpcClient, RpcServer,
+};
+
+mod math;
+use math::*;
+
+/// A writer that can be read back after recording
+#[derive(Debug, Clone, Default)]
+struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+impl io::Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[tokio::test]
+async fn record_and_replay() -> anyhow::Result<()> {
+    tracing_subscriber::fmt::try_init().ok();
+
+    // record a session against a real server
+    let buffer = SharedBuffer::default();
+    let recorder = Recorder::new(buffer.clone());
+    let (server, client) = flume::connection::<ComputeRequest, ComputeResponse>(1);
+    let server = RpcServer::<ComputeService, _>::new(server);
+    let server_handle = tokio::task::spawn(ComputeService::server(server));
+    smoke_test(RecordingConnection::new(client, recorder.clone())).await?;
+    recorder.finish()?;
+    server_handle.abort();
+
+    let data = buffer.0.lock().unwrap().clone();
+    let recording = Recording::load(data.as_slice())?;
+    let first = &recording.entries()[0];
+    assert_eq!(first.channel, 0);
+    assert_eq!(first.direction, Direction::ClientToServer);
+    assert!(matches!(
+        first.decode::<ComputeRequest>()?,
+        ComputeRequest::Sqr(Sqr(1234))
+    ));
+    // 4 interactions, each on its own channel
+    let last = recording.entries().last().unwrap();
+    assert_eq!(last.channel, 3);
+
+    // replay the session without the real server
+    let (server, client) = flume::connection::<ComputeRequest, ComputeResponse>(1);
+    let replay_handle = tokio::task::spawn(async move { recording.replay(server).await });
+    smoke_test(client.clone()).await?;
+
+    // requests that are not in the recording are closed
+    let client = RpcClient::<ComputeService, _>::new(client);
+    let res = client.rpc(Sqr(4321)).await;
+    assert!(matches!(res, Err(RpcClientError::EarlyClose)));
+
+    // each recorded channel is only used once
+    let res = client.rpc(Sqr(1234)).await;
+    assert!(matches!(res, Err(RpcClientError::EarlyClose)));
+
+    replay_handle.abort();
+    Ok(())
+}
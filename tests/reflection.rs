@@ -0,0 +1,140 @@
+#![cfg(all(feature = "flume-transport", feature = "macros"))]
+use quic_rpc::{
+    message::Msg,
+    reflection::{
+        self, Describe, MethodInfo, PatternKind, Reflect, ReflectionService, ServiceInfo,
+    },
+    rpc_service,
+    transport::flume,
+    RpcClient, RpcServer,
+};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Get(pub u64);
+#[derive(Debug, Serialize, Deserialize)]
+pub struct GetResponse(pub Vec<u8>);
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Upload;
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UploadUpdate(pub Vec<u8>);
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UploadResponse(pub u64);
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct List;
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ListResponse(pub u64);
+
+rpc_service! {
+    Request = StoreRequest;
+    Response = StoreResponse;
+    Service = StoreService;
+    CreateDispatch = _;
+
+    Rpc get = Get, _ -> GetResponse;
+    ClientStreaming upload = Upload, UploadUpdate -> UploadResponse;
+    ServerStreaming list = List, _ -> ListResponse;
+    Rpc describe = Describe, _ -> ServiceInfo;
+}
+
+fn method(
+    name: &str,
+    request: &str,
+    pattern: PatternKind,
+    update: Option<&str>,
+    response: &str,
+) -> MethodInfo {
+    MethodInfo {
+        name: name.to_string(),
+        request: request.to_string(),
+        pattern,
+        update: update.map(ToString::to_string),
+        response: Some(response.to_string()),
+    }
+}
+
+fn expected() -> ServiceInfo {
+    ServiceInfo {
+        name: "StoreService".to_string(),
+        methods: vec![
+            method("get", "Get", PatternKind::Rpc, None, "GetResponse"),
+            method(
+                "upload",
+                "Upload",
+                PatternKind::ClientStreaming,
+                Some("UploadUpdate"),
+                "UploadResponse",
+            ),
+            method(
+                "list",
+                "List",
+                PatternKind::ServerStreaming,
+                None,
+                "ListResponse",
+            ),
+            method(
+                "describe",
+                "Describe",
+                PatternKind::Rpc,
+                None,
+                "ServiceInfo",
+            ),
+        ],
+    }
+}
+
+#[test]
+fn rpc_service_reflection() {
+    let info = StoreService::service_info();
+    assert_eq!(info, expected());
+    assert_eq!(
+        info.method("List").map(|m| m.pattern),
+        Some(PatternKind::ServerStreaming)
+    );
+    assert!(info.method("UploadUpdate").is_none());
+}
+
+/// the methods of the macro are named by their request enum variant
+#[test]
+fn rpc_service_method_names() {
+    assert_eq!(<Get as Msg<StoreService>>::method_name(), "Get");
+    assert_eq!(<Upload as Msg<StoreService>>::method_name(), "Upload");
+    assert_eq!(<List as Msg<StoreService>>::method_name(), "List");
+}
+
+#[tokio::test]
+async fn flume_reflection_mounted() -> anyhow::Result<()> {
+    let (server, client) = flume::connection::<StoreRequest, StoreResponse>(1);
+    let server = RpcServer::<StoreService, _>::new(server);
+    let server_handle = tokio::task::spawn(async move {
+        let (req, chan) = server.accept().await?;
+        match req {
+            StoreRequest::Describe(req) => {
+                reflection::handle::<StoreService, _, _>(req, chan).await?
+            }
+            _ => anyhow::bail!("unexpected request"),
+        }
+        anyhow::Ok(())
+    });
+    let client = RpcClient::<StoreService, _>::new(client);
+    assert_eq!(client.rpc(Describe).await?, expected());
+    server_handle.await??;
+    Ok(())
+}
+
+#[tokio::test]
+async fn flume_reflection_standalone() -> anyhow::Result<()> {
+    let (server, client) = flume::connection::<Describe, ServiceInfo>(1);
+    let server = RpcServer::<ReflectionService, _>::new(server);
+    let server_handle = tokio::task::spawn(async move {
+        let (req, chan) = server.accept().await?;
+        reflection::handle::<StoreService, _, _>(req, chan).await?;
+        anyhow::Ok(())
+    });
+    let client = RpcClient::<ReflectionService, _>::new(client);
+    assert_eq!(client.rpc(Describe).await?, expected());
+    server_handle.await??;
+    Ok(())
+}
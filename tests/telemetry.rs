@@ -0,0 +1,271 @@
+#![cfg(feature = "flume-transport")]
+mod math;
+use futures::StreamExt;
+use math::*;
+use quic_rpc::{
+    telemetry::{
+        DefaultSpanFactory, Propagator, RequestInfo, Side, SpanFactory, Telemetry, TraceContext,
+    },
+    transport::{flume, Metadata, ServerEndpoint},
+    RpcClient, RpcServer,
+};
+use std::{
+    collections::BTreeMap,
+    fmt,
+    sync::{Arc, Mutex},
+};
+use tracing::{
+    field::{Field, Visit},
+    span, Span, Subscriber,
+};
+use tracing_subscriber::{
+    filter::LevelFilter, layer::Context, prelude::*, registry::LookupSpan, Layer,
+};
+
+/// span factory that remembers the requests it created spans for
+#[derive(Debug, Clone, Default)]
+struct Requests(Arc<Mutex<Vec<RequestInfo>>>);
+
+impl SpanFactory for Requests {
+    fn make_span(&self, info: &RequestInfo) -> Span {
+        self.0.lock().unwrap().push(info.clone());
+        DefaultSpanFactory.make_span(info)
+    }
+}
+
+impl Requests {
+    fn take(&self) -> Vec<(Side, &'static str, &'static str)> {
+        std::mem::take(&mut *self.0.lock().unwrap())
+            .into_iter()
+            .map(|info| {
+                assert_eq!(info.service, "ComputeService");
+                (info.side, info.method, info.pattern)
+            })
+            .collect()
+    }
+}
+
+/// propagator that uses the span id as the trace context, and remembers what it did
+#[derive(Debug, Clone, Default)]
+struct Ids {
+    sent: Arc<Mutex<Vec<TraceContext>>>,
+    received: Arc<Mutex<Vec<TraceContext>>>,
+}
+
+impl Propagator for Ids {
+    fn context(&self, span: &Span) -> Option<TraceContext> {
+        let context = TraceContext {
+            trace_id: 42,
+            span_id: span.id()?.into_u64(),
+            flags: TraceContext::SAMPLED,
+        };
+        self.sent.lock().unwrap().push(context);
+        Some(context)
+    }
+
+    fn set_parent(&self, _span: &Span, parent: TraceContext) {
+        self.received.lock().unwrap().push(parent);
+    }
+}
+
+type Fields = BTreeMap<String, String>;
+
+/// layer that remembers the fields of all spans
+#[derive(Debug, Clone, Default)]
+struct Recorder(Arc<Mutex<BTreeMap<u64, Fields>>>);
+
+struct FieldVisitor<'a>(&'a mut Fields);
+
+impl Visit for FieldVisitor<'_> {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.0
+            .insert(field.name().to_string(), format!("{value:?}"));
+    }
+}
+
+impl<S: Subscriber + for<'a> LookupSpan<'a>> Layer<S> for Recorder {
+    fn on_new_span(&self, attrs: &span::Attributes<'_>, id: &span::Id, _ctx: Context<'_, S>) {
+        let mut spans = self.0.lock().unwrap();
+        attrs.record(&mut FieldVisitor(spans.entry(id.into_u64()).or_default()));
+    }
+
+    fn on_record(&self, id: &span::Id, values: &span::Record<'_>, _ctx: Context<'_, S>) {
+        let mut spans = self.0.lock().unwrap();
+        values.record(&mut FieldVisitor(spans.entry(id.into_u64()).or_default()));
+    }
+}
+
+impl Recorder {
+    /// fields of the spans for the given method, client first
+    fn spans(&self, method: &str) -> Vec<Fields> {
+        let mut spans = self
+            .0
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|fields| fields.get("method").map(String::as_str) == Some(method))
+            .cloned()
+            .collect::<Vec<_>>();
+        spans.sort_by_key(|fields| fields["side"].clone());
+        spans
+    }
+}
+
+fn field<'a>(fields: &'a Fields, name: &str) -> Option<&'a str> {
+    fields.get(name).map(String::as_str)
+}
+
+#[tokio::test]
+async fn flume_telemetry_spans() -> anyhow::Result<()> {
+    let recorder = Recorder::default();
+    let _guard = tracing_subscriber::registry()
+        .with(recorder.clone())
+        .set_default();
+    let requests = Requests::default();
+    let telemetry = Telemetry::with_factory(requests.clone());
+
+    let (server, client) = flume::connection::<ComputeRequest, ComputeResponse>(1);
+    let server = RpcServer::<ComputeService, _>::new(server).with_telemetry(telemetry.clone());
+    let server_handle = tokio::task::spawn(ComputeService::server(server));
+    let client = RpcClient::<ComputeService, _>::new(client).with_telemetry(telemetry);
+
+    let sqr = method_name::<Sqr>();
+    let fib = method_name::<Fibonacci>();
+    client.rpc(Sqr(3)).await?;
+    assert_eq!(
+        requests.take(),
+        vec![(Side::Client, sqr, "Rpc"), (Side::Server, sqr, "Rpc")]
+    );
+    let spans = recorder.spans(&format!("{sqr:?}"));
+    assert_eq!(spans.len(), 2);
+    for span in &spans {
+        assert_eq!(field(span, "outcome"), Some("\"ok\""));
+    }
+
+    let items = client.server_streaming(Fibonacci(5)).await?;
+    assert_eq!(items.count().await, 5);
+    assert_eq!(
+        requests.take(),
+        vec![
+            (Side::Client, fib, "ServerStreaming"),
+            (Side::Server, fib, "ServerStreaming")
+        ]
+    );
+    let spans = recorder.spans(&format!("{fib:?}"));
+    assert_eq!(spans.len(), 2);
+    for span in &spans {
+        assert_eq!(field(span, "items"), Some("5"));
+        assert_eq!(field(span, "outcome"), Some("\"ok\""));
+    }
+    // client and server have their own request ids, and the server records the one of
+    // the client
+    assert_ne!(spans[0]["request_id"], spans[1]["request_id"]);
+    assert_eq!(field(&spans[0], "client_request_id"), None);
+    assert_eq!(
+        field(&spans[1], "client_request_id"),
+        field(&spans[0], "request_id")
+    );
+
+    server_handle.abort();
+    Ok(())
+}
+
+#[tokio::test]
+async fn flume_telemetry_disabled() -> anyhow::Result<()> {
+    let (server, client) = flume::connection::<ComputeRequest, ComputeResponse>(1);
+    let server = RpcServer::<ComputeService, _>::new(server).with_telemetry(Telemetry::disabled());
+    let server_handle = tokio::task::spawn(ComputeService::server(server));
+    let requests = Requests::default();
+    let client = RpcClient::<ComputeService, _>::new(client)
+        .with_telemetry(Telemetry::with_factory(requests.clone()));
+    client.rpc(Sqr(3)).await?;
+    // only the client created a span
+    assert_eq!(
+        requests.take(),
+        vec![(Side::Client, method_name::<Sqr>(), "Rpc")]
+    );
+    assert!(!Telemetry::disabled().is_enabled());
+
+    server_handle.abort();
+    Ok(())
+}
+
+#[tokio::test]
+async fn flume_telemetry_propagation() -> anyhow::Result<()> {
+    let _guard = tracing_subscriber::registry()
+        .with(Recorder::default())
+        .set_default();
+    let ids = Ids::default();
+    let telemetry = Telemetry::default().with_propagator(ids.clone());
+
+    let (server, client) = flume::connection::<ComputeRequest, ComputeResponse>(1);
+    let server = RpcServer::<ComputeService, _>::new(server).with_telemetry(telemetry.clone());
+    let server_handle = tokio::task::spawn(ComputeService::server(server));
+    let client = RpcClient::<ComputeService, _>::new(client).with_telemetry(telemetry);
+
+    client.rpc(Sqr(3)).await?;
+    let items = client.server_streaming(Fibonacci(3)).await?;
+    assert_eq!(items.count().await, 3);
+    let sent = ids.sent.lock().unwrap().clone();
+    assert_eq!(sent.len(), 2);
+    assert_ne!(sent[0], sent[1]);
+    assert_eq!(*ids.received.lock().unwrap(), sent);
+
+    server_handle.abort();
+    Ok(())
+}
+
+/// the client only sends its request id if its span is recorded
+#[tokio::test]
+async fn flume_telemetry_request_id() -> anyhow::Result<()> {
+    let (server, client) = flume::connection::<ComputeRequest, ComputeResponse>(1);
+    let client = RpcClient::<ComputeService, _>::new(client);
+    for (level, sent) in [(LevelFilter::INFO, false), (LevelFilter::DEBUG, true)] {
+        let _guard = tracing_subscriber::registry()
+            .with(Recorder::default().with_filter(level))
+            .set_default();
+        let call = tokio::task::spawn({
+            let client = client.clone();
+            async move { client.rpc(Sqr(3)).await }
+        });
+        let (_send, recv) = server.accept_bi().await?;
+        let metadata =
+            flume::FlumeServerEndpoint::<ComputeRequest, ComputeResponse>::metadata(&recv);
+        assert_eq!(metadata.get(RequestInfo::CLIENT_REQUEST_ID).is_some(), sent);
+        call.abort();
+    }
+    Ok(())
+}
+
+#[test]
+fn traceparent_format() {
+    let value = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+    let context: TraceContext = value.parse().unwrap();
+    assert_eq!(context.trace_id, 0x4bf92f3577b34da6a3ce929d0e0e4736);
+    assert_eq!(context.span_id, 0x00f067aa0ba902b7);
+    assert!(context.is_sampled());
+    assert_eq!(context.to_string(), value);
+
+    let mut metadata = Metadata::new();
+    context.to_metadata(&mut metadata);
+    assert_eq!(metadata.get("traceparent"), Some(value));
+    assert_eq!(TraceContext::from_metadata(&metadata), Some(context));
+
+    // future versions may add fields
+    assert!(
+        "cc-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-xyz"
+            .parse::<TraceContext>()
+            .is_ok()
+    );
+    for invalid in [
+        "",
+        "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-xyz",
+        "ff-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+        "00-00000000000000000000000000000000-00f067aa0ba902b7-01",
+        "00-4bf92f3577b34da6a3ce929d0e0e4736-0000000000000000-01",
+        "00-4BF92F3577B34DA6A3CE929D0E0E4736-00f067aa0ba902b7-01",
+        "00-4bf92f3577b34da6a3ce929d0e0e473-00f067aa0ba902b7-01",
+    ] {
+        assert!(invalid.parse::<TraceContext>().is_err(), "{invalid}");
+    }
+}
@@ -0,0 +1,158 @@
+#![cfg(feature = "flume-transport")]
+use derive_more::{From, TryInto};
+use futures::{stream, SinkExt, StreamExt};
+use quic_rpc::{
+    client::{BidiItemError, StreamingResponseItemError},
+    declare_bidi_streaming, declare_server_streaming,
+    message::StreamFrame,
+    transport::flume,
+    RpcClient, RpcServer, Service,
+};
+use serde::{Deserialize, Serialize};
+
+/// count to n, optionally omitting the trailer
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Count {
+    n: u64,
+    trailer: bool,
+}
+
+/// echo the updates, the trailer is the number of updates
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Echo;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct EchoUpdate(u64);
+
+#[derive(Debug, Serialize, Deserialize, From, TryInto)]
+pub enum TrailerRequest {
+    Count(Count),
+    Echo(Echo),
+    EchoUpdate(EchoUpdate),
+}
+
+#[derive(Debug, Serialize, Deserialize, From, TryInto)]
+pub enum TrailerResponse {
+    Count(StreamFrame<u64, String>),
+    Echo(StreamFrame<u64, usize>),
+}
+
+#[derive(Debug, Clone)]
+pub struct TrailerService;
+
+impl Service for TrailerService {
+    type Req = TrailerRequest;
+    type Res = TrailerResponse;
+}
+
+declare_server_streaming!(TrailerService, Count, StreamFrame<u64, String>);
+declare_bidi_streaming!(TrailerService, Echo, EchoUpdate, StreamFrame<u64, usize>);
+
+async fn serve(
+    server: RpcServer<TrailerService, flume::FlumeServerEndpoint<TrailerRequest, TrailerResponse>>,
+) -> anyhow::Result<()> {
+    loop {
+        let (req, chan) = server.accept().await?;
+        match req {
+            TrailerRequest::Count(req) => {
+                chan.server_streaming_with_trailer(req, (), |_, Count { n, trailer }| {
+                    let items = stream::iter((0..n).map(StreamFrame::Item));
+                    // anything after the trailer is not sent
+                    let end = stream::iter(
+                        [
+                            StreamFrame::Trailer("done".to_string()),
+                            StreamFrame::Item(n),
+                        ]
+                        .into_iter()
+                        .filter(move |_| trailer),
+                    );
+                    items.chain(end)
+                })
+                .await?
+            }
+            TrailerRequest::Echo(req) => {
+                chan.bidi_streaming_with_trailer(req, (), |_, _, updates| {
+                    updates
+                        .map(|EchoUpdate(x)| StreamFrame::Item(x))
+                        .chain(stream::once(async { StreamFrame::Trailer(3) }))
+                })
+                .await?
+            }
+            TrailerRequest::EchoUpdate(_) => anyhow::bail!("unexpected request"),
+        }
+    }
+}
+
+#[tokio::test]
+async fn flume_server_streaming_trailer() -> anyhow::Result<()> {
+    let (server, client) = flume::connection::<TrailerRequest, TrailerResponse>(1);
+    let server_handle = tokio::task::spawn(serve(RpcServer::new(server)));
+    let client = RpcClient::<TrailerService, _>::new(client);
+
+    let mut items = client
+        .server_streaming_with_trailer(Count {
+            n: 3,
+            trailer: true,
+        })
+        .await?;
+    let mut res = Vec::new();
+    while let Some(item) = items.next().await {
+        res.push(item?);
+    }
+    assert_eq!(res, vec![0, 1, 2]);
+    assert_eq!(items.into_trailer(), Some("done".to_string()));
+
+    // a stream without trailer ends with an early close error
+    let mut items = client
+        .server_streaming_with_trailer(Count {
+            n: 2,
+            trailer: false,
+        })
+        .await?;
+    assert_eq!(items.next().await.transpose()?, Some(0));
+    assert_eq!(items.next().await.transpose()?, Some(1));
+    assert!(matches!(
+        items.next().await,
+        Some(Err(StreamingResponseItemError::EarlyClose))
+    ));
+    assert!(items.next().await.is_none());
+    assert_eq!(items.trailer(), None);
+
+    server_handle.abort();
+    Ok(())
+}
+
+#[tokio::test]
+async fn flume_bidi_trailer() -> anyhow::Result<()> {
+    let (server, client) = flume::connection::<TrailerRequest, TrailerResponse>(1);
+    let server_handle = tokio::task::spawn(serve(RpcServer::new(server)));
+    let client = RpcClient::<TrailerService, _>::new(client);
+
+    let (mut send, mut recv) = client.bidi_with_trailer(Echo).await?;
+    for i in 0..3 {
+        send.send(EchoUpdate(i)).await?;
+        assert_eq!(recv.next().await.transpose()?, Some(i));
+    }
+    send.finish().await?;
+    assert!(recv.next().await.is_none());
+    assert_eq!(recv.trailer(), Some(&3));
+
+    server_handle.abort();
+
+    // the server closes the channel before sending the trailer
+    let (server, client) = flume::connection::<TrailerRequest, TrailerResponse>(1);
+    let server = RpcServer::<TrailerService, _>::new(server);
+    let server_handle = tokio::task::spawn(async move {
+        let (_req, chan) = server.accept().await?;
+        drop(chan);
+        anyhow::Ok(server)
+    });
+    let client = RpcClient::<TrailerService, _>::new(client);
+    let (_send, mut recv) = client.bidi_with_trailer(Echo).await?;
+    let _server = server_handle.await??;
+    assert!(matches!(
+        recv.next().await,
+        Some(Err(BidiItemError::EarlyClose))
+    ));
+    Ok(())
+}
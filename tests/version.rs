@@ -0,0 +1,165 @@
+#![cfg(all(feature = "flume-transport", feature = "macros"))]
+use quic_rpc::{
+    client::RpcClientError,
+    declare_rpc, rpc_service,
+    transport::{flume, Metadata, Status},
+    version::{Fingerprint, Schema, Variant, Version, Versioned},
+    RpcClient, RpcServer, Service,
+};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Get(pub u64);
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct GetResponse(pub Vec<u8>);
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Upload;
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UploadUpdate(pub Vec<u8>);
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UploadResponse(pub u64);
+
+rpc_service! {
+    Request = StoreRequest;
+    Response = StoreResponse;
+    Service = StoreService;
+    CreateDispatch = _;
+
+    Rpc get = Get, _ -> GetResponse;
+    ClientStreaming upload = Upload, UploadUpdate -> UploadResponse;
+}
+
+/// the store service of a client whose ids are smaller
+mod narrow {
+    pub use super::{GetResponse, Upload, UploadResponse, UploadUpdate};
+    use quic_rpc::rpc_service;
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Debug, Serialize, Deserialize)]
+    pub struct Get(pub u32);
+
+    rpc_service! {
+        Request = StoreRequest;
+        Response = StoreResponse;
+        Service = StoreService;
+        CreateDispatch = _;
+
+        Rpc get = Get, _ -> GetResponse;
+        ClientStreaming upload = Upload, UploadUpdate -> UploadResponse;
+    }
+}
+
+/// the store service of a client that does not know about uploads yet
+#[derive(Debug, Clone)]
+struct OldStoreService;
+
+impl Service for OldStoreService {
+    type Req = StoreRequest;
+    type Res = StoreResponse;
+}
+
+declare_rpc!(OldStoreService, Get, GetResponse);
+
+impl Versioned for OldStoreService {
+    const NAME: &'static str = "StoreService";
+
+    fn schema() -> Schema {
+        Schema {
+            request: vec![Variant::new("Get", "Get")],
+            response: vec![Variant::new("GetResponse", "GetResponse")],
+            formats: Default::default(),
+        }
+    }
+}
+
+fn variants(names: &[&str]) -> Vec<Variant> {
+    names.iter().map(|n| Variant::new(*n, *n)).collect()
+}
+
+#[test]
+fn rpc_service_schema() {
+    let schema = StoreService::schema();
+    assert_eq!(StoreService::NAME, "StoreService");
+    assert_eq!(schema.request, variants(&["Get", "Upload", "UploadUpdate"]));
+    assert_eq!(
+        schema.response,
+        variants(&["GetResponse", "UploadResponse"])
+    );
+    assert_eq!(
+        schema.formats["StoreRequest"],
+        "enum { Get(Get), Upload(Upload), UploadUpdate(UploadUpdate) }"
+    );
+    assert_eq!(schema.formats["Upload"], "struct");
+    assert_eq!(schema.formats["GetResponse"], "struct([u8])");
+    // the fingerprint must not change between builds
+    assert_eq!(schema.fingerprint(), Fingerprint(0x1c97_33e3_7037_a247));
+    assert_ne!(
+        schema.fingerprint(),
+        OldStoreService::schema().fingerprint()
+    );
+}
+
+#[test]
+fn field_type_changes_fingerprint() {
+    let schema = StoreService::schema();
+    let narrow = narrow::StoreService::schema();
+    assert_eq!(schema.request, narrow.request);
+    assert_eq!(schema.response, narrow.response);
+    assert_eq!(schema.formats["Get"], "struct(u64)");
+    assert_eq!(narrow.formats["Get"], "struct(u32)");
+    assert_ne!(schema.fingerprint(), narrow.fingerprint());
+}
+
+#[test]
+fn version_metadata() {
+    let version = Version::of::<StoreService>();
+    let mut metadata = Metadata::new();
+    version.to_metadata(&mut metadata);
+    assert_eq!(metadata.get(Version::SERVICE), Some("StoreService"));
+    assert_eq!(Version::from_metadata(&metadata), Some(version));
+    metadata.insert(Version::FINGERPRINT, "not hex");
+    assert_eq!(Version::from_metadata(&metadata), None);
+}
+
+#[tokio::test]
+async fn flume_handshake() -> anyhow::Result<()> {
+    let (server, client) = flume::connection::<StoreRequest, StoreResponse>(1);
+    let server = RpcServer::<StoreService, _>::new(server).with_handshake();
+    let server_handle = tokio::task::spawn(async move {
+        // the same version is accepted. The old client and the client without a version
+        // are rejected in between without ending the loop
+        for _ in 0..2 {
+            let (req, chan) = server.accept().await?;
+            match req {
+                StoreRequest::Get(req) => {
+                    chan.rpc(req, (), |_, Get(n)| async move {
+                        GetResponse(vec![0; n as usize])
+                    })
+                    .await?
+                }
+                _ => anyhow::bail!("unexpected request"),
+            }
+        }
+        anyhow::Ok(())
+    });
+    let same = RpcClient::<StoreService, _>::new(client.clone()).with_handshake();
+    assert_eq!(same.rpc(Get(1)).await?, GetResponse(vec![0]));
+    let unversioned = RpcClient::<StoreService, _>::new(client.clone());
+    assert!(matches!(
+        unversioned.rpc(Get(2)).await,
+        Err(RpcClientError::RecvError(flume::RecvError::Rejected(
+            Status::FailedPrecondition
+        )))
+    ));
+    let old = RpcClient::<OldStoreService, _>::new(client).with_handshake();
+    match old.rpc(Get(3)).await {
+        Err(RpcClientError::IncompatibleVersion(version)) => {
+            assert_eq!(version, Version::of::<OldStoreService>());
+        }
+        other => panic!("expected an incompatible version, got {other:?}"),
+    }
+    assert_eq!(same.rpc(Get(4)).await?, GetResponse(vec![0; 4]));
+    server_handle.await??;
+    Ok(())
+}